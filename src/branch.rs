@@ -0,0 +1,219 @@
+use crate::{
+    commands::worktree_update, error::OzError, git_repo::GitRepository, hash::ObjectId, refs,
+};
+
+/// Lists the branches under refs/heads, sorted by name
+pub(crate) fn branch_list(repo: &GitRepository) -> Result<Vec<(String, ObjectId)>, OzError> {
+    Ok(refs::list(repo, "refs/heads/")?
+        .into_iter()
+        .map(|(name, sha)| (name["refs/heads/".len()..].to_string(), sha))
+        .collect())
+}
+
+/// Creates the branch `name` on the commit `start`. With `force` an existing
+/// branch is moved, unless HEAD is on it.
+pub(crate) fn branch_create(
+    repo: &GitRepository,
+    name: &str,
+    start: &str,
+    force: bool,
+) -> Result<ObjectId, OzError> {
+    let reference = branch_ref(name)?;
+    if refs::read(repo, &reference)?.is_some() {
+        if !force {
+            Err(OzError::Refused(format!(
+                "a branch named '{name}' already exists"
+            )))?;
+        }
+        if refs::current_branch(repo)?.as_deref() == Some(name) {
+            Err(OzError::Refused(
+                "cannot force update the current branch".to_string(),
+            ))?;
+        }
+    }
+    let sha = repo.object_peel_to(repo.rev_parse(start)?, "commit")?;
+    refs::update(repo, &reference, &sha, None)?;
+    Ok(sha)
+}
+
+/// Deletes the branch `name`, returning the sha it pointed at. Unless
+/// `force`, its commits must all be reachable from HEAD.
+pub(crate) fn branch_delete(
+    repo: &GitRepository,
+    name: &str,
+    force: bool,
+) -> Result<ObjectId, OzError> {
+    let reference = branch_ref(name)?;
+    let sha = refs::resolve(repo, &reference)?
+        .ok_or_else(|| OzError::NotFound(format!("branch '{name}' not found.")))?;
+    if refs::current_branch(repo)?.as_deref() == Some(name) {
+        Err(OzError::Refused(format!(
+            "Cannot delete branch '{name}' checked out at '{}'",
+            repo.worktree.display()
+        )))?;
+    }
+    if !force {
+        let merged = match refs::resolve(repo, "HEAD")? {
+            Some(head) => repo.merge_bases(&sha, &head)?.contains(&sha),
+            None => false,
+        };
+        if !merged {
+            Err(OzError::Refused(format!(
+                "The branch '{name}' is not fully merged.\nIf you are sure you want to delete it, run 'oz branch -D {name}'."
+            )))?;
+        }
+    }
+    refs::delete(repo, &reference)?;
+    Ok(sha)
+}
+
+/// Renames the branch `old` to `new`, replacing an existing `new` only with
+/// `force`. HEAD follows when it is on `old`.
+pub(crate) fn branch_rename(
+    repo: &GitRepository,
+    old: &str,
+    new: &str,
+    force: bool,
+) -> Result<(), OzError> {
+    let (old_ref, new_ref) = (branch_ref(old)?, branch_ref(new)?);
+    let sha = refs::resolve(repo, &old_ref)?
+        .ok_or_else(|| OzError::NotFound(format!("No branch named '{old}'.")))?;
+    if old == new {
+        return Ok(());
+    }
+    if refs::read(repo, &new_ref)?.is_some() && !force {
+        Err(OzError::Refused(format!(
+            "a branch named '{new}' already exists"
+        )))?;
+    }
+    refs::update(repo, &new_ref, &sha, None)?;
+    refs::delete(repo, &old_ref)?;
+    if refs::current_branch(repo)?.as_deref() == Some(old) {
+        refs::write_symbolic(repo, "HEAD", &new_ref)?;
+    }
+    Ok(())
+}
+
+/// Puts HEAD on the branch `name`, moving the worktree and the index to its
+/// tree. With `start`, the branch is first created on that commit.
+pub(crate) fn switch(repo: &GitRepository, name: &str, start: Option<&str>) -> Result<(), OzError> {
+    let reference = branch_ref(name)?;
+    let head = refs::resolve(repo, "HEAD")?;
+    let target = match start {
+        Some(_) if refs::read(repo, &reference)?.is_some() => Err(OzError::Refused(format!(
+            "a branch named '{name}' already exists"
+        )))?,
+        // Before the first commit, the new branch is born with it
+        Some("HEAD") if head.is_none() => None,
+        Some(start) => Some(repo.object_peel_to(repo.rev_parse(start)?, "commit")?),
+        None => Some(
+            refs::resolve(repo, &reference)?
+                .ok_or_else(|| OzError::NotFound(format!("invalid reference: {name}")))?,
+        ),
+    };
+
+    if let Some(target) = &target {
+        move_head(repo, head, target)?;
+        if start.is_some() {
+            refs::update(repo, &reference, target, Some(None))?;
+        }
+    }
+    refs::write_symbolic(repo, "HEAD", &reference)
+}
+
+/// Detaches HEAD at the commit `rev`, moving the worktree and the index to
+/// its tree. Returns the commit.
+pub(crate) fn switch_detach(repo: &GitRepository, rev: &str) -> Result<ObjectId, OzError> {
+    let target = repo.object_peel_to(repo.rev_parse(rev)?, "commit")?;
+    move_head(repo, refs::resolve(repo, "HEAD")?, &target)?;
+    refs::write_direct(repo, "HEAD", &target)?;
+    Ok(target)
+}
+
+/// Moves the worktree and the index from the tree of `head` to the one of
+/// `target`
+fn move_head(
+    repo: &GitRepository,
+    head: Option<ObjectId>,
+    target: &ObjectId,
+) -> Result<(), OzError> {
+    let old = head
+        .map(|head| repo.object_peel_to(head, "tree"))
+        .transpose()?;
+    let new = repo.object_peel_to(*target, "tree")?;
+    if old.as_ref() == Some(&new) {
+        return Ok(());
+    }
+    worktree_update(repo, old.as_ref(), &new)
+}
+
+fn branch_ref(name: &str) -> Result<String, OzError> {
+    let reference = format!("refs/heads/{name}");
+    refs::check_ref_name(&reference)
+        .map_err(|_| OzError::InvalidArgument(format!("'{name}' is not a valid branch name")))?;
+    Ok(reference)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::tests::{commit, temp_repo};
+    use std::fs;
+
+    #[test]
+    fn switch_keeps_local_changes_to_unchanged_files() {
+        let repo = temp_repo("switch");
+        let base = commit(
+            &repo,
+            &[("changed", "one\n"), ("same", "same\n")],
+            &[],
+            0,
+            "base\n",
+        );
+        let topic = commit(
+            &repo,
+            &[("added", "new\n"), ("changed", "two\n"), ("same", "same\n")],
+            &[],
+            0,
+            "topic\n",
+        );
+        branch_create(&repo, "topic", &topic.to_string(), false).unwrap();
+        branch_create(&repo, "base", &base.to_string(), false).unwrap();
+        let read = |name: &str| fs::read_to_string(repo.worktree.join(name)).unwrap();
+
+        // From before the first commit, everything is written
+        switch(&repo, "topic", None).unwrap();
+        assert_eq!(read("added"), "new\n");
+        // Local changes to a file the switch doesn't touch are carried
+        fs::write(repo.worktree.join("same"), "local\n").unwrap();
+        switch(&repo, "base", None).unwrap();
+        assert_eq!(refs::resolve(&repo, "HEAD").unwrap(), Some(base));
+        assert_eq!(read("changed"), "one\n");
+        assert!(!repo.worktree.join("added").exists());
+        assert_eq!(read("same"), "local\n");
+
+        // Nothing moves when a file that changes has local changes
+        fs::write(repo.worktree.join("changed"), "local\n").unwrap();
+        fs::write(repo.worktree.join("added"), "untracked\n").unwrap();
+        let Err(OzError::Refused(reason)) = switch(&repo, "topic", None) else {
+            panic!("switched over local changes");
+        };
+        assert!(reason.contains("\tchanged\n") && reason.contains("\tadded\n"));
+        assert_eq!(
+            refs::current_branch(&repo).unwrap().as_deref(),
+            Some("base")
+        );
+        assert_eq!(read("changed"), "local\n");
+
+        assert!(branch_delete(&repo, "topic", false).is_err());
+        branch_rename(&repo, "base", "main", false).unwrap();
+        assert_eq!(
+            refs::current_branch(&repo).unwrap().as_deref(),
+            Some("main")
+        );
+        assert_eq!(
+            branch_list(&repo).unwrap(),
+            [("main".to_string(), base), ("topic".to_string(), topic)]
+        );
+    }
+}
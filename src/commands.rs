@@ -1,22 +1,43 @@
-use crate::git_repo::{
-    GitBlob, GitCommit, GitObject, GitRepository, GitTag, GitTree, Serializable,
+use crate::{
+    error::OzError,
+    git_repo::{
+        FileMode, GitBlob, GitCommit, GitIndex, GitIndexEntry, GitObject, GitRepository, GitTag,
+        GitTree, Serializable, Signature, TreeEntry,
+    },
+    hash::{ObjectFormat, ObjectId},
+    ignore::{GitIgnore, IgnorePattern},
+    log::parse_date,
+    pack::{PackObject, pack_write},
+    refs,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
+    fs,
+    io::Write,
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::{MetadataExt, OpenOptionsExt},
+    },
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime},
 };
-use std::path::PathBuf;
 
-pub(crate) fn repo_create(path: PathBuf) -> Result<GitRepository, String> {
-    let repo = GitRepository::new(path.into(), true).unwrap();
+pub(crate) fn repo_create(path: PathBuf, format: ObjectFormat) -> Result<GitRepository, OzError> {
+    let mut repo = GitRepository::new(path, true)?;
+    repo.format = format;
     if repo.worktree.exists() {
         if !repo.worktree.is_dir() {
-            return Err("Not a directory".to_string());
+            return Err(OzError::InvalidArgument("Not a directory".to_string()));
         }
         if repo.git_dir.exists() {
             let x = std::fs::read_dir(&repo.git_dir); // Assuming it exist
             if x.is_err() || x.unwrap().next().is_some() {
-                return Err("Not an empty repo".to_string());
+                return Err(OzError::Refused("Not an empty repo".to_string()));
             }
         }
     } else {
-        std::fs::create_dir_all(&repo.worktree).expect("Unable to create the project root");
+        std::fs::create_dir_all(&repo.worktree)?;
     }
 
     // Create Required Directories
@@ -29,55 +50,1307 @@ pub(crate) fn repo_create(path: PathBuf) -> Result<GitRepository, String> {
     std::fs::write(
         repo.repo_file("description".into()),
         "Unnamed repository; edit this file 'description' to name the repository.\n",
-    )
-    .map_err(|e| e.to_string())?;
+    )?;
 
     // .git/HEAD
-    std::fs::write(repo.repo_file("HEAD".into()), "ref: refs/heads/master\n")
-        .map_err(|e| e.to_string())?;
+    refs::write_symbolic(&repo, "HEAD", "refs/heads/master")?;
 
     // .git/config
-    GitRepository::default_config()
-        .write(repo.repo_file("config".into()))
-        .map_err(|e| e.to_string())?;
+    GitRepository::default_config(format).write(repo.repo_file("config".into()))?;
 
     Ok(repo)
 }
 
-pub(crate) fn repo_find() -> Result<GitRepository, String> {
-    // Finds the .git folder for the current dir
-    let mut node: PathBuf = ".".into();
-    node = node.canonicalize().map_err(|e| e.to_string())?;
-    for path in node.ancestors() {
-        if path.join(".git").exists() {
-            // This is what we have been looking
-            return Ok(GitRepository::new(path.into(), false)?);
+/// Hashes `data` as an object of `obj_type` with `format`, storing it in
+/// `repo` if given (whose format must then be `format`)
+pub(crate) fn hash_object(
+    repo: Option<&GitRepository>,
+    format: ObjectFormat,
+    obj_type: &str,
+    data: Vec<u8>,
+) -> Result<ObjectId, OzError> {
+    let x = match obj_type {
+        "blob" => GitObject::Blob(GitBlob::from(data)),
+        "tree" => GitObject::Tree(GitTree::parse(&data, format)?),
+        "commit" => GitObject::Commit(GitCommit::try_from(data)?),
+        "tag" => GitObject::Tag(GitTag::try_from(data)?),
+        _ => Err(OzError::InvalidArgument(format!(
+            "Unknown object type {obj_type}"
+        )))?,
+    };
+
+    match repo {
+        Some(repo) => GitRepository::object_write(Some(repo), x),
+        None => {
+            let obj_type = x.obj_type();
+            Ok(GitRepository::object_hash(format, obj_type, &x.serialize()))
+        }
+    }
+}
+
+/// Converts a path given on the command line (relative to the current
+/// directory) to a path relative to the worktree, using `/` as separator.
+pub(crate) fn worktree_path(repo: &GitRepository, path: &str) -> Result<Vec<u8>, OzError> {
+    let cwd = std::env::current_dir()?;
+    let mut full = PathBuf::new();
+    for component in cwd.join(path).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                full.pop();
+            }
+            component => full.push(component),
         }
     }
-    return Err("Not a .git repository (or any of the parent directories): .git".to_string());
+    let relative = full
+        .strip_prefix(&repo.worktree)
+        .map_err(|_| OzError::InvalidArgument(format!("'{path}' is outside repository")))?;
+    let parts: Vec<&[u8]> = relative
+        .components()
+        .map(|part| part.as_os_str().as_bytes())
+        .collect();
+    Ok(parts.join(&b'/'))
 }
 
-pub(crate) fn cat_file(repo: GitRepository, sha: String, obj_type: String) -> Result<(), String> {
-    let obj = GitRepository::object_read(&repo, &repo.object_find(sha, obj_type))
-        .ok_or("Unable to read Object")?;
+/// Stages the given files, or everything below the given directories.
+/// Tracked files that were deleted from the worktree are removed from the index.
+pub(crate) fn add(repo: &GitRepository, paths: Vec<String>) -> Result<(), OzError> {
+    let mut index = repo.index_read()?;
+    let mut ignore = GitIgnore::new(repo);
+    for path in paths {
+        let name = worktree_path(repo, &path)?;
+        let prefix = if name.is_empty() {
+            name.clone()
+        } else {
+            [&name[..], b"/"].concat()
+        };
+        match fs::symlink_metadata(repo.worktree_file(&name)) {
+            Ok(meta) if meta.is_dir() => add_dir(repo, &mut index, &mut ignore, &name)?,
+            Ok(meta) => {
+                if index.get(&name).is_none()
+                    && ignore.is_ignored(&String::from_utf8_lossy(&name), false)
+                {
+                    Err(OzError::Refused(format!(
+                        "The path '{path}' is ignored by one of your .gitignore files"
+                    )))?;
+                }
+                add_file(repo, &mut index, &name, &meta)?;
+            }
+            Err(_) => {
+                if !index
+                    .entries
+                    .iter()
+                    .any(|entry| entry.name == name || entry.name.starts_with(&prefix))
+                {
+                    Err(OzError::NotFound(format!(
+                        "pathspec '{path}' did not match any files"
+                    )))?;
+                }
+            }
+        }
 
-    let content = obj.serialize();
-    content.iter().for_each(|&ch| print!("{}", ch as char));
+        // Stage the deletion of tracked files that are gone
+        let deleted: Vec<Vec<u8>> = index
+            .entries
+            .iter()
+            .filter(|entry| entry.name == name || entry.name.starts_with(&prefix))
+            .filter(|entry| fs::symlink_metadata(repo.worktree_file(&entry.name)).is_err())
+            .map(|entry| entry.name.clone())
+            .collect();
+        for name in deleted {
+            index.remove(&name);
+        }
+    }
+    repo.index_write(&index)
+}
+
+fn add_dir(
+    repo: &GitRepository,
+    index: &mut GitIndex,
+    ignore: &mut GitIgnore,
+    dir: &[u8],
+) -> Result<(), OzError> {
+    let mut entries = fs::read_dir(repo.worktree_file(dir))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let file_name = entry.file_name().into_vec();
+        if file_name == b".git" {
+            continue;
+        }
+        let name = if dir.is_empty() {
+            file_name
+        } else {
+            [dir, b"/", &file_name].concat()
+        };
+        let lossy = String::from_utf8_lossy(&name);
+        let meta = entry.metadata()?;
+
+        if meta.is_dir() {
+            // Nested repositories are left alone
+            if entry.path().join(".git").exists() {
+                continue;
+            }
+            let prefix = [&name[..], b"/"].concat();
+            let tracked = index
+                .entries
+                .iter()
+                .any(|entry| entry.name.starts_with(&prefix));
+            if tracked || !ignore.is_ignored(&lossy, true) {
+                add_dir(repo, index, ignore, &name)?;
+            }
+        } else if index.get(&name).is_some() || !ignore.is_ignored(&lossy, false) {
+            add_file(repo, index, &name, &meta)?;
+        }
+    }
     Ok(())
 }
 
-pub(crate) fn hash_object(
-    repo: Option<GitRepository>,
-    obj_type: String,
-    data: Vec<u8>,
-) -> Result<String, String> {
-    let x = match obj_type.as_str() {
-        "blob" => GitObject::Blob(GitBlob::from(data)),
-        "tree" => GitObject::Tree(GitTree::from(data)),
-        "commit" => GitObject::Commit(GitCommit::from(data)),
-        "tag" => GitObject::Tag(GitTag::from(data)),
-        _ => Err("Unknown object type encountered")?,
+/// Writes the blob of the worktree file `name` and records it in the index
+fn add_file(
+    repo: &GitRepository,
+    index: &mut GitIndex,
+    name: &[u8],
+    meta: &fs::Metadata,
+) -> Result<(), OzError> {
+    let data = worktree_data(&repo.worktree_file(name), meta)?;
+    let sha = GitRepository::object_write(Some(repo), GitObject::Blob(GitBlob::from(data)))?;
+
+    let mut entry = GitIndexEntry::from_metadata(name, sha, meta);
+    // Without core.filemode the executable bit of the worktree can't be trusted
+    let filemode = repo.conf.getbool("core", "filemode").ok().flatten();
+    if filemode == Some(false) && entry.mode != 0o120000 {
+        entry.mode = index.get(name).map_or(0o100644, |old| old.mode);
+    }
+    index.add(entry);
+    Ok(())
+}
+
+/// Finds the pattern deciding whether each of `paths` is ignored. Tracked
+/// files are never ignored, and paths no pattern matches get `None`.
+pub(crate) fn check_ignore(
+    repo: &GitRepository,
+    paths: &[String],
+) -> Result<Vec<(String, Option<IgnorePattern>)>, OzError> {
+    let index = repo.index_read()?;
+    let mut ignore = GitIgnore::new(repo);
+    let mut result = Vec::new();
+    for path in paths {
+        let name = worktree_path(repo, path)?;
+        let pattern = if index.contains(&name) {
+            None
+        } else {
+            let is_dir = path.ends_with('/') || repo.worktree_file(&name).is_dir();
+            ignore
+                .check(&String::from_utf8_lossy(&name), is_dir)
+                .cloned()
+        };
+        result.push((path.clone(), pattern));
+    }
+    Ok(result)
+}
+
+/// Removes files from the index and, unless `cached`, from the worktree,
+/// returning the paths removed.
+///
+/// Unless `force`, nothing is touched when a file has local modifications or
+/// changes staged since HEAD. With `cached`, only a staged version that is in
+/// neither HEAD nor the worktree stops it, as it would be lost.
+pub(crate) fn rm(
+    repo: &GitRepository,
+    paths: Vec<String>,
+    cached: bool,
+    recursive: bool,
+    force: bool,
+) -> Result<Vec<String>, OzError> {
+    let mut index = repo.index_read()?;
+    let mut removed: Vec<Vec<u8>> = Vec::new();
+    for path in paths {
+        let name = worktree_path(repo, &path)?;
+        let prefix = if name.is_empty() {
+            name.clone()
+        } else {
+            [&name[..], b"/"].concat()
+        };
+        let matched: Vec<Vec<u8>> = index
+            .entries
+            .iter()
+            .filter(|entry| entry.name == name || entry.name.starts_with(&prefix))
+            .map(|entry| entry.name.clone())
+            .filter(|name| !removed.contains(name))
+            .collect();
+        if matched.is_empty() {
+            Err(OzError::NotFound(format!(
+                "pathspec '{path}' did not match any files"
+            )))?;
+        }
+        if !recursive && matched.iter().any(|other| *other != name) {
+            Err(OzError::Refused(format!(
+                "not removing '{path}' recursively without -r"
+            )))?;
+        }
+        removed.extend(matched);
+    }
+    removed.dedup();
+
+    if !force {
+        let mut head = HashMap::new();
+        if let Some(commit) = refs::resolve(repo, "HEAD")? {
+            let tree = repo.object_peel_to(commit, "tree")?;
+            for change in repo.diff_trees(None, Some(&tree), &[])? {
+                if let Some(entry) = change.new {
+                    head.insert(change.path, (entry.mode.raw(), entry.id));
+                }
+            }
+        }
+        for name in &removed {
+            let lossy = String::from_utf8_lossy(name);
+            let Some(entry) = index.get(name) else {
+                if cached {
+                    continue;
+                }
+                Err(OzError::Refused(format!("'{lossy}' has unmerged changes")))?
+            };
+            let path = repo.worktree_file(name);
+            let Ok(meta) = fs::symlink_metadata(&path) else {
+                continue;
+            };
+            let data = if meta.is_symlink() {
+                fs::read_link(&path)?.into_os_string().into_vec()
+            } else {
+                fs::read(&path)?
+            };
+            let local = GitRepository::object_hash(repo.format, "blob", &data) != entry.sha;
+            let staged = head.get(name) != Some(&(entry.mode, entry.sha));
+            if local && staged && !(cached && entry.intent_to_add) {
+                Err(OzError::Refused(format!(
+                    "'{lossy}' has staged content different from both the file and the HEAD\n(use -f to force removal)"
+                )))?;
+            } else if !cached && staged {
+                Err(OzError::Refused(format!(
+                    "'{lossy}' has changes staged in the index\n(use --cached to keep the file, or -f to force removal)"
+                )))?;
+            } else if !cached && local {
+                Err(OzError::Refused(format!(
+                    "'{lossy}' has local modifications\n(use --cached to keep the file, or -f to force removal)"
+                )))?;
+            }
+        }
+    }
+
+    let cwd = std::env::current_dir()?;
+    for name in &removed {
+        index.remove(name);
+        if !cached {
+            let path = repo.worktree_file(name);
+            if fs::symlink_metadata(&path).is_ok() {
+                fs::remove_file(&path)?;
+            }
+            // Clean up the directories left empty, except the one we are in
+            for dir in path.ancestors().skip(1) {
+                if cwd.starts_with(dir) || fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    repo.index_write(&index)?;
+    Ok(removed
+        .iter()
+        .map(|name| String::from_utf8_lossy(name).to_string())
+        .collect())
+}
+
+/// The author, committer and tagger of the objects made now, using
+/// `user.name` and `user.email` from the config.
+fn signature(repo: &GitRepository) -> Result<Signature, OzError> {
+    let (Some(name), Some(email)) = (
+        repo.config_get("user", "name"),
+        repo.config_get("user", "email"),
+    ) else {
+        return Err(OzError::Config(
+            "Author identity unknown: please set user.name and user.email in the config"
+                .to_string(),
+        ));
+    };
+
+    let now = chrono::Local::now();
+    Ok(Signature {
+        name,
+        email,
+        time: now.timestamp(),
+        offset: now.offset().local_minus_utc() / 60,
+    })
+}
+
+/// Records the staged tree as a new commit on top of HEAD and moves the
+/// current branch (or HEAD itself when detached) to it.
+pub(crate) fn commit(
+    repo: &GitRepository,
+    messages: Vec<String>,
+    allow_empty: bool,
+) -> Result<ObjectId, OzError> {
+    let signature = signature(repo)?;
+    let tree = repo.tree_from_index(&repo.index_read()?)?;
+    let parent = refs::resolve(repo, "HEAD")?;
+    if !allow_empty {
+        // A root commit changes nothing when its tree is empty
+        let (head_tree, reason) = match parent {
+            Some(parent) => (
+                repo.object_peel_to(parent, "tree")?,
+                "nothing to commit, working tree clean",
+            ),
+            None => (
+                GitRepository::object_hash(repo.format, "tree", b""),
+                "nothing to commit (create/copy files and use \"oz add\" to track)",
+            ),
+        };
+        if head_tree == tree {
+            Err(OzError::Refused(reason.to_string()))?;
+        }
+    }
+
+    let message = messages.join("\n\n").trim_end().to_string() + "\n";
+    let parents: Vec<ObjectId> = parent.into_iter().collect();
+    let commit = GitCommit::new(tree, &parents, &signature, &signature, &message);
+    let sha = GitRepository::object_write(Some(repo), GitObject::Commit(commit))?;
+
+    // Move the branch HEAD points to, or HEAD itself when detached
+    refs::update(repo, "HEAD", &sha, Some(parent.as_ref()))?;
+    Ok(sha)
+}
+
+/// Writes the tree `rev` resolves to (through commits and tags) in the
+/// directory `path`, which must be empty or not exist yet.
+pub(crate) fn checkout_into(repo: &GitRepository, rev: &str, path: &Path) -> Result<(), OzError> {
+    let tree = repo.object_peel_to(repo.rev_parse(rev)?, "tree")?;
+    let tree = match repo.object_read(&tree)? {
+        GitObject::Tree(tree) => tree,
+        object => Err(OzError::WrongObjectType {
+            sha: tree,
+            expected: "tree",
+            actual: object.obj_type(),
+        })?,
+    };
+
+    if path.exists() {
+        if !path.is_dir() {
+            Err(OzError::InvalidArgument("Not a directory".to_string()))?;
+        }
+        if path.read_dir()?.count() > 0 {
+            Err(OzError::Refused("The directory is not empty".to_string()))?;
+        }
+    } else {
+        fs::create_dir_all(path)?;
+    }
+    checkout_tree(repo, &tree, path)
+}
+
+fn checkout_tree(repo: &GitRepository, tree: &GitTree, path: &Path) -> Result<(), OzError> {
+    for entry in &tree.items {
+        let path = path.join(OsStr::from_bytes(&entry.name));
+        if entry.mode.is_tree() {
+            fs::create_dir(&path)?;
+            checkout_tree(repo, &repo.tree_read(&entry.id)?, &path)?;
+        } else {
+            file_write(repo, &path, entry.mode, &entry.id)?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the file of a tree entry at `path`: the blob `sha` as a regular
+/// or executable file, or as the target of a symlink. Submodules, whose
+/// commits aren't here, get an empty directory.
+fn file_write(
+    repo: &GitRepository,
+    path: &Path,
+    mode: FileMode,
+    sha: &ObjectId,
+) -> Result<(), OzError> {
+    if mode == FileMode::Gitlink {
+        fs::create_dir_all(path)?;
+        return Ok(());
+    }
+    let data = match repo.object_read(sha)? {
+        GitObject::Blob(blob) => blob.buffer,
+        object => Err(OzError::corrupt_object(format!(
+            "{} {sha} listed as a file",
+            object.obj_type()
+        )))?,
+    };
+    match mode {
+        FileMode::Symlink => std::os::unix::fs::symlink(OsStr::from_bytes(&data), path)?,
+        // Like git, the umask decides the permissions
+        _ => fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(if mode == FileMode::Executable {
+                0o777
+            } else {
+                0o666
+            })
+            .open(path)?
+            .write_all(&data)?,
+    }
+    Ok(())
+}
+
+/// Moves the worktree and the index from the tree `old` (`None` before the
+/// first commit) to the tree `new`. Local changes to the files that are the
+/// same in both trees are kept. Nothing is touched when a file that changes
+/// has local changes, or when an untracked file is in the way.
+pub(crate) fn worktree_update(
+    repo: &GitRepository,
+    old: Option<&ObjectId>,
+    new: &ObjectId,
+) -> Result<(), OzError> {
+    let mut index = repo.index_read()?;
+    if index.entries.iter().any(|entry| entry.stage != 0) {
+        Err(OzError::Refused(
+            "you need to resolve your current index first".to_string(),
+        ))?;
+    }
+    let index_mtime = index_mtime(repo);
+    let mut ignore = GitIgnore::new(repo);
+
+    let mut modified = Vec::new();
+    let mut untracked = Vec::new();
+    let mut updates = Vec::new();
+    for change in repo.diff_trees(old, Some(new), &[])? {
+        if !index_path_valid(&change.path) {
+            Err(OzError::Refused(format!(
+                "invalid path '{}'",
+                change.path_lossy()
+            )))?;
+        }
+        let lossy = change.path_lossy().to_string();
+        let path = change.path;
+        let side =
+            |entry: &Option<TreeEntry>| entry.as_ref().map(|entry| (entry.mode.raw(), entry.id));
+        let old = side(&change.old);
+        let staged = index.get(&path);
+        let staged_side = staged.map(|entry| (entry.mode, entry.sha));
+        if staged_side == side(&change.new) {
+            // Already staged, the worktree keeps whatever it has
+            continue;
+        }
+        match staged {
+            _ if staged_side != old => modified.push(lossy),
+            Some(entry) if !worktree_clean(repo, entry, index_mtime)? => modified.push(lossy),
+            Some(_) => {}
+            // A submodule may already be cloned there
+            None if change.new.as_ref().map(|entry| entry.mode) == Some(FileMode::Gitlink) => {}
+            None => untracked.extend(in_the_way(repo, &index, &mut ignore, &path)?),
+        }
+        updates.push((path, change.new));
+    }
+    if !modified.is_empty() || !untracked.is_empty() {
+        let mut reasons = Vec::new();
+        if !modified.is_empty() {
+            reasons.push(format!(
+                "Your local changes to the following files would be overwritten by checkout:\n\t{}\nPlease commit your changes or stash them before you switch branches.",
+                modified.join("\n\t")
+            ));
+        }
+        if !untracked.is_empty() {
+            reasons.push(format!(
+                "The following untracked working tree files would be overwritten by checkout:\n\t{}\nPlease move or remove them before you switch branches.",
+                untracked.join("\n\t")
+            ));
+        }
+        Err(OzError::Refused(reasons.join("\n")))?;
+    }
+
+    // Everything going away first, as a file may be where a directory goes
+    let cwd = std::env::current_dir()?;
+    for (path, _) in &updates {
+        index.remove(path);
+        let path = repo.worktree_file(path);
+        match fs::symlink_metadata(&path) {
+            // A submodule's directory goes only when empty
+            Ok(meta) if meta.is_dir() => _ = fs::remove_dir(&path),
+            Ok(_) => fs::remove_file(&path)?,
+            Err(_) => {}
+        }
+        for dir in path.ancestors().skip(1) {
+            if cwd.starts_with(dir) || fs::remove_dir(dir).is_err() {
+                break;
+            }
+        }
+    }
+    for (path, new) in updates {
+        let Some(new) = new else {
+            continue;
+        };
+        let full = repo.worktree_file(&path);
+        if let Some(parent) = full.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Only ignored files can be left where a file goes
+        match fs::symlink_metadata(&full) {
+            Ok(meta) if meta.is_dir() && new.mode != FileMode::Gitlink => {
+                fs::remove_dir_all(&full)?
+            }
+            Ok(meta) if !meta.is_dir() => fs::remove_file(&full)?,
+            _ => {}
+        }
+        file_write(repo, &full, new.mode, &new.id)?;
+        let meta = fs::symlink_metadata(&full)?;
+        let mut entry = GitIndexEntry::from_metadata(path, new.id, &meta);
+        entry.mode = new.mode.raw();
+        index.add(entry);
+    }
+    repo.index_write(&index)
+}
+
+/// Whether the index can hold `path`. Like git, empty, `.`, `..` and `.git`
+/// components are refused, as they would lead outside of the worktree.
+fn index_path_valid(path: &[u8]) -> bool {
+    path.split(|&ch| ch == b'/')
+        .all(|part| !matches!(part, b"" | b"." | b"..") && !part.eq_ignore_ascii_case(b".git"))
+}
+
+/// Whether the worktree file of `entry` is missing or has the staged content
+fn worktree_clean(
+    repo: &GitRepository,
+    entry: &GitIndexEntry,
+    index_mtime: (u32, u32),
+) -> Result<bool, OzError> {
+    let path = repo.worktree_file(&entry.name);
+    let Ok(meta) = fs::symlink_metadata(&path) else {
+        return Ok(true);
+    };
+    // A submodule's checkout is its own business
+    if entry.mode == 0o160000 {
+        return Ok(true);
+    }
+    if meta.is_dir() || (entry.mode == 0o120000) != meta.is_symlink() {
+        return Ok(false);
+    }
+    let filemode = repo.conf.getbool("core", "filemode").ok().flatten() != Some(false);
+    if filemode
+        && GitIndexEntry::from_metadata(&entry.name[..], entry.sha, &meta).mode != entry.mode
+    {
+        return Ok(false);
+    }
+    if entry.stat_matches(&meta) && entry.mtime < index_mtime {
+        return Ok(true);
+    }
+    let data = worktree_data(&path, &meta)?;
+    Ok(GitRepository::object_hash(repo.format, "blob", &data) == entry.sha)
+}
+
+/// The untracked files a new file at `path` would overwrite: one already
+/// there, those in a directory there, or a file where a directory is needed.
+/// Ignored files don't count.
+fn in_the_way(
+    repo: &GitRepository,
+    index: &GitIndex,
+    ignore: &mut GitIgnore,
+    path: &[u8],
+) -> Result<Vec<String>, OzError> {
+    let mut found = Vec::new();
+    for (end, _) in path.iter().enumerate().filter(|&(_, &ch)| ch == b'/') {
+        let dir = &path[..end];
+        let lossy = String::from_utf8_lossy(dir);
+        if fs::symlink_metadata(repo.worktree_file(dir)).is_ok_and(|meta| !meta.is_dir())
+            && !index.contains(dir)
+            && !ignore.is_ignored(&lossy, false)
+        {
+            found.push(lossy.to_string());
+        }
+    }
+    let lossy = String::from_utf8_lossy(path);
+    match fs::symlink_metadata(repo.worktree_file(path)) {
+        Ok(meta) if meta.is_dir() => {
+            untracked_files(repo, index, ignore, &[path, b"/"].concat(), &mut found)?;
+        }
+        Ok(_) if !ignore.is_ignored(&lossy, false) => found.push(lossy.to_string()),
+        _ => {}
+    }
+    Ok(found)
+}
+
+/// How a path differs from one side of the comparison to the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Modified,
+    Deleted,
+    TypeChanged,
+    Renamed,
+}
+
+impl Change {
+    /// The letter used by the short and porcelain formats
+    pub fn code(change: Option<Change>) -> char {
+        match change {
+            None => '.',
+            Some(Change::Added) => 'A',
+            Some(Change::Modified) => 'M',
+            Some(Change::Deleted) => 'D',
+            Some(Change::TypeChanged) => 'T',
+            Some(Change::Renamed) => 'R',
+        }
+    }
+}
+
+/// A tracked path whose HEAD, index and worktree versions are not all the same
+#[derive(Debug)]
+pub struct StatusEntry {
+    pub path: String,
+    /// HEAD compared to the index
+    pub staged: Option<Change>,
+    /// Index compared to the worktree
+    pub unstaged: Option<Change>,
+    /// `(mode, sha)` in HEAD, and in the index at stages 0 to 3
+    pub head: Option<(u32, ObjectId)>,
+    pub stages: [Option<(u32, ObjectId)>; 4],
+    pub worktree_mode: Option<u32>,
+}
+
+impl StatusEntry {
+    pub fn is_unmerged(&self) -> bool {
+        self.stages[1..].iter().any(Option::is_some)
+    }
+}
+
+#[derive(Debug)]
+pub struct Status {
+    /// `None` when HEAD is detached
+    pub branch: Option<String>,
+    /// `None` before the first commit
+    pub head: Option<ObjectId>,
+    pub entries: Vec<StatusEntry>,
+    /// Untracked files, and directories (ending with `/`) holding only
+    /// untracked files
+    pub untracked: Vec<String>,
+}
+
+/// When the index was written. Files modified in the same second can't be
+/// trusted by their stat data alone.
+pub(crate) fn index_mtime(repo: &GitRepository) -> (u32, u32) {
+    fs::metadata(repo.repo_file("index".into()))
+        .map(|meta| (meta.mtime() as u32, meta.mtime_nsec() as u32))
+        .unwrap_or_default()
+}
+
+/// The contents of a worktree file: a symlink's target, not what it points to
+pub(crate) fn worktree_data(path: &Path, meta: &fs::Metadata) -> Result<Vec<u8>, OzError> {
+    Ok(if meta.is_symlink() {
+        fs::read_link(path)?.into_os_string().into_vec()
+    } else {
+        fs::read(path)?
+    })
+}
+
+/// Compares HEAD with the index, and the index with the worktree.
+pub(crate) fn status(repo: &GitRepository) -> Result<Status, OzError> {
+    let branch = refs::current_branch(repo)?;
+    let head = refs::resolve(repo, "HEAD")?;
+
+    let mut head_files = BTreeMap::new();
+    if let Some(head) = &head {
+        repo.tree_flatten(&repo.object_peel_to(*head, "tree")?, "", &mut head_files)?;
+    }
+
+    let index = repo.index_read()?;
+    let index_mtime = index_mtime(repo);
+    let filemode = repo.conf.getbool("core", "filemode").ok().flatten() != Some(false);
+
+    let mut entries: BTreeMap<String, StatusEntry> = BTreeMap::new();
+    for entry in &index.entries {
+        let name = entry.name_lossy();
+        let status = entries
+            .entry(name.to_string())
+            .or_insert_with(|| StatusEntry {
+                path: name.to_string(),
+                staged: None,
+                unstaged: None,
+                head: head_files.remove(&*name),
+                stages: Default::default(),
+                worktree_mode: None,
+            });
+        // Intent-to-add entries only hold a placeholder
+        if !entry.intent_to_add {
+            status.stages[entry.stage as usize] = Some((entry.mode, entry.sha));
+        }
+        let path = repo.worktree_file(&entry.name);
+        // Submodules are directories, anything else there is a deleted file
+        let meta = fs::symlink_metadata(&path)
+            .ok()
+            .filter(|meta| !meta.is_dir() || entry.mode == 0o160000);
+        status.worktree_mode = meta.as_ref().map(|meta| match meta.is_dir() {
+            true => 0o160000,
+            false => GitIndexEntry::from_metadata(&entry.name[..], ObjectId::default(), meta).mode,
+        });
+        if entry.stage != 0 {
+            continue;
+        }
+
+        status.staged = match &status.head {
+            _ if entry.intent_to_add => None,
+            None => Some(Change::Added),
+            Some((mode, _)) if mode & 0o170000 != entry.mode & 0o170000 => {
+                Some(Change::TypeChanged)
+            }
+            Some((mode, sha)) if *mode != entry.mode || *sha != entry.sha => Some(Change::Modified),
+            Some(_) => None,
+        };
+
+        let (Some(meta), Some(worktree_mode)) = (meta, status.worktree_mode) else {
+            status.unstaged = Some(Change::Deleted);
+            continue;
+        };
+        if entry.intent_to_add {
+            status.unstaged = Some(Change::Added);
+            continue;
+        }
+        if entry.mode == 0o160000 {
+            if !meta.is_dir() {
+                status.unstaged = Some(Change::TypeChanged);
+            }
+            continue;
+        }
+        if (entry.mode == 0o120000) != meta.is_symlink() {
+            status.unstaged = Some(Change::TypeChanged);
+            continue;
+        }
+        if filemode && entry.mode != worktree_mode {
+            status.unstaged = Some(Change::Modified);
+            continue;
+        }
+        if entry.stat_matches(&meta) && entry.mtime < index_mtime {
+            continue;
+        }
+        let data = worktree_data(&path, &meta)?;
+        let sha = GitRepository::object_hash(repo.format, "blob", &data);
+        if sha != entry.sha {
+            status.unstaged = Some(Change::Modified);
+        }
+    }
+
+    // What is left in HEAD has been removed from the index
+    for (path, head) in head_files {
+        entries.insert(
+            path.clone(),
+            StatusEntry {
+                path,
+                staged: Some(Change::Deleted),
+                unstaged: None,
+                head: Some(head),
+                stages: Default::default(),
+                worktree_mode: None,
+            },
+        );
+    }
+
+    let mut untracked = Vec::new();
+    untracked_files(repo, &index, &mut GitIgnore::new(repo), b"", &mut untracked)?;
+
+    Ok(Status {
+        branch,
+        head,
+        entries: entries
+            .into_values()
+            .filter(|entry| {
+                entry.staged.is_some() || entry.unstaged.is_some() || entry.is_unmerged()
+            })
+            .collect(),
+        untracked,
+    })
+}
+
+/// Lists the untracked, not ignored, files below `dir`. Directories without
+/// any tracked file are reported as a whole.
+fn untracked_files(
+    repo: &GitRepository,
+    index: &GitIndex,
+    ignore: &mut GitIgnore,
+    dir: &[u8],
+    untracked: &mut Vec<String>,
+) -> Result<bool, OzError> {
+    let mut entries = fs::read_dir(repo.worktree_file(dir))?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut found = false;
+    for entry in entries {
+        let file_name = entry.file_name().into_vec();
+        if file_name == b".git" {
+            continue;
+        }
+        let name = [dir, &file_name].concat();
+        let lossy = String::from_utf8_lossy(&name).to_string();
+        let is_dir = entry.file_type()?.is_dir();
+        if !is_dir {
+            if !index.contains(&name) && !ignore.is_ignored(&lossy, false) {
+                untracked.push(lossy);
+                found = true;
+            }
+            continue;
+        }
+
+        // A submodule
+        if index.contains(&name) {
+            continue;
+        }
+        let prefix = [&name[..], b"/"].concat();
+        let tracked = index
+            .entries
+            .iter()
+            .any(|entry| entry.name.starts_with(&prefix));
+        if tracked {
+            found |= untracked_files(repo, index, ignore, &prefix, untracked)?;
+        } else if !ignore.is_ignored(&lossy, true)
+            && (entry.path().join(".git").exists()
+                || untracked_files(repo, index, ignore, &prefix, &mut Vec::new())?)
+        {
+            untracked.push(lossy + "/");
+            found = true;
+        }
+    }
+    Ok(found)
+}
+
+/// Lists the tags under refs/tags, sorted by name
+pub(crate) fn tag_list(repo: &GitRepository) -> Result<Vec<String>, OzError> {
+    Ok(refs::list(repo, "refs/tags/")?
+        .into_iter()
+        .map(|(name, _)| name["refs/tags/".len()..].to_string())
+        .collect())
+}
+
+/// Creates the tag `name` pointing at `object`. An annotated tag object is
+/// written when `messages` is not empty, otherwise the tag is just a ref.
+pub(crate) fn tag_create(
+    repo: &GitRepository,
+    name: &str,
+    object: String,
+    messages: Vec<String>,
+    force: bool,
+) -> Result<ObjectId, OzError> {
+    let reference = format!("refs/tags/{name}");
+    refs::check_ref_name(&reference)?;
+    if refs::read(repo, &reference)?.is_some() && !force {
+        Err(OzError::Refused(format!("tag '{name}' already exists")))?;
+    }
+    let target = repo.rev_parse(&object)?;
+
+    let sha = if messages.is_empty() {
+        target
+    } else {
+        let obj_type = repo.object_read(&target)?.obj_type();
+        let message = messages.join("\n\n").trim_end().to_string() + "\n";
+        let tag = GitTag::new(target, obj_type, name, &signature(repo)?, &message);
+        GitRepository::object_write(Some(repo), GitObject::Tag(tag))?
     };
 
-    Ok(GitRepository::object_write(repo, x).unwrap())
+    refs::update(repo, &reference, &sha, None)?;
+    Ok(sha)
+}
+
+/// Deletes the tag `name`, returning the sha it pointed at
+pub(crate) fn tag_delete(repo: &GitRepository, name: &str) -> Result<ObjectId, OzError> {
+    let reference = format!("refs/tags/{name}");
+    refs::check_ref_name(&reference)?;
+    let not_found = || OzError::NotFound(format!("tag '{name}' not found."));
+    if refs::read(repo, &reference)?.is_none() {
+        Err(not_found())?;
+    }
+    refs::delete(repo, &reference)?.ok_or_else(not_found)
+}
+
+/// The objects everything else is reachable from: the targets of the refs
+/// and HEAD, and the files of the index. Each comes with a description of
+/// where it was found (the ref name or the path).
+pub(crate) fn object_roots(repo: &GitRepository) -> Result<Vec<(ObjectId, String)>, OzError> {
+    let mut roots = refs::list(repo, "refs/")?;
+    if let Some(head) = refs::resolve(repo, "HEAD")? {
+        roots.push(("HEAD".to_string(), head));
+    }
+    for entry in repo.index_read()?.entries {
+        // Submodules' commits live in another repository
+        if entry.mode != 0o160000 {
+            roots.push((entry.name_lossy().to_string(), entry.sha));
+        }
+    }
+    Ok(roots.into_iter().map(|(name, sha)| (sha, name)).collect())
+}
+
+/// Walks the objects reachable from [`object_roots`]. Returns each of them
+/// with the path it was first seen at (empty for commits and tags), which
+/// helps finding delta bases.
+pub(crate) fn reachable_objects(
+    repo: &GitRepository,
+) -> Result<BTreeMap<ObjectId, String>, OzError> {
+    let mut pending: Vec<(ObjectId, String)> = object_roots(repo)?
+        .into_iter()
+        .map(|(sha, name)| {
+            // Only index entries have a path
+            let is_ref = name == "HEAD" || name.starts_with("refs/");
+            (sha, if is_ref { String::new() } else { name })
+        })
+        .collect();
+
+    let mut objects = BTreeMap::new();
+    while let Some((sha, path)) = pending.pop() {
+        if objects.contains_key(&sha) {
+            continue;
+        }
+        match repo.object_read(&sha)? {
+            GitObject::Commit(commit) => {
+                for sha in commit.tree().into_iter().chain(commit.parents()) {
+                    pending.push((sha, String::new()));
+                }
+            }
+            GitObject::Tree(tree) => {
+                for entry in tree.items {
+                    if entry.mode != FileMode::Gitlink {
+                        let name = entry.name_lossy();
+                        let path = if path.is_empty() {
+                            name.into_owned()
+                        } else {
+                            format!("{path}/{name}")
+                        };
+                        pending.push((entry.id, path));
+                    }
+                }
+            }
+            GitObject::Tag(tag) => {
+                if let Some(sha) = tag.object() {
+                    pending.push((sha, String::new()));
+                }
+            }
+            GitObject::Blob(_) => {}
+        }
+        objects.insert(sha, path);
+    }
+    Ok(objects)
+}
+
+/// Packs the reachable loose objects, or with `all` every reachable object,
+/// into a new pack and deletes the loose copies. With `all` the previous
+/// packs are deleted too: the unreachable objects they held are kept as
+/// loose objects, dated like their pack, for [`gc`] to prune.
+///
+/// Returns the name of the new pack, its number of objects and of deltas.
+pub(crate) fn repack(
+    repo: &GitRepository,
+    all: bool,
+    window: usize,
+    depth: usize,
+) -> Result<Option<(String, usize, usize)>, OzError> {
+    let reachable = reachable_objects(repo)?;
+    let loose: HashSet<ObjectId> = repo.loose_objects()?.into_iter().collect();
+    let mut objects = Vec::new();
+    for (sha, path) in &reachable {
+        if !all && (!loose.contains(sha) || repo.object_packed(sha)) {
+            continue;
+        }
+        let (obj_type, data) = repo
+            .object_read_raw(sha)?
+            .ok_or(OzError::ObjectNotFound(*sha))?;
+        objects.push(PackObject {
+            sha: *sha,
+            obj_type,
+            data,
+            name_hash: PackObject::name_hash(path),
+        });
+    }
+    if objects.is_empty() {
+        return Ok(None);
+    }
+
+    let count = objects.len();
+    let packed: Vec<ObjectId> = objects.iter().map(|object| object.sha).collect();
+    let dir = repo.repo_file("objects/pack".into());
+    let (name, deltas) = pack_write(&dir, objects, window, depth, repo.format)?;
+
+    if all {
+        for pack in repo.packs().iter() {
+            if pack.path.file_stem() == Some(name.as_ref()) {
+                continue;
+            }
+            let mtime = fs::metadata(&pack.path).and_then(|metadata| metadata.modified())?;
+            for i in 0..pack.len() {
+                let sha = pack.sha(i);
+                if !reachable.contains_key(&sha) {
+                    repo.object_loosen(&sha, mtime)?;
+                }
+            }
+            for ext in ["idx", "pack"] {
+                fs::remove_file(pack.path.with_extension(ext))?;
+            }
+        }
+    }
+    repo.packs_reload();
+
+    // Loose objects already in a pack are redundant
+    let packed: HashSet<ObjectId> = packed.into_iter().collect();
+    for sha in &loose {
+        if packed.contains(sha) || (!all && repo.object_packed(sha)) {
+            remove_loose(repo, sha)?;
+        }
+    }
+    Ok(Some((name, count, deltas)))
+}
+
+fn remove_loose(repo: &GitRepository, sha: &ObjectId) -> Result<(), OzError> {
+    let path = repo.loose_path(sha);
+    fs::remove_file(&path)?;
+    if let Some(dir) = path.parent() {
+        // Fails as long as other objects share the directory
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+/// Parses the `--prune` date of `gc`: `now`, `never` or `<n>.<unit>.ago`,
+/// e.g. `2.weeks.ago`
+pub(crate) fn prune_expiry(spec: &str) -> Result<Option<SystemTime>, OzError> {
+    match spec {
+        "never" => Ok(None),
+        "now" => Ok(Some(SystemTime::now())),
+        _ => {
+            let seconds = parse_date(spec)?.max(0) as u64;
+            Ok(SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds)))
+        }
+    }
+}
+
+/// Packs the refs and the objects, then deletes the unreachable loose objects
+/// older than `expiry`. Returns the number of objects pruned.
+pub(crate) fn gc(repo: &GitRepository, expiry: Option<SystemTime>) -> Result<usize, OzError> {
+    refs::pack(repo)?;
+    let reachable = reachable_objects(repo)?;
+    repack(repo, true, 10, 50)?;
+
+    let mut pruned = 0;
+    let Some(expiry) = expiry else {
+        return Ok(pruned);
+    };
+    for sha in repo.loose_objects()? {
+        if reachable.contains_key(&sha) {
+            continue;
+        }
+        let path = repo.loose_path(&sha);
+        let mtime = fs::metadata(&path).and_then(|metadata| metadata.modified())?;
+        if mtime <= expiry {
+            remove_loose(repo, &sha)?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::{
+        TreeEntry,
+        tests::{temp_repo, write_blob},
+    };
+
+    #[test]
+    fn status_reports_staged_unstaged_and_untracked() {
+        let repo = temp_repo("status");
+        let mut index = GitIndex::new();
+        fs::create_dir_all(repo.worktree.join("dir")).unwrap();
+        for name in ["kept", "changed", "dir/removed"] {
+            let path = repo.worktree.join(name);
+            fs::write(&path, name).unwrap();
+            add_file(
+                &repo,
+                &mut index,
+                name.as_bytes(),
+                &fs::symlink_metadata(&path).unwrap(),
+            )
+            .unwrap();
+        }
+        repo.index_write(&index).unwrap();
+        fs::write(repo.worktree.join("changed"), "new content").unwrap();
+        fs::remove_file(repo.worktree.join("dir/removed")).unwrap();
+        fs::create_dir_all(repo.worktree.join("new/deep")).unwrap();
+        fs::write(repo.worktree.join("new/deep/file"), "").unwrap();
+        fs::write(repo.worktree.join("untracked.log"), "").unwrap();
+        fs::write(repo.worktree.join(".gitignore"), "*.log\n").unwrap();
+
+        let status = status(&repo).unwrap();
+        assert_eq!(status.branch.as_deref(), Some("master"));
+        assert_eq!(status.head, None);
+        let changes: Vec<_> = status
+            .entries
+            .iter()
+            .map(|e| (e.path.as_str(), e.staged, e.unstaged))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                ("changed", Some(Change::Added), Some(Change::Modified)),
+                ("dir/removed", Some(Change::Added), Some(Change::Deleted)),
+                ("kept", Some(Change::Added), None),
+            ]
+        );
+        assert_eq!(status.untracked, [".gitignore", "new/"]);
+    }
+
+    #[test]
+    fn commit_refuses_to_change_nothing() {
+        let mut repo = temp_repo("commit");
+        repo.conf.set("user", "name", Some("A".into()));
+        repo.conf.set("user", "email", Some("a@b".into()));
+        let message = || vec!["msg".to_string()];
+
+        // Even the first commit needs something in it
+        assert!(matches!(
+            commit(&repo, message(), false),
+            Err(OzError::Refused(_))
+        ));
+        assert_eq!(refs::resolve(&repo, "HEAD").unwrap(), None);
+        let path = repo.worktree.join("file");
+        fs::write(&path, "data\n").unwrap();
+        add(&repo, vec![path.to_str().unwrap().to_string()]).unwrap();
+        let first = commit(&repo, message(), false).unwrap();
+
+        let Err(OzError::Refused(reason)) = commit(&repo, message(), false) else {
+            panic!("committed an unchanged tree");
+        };
+        assert_eq!(reason, "nothing to commit, working tree clean");
+        let second = commit(&repo, message(), true).unwrap();
+        assert_eq!(repo.commit_parents(&second).unwrap(), [first]);
+    }
+
+    #[test]
+    fn rm_keeps_staged_changes() {
+        let repo = temp_repo("rm");
+        let path = repo.worktree.join("new.txt");
+        let arg = vec![path.to_str().unwrap().to_string()];
+        fs::write(&path, "new\n").unwrap();
+        add(&repo, arg.clone()).unwrap();
+
+        // A file staged but not committed would be lost
+        let Err(OzError::Refused(reason)) = rm(&repo, arg.clone(), false, false, false) else {
+            panic!("removed a staged file");
+        };
+        assert!(reason.contains("has changes staged in the index"));
+        assert!(path.exists() && repo.index_read().unwrap().contains("new.txt"));
+
+        // With --cached, only a staged version found nowhere else is refused
+        fs::write(&path, "changed\n").unwrap();
+        let Err(OzError::Refused(reason)) = rm(&repo, arg.clone(), true, false, false) else {
+            panic!("removed a staged version from the index");
+        };
+        assert!(reason.contains("different from both the file and the HEAD"));
+        add(&repo, arg.clone()).unwrap();
+        assert_eq!(
+            rm(&repo, arg.clone(), true, false, false).unwrap(),
+            ["new.txt"]
+        );
+        assert!(path.exists() && !repo.index_read().unwrap().contains("new.txt"));
+
+        add(&repo, arg.clone()).unwrap();
+        rm(&repo, arg, false, false, true).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn worktree_update_writes_modes_in_place() {
+        let repo = temp_repo("worktree-update");
+        let blob = |data: &str| write_blob(&repo, data);
+        let tree = |items: Vec<TreeEntry>| {
+            GitRepository::object_write(Some(&repo), GitObject::Tree(GitTree { items })).unwrap()
+        };
+        let (script, target) = (blob("echo\n"), blob("script"));
+        let old = tree(vec![
+            TreeEntry::new(FileMode::Blob, "gone", blob("gone\n")),
+            TreeEntry::new(FileMode::Blob, "script", script),
+        ]);
+        let dir = tree(vec![
+            TreeEntry::new(FileMode::Blob, &b"caf\xe9"[..], blob("caf\n")),
+            TreeEntry::new(FileMode::Blob, "file", blob("file\n")),
+        ]);
+        let new = tree(vec![
+            TreeEntry::new(FileMode::Tree, "dir", dir),
+            TreeEntry::new(FileMode::Symlink, "link", target),
+            TreeEntry::new(FileMode::Executable, "script", script),
+            TreeEntry::new(FileMode::Gitlink, "sub", old),
+        ]);
+        worktree_update(&repo, None, &old).unwrap();
+        fs::write(repo.worktree.join("untracked"), "mine\n").unwrap();
+
+        worktree_update(&repo, Some(&old), &new).unwrap();
+        let path = |name: &str| repo.worktree.join(name);
+        assert!(!path("gone").exists());
+        assert_eq!(fs::read_to_string(path("dir/file")).unwrap(), "file\n");
+        // Names that aren't UTF-8 are kept as they are
+        assert_eq!(
+            fs::read(repo.worktree_file(b"dir/caf\xe9")).unwrap(),
+            b"caf\n"
+        );
+        assert_eq!(fs::read_link(path("link")).unwrap(), Path::new("script"));
+        assert_ne!(fs::metadata(path("script")).unwrap().mode() & 0o111, 0);
+        assert!(path("sub").is_dir());
+        assert_eq!(fs::read_to_string(path("untracked")).unwrap(), "mine\n");
+        assert_eq!(
+            repo.tree_from_index(&repo.index_read().unwrap()).unwrap(),
+            new
+        );
+        let status = status(&repo).unwrap();
+        assert!(status.entries.iter().all(|entry| entry.unstaged.is_none()));
+
+        // Nothing is touched when a local change would be lost
+        fs::write(path("dir/file"), "local\n").unwrap();
+        let Err(OzError::Refused(reason)) = worktree_update(&repo, Some(&new), &old) else {
+            panic!("local changes overwritten");
+        };
+        assert!(reason.contains("\tdir/file\n"));
+        assert!(path("link").is_symlink());
+        assert_eq!(fs::read_to_string(path("dir/file")).unwrap(), "local\n");
+
+        // Paths the index can't hold are refused rather than written
+        let escape = tree(vec![TreeEntry::new(FileMode::Blob, "..", blob("out\n"))]);
+        let Err(OzError::Refused(reason)) = worktree_update(&repo, Some(&new), &escape) else {
+            panic!("wrote outside of the worktree");
+        };
+        assert_eq!(reason, "invalid path '..'");
+    }
+
+    #[test]
+    fn gc_packs_reachable_and_prunes_unreachable_objects() {
+        let repo = temp_repo("gc");
+        let mut shas = Vec::new();
+        let mut head: Option<ObjectId> = None;
+        for i in 0..3 {
+            let text = format!("{}version {i}\n", "some shared text\n".repeat(20));
+            let parents: Vec<ObjectId> = head.into_iter().collect();
+            let sha =
+                crate::git_repo::tests::commit(&repo, &[("file", &text)], &parents, 0, "commit\n");
+            let tree = repo.commit_read(&sha).unwrap().tree().unwrap();
+            shas.extend([write_blob(&repo, &text), tree, sha]);
+            head = Some(sha);
+        }
+        refs::update(&repo, "HEAD", &head.unwrap(), None).unwrap();
+        let garbage = write_blob(&repo, "garbage");
+
+        let (_, count, deltas) = repack(&repo, false, 10, 50).unwrap().unwrap();
+        assert_eq!((count, deltas), (9, 2));
+        assert_eq!(repo.loose_objects().unwrap(), vec![garbage]);
+        assert_eq!(gc(&repo, prune_expiry("1.week.ago").unwrap()).unwrap(), 0);
+        assert_eq!(gc(&repo, prune_expiry("now").unwrap()).unwrap(), 1);
+        assert_eq!(repo.loose_objects().unwrap(), Vec::<ObjectId>::new());
+
+        // A fresh repository sees the new pack
+        let repo = GitRepository::new(repo.worktree.clone(), false).unwrap();
+        for sha in &shas {
+            assert!(repo.object_packed(sha), "{sha}");
+            assert!(repo.object_read(sha).is_ok(), "{sha}");
+        }
+        assert!(matches!(
+            repo.object_read(&garbage),
+            Err(OzError::ObjectNotFound(_))
+        ));
+        assert!(refs::read(&repo, "refs/heads/master").unwrap().is_some());
+        assert!(!repo.repo_file("refs/heads/master".into()).exists());
+    }
 }
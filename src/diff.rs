@@ -0,0 +1,2383 @@
+use crate::{
+    commands::{Change, index_mtime, worktree_data},
+    error::OzError,
+    git_repo::{GitBlob, GitIndexEntry, GitObject, GitRepository, TreeChange, TreeEntry},
+    hash::ObjectId,
+};
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap},
+    fs,
+    ops::Range,
+};
+
+/// How lines are matched between the two versions of a file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DiffAlgorithm {
+    /// git's default: a minimal diff, with git's speedups for big files
+    #[default]
+    Myers,
+    /// Anchors the diff on lines that are rare in the old version, which
+    /// often reads better for moved code
+    Histogram,
+}
+
+impl DiffAlgorithm {
+    /// Parses the names of `--diff-algorithm` and `diff.algorithm`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "myers" | "default" => Some(DiffAlgorithm::Myers),
+            "histogram" => Some(DiffAlgorithm::Histogram),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Lines of context around the changes
+    pub context: usize,
+    pub algorithm: DiffAlgorithm,
+    /// Pair deleted and added files with the same or similar content
+    pub renames: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            context: 3,
+            algorithm: DiffAlgorithm::default(),
+            renames: true,
+        }
+    }
+}
+
+/// The lines `old` of the old version, replaced by the lines `new` of the new
+/// version. One of them may be empty.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub old: Range<usize>,
+    pub new: Range<usize>,
+}
+
+/// Splits `data` in lines, each ending with its `\n` except maybe the last
+pub fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    data.split_inclusive(|&byte| byte == b'\n').collect()
+}
+
+/// Whether `data` is binary rather than text, which git decides by looking
+/// for a NUL byte at the start
+pub fn is_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|&byte| byte == 0)
+}
+
+/// The edits turning the lines of `old` into the lines of `new`, in order
+pub fn diff_lines(old: &[u8], new: &[u8], algorithm: DiffAlgorithm) -> Vec<Edit> {
+    let old = split_lines(old);
+    let new = split_lines(new);
+    let (changed_old, changed_new) = changed_lines(&old, &new, algorithm);
+    edits(&changed_old, &changed_new)
+}
+
+/// The hunks of the unified diff from `old` to `new`, each starting with its
+/// `@@` line. Empty when the contents are the same.
+pub fn unified_diff(old: &[u8], new: &[u8], options: &DiffOptions) -> Vec<u8> {
+    let old = split_lines(old);
+    let new = split_lines(new);
+    let (changed_old, changed_new) = changed_lines(&old, &new, options.algorithm);
+    let edits = edits(&changed_old, &changed_new);
+
+    let mut out = Vec::new();
+    let context = options.context;
+    // The heading of the previous hunk stays until a new one is found
+    let mut heading: Option<&[u8]> = None;
+    let mut searched_to = 0;
+    let mut i = 0;
+    while i < edits.len() {
+        // Changes separated by at most twice the context share a hunk
+        let mut last = i;
+        while last + 1 < edits.len()
+            && edits[last + 1].old.start - edits[last].old.end <= 2 * context
+        {
+            last += 1;
+        }
+        let old_start = edits[i].old.start.saturating_sub(context);
+        let new_start = edits[i].new.start.saturating_sub(context);
+        let old_end = (edits[last].old.end + context).min(old.len());
+        let new_end = (edits[last].new.end + context).min(new.len());
+
+        if let Some(found) = (searched_to..old_start)
+            .rev()
+            .find_map(|line| function_line(old[line]))
+        {
+            heading = Some(found);
+        }
+        searched_to = old_start;
+        out.extend_from_slice(b"@@ -");
+        out.extend(hunk_range(old_start, old_end - old_start).bytes());
+        out.extend_from_slice(b" +");
+        out.extend(hunk_range(new_start, new_end - new_start).bytes());
+        out.extend_from_slice(b" @@");
+        if let Some(heading) = heading {
+            out.push(b' ');
+            out.extend_from_slice(heading);
+        }
+        out.push(b'\n');
+
+        let mut old_line = old_start;
+        for edit in &edits[i..=last] {
+            for line in &old[old_line..edit.old.start] {
+                push_line(&mut out, b' ', line);
+            }
+            for line in &old[edit.old.clone()] {
+                push_line(&mut out, b'-', line);
+            }
+            for line in &new[edit.new.clone()] {
+                push_line(&mut out, b'+', line);
+            }
+            old_line = edit.old.end;
+        }
+        for line in &old[old_line..old_end] {
+            push_line(&mut out, b' ', line);
+        }
+        i = last + 1;
+    }
+    out
+}
+
+/// `start,count` of a hunk header, from a 0-based start
+fn hunk_range(start: usize, count: usize) -> String {
+    match count {
+        // An empty range names the line before it
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{count}", start + 1),
+    }
+}
+
+fn push_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+/// The heading git shows after a hunk header when `line` is the closest one
+/// above the hunk starting with a letter, `_` or `$`
+fn function_line(line: &[u8]) -> Option<&[u8]> {
+    let first = *line.first()?;
+    if !(first.is_ascii_alphabetic() || first == b'_' || first == b'$') {
+        return None;
+    }
+    let line = &line[..line.len().min(80)];
+    Some(line.trim_ascii_end())
+}
+
+/// Which lines of `old` and `new` are changed
+fn changed_lines<'a>(
+    old: &[&'a [u8]],
+    new: &[&'a [u8]],
+    algorithm: DiffAlgorithm,
+) -> (Vec<bool>, Vec<bool>) {
+    // Lines are compared through numbers, equal for equal lines
+    let mut classes: HashMap<&[u8], u32> = HashMap::new();
+    let mut ids = |lines: &[&'a [u8]]| -> Vec<u32> {
+        lines
+            .iter()
+            .map(|line| {
+                let next = classes.len() as u32;
+                *classes.entry(*line).or_insert(next)
+            })
+            .collect()
+    };
+    let old_ids = ids(old);
+    let new_ids = ids(new);
+
+    let mut changed_old = vec![false; old.len()];
+    let mut changed_new = vec![false; new.len()];
+    match algorithm {
+        DiffAlgorithm::Myers => myers(&old_ids, &new_ids, &mut changed_old, &mut changed_new),
+        DiffAlgorithm::Histogram => {
+            histogram(&old_ids, &new_ids, &mut changed_old, &mut changed_new)
+        }
+    }
+    compact(old, &old_ids, &mut changed_old, &changed_new);
+    compact(new, &new_ids, &mut changed_new, &changed_old);
+    (changed_old, changed_new)
+}
+
+/// Groups the changed lines in edits. Unchanged lines pair up in order.
+fn edits(changed_old: &[bool], changed_new: &[bool]) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < changed_old.len() || j < changed_new.len() {
+        let changed = |changed: &[bool], i: usize| changed.get(i).copied().unwrap_or(false);
+        if changed(changed_old, i) || changed(changed_new, j) {
+            let (old_start, new_start) = (i, j);
+            while changed(changed_old, i) {
+                i += 1;
+            }
+            while changed(changed_new, j) {
+                j += 1;
+            }
+            edits.push(Edit {
+                old: old_start..i,
+                new: new_start..j,
+            });
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+    edits
+}
+
+/// Past this many equal lines, a line is too common to guide the diff
+const MAX_EQUAL_LIMIT: usize = 1024;
+/// How far around a common line to look for lines without a match
+const SIMILAR_SCAN_WINDOW: usize = 100;
+/// A common line is ignored when this many times fewer than the lines
+/// without a match around it
+const COMMON_RUN_RATIO: usize = 4;
+/// The edit cost past which the search settles for a good enough path
+const MIN_HEURISTIC_COST: i64 = 256;
+const MIN_MAX_COST: i64 = 256;
+/// The length of a run of equal lines counting as a good path
+const SNAKE_LENGTH: i64 = 20;
+const HEURISTIC_FACTOR: i64 = 4;
+
+/// git's integer square root approximation
+fn bogo_sqrt(mut n: usize) -> usize {
+    let mut root = 1;
+    while n > 0 {
+        root <<= 1;
+        n >>= 2;
+    }
+    root
+}
+
+/// Marks the lines Myers' algorithm finds changed, like git's xdiff: equal
+/// ends are skipped, then lines without a match in the other version are
+/// changed for sure, as are common lines among them, and the rest is
+/// compared by splitting it at the middle of the shortest path.
+fn myers(old: &[u32], new: &[u32], changed_old: &mut [bool], changed_new: &mut [bool]) {
+    let mut start = 0;
+    while start < old.len() && start < new.len() && old[start] == new[start] {
+        start += 1;
+    }
+    let mut end = 0;
+    while end < old.len() - start
+        && end < new.len() - start
+        && old[old.len() - 1 - end] == new[new.len() - 1 - end]
+    {
+        end += 1;
+    }
+
+    let mut in_old: HashMap<u32, usize> = HashMap::new();
+    let mut in_new: HashMap<u32, usize> = HashMap::new();
+    for id in old {
+        *in_old.entry(*id).or_default() += 1;
+    }
+    for id in new {
+        *in_new.entry(*id).or_default() += 1;
+    }
+    let (old_kept, old_index) = discard(old, start, end, &in_new, changed_old);
+    let (new_kept, new_index) = discard(new, start, end, &in_old, changed_new);
+
+    let diagonals = old_kept.len() + new_kept.len() + 3;
+    let mut search = MyersSearch {
+        old: &old_kept,
+        new: &new_kept,
+        forward: vec![0; diagonals],
+        backward: vec![0; diagonals],
+        offset: new_kept.len() as i64 + 1,
+        max_cost: (bogo_sqrt(diagonals) as i64).max(MIN_MAX_COST),
+    };
+    let mut changed = (vec![false; old_kept.len()], vec![false; new_kept.len()]);
+    search.compare(
+        0,
+        old_kept.len() as i64,
+        0,
+        new_kept.len() as i64,
+        false,
+        &mut changed,
+    );
+    for (i, changed) in changed.0.into_iter().enumerate() {
+        changed_old[old_index[i]] |= changed;
+    }
+    for (i, changed) in changed.1.into_iter().enumerate() {
+        changed_new[new_index[i]] |= changed;
+    }
+}
+
+/// Marks the lines of `lines[start..len - end]` that can't or shouldn't be
+/// matched as changed, returning the others with their positions
+fn discard(
+    lines: &[u32],
+    start: usize,
+    end: usize,
+    other_counts: &HashMap<u32, usize>,
+    changed: &mut [bool],
+) -> (Vec<u32>, Vec<usize>) {
+    let limit = bogo_sqrt(lines.len()).min(MAX_EQUAL_LIMIT);
+    let last = lines.len() - end;
+    // 0: no match, 1: some matches, 2: too many matches
+    let kinds: Vec<u8> = lines
+        .iter()
+        .enumerate()
+        .map(|(i, id)| {
+            if i < start || i >= last {
+                return 0;
+            }
+            match other_counts.get(id).copied().unwrap_or(0) {
+                0 => 0,
+                count if count >= limit => 2,
+                _ => 1,
+            }
+        })
+        .collect();
+
+    let mut kept = Vec::new();
+    let mut index = Vec::new();
+    for i in start..last {
+        if kinds[i] == 1 || (kinds[i] == 2 && !among_unmatched(&kinds, i, start, last)) {
+            kept.push(lines[i]);
+            index.push(i);
+        } else {
+            changed[i] = true;
+        }
+    }
+    (kept, index)
+}
+
+/// Whether the common line `i` sits in runs of lines without a match, and
+/// is better left out of the comparison
+fn among_unmatched(kinds: &[u8], i: usize, start: usize, end: usize) -> bool {
+    let start = start.max(i.saturating_sub(SIMILAR_SCAN_WINDOW));
+    let end = (end - 1).min(i + SIMILAR_SCAN_WINDOW);
+
+    let (mut unmatched_before, mut common_before) = (0, 1);
+    for j in (start..i).rev() {
+        match kinds[j] {
+            0 => unmatched_before += 1,
+            2 => common_before += 1,
+            _ => break,
+        }
+    }
+    if unmatched_before == 0 {
+        return false;
+    }
+    let (mut unmatched_after, mut common_after) = (0, 1);
+    for kind in &kinds[i + 1..=end] {
+        match kind {
+            0 => unmatched_after += 1,
+            2 => common_after += 1,
+            _ => break,
+        }
+    }
+    if unmatched_after == 0 {
+        return false;
+    }
+    let unmatched = unmatched_before + unmatched_after;
+    let common = common_before + common_after;
+    common * COMMON_RUN_RATIO < common + unmatched
+}
+
+/// The state of the middle snake search of [`myers`], with the furthest
+/// position reached on each diagonal forward and backward
+struct MyersSearch<'a> {
+    old: &'a [u32],
+    new: &'a [u32],
+    forward: Vec<i64>,
+    backward: Vec<i64>,
+    offset: i64,
+    max_cost: i64,
+}
+
+/// Where [`MyersSearch::split`] cuts the comparison, and whether each half
+/// still needs a minimal diff
+struct Split {
+    old: i64,
+    new: i64,
+    minimal_before: bool,
+    minimal_after: bool,
+}
+
+impl MyersSearch<'_> {
+    fn old_at(&self, i: i64) -> u32 {
+        self.old[i as usize]
+    }
+
+    fn new_at(&self, i: i64) -> u32 {
+        self.new[i as usize]
+    }
+
+    fn forward(&mut self, diagonal: i64) -> &mut i64 {
+        &mut self.forward[(diagonal + self.offset) as usize]
+    }
+
+    fn backward(&mut self, diagonal: i64) -> &mut i64 {
+        &mut self.backward[(diagonal + self.offset) as usize]
+    }
+
+    fn compare(
+        &mut self,
+        mut old_start: i64,
+        mut old_end: i64,
+        mut new_start: i64,
+        mut new_end: i64,
+        minimal: bool,
+        changed: &mut (Vec<bool>, Vec<bool>),
+    ) {
+        while old_start < old_end
+            && new_start < new_end
+            && self.old_at(old_start) == self.new_at(new_start)
+        {
+            old_start += 1;
+            new_start += 1;
+        }
+        while old_start < old_end
+            && new_start < new_end
+            && self.old_at(old_end - 1) == self.new_at(new_end - 1)
+        {
+            old_end -= 1;
+            new_end -= 1;
+        }
+
+        if old_start == old_end {
+            changed.1[new_start as usize..new_end as usize].fill(true);
+        } else if new_start == new_end {
+            changed.0[old_start as usize..old_end as usize].fill(true);
+        } else {
+            let split = self.split(old_start, old_end, new_start, new_end, minimal);
+            self.compare(
+                old_start,
+                split.old,
+                new_start,
+                split.new,
+                split.minimal_before,
+                changed,
+            );
+            self.compare(
+                split.old,
+                old_end,
+                split.new,
+                new_end,
+                split.minimal_after,
+                changed,
+            );
+        }
+    }
+
+    /// Finds a point on the shortest path from the start to the end of the
+    /// box, searching from both ends at once. Past some cost, unless
+    /// `minimal`, settles for a path that is good enough.
+    fn split(
+        &mut self,
+        old_start: i64,
+        old_end: i64,
+        new_start: i64,
+        new_end: i64,
+        minimal: bool,
+    ) -> Split {
+        let min_diagonal = old_start - new_end;
+        let max_diagonal = old_end - new_start;
+        let forward_mid = old_start - new_start;
+        let backward_mid = old_end - new_end;
+        let odd = (forward_mid - backward_mid) & 1 != 0;
+        let (mut forward_min, mut forward_max) = (forward_mid, forward_mid);
+        let (mut backward_min, mut backward_max) = (backward_mid, backward_mid);
+
+        *self.forward(forward_mid) = old_start;
+        *self.backward(backward_mid) = old_end;
+
+        let mut cost = 1;
+        loop {
+            let mut got_snake = false;
+
+            // Extend the diagonals searched by one, or shrink them at the
+            // edges of the box
+            if forward_min > min_diagonal {
+                forward_min -= 1;
+                *self.forward(forward_min - 1) = -1;
+            } else {
+                forward_min += 1;
+            }
+            if forward_max < max_diagonal {
+                forward_max += 1;
+                *self.forward(forward_max + 1) = -1;
+            } else {
+                forward_max -= 1;
+            }
+
+            let mut diagonal = forward_max;
+            while diagonal >= forward_min {
+                let mut i = if *self.forward(diagonal - 1) >= *self.forward(diagonal + 1) {
+                    *self.forward(diagonal - 1) + 1
+                } else {
+                    *self.forward(diagonal + 1)
+                };
+                let before = i;
+                let mut j = i - diagonal;
+                while i < old_end && j < new_end && self.old_at(i) == self.new_at(j) {
+                    i += 1;
+                    j += 1;
+                }
+                if i - before > SNAKE_LENGTH {
+                    got_snake = true;
+                }
+                *self.forward(diagonal) = i;
+                if odd
+                    && backward_min <= diagonal
+                    && diagonal <= backward_max
+                    && *self.backward(diagonal) <= i
+                {
+                    return Split {
+                        old: i,
+                        new: j,
+                        minimal_before: true,
+                        minimal_after: true,
+                    };
+                }
+                diagonal -= 2;
+            }
+
+            if backward_min > min_diagonal {
+                backward_min -= 1;
+                *self.backward(backward_min - 1) = i64::MAX;
+            } else {
+                backward_min += 1;
+            }
+            if backward_max < max_diagonal {
+                backward_max += 1;
+                *self.backward(backward_max + 1) = i64::MAX;
+            } else {
+                backward_max -= 1;
+            }
+
+            let mut diagonal = backward_max;
+            while diagonal >= backward_min {
+                let mut i = if *self.backward(diagonal - 1) < *self.backward(diagonal + 1) {
+                    *self.backward(diagonal - 1)
+                } else {
+                    *self.backward(diagonal + 1) - 1
+                };
+                let before = i;
+                let mut j = i - diagonal;
+                while i > old_start && j > new_start && self.old_at(i - 1) == self.new_at(j - 1) {
+                    i -= 1;
+                    j -= 1;
+                }
+                if before - i > SNAKE_LENGTH {
+                    got_snake = true;
+                }
+                *self.backward(diagonal) = i;
+                if !odd
+                    && forward_min <= diagonal
+                    && diagonal <= forward_max
+                    && i <= *self.forward(diagonal)
+                {
+                    return Split {
+                        old: i,
+                        new: j,
+                        minimal_before: true,
+                        minimal_after: true,
+                    };
+                }
+                diagonal -= 2;
+            }
+
+            if minimal {
+                cost += 1;
+                continue;
+            }
+
+            // Past some cost, a path that went far along a long run of equal
+            // lines is good enough
+            if got_snake && cost > MIN_HEURISTIC_COST {
+                let mut best = 0;
+                let mut found = None;
+                let mut diagonal = forward_max;
+                while diagonal >= forward_min {
+                    let distance = (diagonal - forward_mid).abs();
+                    let i = *self.forward(diagonal);
+                    let j = i - diagonal;
+                    let value = (i - old_start) + (j - new_start) - distance;
+                    if value > HEURISTIC_FACTOR * cost
+                        && value > best
+                        && old_start + SNAKE_LENGTH <= i
+                        && i < old_end
+                        && new_start + SNAKE_LENGTH <= j
+                        && j < new_end
+                        && (1..=SNAKE_LENGTH).all(|k| self.old_at(i - k) == self.new_at(j - k))
+                    {
+                        best = value;
+                        found = Some((i, j));
+                    }
+                    diagonal -= 2;
+                }
+                if let Some((i, j)) = found {
+                    return Split {
+                        old: i,
+                        new: j,
+                        minimal_before: true,
+                        minimal_after: false,
+                    };
+                }
+
+                let mut best = 0;
+                let mut diagonal = backward_max;
+                while diagonal >= backward_min {
+                    let distance = (diagonal - backward_mid).abs();
+                    let i = *self.backward(diagonal);
+                    let j = i - diagonal;
+                    let value = (old_end - i) + (new_end - j) - distance;
+                    if value > HEURISTIC_FACTOR * cost
+                        && value > best
+                        && old_start < i
+                        && i <= old_end - SNAKE_LENGTH
+                        && new_start < j
+                        && j <= new_end - SNAKE_LENGTH
+                        && (0..SNAKE_LENGTH).all(|k| self.old_at(i + k) == self.new_at(j + k))
+                    {
+                        best = value;
+                        found = Some((i, j));
+                    }
+                    diagonal -= 2;
+                }
+                if let Some((i, j)) = found {
+                    return Split {
+                        old: i,
+                        new: j,
+                        minimal_before: false,
+                        minimal_after: true,
+                    };
+                }
+            }
+
+            // Enough: take the path that went the furthest
+            if cost >= self.max_cost {
+                let (mut forward_best, mut forward_old) = (-1, -1);
+                let mut diagonal = forward_max;
+                while diagonal >= forward_min {
+                    let mut i = (*self.forward(diagonal)).min(old_end);
+                    let mut j = i - diagonal;
+                    if new_end < j {
+                        i = new_end + diagonal;
+                        j = new_end;
+                    }
+                    if forward_best < i + j {
+                        forward_best = i + j;
+                        forward_old = i;
+                    }
+                    diagonal -= 2;
+                }
+                let (mut backward_best, mut backward_old) = (i64::MAX, i64::MAX);
+                let mut diagonal = backward_max;
+                while diagonal >= backward_min {
+                    let mut i = (*self.backward(diagonal)).max(old_start);
+                    let mut j = i - diagonal;
+                    if j < new_start {
+                        i = new_start + diagonal;
+                        j = new_start;
+                    }
+                    if i + j < backward_best {
+                        backward_best = i + j;
+                        backward_old = i;
+                    }
+                    diagonal -= 2;
+                }
+                return if (old_end + new_end) - backward_best
+                    < forward_best - (old_start + new_start)
+                {
+                    Split {
+                        old: forward_old,
+                        new: forward_best - forward_old,
+                        minimal_before: true,
+                        minimal_after: false,
+                    }
+                } else {
+                    Split {
+                        old: backward_old,
+                        new: backward_best - backward_old,
+                        minimal_before: false,
+                        minimal_after: true,
+                    }
+                };
+            }
+            cost += 1;
+        }
+    }
+}
+
+/// Past this many occurrences in the old version, lines are too common to
+/// anchor the histogram diff
+const MAX_CHAIN_LENGTH: usize = 64;
+
+/// Marks the changed lines with git's histogram diff: the rarest line common
+/// to both versions, extended to the longest run of equal lines around it,
+/// splits the comparison in two. When all common lines are too common, the
+/// range is left to [`myers`].
+fn histogram(old: &[u32], new: &[u32], changed_old: &mut [bool], changed_new: &mut [bool]) {
+    // Ranges still to compare, as `(old, new)`
+    let mut pending = vec![(0..old.len(), 0..new.len())];
+    while let Some((old_range, new_range)) = pending.pop() {
+        if old_range.is_empty() {
+            changed_new[new_range].fill(true);
+            continue;
+        }
+        if new_range.is_empty() {
+            changed_old[old_range].fill(true);
+            continue;
+        }
+        match longest_rare_run(old, new, old_range.clone(), new_range.clone()) {
+            Anchor::TooCommon => {
+                let (old_changed, new_changed) = (
+                    &mut changed_old[old_range.clone()],
+                    &mut changed_new[new_range.clone()],
+                );
+                myers(&old[old_range], &new[new_range], old_changed, new_changed);
+            }
+            Anchor::None => {
+                changed_old[old_range].fill(true);
+                changed_new[new_range].fill(true);
+            }
+            Anchor::Run(old_run, new_run) => {
+                pending.push((old_run.end..old_range.end, new_run.end..new_range.end));
+                pending.push((
+                    old_range.start..old_run.start,
+                    new_range.start..new_run.start,
+                ));
+            }
+        }
+    }
+}
+
+enum Anchor {
+    /// Equal lines in both versions
+    Run(Range<usize>, Range<usize>),
+    /// No line in common
+    None,
+    /// Only lines too common to be anchors in common
+    TooCommon,
+}
+
+/// Finds the anchor of [`histogram`] in `old[old_range]` and `new[new_range]`
+fn longest_rare_run(
+    old: &[u32],
+    new: &[u32],
+    old_range: Range<usize>,
+    new_range: Range<usize>,
+) -> Anchor {
+    // The first occurrence of each line, and the next one after each line
+    let mut first: HashMap<u32, usize> = HashMap::new();
+    let mut count: HashMap<u32, usize> = HashMap::new();
+    let mut next = vec![None; old_range.len()];
+    for i in old_range.clone().rev() {
+        if let Some(after) = first.insert(old[i], i) {
+            next[i - old_range.start] = Some(after);
+        }
+        *count.entry(old[i]).or_default() += 1;
+    }
+
+    let mut best: Option<(Range<usize>, Range<usize>)> = None;
+    // Ends inclusive, like git, so that a single line doesn't beat nothing
+    let mut best_span = 0;
+    let mut best_count = MAX_CHAIN_LENGTH + 1;
+    let mut has_common = false;
+    let mut j = new_range.start;
+    while j < new_range.end {
+        let mut next_j = j + 1;
+        if let Some(&first) = first.get(&new[j]) {
+            let occurrences = count[&new[j]];
+            if occurrences > best_count {
+                has_common = true;
+                j = next_j;
+                continue;
+            }
+            has_common = true;
+            let mut i = Some(first);
+            while let Some(start) = i {
+                let (mut old_start, mut new_start) = (start, j);
+                let (mut old_end, mut new_end) = (start, j);
+                let mut rarest = occurrences;
+                while old_start > old_range.start
+                    && new_start > new_range.start
+                    && old[old_start - 1] == new[new_start - 1]
+                {
+                    old_start -= 1;
+                    new_start -= 1;
+                    if rarest > 1 {
+                        rarest = rarest.min(count[&old[old_start]]);
+                    }
+                }
+                while old_end + 1 < old_range.end
+                    && new_end + 1 < new_range.end
+                    && old[old_end + 1] == new[new_end + 1]
+                {
+                    old_end += 1;
+                    new_end += 1;
+                    if rarest > 1 {
+                        rarest = rarest.min(count[&old[old_end]]);
+                    }
+                }
+                next_j = next_j.max(new_end + 1);
+                if best_span < old_end - old_start || rarest < best_count {
+                    best = Some((old_start..old_end + 1, new_start..new_end + 1));
+                    best_span = old_end - old_start;
+                    best_count = rarest;
+                }
+                // The next occurrence past this run
+                i = next[start - old_range.start];
+                while let Some(after) = i
+                    && after <= old_end
+                {
+                    i = next[after - old_range.start];
+                }
+            }
+        }
+        j = next_j;
+    }
+
+    match best {
+        _ if has_common && best_count > MAX_CHAIN_LENGTH => Anchor::TooCommon,
+        Some((old_run, new_run)) => Anchor::Run(old_run, new_run),
+        None => Anchor::None,
+    }
+}
+
+/// Slides each group of changed lines of one version, where equal lines
+/// allow it, to merge it with its neighbours or line it up with a change
+/// of the `other` version, or else where git's indent heuristic finds the
+/// most natural boundaries.
+fn compact(lines: &[&[u8]], ids: &[u32], changed: &mut [bool], other: &[bool]) {
+    let mut group = Group::first(changed);
+    let mut other_group = Group::first(other);
+    loop {
+        if group.end != group.start {
+            let mut earliest_end;
+            let mut end_matching_other;
+            loop {
+                let size = group.end - group.start;
+                end_matching_other = None;
+                while group.slide_up(ids, changed) {
+                    other_group.previous(other);
+                }
+                earliest_end = group.end;
+                if other_group.end > other_group.start {
+                    end_matching_other = Some(group.end);
+                }
+                while group.slide_down(ids, changed) {
+                    other_group.next(other);
+                    if other_group.end > other_group.start {
+                        end_matching_other = Some(group.end);
+                    }
+                }
+                if size == group.end - group.start {
+                    break;
+                }
+            }
+
+            if group.end == earliest_end {
+                // Nowhere to go
+            } else if end_matching_other.is_some() {
+                while other_group.end == other_group.start {
+                    group.slide_up(ids, changed);
+                    other_group.previous(other);
+                }
+            } else {
+                let size = group.end - group.start;
+                let mut shift = earliest_end
+                    .max(group.end.saturating_sub(size + 1))
+                    .max(group.end.saturating_sub(INDENT_MAX_SLIDING));
+                let mut best: Option<(usize, SplitScore)> = None;
+                while shift <= group.end {
+                    let mut score = SplitScore::default();
+                    score.add(&SplitMeasure::new(lines, shift));
+                    score.add(&SplitMeasure::new(lines, shift - size));
+                    if best.as_ref().is_none_or(|(_, best)| score.cmp(best) <= 0) {
+                        best = Some((shift, score));
+                    }
+                    shift += 1;
+                }
+                if let Some((best, _)) = best {
+                    while group.end > best {
+                        group.slide_up(ids, changed);
+                        other_group.previous(other);
+                    }
+                }
+            }
+        }
+
+        if !group.next(changed) {
+            break;
+        }
+        other_group.next(other);
+    }
+}
+
+/// A run `start..end` of changed lines, possibly empty between two
+/// unchanged lines
+struct Group {
+    start: usize,
+    end: usize,
+}
+
+impl Group {
+    fn first(changed: &[bool]) -> Self {
+        let mut end = 0;
+        while changed.get(end) == Some(&true) {
+            end += 1;
+        }
+        Group { start: 0, end }
+    }
+
+    fn next(&mut self, changed: &[bool]) -> bool {
+        if self.end == changed.len() {
+            return false;
+        }
+        self.start = self.end + 1;
+        self.end = self.start;
+        while changed.get(self.end) == Some(&true) {
+            self.end += 1;
+        }
+        true
+    }
+
+    fn previous(&mut self, changed: &[bool]) -> bool {
+        if self.start == 0 {
+            return false;
+        }
+        self.end = self.start - 1;
+        self.start = self.end;
+        while self.start > 0 && changed[self.start - 1] {
+            self.start -= 1;
+        }
+        true
+    }
+
+    fn slide_down(&mut self, ids: &[u32], changed: &mut [bool]) -> bool {
+        if self.end < changed.len() && ids[self.start] == ids[self.end] {
+            changed[self.start] = false;
+            changed[self.end] = true;
+            self.start += 1;
+            self.end += 1;
+            while changed.get(self.end) == Some(&true) {
+                self.end += 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn slide_up(&mut self, ids: &[u32], changed: &mut [bool]) -> bool {
+        if self.start > 0 && ids[self.start - 1] == ids[self.end - 1] {
+            self.start -= 1;
+            self.end -= 1;
+            changed[self.start] = true;
+            changed[self.end] = false;
+            while self.start > 0 && changed[self.start - 1] {
+                self.start -= 1;
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+const INDENT_MAX_SLIDING: usize = 100;
+const MAX_INDENT: i32 = 200;
+const MAX_BLANKS: i32 = 20;
+
+/// The indentation of `line`, with tabs to the next multiple of 8, or `None`
+/// for a blank line
+fn indent(line: &[u8]) -> Option<i32> {
+    let mut indent = 0;
+    for &byte in line {
+        if !byte.is_ascii_whitespace() && byte != 0x0b {
+            return Some(indent);
+        }
+        match byte {
+            b' ' => indent += 1,
+            b'\t' => indent += 8 - indent % 8,
+            _ => {}
+        }
+        if indent >= MAX_INDENT {
+            return Some(MAX_INDENT);
+        }
+    }
+    None
+}
+
+/// The surroundings of a boundary before line `split`
+struct SplitMeasure {
+    end_of_file: bool,
+    /// Of the line after the split, -1 when blank
+    indent: i32,
+    /// Blank lines just before the split
+    pre_blank: i32,
+    /// Of the closest non blank line before, -1 if none
+    pre_indent: i32,
+    /// Blank lines after the line after the split
+    post_blank: i32,
+    /// Of the next non blank line after that, -1 if none
+    post_indent: i32,
+}
+
+impl SplitMeasure {
+    fn new(lines: &[&[u8]], split: usize) -> Self {
+        let (end_of_file, line_indent) = match lines.get(split) {
+            Some(line) => (false, indent(line).unwrap_or(-1)),
+            None => (true, -1),
+        };
+        let mut pre_blank = 0;
+        let mut pre_indent = -1;
+        for line in lines[..split.min(lines.len())].iter().rev() {
+            pre_indent = indent(line).unwrap_or(-1);
+            if pre_indent != -1 {
+                break;
+            }
+            pre_blank += 1;
+            if pre_blank == MAX_BLANKS {
+                pre_indent = 0;
+                break;
+            }
+        }
+        let mut post_blank = 0;
+        let mut post_indent = -1;
+        for line in lines.iter().skip(split + 1) {
+            post_indent = indent(line).unwrap_or(-1);
+            if post_indent != -1 {
+                break;
+            }
+            post_blank += 1;
+            if post_blank == MAX_BLANKS {
+                post_indent = 0;
+                break;
+            }
+        }
+        SplitMeasure {
+            end_of_file,
+            indent: line_indent,
+            pre_blank,
+            pre_indent,
+            post_blank,
+            post_indent,
+        }
+    }
+}
+
+/// How bad a boundary is: smaller is better
+#[derive(Default)]
+struct SplitScore {
+    effective_indent: i32,
+    penalty: i32,
+}
+
+impl SplitScore {
+    /// git's weights, tuned on real world diffs
+    fn add(&mut self, m: &SplitMeasure) {
+        if m.pre_indent == -1 && m.pre_blank == 0 {
+            self.penalty += 1;
+        }
+        if m.end_of_file {
+            self.penalty += 21;
+        }
+        let post_blank = if m.indent == -1 { 1 + m.post_blank } else { 0 };
+        let total_blank = m.pre_blank + post_blank;
+        self.penalty += -30 * total_blank;
+        self.penalty += 6 * post_blank;
+
+        let indent = if m.indent != -1 {
+            m.indent
+        } else {
+            m.post_indent
+        };
+        let any_blanks = total_blank != 0;
+        self.effective_indent += indent;
+        if indent == -1 || m.pre_indent == -1 {
+        } else if indent > m.pre_indent {
+            self.penalty += if any_blanks { 10 } else { -4 };
+        } else if indent == m.pre_indent {
+        } else if m.post_indent != -1 && m.post_indent > indent {
+            self.penalty += if any_blanks { 17 } else { 24 };
+        } else {
+            self.penalty += if any_blanks { 17 } else { 23 };
+        }
+    }
+
+    fn cmp(&self, other: &Self) -> i32 {
+        let indents = (self.effective_indent > other.effective_indent) as i32
+            - (self.effective_indent < other.effective_indent) as i32;
+        60 * indents + (self.penalty - other.penalty)
+    }
+}
+
+/// The most parents [`combined_diff`] handles: a line's flag has a bit for
+/// each, and two more for the hunks
+pub const COMBINED_MAX_PARENTS: usize = 62;
+
+/// A line of the result of a merge in its combined diff
+struct MergedLine<'a> {
+    /// Without its newline. `None` past the last line, where the lines
+    /// deleted at the end hang.
+    text: Option<&'a [u8]>,
+    /// Bit `n` is set when the line is not in parent `n`. The next bits
+    /// mark the lines shown and the ones shown without the deleted lines.
+    flag: u64,
+    /// The lines deleted just before this one, with the parents they are
+    /// in as bits
+    lost: Vec<(&'a [u8], u64)>,
+    /// The line of each parent a hunk starting here starts at
+    parent_lines: Vec<usize>,
+}
+
+/// The hunks of the combined diff of the result of a merge against its
+/// `parents`, each starting with its `@@@` line. Like git's `--cc`, hunks
+/// where the result is the version of all parents but one are left out.
+///
+/// Lines keep one bit per parent, so at most [`COMBINED_MAX_PARENTS`] are
+/// supported.
+pub fn combined_diff(
+    parents: &[&[u8]],
+    result: &[u8],
+    options: &DiffOptions,
+) -> Result<Vec<u8>, OzError> {
+    fn strip(line: &[u8]) -> &[u8] {
+        line.strip_suffix(b"\n").unwrap_or(line)
+    }
+    if parents.len() > COMBINED_MAX_PARENTS {
+        Err(OzError::InvalidArgument(format!(
+            "cannot combine the diffs of {} parents, the limit is {COMBINED_MAX_PARENTS}",
+            parents.len()
+        )))?;
+    }
+    let result_lines = split_lines(result);
+    let count = result_lines.len();
+    let mut lines: Vec<MergedLine> = (0..count + 2)
+        .map(|i| MergedLine {
+            text: result_lines.get(i).map(|line| strip(line)),
+            flag: 0,
+            lost: Vec::new(),
+            parent_lines: vec![0; parents.len()],
+        })
+        .collect();
+
+    for (n, parent) in parents.iter().enumerate() {
+        let mask = 1 << n;
+        let parent_lines = split_lines(parent);
+        let mut lost = vec![Vec::new(); count + 1];
+        for edit in diff_lines(parent, result, options.algorithm) {
+            lost[edit.new.start].extend(parent_lines[edit.old].iter().map(|line| strip(line)));
+            for line in &mut lines[edit.new] {
+                line.flag |= mask;
+            }
+        }
+        let mut parent_line = 1;
+        for (i, line) in lines[..=count].iter_mut().enumerate() {
+            line.parent_lines[n] = parent_line;
+            coalesce_lost(&mut line.lost, &lost[i], mask);
+            parent_line += line
+                .lost
+                .iter()
+                .filter(|(_, from)| from & mask != 0)
+                .count();
+            if i < count && line.flag & mask == 0 {
+                parent_line += 1;
+            }
+        }
+        lines[count + 1].parent_lines[n] = parent_line;
+    }
+
+    if !mark_hunks(&mut lines, parents.len(), options.context) {
+        return Ok(Vec::new());
+    }
+    Ok(combined_hunks(&lines, parents.len(), options.context))
+}
+
+/// Merges the lines `new` deleted from the parent `mask` into the lines
+/// deleted from the previous parents, the common ones only once
+fn coalesce_lost<'a>(base: &mut Vec<(&'a [u8], u64)>, new: &[&'a [u8]], mask: u64) {
+    if new.is_empty() {
+        return;
+    }
+    if base.is_empty() {
+        *base = new.iter().map(|line| (*line, mask)).collect();
+        return;
+    }
+    #[derive(Clone, Copy, PartialEq)]
+    enum Step {
+        Match,
+        Base,
+        New,
+    }
+    let (rows, columns) = (base.len() + 1, new.len() + 1);
+    let mut common = vec![0; rows * columns];
+    let mut steps = vec![Step::Base; rows * columns];
+    steps[1..columns].fill(Step::New);
+    for i in 1..rows {
+        for j in 1..columns {
+            let at = i * columns + j;
+            (common[at], steps[at]) = if base[i - 1].0 == new[j - 1] {
+                (common[at - columns - 1] + 1, Step::Match)
+            } else if common[at - 1] >= common[at - columns] {
+                (common[at - 1], Step::New)
+            } else {
+                (common[at - columns], Step::Base)
+            };
+        }
+    }
+
+    let mut merged = Vec::new();
+    let (mut i, mut j) = (base.len(), new.len());
+    while i != 0 || j != 0 {
+        match steps[i * columns + j] {
+            Step::Match => {
+                merged.push((base[i - 1].0, base[i - 1].1 | mask));
+                i -= 1;
+                j -= 1;
+            }
+            Step::New => {
+                merged.push((new[j - 1], mask));
+                j -= 1;
+            }
+            Step::Base => {
+                merged.push(base[i - 1]);
+                i -= 1;
+            }
+        }
+    }
+    merged.reverse();
+    *base = merged;
+}
+
+/// Marks the lines to show: the changed ones with `context` lines around
+/// them, except in hunks that only take one side of the merge. Returns
+/// whether there is anything to show.
+fn mark_hunks(lines: &mut [MergedLine], parents: usize, context: usize) -> bool {
+    let all = (1 << parents) - 1;
+    let mark = 1 << parents;
+    let count = lines.len() - 2;
+    for line in lines.iter_mut() {
+        if line.flag & all != 0 || !line.lost.is_empty() {
+            line.flag |= mark;
+        } else {
+            line.flag &= !mark;
+        }
+    }
+
+    let mut i = 0;
+    while i <= count {
+        while i <= count && lines[i].flag & mark == 0 {
+            i += 1;
+        }
+        if count < i {
+            break;
+        }
+        let start = i;
+        let mut j = i + 1;
+        while j <= count {
+            if lines[j].flag & mark == 0 {
+                // Go on when there are more changes within the context
+                let mut ahead = (hunk_tail(lines, all, start, j) + context).min(count + 1);
+                let mut more = false;
+                while ahead > 0 {
+                    ahead -= 1;
+                    if ahead < j {
+                        break;
+                    }
+                    if lines[ahead].flag & mark != 0 {
+                        more = true;
+                        break;
+                    }
+                }
+                if !more {
+                    break;
+                }
+                j = ahead;
+            }
+            j += 1;
+        }
+        let end = j;
+
+        // With a single set of parents the result differs from, the result
+        // is one of two versions, which only matters if it is neither
+        let mut same = 0;
+        let mut interesting = false;
+        'lines: for line in &lines[start..end] {
+            let differs = std::iter::once(line.flag & all)
+                .filter(|&differs| differs != 0)
+                .chain(line.lost.iter().map(|&(_, from)| from));
+            for differs in differs {
+                if same == 0 {
+                    same = differs;
+                } else if same != differs {
+                    interesting = true;
+                    break 'lines;
+                }
+            }
+        }
+        if !interesting && same != all {
+            for line in &mut lines[start..end] {
+                line.flag &= !mark;
+            }
+        }
+        i = end;
+    }
+
+    add_context(lines, parents, context)
+}
+
+/// Where a hunk ending before `end` needs its trailing context: a last
+/// line only there to show deleted lines counts as context
+fn hunk_tail(lines: &[MergedLine], all: u64, start: usize, end: usize) -> usize {
+    if start < end && lines[end - 1].flag & all == 0 {
+        end - 1
+    } else {
+        end
+    }
+}
+
+/// Marks the context lines of the marked lines, joining hunks closer than
+/// the context. Returns whether any line is marked.
+fn add_context(lines: &mut [MergedLine], parents: usize, context: usize) -> bool {
+    let all = (1 << parents) - 1;
+    let mark = 1 << parents;
+    let no_deleted = 2 << parents;
+    let count = lines.len() - 2;
+    let next = |lines: &[MergedLine], mut i: usize, marked: bool| {
+        while i <= count && (lines[i].flag & mark != 0) != marked {
+            i += 1;
+        }
+        i
+    };
+
+    let mut i = next(lines, 0, true);
+    if count < i {
+        return false;
+    }
+    while i <= count {
+        // The context before doesn't show the lines deleted before it
+        for line in &mut lines[i.saturating_sub(context)..i] {
+            if line.flag & mark == 0 {
+                line.flag |= no_deleted;
+            }
+            line.flag |= mark;
+        }
+        loop {
+            let end = next(lines, i, false);
+            if count < end {
+                return true;
+            }
+            let following = next(lines, end, true);
+            let end = hunk_tail(lines, all, i, end);
+            if following < end + context {
+                for line in &mut lines[end..following] {
+                    line.flag |= mark;
+                }
+                i = following;
+                continue;
+            }
+            i = following;
+            for line in &mut lines[end..(end + context).min(count + 1)] {
+                line.flag |= mark;
+            }
+            break;
+        }
+    }
+    true
+}
+
+fn combined_hunks(lines: &[MergedLine], parents: usize, context: usize) -> Vec<u8> {
+    let mark = 1 << parents;
+    let no_deleted = 2 << parents;
+    let count = lines.len() - 2;
+    let markers = "@".repeat(parents + 1);
+    let mut out = Vec::new();
+    let mut i = 0;
+    loop {
+        let mut heading = None;
+        while i <= count && lines[i].flag & mark == 0 {
+            if let Some(text) = lines[i].text.filter(|text| function_line(text).is_some()) {
+                heading = Some(text);
+            }
+            i += 1;
+        }
+        if count < i {
+            break;
+        }
+        let mut end = i + 1;
+        while end <= count && lines[end].flag & mark != 0 {
+            end += 1;
+        }
+        let mut result_count = end - i;
+        if count < end {
+            result_count -= 1;
+        }
+        // Without context, lines only there to hang deleted lines aren't
+        // shown. The line past the end counts twice then, and the counts
+        // wrap around as they do in git.
+        let mut hidden = 0;
+        if context == 0 {
+            hidden = (i..end)
+                .filter(|&j| lines[j].flag & (mark - 1) == 0)
+                .count();
+            result_count = result_count.wrapping_sub(hidden);
+        }
+
+        out.extend(markers.bytes());
+        for n in 0..parents {
+            let start = lines[i].parent_lines[n];
+            let length = lines[end].parent_lines[n].wrapping_sub(start + hidden);
+            out.extend(format!(" -{start},{length}").bytes());
+        }
+        out.extend(format!(" +{},{result_count} {markers}", i + 1).bytes());
+        if let Some(heading) = heading {
+            // Like git, up to the last non blank character of the first 40,
+            // that one excluded
+            let limit = heading.len().min(40);
+            let limit = heading[..limit]
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(limit);
+            let shown = heading[..limit]
+                .iter()
+                .rposition(|byte| !byte.is_ascii_whitespace())
+                .unwrap_or(0);
+            if shown > 0 {
+                out.push(b' ');
+                out.extend_from_slice(&heading[..shown]);
+            }
+        }
+        out.push(b'\n');
+
+        while i < end {
+            let line = &lines[i];
+            i += 1;
+            if line.flag & no_deleted == 0 {
+                for (text, from) in &line.lost {
+                    for n in 0..parents {
+                        out.push(if from & (1 << n) != 0 { b'-' } else { b' ' });
+                    }
+                    out.extend_from_slice(text);
+                    out.push(b'\n');
+                }
+            }
+            if count < i {
+                break;
+            }
+            if line.flag & (mark - 1) == 0 && context == 0 {
+                continue;
+            }
+            for n in 0..parents {
+                out.push(if line.flag & (1 << n) != 0 {
+                    b'+'
+                } else {
+                    b' '
+                });
+            }
+            out.extend_from_slice(line.text.unwrap_or_default());
+            out.push(b'\n');
+        }
+    }
+    out
+}
+
+/// The combined diff of the tree `tree` of a merge against the trees of its
+/// `parents`, as `git show` prints it for merges: only the files differing
+/// from every parent are compared.
+pub(crate) fn combined_patch(
+    repo: &GitRepository,
+    parents: &[ObjectId],
+    tree: &ObjectId,
+    options: &DiffOptions,
+) -> Result<Vec<u8>, OzError> {
+    let mut others = Vec::new();
+    for parent in &parents[1..] {
+        let changes = repo.diff_trees(Some(parent), Some(tree), &[])?;
+        let changes: HashMap<Vec<u8>, TreeChange> = changes
+            .into_iter()
+            .map(|change| (change.path.clone(), change))
+            .collect();
+        others.push(changes);
+    }
+
+    let mut out = Vec::new();
+    for change in repo.diff_trees(Some(&parents[0]), Some(tree), &[])? {
+        let Some(rest) = others
+            .iter()
+            .map(|changes| changes.get(&change.path))
+            .collect::<Option<Vec<_>>>()
+        else {
+            continue;
+        };
+        let path = change.path_lossy();
+        let result = change.new.as_ref();
+        let sides: Vec<Option<&TreeEntry>> = std::iter::once(change.old.as_ref())
+            .chain(rest.iter().map(|change| change.old.as_ref()))
+            .collect();
+        let mode = |entry: Option<&TreeEntry>| entry.map_or(0, |entry| entry.mode.raw());
+        let id =
+            |entry: Option<&TreeEntry>| entry.map_or(ObjectId::null(repo.format), |entry| entry.id);
+        let content = |entry: Option<&TreeEntry>| match entry {
+            Some(entry) => blob_content(repo, entry.mode.raw(), &entry.id),
+            None => Ok(Vec::new()),
+        };
+
+        let ids: Vec<_> = sides.iter().map(|side| id(*side).abbrev(7)).collect();
+        let mut header = format!(
+            "diff --cc {path}\nindex {}..{}\n",
+            ids.join(","),
+            id(result).abbrev(7)
+        );
+        let added = sides.iter().all(Option::is_none);
+        let deleted = result.is_none();
+        let mode_differs = sides.iter().any(|side| mode(*side) != mode(result));
+        if mode_differs {
+            if added {
+                header += &format!("new file mode {:06o}\n", mode(result));
+            } else {
+                let modes: Vec<_> = sides
+                    .iter()
+                    .map(|side| format!("{:06o}", mode(*side)))
+                    .collect();
+                header += &format!(
+                    "{}mode {}",
+                    if deleted { "deleted file " } else { "" },
+                    modes.join(",")
+                );
+                if !deleted {
+                    header += &format!("..{:06o}", mode(result));
+                }
+                header.push('\n');
+            }
+        }
+
+        let result_data = content(result)?;
+        let parent_data = sides
+            .iter()
+            .map(|side| content(*side))
+            .collect::<Result<Vec<_>, _>>()?;
+        if is_binary(&result_data) || parent_data.iter().any(|data| is_binary(data)) {
+            out.extend(header.bytes());
+            out.extend_from_slice(b"Binary files differ\n");
+            continue;
+        }
+        let parent_data: Vec<&[u8]> = parent_data.iter().map(Vec::as_slice).collect();
+        let hunks = combined_diff(&parent_data, &result_data, options)?;
+        if hunks.is_empty() && !mode_differs {
+            continue;
+        }
+        out.extend(header.bytes());
+        match added {
+            true => out.extend_from_slice(b"--- /dev/null\n"),
+            false => out.extend(format!("--- a/{path}\n").bytes()),
+        }
+        match deleted {
+            true => out.extend_from_slice(b"+++ /dev/null\n"),
+            false => out.extend(format!("+++ b/{path}\n").bytes()),
+        }
+        out.extend(hunks);
+    }
+    Ok(out)
+}
+
+/// One side of a comparison
+#[derive(Debug, Clone, Copy)]
+pub enum DiffSource<'a> {
+    /// The worktree files tracked by the index
+    Worktree,
+    Index,
+    /// A commit, or a tree
+    Rev(&'a str),
+}
+
+/// One version of a file in a [`Diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffFile {
+    pub path: String,
+    pub mode: u32,
+    pub id: ObjectId,
+}
+
+/// A file that differs between the two sides of a [`Diff`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChange {
+    pub change: Change,
+    /// `None` when added
+    pub old: Option<DiffFile>,
+    /// `None` when deleted
+    pub new: Option<DiffFile>,
+    /// How much of the content a rename kept, in percent
+    pub similarity: Option<u32>,
+}
+
+impl FileChange {
+    /// The path on the new side, or the old one when deleted
+    pub fn path(&self) -> &str {
+        let file = self.new.as_ref().or(self.old.as_ref());
+        file.map_or("", |file| &file.path)
+    }
+}
+
+/// The files that differ between two sides, sorted by path, and the
+/// contents to show how
+pub struct Diff<'a> {
+    repo: &'a GitRepository,
+    options: DiffOptions,
+    pub files: Vec<FileChange>,
+    /// The worktree files, which aren't in the object store
+    contents: HashMap<ObjectId, Vec<u8>>,
+}
+
+pub(crate) type FileMap = BTreeMap<String, (u32, ObjectId)>;
+
+/// Similarity scores are out of this, like git's
+const MAX_SCORE: u64 = 60000;
+const MIN_RENAME_SCORE: u64 = MAX_SCORE / 2;
+/// Past this many sources times destinations, only exact renames are found
+const RENAME_LIMIT: usize = 1000 * 1000;
+
+/// Compares the files of `old` and `new`.
+pub(crate) fn diff<'a>(
+    repo: &'a GitRepository,
+    old: DiffSource,
+    new: DiffSource,
+    options: &DiffOptions,
+) -> Result<Diff<'a>, OzError> {
+    if let (DiffSource::Rev(old), DiffSource::Rev(new)) = (old, new) {
+        let (old, new) = (rev_tree(repo, old)?, rev_tree(repo, new)?);
+        return tree_diff(repo, old.as_ref(), new.as_ref(), options);
+    }
+    let mut contents = HashMap::new();
+    let old = source_files(repo, old, &mut contents)?;
+    let new = source_files(repo, new, &mut contents)?;
+    diff_files(repo, &old, &new, contents, options)
+}
+
+/// Compares the trees `old` and `new`, a missing one being empty
+pub(crate) fn tree_diff<'a>(
+    repo: &'a GitRepository,
+    old: Option<&ObjectId>,
+    new: Option<&ObjectId>,
+    options: &DiffOptions,
+) -> Result<Diff<'a>, OzError> {
+    let changes = repo.diff_trees(old, new, &[])?;
+    let files = changes.into_iter().map(FileChange::from).collect();
+    Diff::new(repo, files, HashMap::new(), options)
+}
+
+/// The tree of the commit or tree `rev`. Before the first commit, HEAD has
+/// none.
+fn rev_tree(repo: &GitRepository, rev: &str) -> Result<Option<ObjectId>, OzError> {
+    if rev == "HEAD" && repo.ref_resolve("HEAD")?.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(repo.object_peel_to(repo.rev_parse(rev)?, "tree")?))
+}
+
+fn source_files(
+    repo: &GitRepository,
+    source: DiffSource,
+    contents: &mut HashMap<ObjectId, Vec<u8>>,
+) -> Result<FileMap, OzError> {
+    let mut files = FileMap::new();
+    match source {
+        DiffSource::Rev(rev) => {
+            if let Some(tree) = rev_tree(repo, rev)? {
+                repo.tree_flatten(&tree, "", &mut files)?;
+            }
+        }
+        DiffSource::Index => {
+            for entry in repo.index_read()?.entries {
+                if entry.stage == 0 && !entry.intent_to_add {
+                    files.insert(entry.name_lossy().to_string(), (entry.mode, entry.sha));
+                }
+            }
+        }
+        DiffSource::Worktree => {
+            let index = repo.index_read()?;
+            let index_mtime = index_mtime(repo);
+            let filemode = repo.conf.getbool("core", "filemode").ok().flatten() != Some(false);
+            for entry in &index.entries {
+                if entry.stage != 0 {
+                    continue;
+                }
+                let path = repo.worktree_file(&entry.name);
+                let Ok(meta) = fs::symlink_metadata(&path) else {
+                    continue;
+                };
+                if entry.mode == 0o160000 {
+                    if meta.is_dir() {
+                        files.insert(entry.name_lossy().to_string(), (entry.mode, entry.sha));
+                    }
+                    continue;
+                }
+                if meta.is_dir() {
+                    continue;
+                }
+                let mut mode = GitIndexEntry::from_metadata(&entry.name[..], entry.sha, &meta).mode;
+                if !filemode && mode != 0o120000 && entry.mode != 0o120000 {
+                    mode = entry.mode;
+                }
+                if mode == entry.mode
+                    && !entry.intent_to_add
+                    && entry.stat_matches(&meta)
+                    && entry.mtime < index_mtime
+                {
+                    files.insert(entry.name_lossy().to_string(), (mode, entry.sha));
+                    continue;
+                }
+                let data = worktree_data(&path, &meta)?;
+                let id = GitRepository::object_hash(repo.format, "blob", &data);
+                contents.insert(id, data);
+                files.insert(entry.name_lossy().to_string(), (mode, id));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Pairs the files of `old` and `new` by path, then finds renames among the
+/// deleted and added files.
+pub(crate) fn diff_files<'a>(
+    repo: &'a GitRepository,
+    old: &FileMap,
+    new: &FileMap,
+    contents: HashMap<ObjectId, Vec<u8>>,
+    options: &DiffOptions,
+) -> Result<Diff<'a>, OzError> {
+    let file = |path: &String, (mode, id): &(u32, ObjectId)| DiffFile {
+        path: path.clone(),
+        mode: *mode,
+        id: *id,
+    };
+    let mut files = Vec::new();
+    for (path, old_file) in old {
+        let new_file = new.get(path);
+        let change = match new_file {
+            None => Change::Deleted,
+            Some((mode, _)) if mode & 0o170000 != old_file.0 & 0o170000 => Change::TypeChanged,
+            Some(new_file) if new_file != old_file => Change::Modified,
+            Some(_) => continue,
+        };
+        files.push(FileChange {
+            change,
+            old: Some(file(path, old_file)),
+            new: new_file.map(|new_file| file(path, new_file)),
+            similarity: None,
+        });
+    }
+    for (path, new_file) in new {
+        if !old.contains_key(path) {
+            files.push(FileChange {
+                change: Change::Added,
+                old: None,
+                new: Some(file(path, new_file)),
+                similarity: None,
+            });
+        }
+    }
+
+    Diff::new(repo, files, contents, options)
+}
+
+impl From<TreeChange> for FileChange {
+    fn from(change: TreeChange) -> Self {
+        let path = change.path_lossy().into_owned();
+        let file = |entry: TreeEntry| DiffFile {
+            path: path.clone(),
+            mode: entry.mode.raw(),
+            id: entry.id,
+        };
+        FileChange {
+            change: change.change,
+            old: change.old.map(file),
+            new: change.new.map(file),
+            similarity: None,
+        }
+    }
+}
+
+impl<'a> Diff<'a> {
+    /// Finds the renames among `files`, unless `options` say not to
+    pub(crate) fn new(
+        repo: &'a GitRepository,
+        files: Vec<FileChange>,
+        contents: HashMap<ObjectId, Vec<u8>>,
+        options: &DiffOptions,
+    ) -> Result<Self, OzError> {
+        let mut diff = Diff {
+            repo,
+            options: options.clone(),
+            files,
+            contents,
+        };
+        if options.renames {
+            diff.find_renames()?;
+        }
+        diff.files.sort_by(|one, two| one.path().cmp(two.path()));
+        Ok(diff)
+    }
+}
+
+impl Diff<'_> {
+    /// The contents of one version of a file
+    pub fn content(&self, file: &DiffFile) -> Result<Cow<'_, [u8]>, OzError> {
+        match self.contents.get(&file.id) {
+            Some(data) => Ok(Cow::Borrowed(data)),
+            None => Ok(Cow::Owned(blob_content(self.repo, file.mode, &file.id)?)),
+        }
+    }
+
+    /// Turns deleted and added files into renames, first those with the same
+    /// contents, then those with similar enough contents, most similar first.
+    fn find_renames(&mut self) -> Result<(), OzError> {
+        let candidate = |file: &Option<DiffFile>, change: Change, wanted: Change| {
+            file.as_ref()
+                .is_some_and(|file| change == wanted && file.mode != 0o160000)
+        };
+        let mut deleted: Vec<usize> = (0..self.files.len())
+            .filter(|&i| candidate(&self.files[i].old, self.files[i].change, Change::Deleted))
+            .collect();
+        let mut added: Vec<usize> = (0..self.files.len())
+            .filter(|&i| candidate(&self.files[i].new, self.files[i].change, Change::Added))
+            .collect();
+        if deleted.is_empty() || added.is_empty() {
+            return Ok(());
+        }
+        let old = |i: usize| self.files[i].old.as_ref().unwrap();
+        let new = |i: usize| self.files[i].new.as_ref().unwrap();
+
+        // (deleted, added, score)
+        let mut renames = Vec::new();
+        added.retain(|&add| {
+            let same = deleted.iter().filter(|&&del| old(del).id == new(add).id);
+            let Some(&del) = same
+                .clone()
+                .find(|&&del| basename(&old(del).path) == basename(&new(add).path))
+                .or(same.clone().next())
+            else {
+                return true;
+            };
+            deleted.retain(|&other| other != del);
+            renames.push((del, add, MAX_SCORE));
+            false
+        });
+
+        let regular = |mode: u32| mode & 0o170000 == 0o100000;
+        deleted.retain(|&del| regular(old(del).mode));
+        added.retain(|&add| regular(new(add).mode));
+        if !deleted.is_empty() && !added.is_empty() && deleted.len() * added.len() <= RENAME_LIMIT {
+            let sources = deleted
+                .iter()
+                .map(|&del| Ok(Spans::new(&self.content(old(del))?)))
+                .collect::<Result<Vec<_>, OzError>>()?;
+            let mut pairs = Vec::new();
+            for &add in &added {
+                let spans = Spans::new(&self.content(new(add))?);
+                for (source, &del) in sources.iter().zip(&deleted) {
+                    let score = source.similarity(&spans);
+                    if score >= MIN_RENAME_SCORE {
+                        let same_name = basename(&old(del).path) == basename(&new(add).path);
+                        pairs.push((score, same_name, del, add));
+                    }
+                }
+            }
+            pairs.sort_by_key(|&(score, same_name, _, _)| Reverse((score, same_name)));
+            for (score, _, del, add) in pairs {
+                if deleted.contains(&del) && added.contains(&add) {
+                    deleted.retain(|&other| other != del);
+                    added.retain(|&other| other != add);
+                    renames.push((del, add, score));
+                }
+            }
+        }
+
+        let mut gone = Vec::new();
+        for (del, add, score) in renames {
+            let old = self.files[del].old.clone();
+            let change = &mut self.files[add];
+            change.change = Change::Renamed;
+            change.old = old;
+            change.similarity = Some((score * 100 / MAX_SCORE) as u32);
+            gone.push(del);
+        }
+        let mut i = 0;
+        self.files.retain(|_| {
+            i += 1;
+            !gone.contains(&(i - 1))
+        });
+        Ok(())
+    }
+
+    /// The diff as a patch, like `git diff` prints it
+    pub fn patch(&self) -> Result<Vec<u8>, OzError> {
+        let mut out = Vec::new();
+        for file in &self.files {
+            if file.change == Change::TypeChanged {
+                // Shown as the old file going away and the new one coming
+                for (old, new) in [(file.old.clone(), None), (None, file.new.clone())] {
+                    let change = if old.is_some() {
+                        Change::Deleted
+                    } else {
+                        Change::Added
+                    };
+                    self.file_patch(
+                        &FileChange {
+                            change,
+                            old,
+                            new,
+                            similarity: None,
+                        },
+                        &mut out,
+                    )?;
+                }
+            } else {
+                self.file_patch(file, &mut out)?;
+            }
+        }
+        Ok(out)
+    }
+
+    fn file_patch(&self, file: &FileChange, out: &mut Vec<u8>) -> Result<(), OzError> {
+        let old_path = file.old.as_ref().map_or(file.path(), |old| &old.path);
+        let mut header = format!("diff --git a/{old_path} b/{}\n", file.path());
+        match (&file.old, &file.new) {
+            (None, Some(new)) => header += &format!("new file mode {:06o}\n", new.mode),
+            (Some(old), None) => header += &format!("deleted file mode {:06o}\n", old.mode),
+            (Some(old), Some(new)) => {
+                if old.mode != new.mode {
+                    header += &format!("old mode {:06o}\nnew mode {:06o}\n", old.mode, new.mode);
+                }
+                if let Some(similarity) = file.similarity {
+                    header += &format!(
+                        "similarity index {similarity}%\nrename from {}\nrename to {}\n",
+                        old.path, new.path
+                    );
+                }
+            }
+            (None, None) => {}
+        }
+        let id = |file: &Option<DiffFile>| {
+            file.as_ref()
+                .map_or(ObjectId::null(self.repo.format), |file| file.id)
+        };
+        let (old_id, new_id) = (id(&file.old), id(&file.new));
+        if old_id == new_id {
+            out.extend(header.bytes());
+            return Ok(());
+        }
+        header += &format!("index {}..{}", old_id.abbrev(7), new_id.abbrev(7));
+        if let (Some(old), Some(new)) = (&file.old, &file.new)
+            && old.mode == new.mode
+        {
+            header += &format!(" {:06o}", new.mode);
+        }
+        header.push('\n');
+        out.extend(header.bytes());
+
+        let old_data = match &file.old {
+            Some(old) => self.content(old)?,
+            None => Cow::Borrowed(&[][..]),
+        };
+        let new_data = match &file.new {
+            Some(new) => self.content(new)?,
+            None => Cow::Borrowed(&[][..]),
+        };
+        let old_name = file
+            .old
+            .as_ref()
+            .map_or("/dev/null".into(), |old| format!("a/{}", old.path));
+        let new_name = file
+            .new
+            .as_ref()
+            .map_or("/dev/null".into(), |new| format!("b/{}", new.path));
+        if is_binary(&old_data) || is_binary(&new_data) {
+            out.extend(format!("Binary files {old_name} and {new_name} differ\n").bytes());
+            return Ok(());
+        }
+        let hunks = unified_diff(&old_data, &new_data, &self.options);
+        if !hunks.is_empty() {
+            out.extend(format!("--- {old_name}\n+++ {new_name}\n").bytes());
+            out.extend(hunks);
+        }
+        Ok(())
+    }
+
+    /// The lines added and deleted in each file, or for binary files their
+    /// sizes after and before, with whether they are binary
+    fn counts(&self, file: &FileChange) -> Result<(usize, usize, bool), OzError> {
+        let content = |file: &Option<DiffFile>| match file {
+            Some(file) => self.content(file),
+            None => Ok(Cow::Borrowed(&[][..])),
+        };
+        let (old, new) = (content(&file.old)?, content(&file.new)?);
+        let same = old == new;
+        if is_binary(&old) || is_binary(&new) {
+            return Ok(match same {
+                true => (0, 0, true),
+                false => (new.len(), old.len(), true),
+            });
+        }
+        let (mut added, mut deleted) = (0, 0);
+        if !same {
+            for edit in diff_lines(&old, &new, self.options.algorithm) {
+                added += edit.new.len();
+                deleted += edit.old.len();
+            }
+        }
+        Ok((added, deleted, false))
+    }
+
+    /// The diffstat, like `git diff --stat` prints it on 80 columns
+    pub fn stat(&self) -> Result<String, OzError> {
+        let mut rows = Vec::new();
+        for file in &self.files {
+            let name = match &file.old {
+                Some(old) if file.change == Change::Renamed => rename_name(&old.path, file.path()),
+                _ => file.path().to_string(),
+            };
+            let (added, deleted, binary) = self.counts(file)?;
+            rows.push((name, added, deleted, binary));
+        }
+
+        let decimal_width = |n: usize| n.to_string().len();
+        let (mut max_len, mut max_change, mut bin_width, mut number_width) = (0, 0, 0, 0);
+        for (name, added, deleted, binary) in &rows {
+            max_len = max_len.max(name.chars().count());
+            if *binary {
+                // "Bin XXX -> YYY bytes"
+                bin_width = bin_width.max(14 + decimal_width(*added) + decimal_width(*deleted));
+                number_width = 3;
+            } else {
+                max_change = max_change.max(added + deleted);
+            }
+        }
+        let number_width = number_width.max(decimal_width(max_change));
+        let width = 80.max(16 + 6 + number_width);
+        let mut graph_width = if max_change + 4 > bin_width {
+            max_change
+        } else {
+            bin_width - 4
+        };
+        let mut name_width = max_len;
+        if name_width + number_width + 6 + graph_width > width {
+            if graph_width + number_width + 6 > width * 3 / 8 {
+                graph_width = (width * 3 / 8).saturating_sub(number_width + 6).max(6);
+            }
+            if name_width > width - number_width - 6 - graph_width {
+                name_width = width - number_width - 6 - graph_width;
+            } else {
+                graph_width = width - number_width - 6 - name_width;
+            }
+        }
+
+        let mut out = String::new();
+        let (mut adds, mut dels) = (0, 0);
+        for (name, added, deleted, binary) in &rows {
+            let (mut prefix, mut name) = ("", name.as_str());
+            let mut len = name_width;
+            if name_width < name.chars().count() {
+                prefix = "...";
+                len = len.saturating_sub(3);
+                let skip = name.chars().count() - len;
+                name = &name[name.char_indices().nth(skip).map_or(name.len(), |(i, _)| i)..];
+                if let Some(slash) = name.find('/') {
+                    name = &name[slash..];
+                }
+            }
+            let padding = len.saturating_sub(name.chars().count());
+            out += &format!(" {prefix}{name}{:padding$} | ", "");
+            if *binary {
+                out += &format!("{:>number_width$}", "Bin");
+                if *added != 0 || *deleted != 0 {
+                    out += &format!(" {deleted} -> {added} bytes");
+                }
+                out.push('\n');
+                continue;
+            }
+            adds += added;
+            dels += deleted;
+            let (mut add, mut del) = (*added, *deleted);
+            if graph_width <= max_change {
+                let scale = |n: usize| match n {
+                    0 => 0,
+                    n => 1 + n * (graph_width - 1) / max_change,
+                };
+                let mut total = scale(add + del);
+                if total < 2 && add != 0 && del != 0 {
+                    total = 2;
+                }
+                if add < del {
+                    add = scale(add);
+                    del = total - add;
+                } else {
+                    del = scale(del);
+                    add = total - del;
+                }
+            }
+            out += &format!("{:>number_width$}", added + deleted);
+            if added + deleted != 0 {
+                out.push(' ');
+            }
+            out += &"+".repeat(add);
+            out += &"-".repeat(del);
+            out.push('\n');
+        }
+
+        let plural = |n: usize| if n == 1 { "" } else { "s" };
+        let files = rows.len();
+        out += &format!(" {files} file{} changed", plural(files));
+        if adds != 0 || dels == 0 {
+            out += &format!(", {adds} insertion{}(+)", plural(adds));
+        }
+        if dels != 0 || adds == 0 {
+            out += &format!(", {dels} deletion{}(-)", plural(dels));
+        }
+        out.push('\n');
+        Ok(out)
+    }
+
+    /// One line per file with its change letter, like `git diff --name-status`
+    pub fn name_status(&self) -> String {
+        let mut out = String::new();
+        for file in &self.files {
+            match (&file.old, file.similarity) {
+                (Some(old), Some(similarity)) => {
+                    out += &format!("R{similarity:03}\t{}\t{}\n", old.path, file.path())
+                }
+                _ => out += &format!("{}\t{}\n", Change::code(Some(file.change)), file.path()),
+            }
+        }
+        out
+    }
+}
+
+/// The contents of the blob `id`, or the text git shows for a submodule
+fn blob_content(repo: &GitRepository, mode: u32, id: &ObjectId) -> Result<Vec<u8>, OzError> {
+    if mode == 0o160000 {
+        return Ok(format!("Subproject commit {id}\n").into_bytes());
+    }
+    match repo.object_read(id)? {
+        GitObject::Blob(GitBlob { buffer }) => Ok(buffer),
+        object => Err(OzError::WrongObjectType {
+            sha: *id,
+            expected: "blob",
+            actual: object.obj_type(),
+        }),
+    }
+}
+
+fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// How `git diff --stat` names a renamed file: `dir/{old => new}/file`
+fn rename_name(old: &str, new: &str) -> String {
+    let (a, b) = (old.as_bytes(), new.as_bytes());
+    let mut prefix = 0;
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x != y {
+            break;
+        }
+        if *x == b'/' {
+            prefix = i + 1;
+        }
+    }
+    // Both ends of the common suffix must be whole path components
+    let mut suffix = 0;
+    let (mut i, mut j) = (a.len() as isize, b.len() as isize);
+    let limit = prefix as isize - (prefix > 0) as isize;
+    let at = |bytes: &[u8], i: isize| bytes.get(i as usize).copied().unwrap_or(0);
+    while limit <= i && limit <= j && at(a, i) == at(b, j) {
+        if at(a, i) == b'/' {
+            suffix = a.len() - i as usize;
+        }
+        i -= 1;
+        j -= 1;
+    }
+    let old_middle = &old[prefix..a.len().saturating_sub(suffix).max(prefix)];
+    let new_middle = &new[prefix..b.len().saturating_sub(suffix).max(prefix)];
+    if prefix + suffix == 0 {
+        return format!("{old_middle} => {new_middle}");
+    }
+    format!(
+        "{}{{{old_middle} => {new_middle}}}{}",
+        &old[..prefix],
+        &old[a.len() - suffix..]
+    )
+}
+
+/// The contents of a file cut in chunks, each ending at a newline or after
+/// 64 bytes, and counted by hash, which tells how much of a file another
+/// one kept the way git does
+struct Spans {
+    bytes: HashMap<u32, u64>,
+    len: u64,
+}
+
+impl Spans {
+    fn new(data: &[u8]) -> Self {
+        const HASH_BASE: u32 = 107927;
+        let text = !is_binary(data);
+        let mut bytes = HashMap::new();
+        let (mut accum1, mut accum2, mut n) = (0u32, 0u32, 0);
+        for (i, &byte) in data.iter().enumerate() {
+            // CRLF counts as LF in text
+            if text && byte == b'\r' && data.get(i + 1) == Some(&b'\n') {
+                continue;
+            }
+            let old = accum1;
+            accum1 = (accum1 << 7) ^ (accum2 >> 25);
+            accum2 = (accum2 << 7) ^ (old >> 25);
+            accum1 = accum1.wrapping_add(byte as u32);
+            n += 1;
+            if n < 64 && byte != b'\n' {
+                continue;
+            }
+            let hash = accum1.wrapping_add(accum2.wrapping_mul(0x61)) % HASH_BASE;
+            *bytes.entry(hash).or_default() += n;
+            (accum1, accum2, n) = (0, 0, 0);
+        }
+        Spans {
+            bytes,
+            len: data.len() as u64,
+        }
+    }
+
+    /// The share of `self` found in `other`, out of [`MAX_SCORE`]
+    fn similarity(&self, other: &Spans) -> u64 {
+        let max = self.len.max(other.len);
+        let min = self.len.min(other.len);
+        if max == 0 || (max - min) * MAX_SCORE > max * (MAX_SCORE - MIN_RENAME_SCORE) {
+            return 0;
+        }
+        let copied: u64 = self
+            .bytes
+            .iter()
+            .map(|(hash, &count)| count.min(other.bytes.get(hash).copied().unwrap_or(0)))
+            .sum();
+        copied * MAX_SCORE / max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::tests::{temp_repo, write_blob};
+
+    #[test]
+    fn unified_diff_matches_git() {
+        let old = b"fn one() {\n    a;\n    b;\n    c;\n    d;\n}\n\nfn two() {\n    e;\n}";
+        let new = b"fn one() {\n    a;\n    b;\n    c;\n    d;\n}\n\nfn two() {\n    f;\n}\n";
+        let options = DiffOptions {
+            context: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            String::from_utf8(unified_diff(old, new, &options)).unwrap(),
+            "@@ -8,3 +8,3 @@ fn one() {\n fn two() {\n-    e;\n-}\n\\ No newline at end of file\n+    f;\n+}\n"
+        );
+        assert!(unified_diff(old, old, &options).is_empty());
+    }
+
+    #[test]
+    fn combined_diff_skips_hunks_taken_from_one_parent() {
+        let ours = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\neight\nnine\n";
+        let theirs = b"one\nTWO\nthree\nfour\nfive\nsix\nseven\nate\nnine\n";
+        let merged = b"one\nTWO\nthree\nfour\nfive\nsix\nseven\n8\nnine\n";
+        let options = DiffOptions {
+            context: 1,
+            ..Default::default()
+        };
+        // The same as git's, down to its heading losing its last character
+        assert_eq!(
+            String::from_utf8(combined_diff(&[ours, theirs], merged, &options).unwrap()).unwrap(),
+            "@@@ -7,3 -7,3 +7,3 @@@ si\n  seven\n- eight\n -ate\n++8\n  nine\n"
+        );
+        assert!(
+            combined_diff(&[ours, theirs], theirs, &options)
+                .unwrap()
+                .is_empty()
+        );
+        // Past the bits of a line's flag
+        let many = vec![&ours[..]; COMBINED_MAX_PARENTS + 1];
+        assert!(combined_diff(&many, merged, &options).is_err());
+    }
+
+    #[test]
+    fn algorithms_find_the_same_changes() {
+        let old = b"a\nb\nc\na\nb\nb\na\n";
+        let new = b"c\nb\na\nb\na\nc\n";
+        for algorithm in [DiffAlgorithm::Myers, DiffAlgorithm::Histogram] {
+            let old_lines = split_lines(old);
+            let new_lines = split_lines(new);
+            // Replaying the edits on the old lines gives the new ones
+            let mut result = Vec::new();
+            let mut i = 0;
+            for edit in diff_lines(old, new, algorithm) {
+                result.extend_from_slice(&old_lines[i..edit.old.start]);
+                result.extend_from_slice(&new_lines[edit.new]);
+                i = edit.old.end;
+            }
+            result.extend_from_slice(&old_lines[i..]);
+            assert_eq!(result, new_lines);
+        }
+        // The same as git's
+        let edits: Vec<_> = diff_lines(old, new, DiffAlgorithm::Myers)
+            .into_iter()
+            .map(|edit| (edit.old, edit.new))
+            .collect();
+        assert_eq!(
+            edits,
+            [(0..2, 0..0), (3..4, 1..1), (5..5, 2..3), (7..7, 5..6)]
+        );
+    }
+
+    #[test]
+    fn files_diff_with_renames() {
+        let repo = temp_repo("diff");
+        let blob = |data: &str| write_blob(&repo, data);
+        let lines: String = (0..20).map(|i| format!("line {i}\n")).collect();
+        let mut old = FileMap::new();
+        old.insert("kept".into(), (0o100644, blob("kept\n")));
+        old.insert("moved".into(), (0o100644, blob(&lines)));
+        old.insert("gone".into(), (0o100644, blob("gone\n")));
+        let mut new = FileMap::new();
+        new.insert("kept".into(), (0o100755, blob("kept\n")));
+        new.insert(
+            "dir/moved".into(),
+            (0o100644, blob(&lines.replace("7", "seven"))),
+        );
+        new.insert("binary".into(), (0o100644, blob("\0\0")));
+
+        let diff = diff_files(&repo, &old, &new, HashMap::new(), &DiffOptions::default()).unwrap();
+        assert_eq!(
+            diff.name_status(),
+            "A\tbinary\nR085\tmoved\tdir/moved\nD\tgone\nM\tkept\n"
+        );
+        assert_eq!(
+            diff.stat().unwrap(),
+            concat!(
+                " binary             | Bin 0 -> 2 bytes\n",
+                " moved => dir/moved |   4 ++--\n",
+                " gone               |   1 -\n",
+                " kept               |   0\n",
+                " 4 files changed, 2 insertions(+), 3 deletions(-)\n"
+            )
+        );
+        let patch = String::from_utf8(diff.patch().unwrap()).unwrap();
+        assert!(patch.contains(
+            "diff --git a/moved b/dir/moved\nsimilarity index 85%\nrename from moved\nrename to dir/moved\n"
+        ));
+        assert!(patch.contains("diff --git a/kept b/kept\nold mode 100644\nnew mode 100755\n"));
+        assert!(patch.contains("Binary files /dev/null and b/binary differ\n"));
+        assert_eq!(rename_name("a/b/c.rs", "a/d/c.rs"), "a/{b => d}/c.rs");
+        assert_eq!(rename_name("old", "new"), "old => new");
+    }
+}
@@ -0,0 +1,123 @@
+use crate::hash::ObjectId;
+use std::{fmt, io, path::PathBuf};
+
+/// Everything that can go wrong in oz
+#[derive(Debug)]
+pub enum OzError {
+    /// Reading or writing a file failed
+    Io(io::Error),
+    /// The current directory is not in a repository, or one oz can't use
+    NotARepository(String),
+    /// The configuration can't be read, or has an invalid value
+    Config(String),
+    /// The repository has no object with this id
+    ObjectNotFound(ObjectId),
+    /// A ref, revision or path that doesn't exist
+    NotFound(String),
+    /// A name matching several objects
+    AmbiguousRef {
+        name: String,
+        candidates: Vec<ObjectId>,
+    },
+    /// An object that isn't of the type the operation needs
+    WrongObjectType {
+        sha: ObjectId,
+        expected: &'static str,
+        actual: &'static str,
+    },
+    /// An object whose content can't be parsed. The sha is unknown when
+    /// parsing data that isn't stored yet.
+    CorruptObject {
+        sha: Option<ObjectId>,
+        reason: String,
+    },
+    /// A damaged index, pack, packed-refs or other repository file
+    CorruptFile { path: PathBuf, reason: String },
+    /// A lock file is held by another process
+    Locked(PathBuf),
+    /// A malformed ref name, revision, path or argument
+    InvalidArgument(String),
+    /// The operation makes no sense in the current state, or would lose data
+    Refused(String),
+}
+
+impl OzError {
+    pub(crate) fn corrupt_object(reason: impl Into<String>) -> Self {
+        OzError::CorruptObject {
+            sha: None,
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn corrupt_file(path: impl Into<PathBuf>, reason: impl Into<String>) -> Self {
+        OzError::CorruptFile {
+            path: path.into(),
+            reason: reason.into(),
+        }
+    }
+
+    /// Names the object a parsing error comes from
+    pub(crate) fn in_object(self, sha: &ObjectId) -> Self {
+        match self {
+            OzError::CorruptObject { sha: None, reason } => OzError::CorruptObject {
+                sha: Some(*sha),
+                reason,
+            },
+            error => error,
+        }
+    }
+}
+
+impl fmt::Display for OzError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OzError::Io(error) => write!(f, "{error}"),
+            OzError::NotARepository(reason) => write!(f, "{reason}"),
+            OzError::ObjectNotFound(sha) => write!(f, "Unable to read object {sha}"),
+            OzError::NotFound(what) => write!(f, "{what}"),
+            OzError::AmbiguousRef { name, candidates } => {
+                write!(f, "Ambiguous reference {name}: candidates are:")?;
+                for candidate in candidates {
+                    write!(f, "\n - {candidate}")?;
+                }
+                Ok(())
+            }
+            OzError::WrongObjectType {
+                sha,
+                expected,
+                actual,
+            } => write!(f, "{sha} is a {actual}, not a {expected}"),
+            OzError::CorruptObject {
+                sha: Some(sha),
+                reason,
+            } => write!(f, "corrupt object {sha}: {reason}"),
+            OzError::CorruptObject { sha: None, reason } => write!(f, "corrupt object: {reason}"),
+            OzError::CorruptFile { path, reason } => write!(f, "{}: {reason}", path.display()),
+            OzError::Locked(path) => write!(
+                f,
+                "Unable to create {}: is another oz or git running?",
+                path.display()
+            ),
+            OzError::Config(reason)
+            | OzError::InvalidArgument(reason)
+            | OzError::Refused(reason) => {
+                write!(f, "{reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OzError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OzError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for OzError {
+    fn from(error: io::Error) -> Self {
+        OzError::Io(error)
+    }
+}
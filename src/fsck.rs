@@ -0,0 +1,600 @@
+use crate::{
+    commands::object_roots,
+    error::OzError,
+    git_repo::GitRepository,
+    hash::{ObjectFormat, ObjectId},
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+};
+
+/// Something `fsck` found wrong, or worth reporting, about an object
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckIssue {
+    /// The object is corrupt or malformed. `id` names the check that failed,
+    /// like git's `fsck.<id>` message ids.
+    Error {
+        obj_type: &'static str,
+        sha: ObjectId,
+        id: &'static str,
+        message: String,
+    },
+    /// The object is readable but doesn't follow the format strictly
+    Warning {
+        obj_type: &'static str,
+        sha: ObjectId,
+        id: &'static str,
+        message: String,
+    },
+    /// A pack or its index is damaged
+    BadPack { path: String, message: String },
+    /// A ref, the index or another object points to an object we don't have
+    Missing {
+        obj_type: &'static str,
+        sha: ObjectId,
+    },
+    /// `from` points to the missing object `to`
+    BrokenLink {
+        from_type: &'static str,
+        from: ObjectId,
+        to_type: &'static str,
+        to: ObjectId,
+    },
+    /// Unreachable object that no other object points to
+    Dangling {
+        obj_type: &'static str,
+        sha: ObjectId,
+    },
+    Unreachable {
+        obj_type: &'static str,
+        sha: ObjectId,
+    },
+}
+
+impl FsckIssue {
+    /// Whether the repository is damaged, as opposed to merely untidy
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self,
+            FsckIssue::Warning { .. } | FsckIssue::Dangling { .. } | FsckIssue::Unreachable { .. }
+        )
+    }
+}
+
+impl fmt::Display for FsckIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckIssue::Error {
+                obj_type,
+                sha,
+                id,
+                message,
+            } => write!(f, "error in {obj_type} {sha}: {id}: {message}"),
+            FsckIssue::Warning {
+                obj_type,
+                sha,
+                id,
+                message,
+            } => write!(f, "warning in {obj_type} {sha}: {id}: {message}"),
+            FsckIssue::BadPack { path, message } => write!(f, "error in pack {path}: {message}"),
+            FsckIssue::Missing { obj_type, sha } => write!(f, "missing {obj_type} {sha}"),
+            FsckIssue::BrokenLink {
+                from_type,
+                from,
+                to_type,
+                to,
+            } => write!(f, "broken link from {from_type} {from} to {to_type} {to}"),
+            FsckIssue::Dangling { obj_type, sha } => write!(f, "dangling {obj_type} {sha}"),
+            FsckIssue::Unreachable { obj_type, sha } => write!(f, "unreachable {obj_type} {sha}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Number of objects read, a packed object also stored loose counts twice
+    pub checked: usize,
+    pub issues: Vec<FsckIssue>,
+}
+
+impl FsckReport {
+    pub fn has_errors(&self) -> bool {
+        self.issues.iter().any(FsckIssue::is_error)
+    }
+}
+
+/// Checks every loose and packed object: its sha must match its content, and
+/// commits, trees and tags must be well formed and point to existing objects.
+/// Objects not reachable from the refs, HEAD or the index are reported as
+/// dangling, or all as unreachable when `unreachable` is set.
+pub(crate) fn fsck(repo: &GitRepository, unreachable: bool) -> Result<FsckReport, OzError> {
+    let mut report = FsckReport::default();
+    // The valid objects, with the objects they point to and the type expected
+    let mut objects: BTreeMap<ObjectId, (&'static str, Links)> = BTreeMap::new();
+    let mut check =
+        |report: &mut FsckReport, sha: ObjectId, obj_type: &'static str, data: &[u8]| {
+            report.checked += 1;
+            let mut header = format!("{obj_type} {}\0", data.len()).into_bytes();
+            header.extend_from_slice(data);
+            let actual = ObjectId::hash(repo.format, &header);
+            if actual != sha {
+                report.issues.push(FsckIssue::Error {
+                    obj_type,
+                    sha,
+                    id: "hashMismatch",
+                    message: format!("content hashes to {actual}"),
+                });
+                return;
+            }
+            let mut checker = ObjectChecker {
+                obj_type,
+                sha,
+                format: repo.format,
+                issues: Vec::new(),
+                links: Vec::new(),
+            };
+            match obj_type {
+                "commit" => checker.commit(data),
+                "tree" => checker.tree(data),
+                "tag" => checker.tag(data),
+                _ => {}
+            }
+            report.issues.append(&mut checker.issues);
+            let links = checker.links;
+            objects.insert(sha, (obj_type, links));
+        };
+
+    for sha in repo.loose_objects()? {
+        match repo.object_read_raw(&sha) {
+            Ok(Some((obj_type, data))) => check(&mut report, sha, obj_type, &data),
+            Ok(None) => {}
+            Err(error) => report.issues.push(FsckIssue::Error {
+                obj_type: "object",
+                sha,
+                id: "badObject",
+                message: reason(error),
+            }),
+        }
+    }
+    let packs = repo.packs();
+    for (i, pack) in packs.iter().enumerate() {
+        if let Err(error) = pack.verify() {
+            report.issues.push(FsckIssue::BadPack {
+                path: pack.path.to_string_lossy().to_string(),
+                message: reason(error),
+            });
+        }
+        for j in 0..pack.len() {
+            let sha = pack.sha(j);
+            match repo.pack_object(i, pack.offset(j)) {
+                Ok((obj_type, data)) => check(&mut report, sha, obj_type, &data),
+                Err(error) => report.issues.push(FsckIssue::Error {
+                    obj_type: "object",
+                    sha,
+                    id: "badObject",
+                    message: reason(error),
+                }),
+            }
+        }
+    }
+
+    // Links between objects
+    let mut missing = BTreeMap::new();
+    let mut referenced = HashSet::new();
+    for (sha, (obj_type, links)) in &objects {
+        for (to, to_type) in links {
+            referenced.insert(*to);
+            match objects.get(to) {
+                None => {
+                    report.issues.push(FsckIssue::BrokenLink {
+                        from_type: obj_type,
+                        from: *sha,
+                        to_type,
+                        to: *to,
+                    });
+                    missing.insert(*to, *to_type);
+                }
+                Some((actual, _)) if actual != to_type => report.issues.push(FsckIssue::Error {
+                    obj_type,
+                    sha: *sha,
+                    id: "wrongObjectType",
+                    message: format!("{to} is a {actual}, not a {to_type}"),
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+
+    // Connectivity
+    let mut reachable = HashSet::new();
+    let mut pending = Vec::new();
+    for (sha, _) in object_roots(repo)? {
+        if !objects.contains_key(&sha) {
+            missing.entry(sha).or_insert("object");
+        }
+        pending.push(sha);
+    }
+    while let Some(sha) = pending.pop() {
+        if !reachable.insert(sha) {
+            continue;
+        }
+        if let Some((_, links)) = objects.get(&sha) {
+            pending.extend(links.iter().map(|(to, _)| *to));
+        }
+    }
+    for (sha, obj_type) in missing {
+        report.issues.push(FsckIssue::Missing { obj_type, sha });
+    }
+    for (sha, (obj_type, _)) in &objects {
+        if reachable.contains(sha) {
+            continue;
+        }
+        let (obj_type, sha) = (*obj_type, *sha);
+        if unreachable {
+            report.issues.push(FsckIssue::Unreachable { obj_type, sha });
+        } else if !referenced.contains(&sha) {
+            report.issues.push(FsckIssue::Dangling { obj_type, sha });
+        }
+    }
+    Ok(report)
+}
+
+/// The objects an object points to, with the type they should have
+type Links = Vec<(ObjectId, &'static str)>;
+
+/// Validates the content of a single object and gathers its links
+struct ObjectChecker {
+    obj_type: &'static str,
+    sha: ObjectId,
+    format: ObjectFormat,
+    issues: Vec<FsckIssue>,
+    links: Links,
+}
+
+impl ObjectChecker {
+    fn error(&mut self, id: &'static str, message: &str) {
+        let issue = FsckIssue::Error {
+            obj_type: self.obj_type,
+            sha: self.sha,
+            id,
+            message: message.to_string(),
+        };
+        // Each problem is reported once per object
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+
+    fn warning(&mut self, id: &'static str, message: &str) {
+        let issue = FsckIssue::Warning {
+            obj_type: self.obj_type,
+            sha: self.sha,
+            id,
+            message: message.to_string(),
+        };
+        if !self.issues.contains(&issue) {
+            self.issues.push(issue);
+        }
+    }
+
+    /// Splits the headers of a commit or tag, until the blank line
+    fn headers(data: &[u8]) -> Vec<(String, String)> {
+        let end = data
+            .windows(2)
+            .position(|pair| pair == b"\n\n")
+            .map_or(data.len(), |end| end + 1);
+        String::from_utf8_lossy(&data[..end])
+            .lines()
+            // Continuation lines (like in signatures) start with a space
+            .filter(|line| !line.starts_with(' '))
+            .map(|line| line.split_once(' ').unwrap_or((line, "")))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn commit(&mut self, data: &[u8]) {
+        let headers = Self::headers(data);
+        let mut headers = headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .peekable();
+        match headers.next() {
+            Some(("tree", sha)) if let Some(sha) = self.format.parse_id(sha) => {
+                self.links.push((sha, "tree"))
+            }
+            Some(("tree", _)) => self.error("badTreeSha", "invalid 'tree' line format"),
+            _ => self.error("missingTree", "invalid format - expected 'tree' line"),
+        }
+        while let Some((_, sha)) = headers.next_if(|(key, _)| *key == "parent") {
+            if let Some(sha) = self.format.parse_id(sha) {
+                self.links.push((sha, "commit"));
+            } else {
+                self.error("badParentSha", "invalid 'parent' line format");
+            }
+        }
+        for (key, id) in [
+            ("author", "missingAuthor"),
+            ("committer", "missingCommitter"),
+        ] {
+            match headers.next_if(|(name, _)| *name == key) {
+                Some((_, ident)) => self.ident(ident),
+                None => self.error(id, &format!("invalid format - expected '{key}' line")),
+            }
+        }
+    }
+
+    fn tag(&mut self, data: &[u8]) {
+        let headers = Self::headers(data);
+        let mut headers = headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()));
+        let object = match headers.next() {
+            Some(("object", sha)) if let Some(sha) = self.format.parse_id(sha) => Some(sha),
+            Some(("object", _)) => {
+                self.error("badObjectSha", "invalid 'object' line format");
+                None
+            }
+            _ => {
+                self.error("missingObject", "invalid format - expected 'object' line");
+                None
+            }
+        };
+        match headers.next() {
+            Some(("type", obj_type)) => match ["commit", "tree", "blob", "tag"]
+                .into_iter()
+                .find(|known| *known == obj_type)
+            {
+                Some(obj_type) => {
+                    if let Some(sha) = object {
+                        self.links.push((sha, obj_type));
+                    }
+                }
+                None => self.error("badType", "invalid 'type' value"),
+            },
+            _ => self.error("missingTypeEntry", "invalid format - expected 'type' line"),
+        }
+        match headers.next() {
+            Some(("tag", name)) if !name.is_empty() => {}
+            _ => self.error("missingTagEntry", "invalid format - expected 'tag' line"),
+        }
+        match headers.next() {
+            Some(("tagger", ident)) => self.ident(ident),
+            _ => self.warning(
+                "missingTaggerEntry",
+                "invalid format - expected 'tagger' line",
+            ),
+        }
+    }
+
+    /// `Name <email> <seconds> <+hhmm>`
+    fn ident(&mut self, ident: &str) {
+        let Some((_, rest)) = ident.split_once(" <") else {
+            return self.error("badName", "invalid author/committer line - bad name");
+        };
+        let Some((email, date)) = rest.split_once("> ") else {
+            return self.error("badEmail", "invalid author/committer line - bad email");
+        };
+        if email.contains(['<', '>']) {
+            return self.error("badEmail", "invalid author/committer line - bad email");
+        }
+        let Some((seconds, zone)) = date.split_once(' ') else {
+            return self.error("badDate", "invalid author/committer line - bad date");
+        };
+        if seconds.is_empty() || !seconds.bytes().all(|ch| ch.is_ascii_digit()) {
+            return self.error("badDate", "invalid author/committer line - bad date");
+        }
+        let zone = zone.as_bytes();
+        if zone.len() != 5
+            || !matches!(zone[0], b'+' | b'-')
+            || !zone[1..].iter().all(u8::is_ascii_digit)
+        {
+            self.error(
+                "badTimezone",
+                "invalid author/committer line - bad time zone",
+            );
+        }
+    }
+
+    fn tree(&mut self, data: &[u8]) {
+        let mut names = HashSet::new();
+        let mut previous: Option<Vec<u8>> = None;
+        let raw_len = self.format.raw_len();
+        let mut pos = 0;
+        while pos < data.len() {
+            let entry = &data[pos..];
+            let (Some(space), Some(null)) = (
+                entry.iter().position(|&ch| ch == b' '),
+                entry.iter().position(|&ch| ch == 0),
+            ) else {
+                return self.error("badTree", "cannot be parsed as a tree");
+            };
+            if space > null || entry.len() < null + 1 + raw_len {
+                return self.error("badTree", "cannot be parsed as a tree");
+            }
+            let (mode, name) = (&entry[..space], &entry[space + 1..null]);
+            let sha = ObjectId::from_raw(&entry[null + 1..null + 1 + raw_len]).unwrap();
+            pos += null + 1 + raw_len;
+
+            let Some(mode) = std::str::from_utf8(mode)
+                .ok()
+                .filter(|mode| !mode.is_empty())
+                .and_then(|mode| u32::from_str_radix(mode, 8).ok().map(|value| (mode, value)))
+            else {
+                return self.error("badTree", "cannot be parsed as a tree");
+            };
+            if mode.0.starts_with('0') {
+                self.warning("zeroPaddedFilemode", "contains zero-padded file modes");
+            }
+            let is_tree = mode.1 == 0o40000;
+            match mode.1 {
+                0o40000 => self.links.push((sha, "tree")),
+                0o100644 | 0o100755 | 0o120000 => self.links.push((sha, "blob")),
+                0o160000 => {}
+                // Old git versions wrote group writable files
+                0o100664 => {
+                    self.warning("badFilemode", "contains bad file modes");
+                    self.links.push((sha, "blob"));
+                }
+                _ => self.warning("badFilemode", "contains bad file modes"),
+            }
+
+            match name {
+                b"" => self.warning("emptyName", "contains empty pathname"),
+                b"." => self.warning("hasDot", "contains '.'"),
+                b".." => self.warning("hasDotdot", "contains '..'"),
+                _ if name.eq_ignore_ascii_case(b".git") => {
+                    self.warning("hasDotgit", "contains '.git'")
+                }
+                _ if name.contains(&b'/') => {
+                    self.warning("fullPathname", "contains full pathnames")
+                }
+                _ => {}
+            }
+            if !names.insert(name.to_vec()) {
+                self.error("duplicateEntries", "contains duplicate file entries");
+            }
+            // The order of `tree_serialize`: trees sort as if ending with "/"
+            let mut key = name.to_vec();
+            if is_tree {
+                key.push(b'/');
+            }
+            if previous.as_ref().is_some_and(|previous| *previous > key) {
+                self.error("treeNotSorted", "not properly sorted");
+            }
+            previous = Some(key);
+        }
+    }
+}
+
+/// The message of an error about an object or pack, which the report
+/// already names
+fn reason(error: OzError) -> String {
+    match error {
+        OzError::CorruptObject { reason, .. } | OzError::CorruptFile { reason, .. } => reason,
+        error => error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::tests::temp_repo;
+
+    fn issues(obj_type: &'static str, data: &[u8]) -> Vec<&'static str> {
+        check(obj_type, data)
+            .iter()
+            .map(|issue| match issue {
+                FsckIssue::Error { id, .. } | FsckIssue::Warning { id, .. } => *id,
+                _ => "",
+            })
+            .collect()
+    }
+
+    fn check(obj_type: &'static str, data: &[u8]) -> Vec<FsckIssue> {
+        let mut checker = ObjectChecker {
+            obj_type,
+            sha: ObjectId::default(),
+            format: ObjectFormat::Sha1,
+            issues: Vec::new(),
+            links: Vec::new(),
+        };
+        match obj_type {
+            "commit" => checker.commit(data),
+            "tree" => checker.tree(data),
+            _ => checker.tag(data),
+        }
+        checker.issues
+    }
+
+    fn tree(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for (mode, name) in entries {
+            data.extend_from_slice(format!("{mode} {name}\0").as_bytes());
+            data.extend_from_slice(&[0xab; 20]);
+        }
+        data
+    }
+
+    #[test]
+    fn trees_follow_the_canonical_format() {
+        let valid = tree(&[
+            ("100644", "a"),
+            ("40000", "a-b"),
+            ("40000", "a.c"),
+            ("100755", "a0"),
+        ]);
+        assert_eq!(issues("tree", &valid), Vec::<&str>::new());
+        assert_eq!(
+            issues("tree", &tree(&[("40000", "b"), ("100644", "b.c")])),
+            ["treeNotSorted"]
+        );
+        assert_eq!(
+            issues("tree", &tree(&[("100644", "b"), ("40000", "b")])),
+            ["duplicateEntries"]
+        );
+        assert_eq!(
+            issues(
+                "tree",
+                &tree(&[("040000", "x"), ("100600", "y"), ("100644", ".git")])
+            ),
+            [
+                "zeroPaddedFilemode",
+                "badFilemode",
+                "hasDotgit",
+                "treeNotSorted"
+            ]
+        );
+        // Like git, names that are only a danger to a checkout are warnings
+        let odd = tree(&[("100644", ""), ("100644", ".."), ("100644", ".GIT")]);
+        assert_eq!(
+            issues("tree", &odd),
+            ["emptyName", "hasDotdot", "hasDotgit"]
+        );
+        assert!(
+            check("tree", &odd)
+                .iter()
+                .all(|issue| matches!(issue, FsckIssue::Warning { .. }))
+        );
+        assert_eq!(issues("tree", b"100644 truncated\0abc"), ["badTree"]);
+    }
+
+    #[test]
+    fn commits_and_tags_need_their_headers() {
+        let tree = "a".repeat(40);
+        let commit =
+            format!("tree {tree}\nauthor A <a@b> 0 +0000\ncommitter A <a@b> 0 +0000\n\nm\n");
+        assert_eq!(issues("commit", commit.as_bytes()), Vec::<&str>::new());
+        let commit = format!("tree {tree}\nparent xyz\nauthor A a@b 0 +0000\n\nm\n");
+        assert_eq!(
+            issues("commit", commit.as_bytes()),
+            ["badParentSha", "badName", "missingCommitter"]
+        );
+        let tag = format!("object {tree}\ntype commit\ntag v1\ntagger A <a@b> 1 0000\n\nm\n");
+        assert_eq!(issues("tag", tag.as_bytes()), ["badTimezone"]);
+    }
+
+    #[test]
+    fn fsck_finds_corruption_and_dangling_objects() {
+        use crate::git_repo::tests::write_blob;
+
+        let repo = temp_repo("fsck");
+        let blob = |text: &str| write_blob(&repo, text);
+        let dangling = blob("dangling");
+        let corrupt = blob("corrupt");
+        // Swap the content of `corrupt` for another object's
+        std::fs::copy(repo.loose_path(&dangling), repo.loose_path(&corrupt)).unwrap();
+
+        let report = fsck(&repo, false).unwrap();
+        assert_eq!(report.checked, 2);
+        assert!(report.has_errors());
+        let issues: Vec<String> = report
+            .issues
+            .iter()
+            .map(|issue| issue.to_string())
+            .collect();
+        assert_eq!(issues.len(), 2);
+        assert!(issues.contains(&format!("dangling blob {dangling}")));
+        assert!(issues[0].starts_with(&format!("error in blob {corrupt}: hashMismatch")));
+    }
+}
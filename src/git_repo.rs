@@ -1,47 +1,138 @@
+use crate::{
+    commands::Change,
+    error::OzError,
+    hash::{ObjectFormat, ObjectId},
+    pack::{DeltaBaseCache, Pack, PackEntry, PackedObject, delta_apply},
+    refs,
+};
 use configparser::ini::Ini;
 use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
-use indexmap::IndexMap;
-use sha1::{Digest, Sha1};
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    ffi::OsStr,
+    fmt,
     io::{Read, Write},
-    path::PathBuf,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::SystemTime,
 };
 
+/// Size of the cache of delta bases, in bytes
+const DELTA_BASE_CACHE_SIZE: usize = 96 * 1024 * 1024;
+
+/// Longest delta chain followed before giving up on a pack
+const MAX_DELTA_CHAIN: usize = 10000;
+
+/// A repository with a worktree, as created by `git init` or `oz init`
 #[derive(Debug)]
 pub struct GitRepository {
     pub(super) worktree: PathBuf,
     pub(super) git_dir: PathBuf,
     pub(super) conf: Ini,
+    pub(super) format: ObjectFormat,
+    /// The packs of objects/pack, loaded on first use
+    packs: RefCell<Option<Rc<Vec<Pack>>>>,
+    delta_cache: RefCell<DeltaBaseCache>,
 }
 
 impl GitRepository {
+    /// Opens the repository whose worktree is `path`
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, OzError> {
+        GitRepository::new(path.into(), false)
+    }
+
+    /// Opens the repository `path` is in, looking at its parent directories
+    pub fn discover(path: impl AsRef<Path>) -> Result<Self, OzError> {
+        let path = path.as_ref().canonicalize()?;
+        for path in path.ancestors() {
+            if path.join(".git").exists() {
+                return GitRepository::open(path);
+            }
+        }
+        Err(OzError::NotARepository(
+            "Not a .git repository (or any of the parent directories): .git".to_string(),
+        ))
+    }
+
+    pub fn worktree(&self) -> &Path {
+        &self.worktree
+    }
+
+    pub fn git_dir(&self) -> &Path {
+        &self.git_dir
+    }
+
+    /// The hash function naming the objects of the repository
+    pub fn object_format(&self) -> ObjectFormat {
+        self.format
+    }
+
     pub(crate) fn repo_file(&self, file: PathBuf) -> PathBuf {
         self.git_dir.join(file)
     }
 
-    pub(crate) fn repo_dir(&self, dir: String) -> Result<PathBuf, String> {
+    /// The worktree file at `path`, as stored in trees and in the index
+    pub(crate) fn worktree_file(&self, path: &[u8]) -> PathBuf {
+        self.worktree.join(OsStr::from_bytes(path))
+    }
+
+    pub(crate) fn repo_dir(&self, dir: String) -> Result<PathBuf, OzError> {
         let dir = self.git_dir.join(dir);
         if dir.is_dir() {
             Ok(dir)
         } else {
-            Err("No Dir".to_string())
+            Err(OzError::NotFound(format!("No directory {dir:?}")))
         }
     }
-    pub(crate) fn new(path: PathBuf, no_check: bool) -> Result<Self, String> {
+    pub(crate) fn new(path: PathBuf, no_check: bool) -> Result<Self, OzError> {
         let worktree = path;
         let git_dir = worktree.join(".git");
         let mut conf = Ini::new();
+        let mut format = ObjectFormat::Sha1;
 
         if !no_check && !git_dir.is_dir() {
-            return Err("Not a Git repo".to_string());
+            return Err(OzError::NotARepository("Not a Git repo".to_string()));
         }
         let config_file = git_dir.join("config");
         if !no_check && config_file.is_file() {
-            conf.load(config_file)?;
-            let ver = conf.getuint("core", "repositoryformatversion")?;
-            if ver.is_none() || ver.unwrap() != 0 {
-                return Err("Unsupported repository format version".to_string());
+            conf.load(config_file)
+                .map_err(|e| OzError::Config(format!("bad config: {e}")))?;
+            let ver = conf
+                .getuint("core", "repositoryformatversion")
+                .map_err(|e| OzError::Config(format!("bad config: {e}")))?;
+            match ver {
+                Some(0) => {}
+                // Version 1 repositories must not use extensions we don't know
+                Some(1) => {
+                    let extensions = conf.get_map_ref().get("extensions").cloned();
+                    for (key, value) in extensions.unwrap_or_default() {
+                        let value = value.unwrap_or_default();
+                        match key.as_str() {
+                            "objectformat" => {
+                                format = ObjectFormat::from_name(&value).ok_or_else(|| {
+                                    OzError::NotARepository(format!(
+                                        "Unknown object format {value}"
+                                    ))
+                                })?;
+                            }
+                            "noop" => {}
+                            _ => {
+                                return Err(OzError::NotARepository(format!(
+                                    "Unknown repository extension {key}"
+                                )));
+                            }
+                        }
+                    }
+                }
+                _ => {
+                    return Err(OzError::NotARepository(
+                        "Unsupported repository format version".to_string(),
+                    ));
+                }
             }
         }
 
@@ -49,489 +140,2851 @@ impl GitRepository {
             worktree,
             git_dir,
             conf,
+            format,
+            packs: RefCell::new(None),
+            delta_cache: RefCell::new(DeltaBaseCache::new(DELTA_BASE_CACHE_SIZE)),
         })
     }
 
-    pub(crate) fn create_repo_dir(&self, path: PathBuf) -> Result<(), String> {
-        std::fs::create_dir_all(self.git_dir.join(&path))
-            .map_err(|_| format!("Unable to create dir: {path:?}"))
+    pub(crate) fn create_repo_dir(&self, path: PathBuf) -> Result<(), OzError> {
+        Ok(std::fs::create_dir_all(self.git_dir.join(path))?)
     }
 
-    pub(crate) fn default_config() -> Ini {
+    pub(crate) fn default_config(format: ObjectFormat) -> Ini {
         let mut x = Ini::new();
-        x.set("core", "repositoryformatversion", Some("0".into()));
+        // Anything but sha1 needs the extension, which needs version 1
+        let version = if format == ObjectFormat::Sha1 {
+            "0"
+        } else {
+            "1"
+        };
+        x.set("core", "repositoryformatversion", Some(version.into()));
         x.set("core", "filemode", Some("false".into()));
         x.set("core", "bare", Some("false".into()));
+        if format != ObjectFormat::Sha1 {
+            x.set("extensions", "objectformat", Some(format.name().into()));
+        }
         x
     }
 
-    pub(crate) fn object_read(&self, sha: &str) -> Option<GitObject> {
-        let path = self.repo_file(["objects", &sha[0..2], &sha[2..]].iter().collect());
+    /// Reads and parses the object `sha`, loose or packed
+    pub fn object_read(&self, sha: &ObjectId) -> Result<GitObject, OzError> {
+        let (obj_type, data) = self
+            .object_read_raw(sha)?
+            .ok_or(OzError::ObjectNotFound(*sha))?;
+        let object = match obj_type {
+            "commit" => GitCommit::try_from(data).map(GitObject::Commit),
+            "tree" => GitTree::parse(&data, self.format).map(GitObject::Tree),
+            "tag" => GitTag::try_from(data).map(GitObject::Tag),
+            _ => Ok(GitObject::Blob(GitBlob::from(data))),
+        };
+        object.map_err(|e| e.in_object(sha))
+    }
+
+    /// Reads the type and content of the object `sha`, loose or packed.
+    /// Returns `None` when the repository doesn't have it.
+    pub(crate) fn object_read_raw(
+        &self,
+        sha: &ObjectId,
+    ) -> Result<Option<(&'static str, Vec<u8>)>, OzError> {
+        if sha.format() != self.format {
+            return Ok(None);
+        }
+        let path = self.loose_path(sha);
         if !path.is_file() {
-            return None;
+            for (i, pack) in self.packs().iter().enumerate() {
+                if let Some(offset) = pack.find(sha) {
+                    let (obj_type, data) = self.pack_object(i, offset)?;
+                    return Ok(Some((obj_type, Rc::unwrap_or_clone(data))));
+                }
+            }
+            return Ok(None);
         }
         // Open file in binary read mode and decompress it using zlib
-        let file = std::fs::File::open(&path)
-            .map_err(|_| format!("Unable to open file: {path:?}"))
-            .expect("Unable to open file");
+        let file = std::fs::File::open(&path)?;
         let mut zlib = ZlibDecoder::new(file);
         let mut buffer = Vec::new();
-        zlib.read_to_end(&mut buffer).expect("Unable to decompress");
-
-        // Find the first space in the buffer
-        // And split the buffer on that first space
-        let mut space_pos = 0;
-        let mut null_pos = 0;
-        for (i, &ch) in buffer.iter().enumerate() {
-            if space_pos == 0 {
-                if ch == b' ' {
-                    space_pos = i;
+        zlib.read_to_end(&mut buffer)
+            .map_err(|_| OzError::corrupt_object("unable to decompress").in_object(sha))?;
+
+        // The header is `<type> <size>\0`
+        let malformed = || OzError::corrupt_object("malformed header").in_object(sha);
+        let null_pos = buffer
+            .iter()
+            .position(|&ch| ch == 0)
+            .ok_or_else(malformed)?;
+        let (obj_type, obj_size) = std::str::from_utf8(&buffer[..null_pos])
+            .ok()
+            .and_then(|header| header.split_once(' '))
+            .ok_or_else(malformed)?;
+        let obj_type = match obj_type {
+            "commit" => "commit",
+            "tree" => "tree",
+            "tag" => "tag",
+            "blob" => "blob",
+            _ => {
+                return Err(
+                    OzError::corrupt_object(format!("unknown object type {obj_type}"))
+                        .in_object(sha),
+                );
+            }
+        };
+        // Verify size
+        if obj_size.parse() != Ok(buffer.len() - null_pos - 1) {
+            return Err(OzError::corrupt_object("size mismatch").in_object(sha));
+        }
+        buffer.drain(..=null_pos);
+        Ok(Some((obj_type, buffer)))
+    }
+
+    pub(crate) fn packs(&self) -> Rc<Vec<Pack>> {
+        self.packs
+            .borrow_mut()
+            .get_or_insert_with(|| {
+                Rc::new(Pack::open_all(
+                    &self.repo_file("objects/pack".into()),
+                    self.format,
+                ))
+            })
+            .clone()
+    }
+
+    /// Forgets the packs loaded so far, once packs have been added or removed
+    pub(crate) fn packs_reload(&self) {
+        *self.packs.borrow_mut() = None;
+        *self.delta_cache.borrow_mut() = DeltaBaseCache::new(DELTA_BASE_CACHE_SIZE);
+    }
+
+    /// Reads the object at `offset` in the `pack`th pack, applying the deltas
+    /// down to its base. Every object used as a base is cached.
+    pub(crate) fn pack_object(&self, pack: usize, offset: u64) -> Result<PackedObject, OzError> {
+        let packs = self.packs();
+        let mut deltas = Vec::new();
+        let mut offset = offset;
+        // Offset of the base in the pack, `None` when it lives outside of it
+        let mut base_offset = Some(offset);
+        let (obj_type, mut data) = loop {
+            if deltas.len() > MAX_DELTA_CHAIN {
+                return Err(OzError::corrupt_file(
+                    &packs[pack].path,
+                    "delta chain too long",
+                ));
+            }
+            if let Some(cached) = self.delta_cache.borrow().get(pack, offset) {
+                break cached;
+            }
+            let (entry, data) = packs[pack].entry(offset)?;
+            match entry {
+                PackEntry::Whole(obj_type) => break (obj_type, Rc::new(data)),
+                PackEntry::OfsDelta(base) => {
+                    deltas.push((offset, data));
+                    offset = base;
                 }
-            } else if null_pos == 0 {
-                if ch == 0x0 {
-                    null_pos = i;
+                PackEntry::RefDelta(sha) => {
+                    deltas.push((offset, data));
+                    if let Some(base) = packs[pack].find(&sha) {
+                        offset = base;
+                    } else {
+                        let (obj_type, base) = self.object_read_raw(&sha)?.ok_or_else(|| {
+                            OzError::corrupt_file(
+                                &packs[pack].path,
+                                format!("missing delta base {sha}"),
+                            )
+                        })?;
+                        base_offset = None;
+                        break (obj_type, Rc::new(base));
+                    }
                 }
-            } else {
-                break;
             }
-        }
-        let obj_type = String::from_utf8(buffer[..space_pos].into()).expect("Malformed object");
-        let obj_size: usize = String::from_utf8(buffer[space_pos + 1..null_pos].into())
-            .expect("Malformed object")
-            .parse()
-            .expect("Malformed object: Size is not a number");
+            base_offset = Some(offset);
+        };
 
-        // Verify size
-        assert_eq!(buffer.len() - null_pos - 1, obj_size);
-
-        Some(match obj_type.as_str() {
-            "commit" => GitObject::Commit(GitCommit::from(buffer[null_pos + 1..].into())),
-            "tree" => GitObject::Tree(GitTree::from(buffer[null_pos + 1..].into())),
-            "tag" => GitObject::Tag(GitTag::from(buffer[null_pos + 1..].into())),
-            "blob" => GitObject::Blob(GitBlob::from(buffer[null_pos + 1..].into())),
-            _ => panic!("Unknown object type: {}", obj_type),
-        })
+        while let Some((delta_offset, delta)) = deltas.pop() {
+            if let Some(base_offset) = base_offset {
+                self.delta_cache
+                    .borrow_mut()
+                    .insert(pack, base_offset, obj_type, data.clone());
+            }
+            data = Rc::new(
+                delta_apply(&data, &delta)
+                    .map_err(|e| OzError::corrupt_file(&packs[pack].path, e))?,
+            );
+            base_offset = Some(delta_offset);
+        }
+        Ok((obj_type, data))
     }
 
     pub(crate) fn object_write(
-        repo: Option<GitRepository>,
+        repo: Option<&GitRepository>,
         object: GitObject,
-    ) -> Result<String, String> {
-        let (obj_type, data) = match object {
-            GitObject::Blob(d) => ("blob", d.serialize()),
-            GitObject::Commit(d) => ("commit", d.serialize()),
-            GitObject::Tag(d) => ("tag", d.serialize()),
-            GitObject::Tree(d) => ("tree", d.serialize()),
+    ) -> Result<ObjectId, OzError> {
+        GitRepository::object_write_raw(repo, object.obj_type(), &object.serialize())
+    }
+
+    /// Hashes the object made of `obj_type` and `data` and, given a `repo`,
+    /// stores it as a loose object unless the repository already has it.
+    /// Without a repository the object is hashed with sha1.
+    pub(crate) fn object_write_raw(
+        repo: Option<&GitRepository>,
+        obj_type: &str,
+        data: &[u8],
+    ) -> Result<ObjectId, OzError> {
+        let buffer = object_buffer(obj_type, data);
+        let format = repo.map_or(ObjectFormat::Sha1, |repo| repo.format);
+        let sha = ObjectId::hash(format, &buffer);
+
+        if let Some(repo) = repo
+            && !repo.loose_path(&sha).exists()
+            && !repo.object_packed(&sha)
+        {
+            repo.loose_write(&sha, &buffer)?;
+        }
+        Ok(sha)
+    }
+
+    /// The id the object made of `obj_type` and `data` has with `format`
+    pub(crate) fn object_hash(format: ObjectFormat, obj_type: &str, data: &[u8]) -> ObjectId {
+        ObjectId::hash(format, &object_buffer(obj_type, data))
+    }
+
+    /// Where the loose object `sha` is stored: `objects/<2 digits>/<the others>`
+    pub(crate) fn loose_path(&self, sha: &ObjectId) -> PathBuf {
+        let hex = sha.to_hex();
+        self.repo_file(["objects", &hex[..2], &hex[2..]].iter().collect())
+    }
+
+    /// Stores `buffer` (header included) as the loose object `sha`
+    fn loose_write(&self, sha: &ObjectId, buffer: &[u8]) -> Result<std::fs::File, OzError> {
+        // Create path and write the content
+        let path = self.loose_path(sha);
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let file = std::fs::File::create_new(path)?;
+
+        let mut compressor = ZlibEncoder::new(file, Compression::default());
+        compressor.write_all(buffer)?;
+        Ok(compressor.finish()?)
+    }
+
+    /// Copies the packed object `sha` to a loose object, dated `mtime`, so it
+    /// survives its pack being deleted.
+    pub(crate) fn object_loosen(&self, sha: &ObjectId, mtime: SystemTime) -> Result<(), OzError> {
+        if self.loose_path(sha).exists() {
+            return Ok(());
+        }
+        let (obj_type, data) = self
+            .object_read_raw(sha)?
+            .ok_or(OzError::ObjectNotFound(*sha))?;
+        let mut buffer = format!("{obj_type} {}\0", data.len()).into_bytes();
+        buffer.extend_from_slice(&data);
+        let file = self.loose_write(sha, &buffer)?;
+        Ok(file.set_modified(mtime)?)
+    }
+
+    /// Whether one of the packs holds `sha`
+    pub(crate) fn object_packed(&self, sha: &ObjectId) -> bool {
+        self.packs().iter().any(|pack| pack.find(sha).is_some())
+    }
+
+    /// Lists the ids of the loose objects
+    pub(crate) fn loose_objects(&self) -> Result<Vec<ObjectId>, OzError> {
+        let mut objects = Vec::new();
+        let Ok(dirs) = std::fs::read_dir(self.repo_file("objects".into())) else {
+            return Ok(objects);
+        };
+        for dir in dirs {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().to_string();
+            if prefix.len() != 2 || !prefix.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+                continue;
+            }
+            for file in std::fs::read_dir(dir.path())? {
+                let file = file?.file_name();
+                let file = file.to_string_lossy();
+                if let Ok(sha) = ObjectId::from_hex(&format!("{prefix}{file}"))
+                    && sha.format() == self.format
+                {
+                    objects.push(sha);
+                }
+            }
+        }
+        objects.sort();
+        Ok(objects)
+    }
+
+    /// Resolves `name` to the full sha of an object.
+    ///
+    /// `name` can be `HEAD`, a full or abbreviated (4+ chars) sha, or a ref name
+    /// such as `master`, `tags/v1.0` or `refs/heads/master`. When `fmt` is not
+    /// empty the object is peeled until it has that type: tags are followed to
+    /// the object they point at and commits to their tree.
+    pub(crate) fn object_find(&self, name: String, fmt: String) -> Result<ObjectId, OzError> {
+        let mut candidates = self.object_resolve(&name)?;
+        let sha = match candidates.len() {
+            0 => return Err(OzError::NotFound(format!("No such reference: {name}"))),
+            1 => candidates.remove(0),
+            _ => return Err(OzError::AmbiguousRef { name, candidates }),
         };
-        let mut buffer: Vec<u8> = Vec::new();
-        obj_type.as_bytes().iter().for_each(|&ch| buffer.push(ch));
+        if fmt.is_empty() {
+            return Ok(sha);
+        }
+        self.object_peel_to(sha, &fmt).map_err(|e| match e {
+            OzError::InvalidArgument(_) => {
+                OzError::InvalidArgument(format!("{name} can't be resolved to a {fmt}"))
+            }
+            e => e,
+        })
+    }
+
+    /// Follows tags to the object they point at, and commits to their tree,
+    /// until reaching an object of type `fmt`
+    pub(crate) fn object_peel_to(&self, sha: ObjectId, fmt: &str) -> Result<ObjectId, OzError> {
+        let mut sha = sha;
+        loop {
+            let obj = self.object_read(&sha)?;
+            if obj.obj_type() == fmt {
+                return Ok(sha);
+            }
+            let next = match obj {
+                GitObject::Tag(tag) => tag.object(),
+                GitObject::Commit(commit) if fmt == "tree" => commit.tree(),
+                _ => None,
+            };
+            sha = next.ok_or_else(|| {
+                OzError::InvalidArgument(format!("{sha} can't be resolved to a {fmt}"))
+            })?;
+        }
+    }
 
-        buffer.push(b' ');
+    /// Lists every object `name` could refer to.
+    ///
+    /// Like git, a ref wins over a sha prefix: refs are looked up in git's
+    /// order and the first match is the answer. Only when no ref matches are
+    /// hex names matched as sha prefixes, possibly several. A full sha is
+    /// taken before refs.
+    fn object_resolve(&self, name: &str) -> Result<Vec<ObjectId>, OzError> {
+        if name.is_empty() {
+            return Ok(Vec::new());
+        }
+        let is_hex = (4..=self.format.hex_len()).contains(&name.len())
+            && name.bytes().all(|ch| ch.is_ascii_hexdigit());
+        let full_sha = is_hex && name.len() == self.format.hex_len();
+        if !full_sha && let Some(sha) = self.ref_dwim(name)? {
+            return Ok(vec![sha]);
+        }
 
-        // push length of data
-        data.len()
-            .to_string()
-            .as_bytes()
-            .iter()
-            .for_each(|&ch| buffer.push(ch));
+        let mut candidates = Vec::new();
+        if is_hex {
+            let name = name.to_ascii_lowercase();
+            if let Ok(dir) = self.repo_dir(format!("objects/{}", &name[..2])) {
+                for entry in std::fs::read_dir(dir)? {
+                    let file = entry?.file_name();
+                    let file = file.to_string_lossy();
+                    if file.starts_with(&name[2..])
+                        && let Ok(sha) = ObjectId::from_hex(&format!("{}{}", &name[..2], file))
+                    {
+                        candidates.push(sha);
+                    }
+                }
+            }
+            for pack in self.packs().iter() {
+                for sha in pack.find_prefix(&name) {
+                    if !candidates.contains(&sha) {
+                        candidates.push(sha);
+                    }
+                }
+            }
+        }
+        if candidates.is_empty() && full_sha {
+            candidates.extend(self.ref_dwim(name)?);
+        }
+        Ok(candidates)
+    }
+
+    /// The sha of the first ref `name` is short for, in git's order
+    fn ref_dwim(&self, name: &str) -> Result<Option<ObjectId>, OzError> {
+        // Never let a ref name escape the git directory
+        if name.split('/').any(|part| part == ".." || part == ".") {
+            return Ok(None);
+        }
+        let is_root_ref = name.bytes().all(|ch| ch.is_ascii_uppercase() || ch == b'_');
+        let rules = ["", "refs/", "refs/tags/", "refs/heads/", "refs/remotes/"]
+            .map(|prefix| format!("{prefix}{name}"));
+        let remote_head = format!("refs/remotes/{name}/HEAD");
+        for (i, reference) in rules.into_iter().chain([remote_head]).enumerate() {
+            if i == 0 && !is_root_ref && !name.starts_with("refs/") {
+                continue;
+            }
+            if !is_root_ref && refs::check_ref_name(&reference).is_err() {
+                continue;
+            }
+            if let Some(sha) = refs::resolve(self, &reference)? {
+                return Ok(Some(sha));
+            }
+        }
+        Ok(None)
+    }
 
-        buffer.push(0x0);
+    /// Reads `.git/index`, returning an empty index when there is none yet.
+    pub fn index_read(&self) -> Result<GitIndex, OzError> {
+        let path = self.repo_file("index".into());
+        if !path.exists() {
+            return Ok(GitIndex::new());
+        }
+        GitIndex::parse(&std::fs::read(&path)?, self.format)
+            .map_err(|e| OzError::corrupt_file(path, e))
+    }
 
-        // push data
-        data.iter().for_each(|&ch| buffer.push(ch));
+    /// Writes `.git/index` through `index.lock`, so that readers (including git)
+    /// never see a partially written file.
+    pub(crate) fn index_write(&self, index: &GitIndex) -> Result<(), OzError> {
+        let lock = self.repo_file("index.lock".into());
+        let Ok(mut file) = std::fs::File::create_new(&lock) else {
+            return Err(OzError::Locked(lock));
+        };
+        let result = file
+            .write_all(&index.serialize(self.format))
+            .and_then(|_| file.sync_all())
+            .and_then(|_| std::fs::rename(&lock, self.repo_file("index".into())));
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&lock);
+            return Err(e.into());
+        }
+        Ok(())
+    }
 
-        // Compute Hash
-        let digest: String = Sha1::digest(&buffer)
+    /// Writes the tree objects for the stage 0 entries of `index` and returns
+    /// the sha of the root tree.
+    pub(crate) fn tree_from_index(&self, index: &GitIndex) -> Result<ObjectId, OzError> {
+        if let Some(entry) = index.entries.iter().find(|entry| entry.stage != 0) {
+            return Err(OzError::Refused(format!(
+                "{}: needs merge",
+                entry.name_lossy()
+            )));
+        }
+        let entries: Vec<&GitIndexEntry> = index
+            .entries
             .iter()
-            .map(|ch| format!("{:02x}", ch))
+            .filter(|entry| !entry.intent_to_add)
             .collect();
+        self.tree_from_entries(&entries, b"")
+    }
 
-        if let Some(repo) = repo {
-            let path = repo.repo_file(["objects", &digest[..2], &digest[2..]].iter().collect());
-            if !path.exists() {
-                // Create path and write the content
-                let file = std::fs::File::create_new(path).unwrap();
+    /// `entries` are sorted and all start with `prefix`, which is empty or
+    /// ends with a slash.
+    fn tree_from_entries(
+        &self,
+        entries: &[&GitIndexEntry],
+        prefix: &[u8],
+    ) -> Result<ObjectId, OzError> {
+        let mut tree = GitTree::new();
+        let mut i = 0;
+        while i < entries.len() {
+            let name = &entries[i].name[prefix.len()..];
+            match name.iter().position(|&ch| ch == b'/') {
+                Some(slash) => {
+                    let dir = &name[..slash];
+                    let prefix = [prefix, dir, b"/"].concat();
+                    let end = i + entries[i..]
+                        .iter()
+                        .take_while(|entry| entry.name.starts_with(&prefix))
+                        .count();
+                    let sha = self.tree_from_entries(&entries[i..end], &prefix)?;
+                    tree.items.push(TreeEntry::new(FileMode::Tree, dir, sha));
+                    i = end;
+                }
+                None => {
+                    let mode = FileMode::from_raw(entries[i].mode).ok_or_else(|| {
+                        OzError::corrupt_file(
+                            self.repo_file("index".into()),
+                            format!(
+                                "bad mode {:o} for {}",
+                                entries[i].mode,
+                                entries[i].name_lossy()
+                            ),
+                        )
+                    })?;
+                    tree.items.push(TreeEntry::new(mode, name, entries[i].sha));
+                    i += 1;
+                }
+            }
+        }
+        GitRepository::object_write(Some(self), GitObject::Tree(tree))
+    }
 
-                let mut compressor = ZlibEncoder::new(file, Compression::default());
-                let x = compressor.write(&buffer).unwrap();
-                println!("Written bytes: {x}");
+    /// Lists every blob below the tree `sha` as `path -> (mode, sha)`. Paths
+    /// that aren't UTF-8 have their invalid bytes replaced.
+    pub(crate) fn tree_flatten(
+        &self,
+        sha: &ObjectId,
+        prefix: &str,
+        files: &mut BTreeMap<String, (u32, ObjectId)>,
+    ) -> Result<(), OzError> {
+        for entry in self.tree_read(sha)?.items {
+            let path = format!("{prefix}{}", entry.name_lossy());
+            if entry.mode.is_tree() {
+                self.tree_flatten(&entry.id, &format!("{path}/"), files)?;
             } else {
-                println!("Path: {path:#?} Already exists");
+                files.insert(path, (entry.mode.raw(), entry.id));
             }
         }
-        return Ok(digest);
+        Ok(())
     }
 
-    pub(crate) fn object_find(&self, name: String, fmt: String) -> String {
-        name
+    /// The files that differ between the trees `old` and `new`, in git's
+    /// order. A missing tree counts as empty. Subtrees with the same id on
+    /// both sides are skipped without being read. With `paths`, only the
+    /// files at or below them are compared.
+    pub fn diff_trees(
+        &self,
+        old: Option<&ObjectId>,
+        new: Option<&ObjectId>,
+        paths: &[String],
+    ) -> Result<Vec<TreeChange>, OzError> {
+        let mut changes = Vec::new();
+        if old != new {
+            let (old, new) = (self.tree_items(old)?, self.tree_items(new)?);
+            self.diff_tree_entries(old, new, b"", paths, &mut changes)?;
+        }
+        Ok(changes)
+    }
+
+    fn tree_items(&self, tree: Option<&ObjectId>) -> Result<Vec<TreeEntry>, OzError> {
+        match tree {
+            Some(tree) => Ok(self.tree_read(tree)?.items),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn diff_tree_entries(
+        &self,
+        old: Vec<TreeEntry>,
+        new: Vec<TreeEntry>,
+        prefix: &[u8],
+        paths: &[String],
+        changes: &mut Vec<TreeChange>,
+    ) -> Result<(), OzError> {
+        let mut old = old.into_iter().peekable();
+        let mut new = new.into_iter().peekable();
+        loop {
+            let order = match (old.peek(), new.peek()) {
+                (None, None) => return Ok(()),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(one), Some(two)) => one.tree_order(two),
+            };
+            let (old_entry, new_entry) = match order {
+                Ordering::Less => (old.next(), None),
+                Ordering::Greater => (None, new.next()),
+                Ordering::Equal => (old.next(), new.next()),
+            };
+            let entry = old_entry.as_ref().or(new_entry.as_ref()).unwrap();
+            let path = [prefix, &entry.name].concat();
+            if old_entry == new_entry || !path_wanted(&path, entry.mode.is_tree(), paths) {
+                continue;
+            }
+
+            if entry.mode.is_tree() {
+                // Both sides are trees when there are two, as they sort the same
+                let old = self.tree_items(old_entry.as_ref().map(|entry| &entry.id))?;
+                let new = self.tree_items(new_entry.as_ref().map(|entry| &entry.id))?;
+                let prefix = [&path[..], b"/"].concat();
+                self.diff_tree_entries(old, new, &prefix, paths, changes)?;
+                continue;
+            }
+            let change = match (&old_entry, &new_entry) {
+                (None, _) => Change::Added,
+                (_, None) => Change::Deleted,
+                (Some(one), Some(two))
+                    if one.mode.raw() & 0o170000 != two.mode.raw() & 0o170000 =>
+                {
+                    Change::TypeChanged
+                }
+                _ => Change::Modified,
+            };
+            changes.push(TreeChange {
+                change,
+                path,
+                old: old_entry,
+                new: new_entry,
+            });
+        }
+    }
+
+    /// Looks `section.key` up in the repository config, then in the user's
+    /// `~/.gitconfig`.
+    pub fn config_get(&self, section: &str, key: &str) -> Option<String> {
+        if let Some(value) = self.conf.get(section, key) {
+            return Some(value);
+        }
+        let mut global = Ini::new();
+        global
+            .load(PathBuf::from(std::env::var_os("HOME")?).join(".gitconfig"))
+            .ok()?;
+        global.get(section, key)
     }
-}
 
-// Note: Could be optimized.
-pub(crate) fn kv_parser(data: Vec<u8>) -> Result<IndexMap<String, Vec<String>>, String> {
-    let data: String = String::from_utf8(data).map_err(|err| err.to_string())?;
+    /// Resolves a revision expression, as understood by `git rev-parse`, to a sha.
+    ///
+    /// On top of the names accepted by [`GitRepository::object_find`] this handles
+    /// `@` (HEAD), `<rev>@{upstream}` (or `@{u}`), `<rev>~<n>`, `<rev>^<n>`,
+    /// `<rev>^{<type>}`, `<rev>^{}` and `<rev>:<path>`. Suffixes can be chained,
+    /// e.g. `main@{u}~2^2:src/lib.rs`.
+    pub fn rev_parse(&self, rev: &str) -> Result<ObjectId, OzError> {
+        let (rev, path) = match rev.split_once(':') {
+            Some((rev, path)) => (rev, Some(path)),
+            None => (rev, None),
+        };
+        if let (true, Some(path)) = (rev.is_empty(), path) {
+            // `:<path>` and `:<stage>:<path>` name blobs staged in the index
+            let (stage, path) = match path.split_once(':') {
+                Some((stage @ ("0" | "1" | "2" | "3"), path)) => (stage.parse().unwrap(), path),
+                _ => (0, path),
+            };
+            return self
+                .index_read()?
+                .entries
+                .into_iter()
+                .find(|entry| entry.name == path.as_bytes() && entry.stage == stage)
+                .map(|entry| entry.sha)
+                .ok_or_else(|| {
+                    OzError::NotFound(format!(
+                        "path '{path}' is not in the index at stage {stage}"
+                    ))
+                });
+        }
 
-    let mut map: IndexMap<String, Vec<String>> = IndexMap::new();
+        let end = rev.find(['~', '^']).unwrap_or(rev.len());
+        let end = rev[..end].find("@{").unwrap_or(end);
+        let (base, mut suffix) = rev.split_at(end);
+
+        let mut sha = match suffix.strip_prefix("@{") {
+            Some(rest) => {
+                let (spec, rest) = rest.split_once('}').ok_or_else(|| {
+                    OzError::InvalidArgument(format!("{rev}: unterminated @{{...}}"))
+                })?;
+                suffix = rest;
+                if !["u", "upstream"].contains(&spec.to_ascii_lowercase().as_str()) {
+                    return Err(OzError::InvalidArgument(format!(
+                        "{rev}: @{{{spec}}} is not supported"
+                    )));
+                }
+                let upstream = self.upstream(base)?;
+                self.object_find(upstream, "".into())?
+            }
+            None if base.is_empty() || base == "@" => self.object_find("HEAD".into(), "".into())?,
+            None => self.object_find(base.into(), "".into())?,
+        };
 
-    let (header, msg) = data.split_once("\n\n").ok_or("No message section")?;
-    let null = "\x00";
-    let header = header.replace("\n ", null); // Escape the \n followed by space
+        while !suffix.is_empty() {
+            let op = suffix.as_bytes()[0];
+            suffix = &suffix[1..];
+            if op == b'^' && suffix.starts_with('{') {
+                let (spec, rest) = suffix[1..].split_once('}').ok_or_else(|| {
+                    OzError::InvalidArgument(format!("{rev}: unterminated ^{{...}}"))
+                })?;
+                suffix = rest;
+                sha = self.object_peel(sha, spec)?;
+                continue;
+            }
+            if op != b'^' && op != b'~' {
+                return Err(OzError::InvalidArgument(format!(
+                    "{rev}: invalid revision syntax"
+                )));
+            }
 
-    for line in header.split("\n") {
-        assert!(!line.is_empty(), "There can't be an empty line");
+            let digits = suffix.bytes().take_while(u8::is_ascii_digit).count();
+            let n: usize = match digits {
+                0 => 1,
+                _ => suffix[..digits]
+                    .parse()
+                    .map_err(|_| OzError::InvalidArgument(format!("{rev}: invalid number")))?,
+            };
+            suffix = &suffix[digits..];
+
+            sha = self.object_peel_to(sha, "commit")?;
+            if op == b'~' {
+                for _ in 0..n {
+                    sha = self
+                        .commit_parents(&sha)?
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| {
+                            OzError::NotFound(format!("{rev}: commit {sha} has no parent"))
+                        })?;
+                }
+            } else if n > 0 {
+                sha = self
+                    .commit_parents(&sha)?
+                    .into_iter()
+                    .nth(n - 1)
+                    .ok_or_else(|| {
+                        OzError::NotFound(format!("{rev}: commit {sha} has no parent {n}"))
+                    })?;
+            }
+        }
 
-        let (key, value) = line.split_once(" ").ok_or("Malformed Header section")?;
-        let value = value.replace(null, "\n"); // Remove the placeholder to reveal original data
+        match path {
+            Some(path) => self.tree_lookup(self.object_peel_to(sha, "tree")?, path),
+            None => Ok(sha),
+        }
+    }
 
-        if map.get(key).is_none() {
-            // There is no value
-            map.insert(key.into(), Vec::new());
+    /// Implements `^{<spec>}`: an empty spec peels tags away, `object` only checks
+    /// the object exists, and anything else is the type to peel to.
+    fn object_peel(&self, sha: ObjectId, spec: &str) -> Result<ObjectId, OzError> {
+        match spec {
+            "object" => {
+                self.object_read(&sha)?;
+                Ok(sha)
+            }
+            "" => {
+                let mut sha = sha;
+                while let GitObject::Tag(tag) = self.object_read(&sha)? {
+                    sha = tag
+                        .object()
+                        .ok_or_else(|| OzError::corrupt_object("missing object").in_object(&sha))?;
+                }
+                Ok(sha)
+            }
+            "commit" | "tree" | "blob" | "tag" => self.object_peel_to(sha, spec),
+            _ => Err(OzError::InvalidArgument(format!(
+                "Unknown object type in ^{{{spec}}}"
+            ))),
         }
-        let list = map.get_mut(key).unwrap();
-        list.push(value);
     }
-    let mut value = Vec::new();
-    value.push(msg.into());
 
-    map.insert(null.into(), value);
-    Ok(map)
-}
+    /// Reads the object `sha`, which must be a commit
+    pub fn commit_read(&self, sha: &ObjectId) -> Result<GitCommit, OzError> {
+        match self.object_read(sha)? {
+            GitObject::Commit(commit) => Ok(commit),
+            object => Err(OzError::WrongObjectType {
+                sha: *sha,
+                expected: "commit",
+                actual: object.obj_type(),
+            }),
+        }
+    }
 
-pub(crate) fn kv_serialize(map: IndexMap<String, Vec<String>>) -> Vec<u8> {
-    let null = "\x00";
-    let mut val = String::new();
-    for (key, array) in &map {
-        if key == null {
-            continue; // The commit msg will be the last one
+    pub fn tree_read(&self, sha: &ObjectId) -> Result<GitTree, OzError> {
+        match self.object_read(sha)? {
+            GitObject::Tree(tree) => Ok(tree),
+            object => Err(OzError::WrongObjectType {
+                sha: *sha,
+                expected: "tree",
+                actual: object.obj_type(),
+            }),
         }
-        for value in array {
-            val.push_str(key);
-            val.push(' ');
+    }
 
-            // The value with multiple line
-            let value = value.replace("\n", "\n ");
+    /// Returns the parents of the commit `sha`, in order.
+    pub fn commit_parents(&self, sha: &ObjectId) -> Result<Vec<ObjectId>, OzError> {
+        Ok(self.commit_read(sha)?.parents())
+    }
 
-            val.push_str(&value);
-            val.push('\n');
-        }
+    /// Lists the commits reachable from the revision `rev`, each once, newest
+    /// committer date first.
+    pub fn history(&self, rev: &str) -> Result<Vec<(ObjectId, GitCommit)>, OzError> {
+        let mut walk = self.rev_walk();
+        walk.push_rev(rev)?;
+        walk.map(|entry| entry.map(|entry| (entry.id, entry.commit)))
+            .collect()
     }
-    val.push('\n');
-    if let Some(msg) = map.get(null) {
-        val.push_str(&msg[0]);
+
+    /// Starts a walk of the history, see [`RevWalk`]
+    pub fn rev_walk(&self) -> RevWalk<'_> {
+        RevWalk::new(self)
     }
 
-    val.into()
-}
+    /// The best common ancestors of the commits `one` and `two`: the common
+    /// ancestors no other common ancestor descends from, newest first.
+    pub fn merge_bases(&self, one: &ObjectId, two: &ObjectId) -> Result<Vec<ObjectId>, OzError> {
+        const ONE: u8 = 1;
+        const TWO: u8 = 2;
+        // Reached from a common ancestor, so can't be a best one
+        const STALE: u8 = 4;
+        if one == two {
+            return Ok(vec![*one]);
+        }
 
-pub(crate) trait Serializable {
-    fn serialize(self) -> Vec<u8>;
-    fn deserialize(&mut self, data: Vec<u8>);
-}
+        let mut flags = HashMap::from([(*one, ONE), (*two, TWO)]);
+        let mut queue = DateQueue::default();
+        queue.push(*one, self.commit_read(one)?);
+        queue.push(*two, self.commit_read(two)?);
+        let mut bases = Vec::new();
+        while queue.ids().any(|id| flags[id] & STALE == 0) {
+            let Some((id, commit)) = queue.pop() else {
+                break;
+            };
+            let mut flag = flags[&id];
+            if flag == ONE | TWO {
+                bases.push(id);
+                flag |= STALE;
+            }
+            for parent in commit.parents() {
+                let parent_flag = flags.entry(parent).or_default();
+                if *parent_flag & flag != flag {
+                    *parent_flag |= flag;
+                    queue.push(parent, self.commit_read(&parent)?);
+                }
+            }
+        }
 
-#[derive(Debug)]
-pub(crate) enum GitObject {
-    Blob(GitBlob),
-    Commit(GitCommit),
-    Tag(GitTag),
-    Tree(GitTree),
-}
+        // A base found before the walk went stale may still be an ancestor of
+        // another one, through a longer path
+        let mut best = Vec::new();
+        for base in &bases {
+            let mut walk = self.rev_walk();
+            for other in bases.iter().filter(|other| *other != base) {
+                walk.push(*other)?;
+            }
+            let mut descends = false;
+            for entry in walk {
+                if entry?.id == *base {
+                    descends = true;
+                    break;
+                }
+            }
+            if !descends {
+                best.push(*base);
+            }
+        }
+        Ok(best)
+    }
 
-impl Serializable for GitObject {
-    fn serialize(self) -> Vec<u8> {
-        match self {
-            GitObject::Blob(data) => data.serialize(),
-            GitObject::Commit(data) => data.serialize(),
-            GitObject::Tag(data) => data.serialize(),
-            GitObject::Tree(data) => data.serialize(),
+    /// Follows `path` from the tree `sha` and returns the id of the entry found.
+    pub(crate) fn tree_lookup(&self, sha: ObjectId, path: &str) -> Result<ObjectId, OzError> {
+        let missing = || OzError::NotFound(format!("path '{path}' does not exist"));
+        let mut sha = sha;
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let GitObject::Tree(tree) = self.object_read(&sha)? else {
+                return Err(missing());
+            };
+            sha = tree
+                .items
+                .into_iter()
+                .find(|entry| entry.name == name.as_bytes())
+                .ok_or_else(missing)?
+                .id;
         }
+        Ok(sha)
     }
 
-    fn deserialize(&mut self, data: Vec<u8>) {
-        todo!()
+    /// Returns the ref tracked by `branch` (or the current branch when empty),
+    /// using the `branch.<name>.remote` and `branch.<name>.merge` settings.
+    fn upstream(&self, branch: &str) -> Result<String, OzError> {
+        let branch = match branch {
+            "" | "@" | "HEAD" => refs::current_branch(self)?
+                .ok_or_else(|| OzError::NotFound("HEAD does not point to a branch".into()))?,
+            branch => branch
+                .strip_prefix("refs/heads/")
+                .unwrap_or(branch)
+                .to_string(),
+        };
+        let section = format!("branch \"{branch}\"");
+        let (Some(remote), Some(merge)) = (
+            self.conf.get(&section, "remote"),
+            self.conf.get(&section, "merge"),
+        ) else {
+            return Err(OzError::NotFound(format!(
+                "no upstream configured for branch '{branch}'"
+            )));
+        };
+        if remote == "." {
+            return Ok(merge);
+        }
+        let merge = merge.strip_prefix("refs/heads/").unwrap_or(&merge);
+        Ok(format!("refs/remotes/{remote}/{merge}"))
     }
 }
 
+/// A commit listed by [`RevWalk`]
 #[derive(Debug)]
-pub(crate) struct GitBlob {
-    pub(crate) buffer: Vec<u8>,
-}
-#[derive(Debug)]
-pub(crate) struct GitTree {
-    pub(crate) items: Vec<([u8; 6], String, String)>,
+pub struct RevCommit {
+    pub id: ObjectId,
+    pub commit: GitCommit,
+    /// The parents the walk followed. When parents are rewritten, the
+    /// closest listed ancestors along each of them instead.
+    pub parents: Vec<ObjectId>,
 }
-#[derive(Debug)]
-pub(crate) struct GitCommit {
-    pub(crate) data: IndexMap<String, Vec<String>>,
+
+/// The order of the commits listed by [`RevWalk`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RevOrder {
+    /// Newest committer date first, as the commits are walked
+    #[default]
+    Walk,
+    /// No parent before all its children, otherwise newest committer date
+    /// first
+    Date,
+    /// No parent before all its children, keeping the commits of each line of
+    /// history together
+    Topo,
 }
-#[derive(Debug)]
-pub(crate) struct GitTag {
-    buffer: Vec<u8>,
+
+type CommitFilter<'a> = Box<dyn FnMut(&GitCommit) -> bool + 'a>;
+
+/// Walks the history back from the pushed commits, newest committer date
+/// first, without ever recursing. Commits reachable from hidden commits are
+/// left out, like `A..B` or `^A B` in git.
+///
+/// Listing the commits in topological order, in reverse or with rewritten
+/// parents needs the whole history first; otherwise the commits are read as
+/// they are listed.
+pub struct RevWalk<'a> {
+    repo: &'a GitRepository,
+    queue: DateQueue,
+    seen: HashSet<ObjectId>,
+    hidden: HashSet<ObjectId>,
+    order: RevOrder,
+    reverse: bool,
+    first_parent: bool,
+    rewrite: bool,
+    max_count: Option<usize>,
+    since: Option<i64>,
+    paths: Vec<String>,
+    filter: Option<CommitFilter<'a>>,
+    /// Commits walked but not listed, with the parents followed
+    skipped: Vec<(ObjectId, Vec<ObjectId>)>,
+    listed: usize,
+    /// Every commit to list, once the whole history was needed
+    sorted: Option<std::vec::IntoIter<RevCommit>>,
 }
 
-impl GitBlob {
-    pub(crate) fn from(buffer: Vec<u8>) -> Self {
-        GitBlob { buffer }
+impl<'a> RevWalk<'a> {
+    pub fn new(repo: &'a GitRepository) -> Self {
+        RevWalk {
+            repo,
+            queue: DateQueue::default(),
+            seen: HashSet::new(),
+            hidden: HashSet::new(),
+            order: RevOrder::default(),
+            reverse: false,
+            first_parent: false,
+            rewrite: false,
+            max_count: None,
+            since: None,
+            paths: Vec::new(),
+            filter: None,
+            skipped: Vec::new(),
+            listed: 0,
+            sorted: None,
+        }
     }
-}
 
-impl GitTree {
-    pub(crate) fn from(buffer: Vec<u8>) -> Self {
-        GitTree {
-            items: tree_parse(&buffer),
+    /// Lists the commit `id` and its ancestors
+    pub fn push(&mut self, id: ObjectId) -> Result<(), OzError> {
+        if self.seen.insert(id) {
+            self.queue.push(id, self.repo.commit_read(&id)?);
         }
+        Ok(())
     }
-    pub(crate) fn new() -> Self {
-        GitTree { items: Vec::new() }
+
+    /// Leaves out the commit `id` and its ancestors
+    pub fn hide(&mut self, id: ObjectId) -> Result<(), OzError> {
+        self.hidden.insert(id);
+        self.push(id)
     }
-}
-impl GitCommit {
-    pub(crate) fn from(buffer: Vec<u8>) -> Self {
-        GitCommit {
-            data: kv_parser(buffer).unwrap(),
+
+    /// Pushes the revision `rev`, or with `^rev` hides it. `A..B` hides `A`
+    /// and pushes `B`, either defaulting to HEAD.
+    pub fn push_rev(&mut self, rev: &str) -> Result<(), OzError> {
+        let commit = |rev: &str| {
+            let rev = if rev.is_empty() { "HEAD" } else { rev };
+            self.repo
+                .object_peel_to(self.repo.rev_parse(rev)?, "commit")
+        };
+        // `rev:path` names a blob, whose path may contain `..`
+        let range = if rev.contains(':') {
+            None
+        } else if rev.contains("...") {
+            return Err(OzError::InvalidArgument(format!(
+                "{rev}: symmetric differences are not supported"
+            )));
+        } else {
+            rev.split_once("..")
+        };
+        if let Some((hidden, pushed)) = range {
+            let (hidden, pushed) = (commit(hidden)?, commit(pushed)?);
+            self.hide(hidden)?;
+            self.push(pushed)
+        } else if let Some(hidden) = rev.strip_prefix('^') {
+            let hidden = commit(hidden)?;
+            self.hide(hidden)
+        } else {
+            let pushed = commit(rev)?;
+            self.push(pushed)
         }
     }
-}
-impl GitTag {
-    pub(crate) fn from(buffer: Vec<u8>) -> Self {
-        GitTag { buffer }
+
+    pub fn sort(&mut self, order: RevOrder) {
+        self.order = order;
     }
-}
 
-impl Serializable for GitBlob {
-    fn serialize(self) -> Vec<u8> {
-        self.buffer
+    /// Lists the oldest commits first
+    pub fn reverse(&mut self) {
+        self.reverse = true;
     }
 
-    fn deserialize(&mut self, data: Vec<u8>) {
-        self.buffer = data;
+    /// Only follows the first parent of merges
+    pub fn first_parent(&mut self) {
+        self.first_parent = true;
     }
-}
 
-// Todo implement these
-impl Serializable for GitCommit {
-    fn serialize(self) -> Vec<u8> {
-        kv_serialize(self.data)
+    /// Gives as [`RevCommit::parents`] the closest listed ancestors, as a
+    /// graph of the listed commits needs
+    pub fn rewrite_parents(&mut self) {
+        self.rewrite = true;
     }
 
-    fn deserialize(&mut self, data: Vec<u8>) {
-        self.data = kv_parser(data).unwrap();
+    /// Lists at most `count` commits, before reversing them
+    pub fn max_count(&mut self, count: usize) {
+        self.max_count = Some(count);
     }
-}
-impl Serializable for GitTree {
-    fn serialize(self) -> Vec<u8> {
-        let mut items = self.items.clone();
-        tree_serialize(&mut items)
+
+    /// Stops at commits older than `time` (seconds since the epoch)
+    pub fn since(&mut self, time: i64) {
+        self.since = Some(time);
     }
 
-    fn deserialize(&mut self, data: Vec<u8>) {
-        self.items = tree_parse(&data);
+    /// Only lists commits changing `paths`, relative to the worktree. A
+    /// commit with a parent that has the same content at these paths is
+    /// left out and only that parent is followed, like git's default history
+    /// simplification.
+    pub fn paths(&mut self, paths: Vec<String>) {
+        self.paths = paths;
     }
-}
-impl Serializable for GitTag {
-    fn serialize(self) -> Vec<u8> {
-        self.buffer
+
+    /// Only lists the commits `filter` accepts. The others are still walked.
+    pub fn filter_commits(&mut self, filter: impl FnMut(&GitCommit) -> bool + 'a) {
+        self.filter = Some(Box::new(filter));
     }
 
-    fn deserialize(&mut self, data: Vec<u8>) {
-        todo!()
+    fn needs_all(&self) -> bool {
+        self.order != RevOrder::Walk || self.reverse || self.rewrite
     }
-}
 
-/// **Recursive algorithm to log history of the commit provided**
-///
-/// It prints directly to stdout.
-pub fn log_graphviz(
-    repo: &GitRepository,
-    sha: &String,
-    seen: &mut HashSet<String>,
-) -> Result<(), String> {
-    if seen.contains(sha) {
-        return Ok(());
-    }
-    seen.insert(sha.to_owned());
-
-    if let GitObject::Commit(commit) = repo.object_read(sha).ok_or("The object can't be read")? {
-        let msg = commit
-            .data
-            .get("\x00")
-            .expect("No commit section")
-            .first()
-            .expect("No commit msg found");
-
-        let msg = msg.replace("\\", "\\\\");
-        let msg = msg.replace("\"", "\\\"");
-
-        // Only 1st line when there are multiple lines in the commit
-        let msg = msg.split_once("\n").unwrap_or(("default", "")).0;
-
-        println!("  c_{} [label=\"{} : {}\"]", sha, &sha[..7], &msg);
-
-        // Lets print parent now
-        if let Some(parents) = commit.data.get("parent") {
-            for parent in parents {
-                println!("  c_{sha} -> c_{parent};");
-                log_graphviz(repo, parent, seen)?;
+    /// Walks commits until one is to be listed
+    fn walk(&mut self) -> Result<Option<RevCommit>, OzError> {
+        loop {
+            if !self.hidden.is_empty() && self.queue.ids().all(|id| self.hidden.contains(id)) {
+                // Only commits left out remain
+                return Ok(None);
+            }
+            let Some((id, commit)) = self.queue.pop() else {
+                return Ok(None);
+            };
+            let hidden = self.hidden.contains(&id);
+            let time = commit.committer().map_or(0, |committer| committer.time);
+            if !hidden && self.since.is_some_and(|since| time < since) {
+                // Older history can't be more recent
+                continue;
+            }
+
+            let mut parents = commit.parents();
+            if self.first_parent {
+                parents.truncate(1);
+            }
+            let mut listed = !hidden;
+            if listed && !self.paths.is_empty() {
+                let tree = commit_tree(&id, &commit)?;
+                let mut same = None;
+                for parent in &parents {
+                    let parent_tree = commit_tree(parent, &self.repo.commit_read(parent)?)?;
+                    let changes =
+                        self.repo
+                            .diff_trees(Some(&parent_tree), Some(&tree), &self.paths)?;
+                    if changes.is_empty() {
+                        same = Some(*parent);
+                        break;
+                    }
+                }
+                match same {
+                    Some(parent) => {
+                        parents = vec![parent];
+                        listed = false;
+                    }
+                    // A root commit lists the paths it adds
+                    None => {
+                        listed = !parents.is_empty()
+                            || !self
+                                .repo
+                                .diff_trees(None, Some(&tree), &self.paths)?
+                                .is_empty()
+                    }
+                }
+            }
+
+            for parent in &parents {
+                if hidden {
+                    self.hidden.insert(*parent);
+                }
+                self.push(*parent)?;
+            }
+            if listed && let Some(filter) = &mut self.filter {
+                listed = filter(&commit);
+            }
+            if listed {
+                return Ok(Some(RevCommit {
+                    id,
+                    commit,
+                    parents,
+                }));
+            }
+            if !hidden {
+                self.skipped.push((id, parents));
             }
-        } else {
-            // First commit, no parent
-            // return
         }
-    } else {
-        panic!("Inconsistent");
     }
 
-    Ok(())
-}
-
-pub(crate) fn tree_parse(data: &Vec<u8>) -> Vec<([u8; 6], String, String)> {
-    let mut list = Vec::new();
-    let mut i = 0;
-    loop {
-        if i >= data.len() {
-            break;
+    /// Walks the whole history, then rewrites the parents and sorts
+    fn walk_all(&mut self) -> Result<Vec<RevCommit>, OzError> {
+        let mut commits = Vec::new();
+        while let Some(commit) = self.walk()? {
+            commits.push(commit);
         }
-        let frame = &data[i..];
 
-        let null_pos = frame
-            .iter()
-            .position(|&ch| ch == 0)
-            .expect("Malformed tree");
-        let sha = format!(
-            "{:0>40}",
-            frame[null_pos + 1..=null_pos + 20]
-                .iter()
-                .map(|ch| format!("{:02x}", ch))
-                .collect::<String>()
-        );
+        // Parents are walked after their children, so going backwards the
+        // parents of a skipped commit are already rewritten
+        let mut rewritten: HashMap<ObjectId, Vec<ObjectId>> = HashMap::new();
+        for (id, parents) in std::mem::take(&mut self.skipped).into_iter().rev() {
+            let parents = rewrite(&parents, &rewritten);
+            rewritten.insert(id, parents);
+        }
+        let listed: HashSet<ObjectId> = commits.iter().map(|commit| commit.id).collect();
+        for commit in &mut commits {
+            commit.parents = rewrite(&commit.parents, &rewritten);
+            // Hidden or older parents aren't listed
+            commit.parents.retain(|parent| listed.contains(parent));
+        }
 
-        let (m, path) = frame[..null_pos].split_at(
-            frame
-                .iter()
-                .position(|&ch| ch == b' ')
-                .expect("Malformed tree"),
-        );
-        let mut file_mode: [u8; 6] = [0; 6];
-        file_mode[if m.len() == 6 { 0 } else { 1 }..].copy_from_slice(m);
-        let path = String::from_utf8(path[1..].into()).expect("Malformed tree");
-        list.push((file_mode, path, sha));
-        i += null_pos + 21;
-    }
-    list
-}
-
-// pub(crate) fn tree_parse_line(data: &[u8]) -> ([u8; 6], String, String) {
-//     let (file_info, sha) =
-//         data.split_at(data.iter().position(|&x| x == 0).expect("Malformed tree"));
-//     let (file_mode, file_path) = file_info.split_at(
-//         file_info
-//             .iter()
-//             .position(|&x| x == b' ')
-//             .expect("Malformed tree"),
-//     );
-//     assert!(file_mode.len() == 5 || file_mode.len() == 6);
-//     let mut mode: [u8; 6] = [0; 6];
-
-//     mode[if file_mode.len() == 6 { 0 } else { 1 }..].copy_from_slice(file_mode);
-
-//     let file_path = String::from_utf8(file_path.into()).expect("Malformed tree");
-//     let sha = format!(
-//         "{:0>40}",
-//         sha.iter()
-//             .map(|ch| format!("{:02x}", ch))
-//             .collect::<String>()
-//     );
-
-//     (mode, file_path, sha)
-// }
-
-pub(crate) fn tree_serialize(list: &mut Vec<([u8; 6], String, String)>) -> Vec<u8> {
-    // Sort the list
-    list.sort_by(|a, b| {
-        let x = if a.0[0] == b'1' && a.0[1] == b'0' {
-            a.1.clone()
+        if self.order != RevOrder::Walk {
+            commits = topo_sort(commits, self.order);
+        }
+        commits.truncate(self.max_count.unwrap_or(usize::MAX));
+        if self.reverse {
+            commits.reverse();
+        }
+        Ok(commits)
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<RevCommit, OzError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.needs_all() {
+            if self.sorted.is_none() {
+                match self.walk_all() {
+                    Ok(commits) => self.sorted = Some(commits.into_iter()),
+                    Err(error) => {
+                        self.sorted = Some(Vec::new().into_iter());
+                        return Some(Err(error));
+                    }
+                }
+            }
+            return self.sorted.as_mut()?.next().map(Ok);
+        }
+        if self.max_count.is_some_and(|max| self.listed >= max) {
+            return None;
+        }
+        let commit = self.walk().transpose()?;
+        self.listed += 1;
+        Some(commit)
+    }
+}
+
+/// Replaces the skipped commits among `parents` with their rewritten parents
+fn rewrite(parents: &[ObjectId], rewritten: &HashMap<ObjectId, Vec<ObjectId>>) -> Vec<ObjectId> {
+    let mut parents_rewritten = Vec::new();
+    for parent in parents {
+        for id in rewritten
+            .get(parent)
+            .map_or(std::slice::from_ref(parent), Vec::as_slice)
+        {
+            if !parents_rewritten.contains(id) {
+                parents_rewritten.push(*id);
+            }
+        }
+    }
+    parents_rewritten
+}
+
+/// Orders `commits` so that they come after all their children. Like git,
+/// with [`RevOrder::Topo`] the last parent made ready is listed first, which
+/// keeps each line of history together.
+fn topo_sort(commits: Vec<RevCommit>, order: RevOrder) -> Vec<RevCommit> {
+    let mut children: HashMap<ObjectId, usize> = HashMap::new();
+    for commit in &commits {
+        for parent in &commit.parents {
+            *children.entry(*parent).or_default() += 1;
+        }
+    }
+    let mut ready = match order {
+        RevOrder::Topo => Ready::Stack(Vec::new()),
+        _ => Ready::Dates(DateQueue::default()),
+    };
+    let tips: Vec<&RevCommit> = commits
+        .iter()
+        .filter(|commit| !children.contains_key(&commit.id))
+        .collect();
+    if order == RevOrder::Topo {
+        // The first tip comes out first
+        tips.iter().rev().for_each(|tip| ready.push(tip));
+    } else {
+        tips.iter().for_each(|tip| ready.push(tip));
+    }
+    let mut commits: HashMap<ObjectId, RevCommit> = commits
+        .into_iter()
+        .map(|commit| (commit.id, commit))
+        .collect();
+
+    let mut sorted = Vec::new();
+    while let Some(id) = ready.pop() {
+        let Some(commit) = commits.remove(&id) else {
+            continue;
+        };
+        for parent in &commit.parents {
+            let count = children.get_mut(parent).unwrap();
+            *count -= 1;
+            if *count == 0
+                && let Some(parent) = commits.get(parent)
+            {
+                ready.push(parent);
+            }
+        }
+        sorted.push(commit);
+    }
+    sorted
+}
+
+/// The commits [`topo_sort`] can list next
+enum Ready {
+    Stack(Vec<ObjectId>),
+    Dates(DateQueue),
+}
+
+impl Ready {
+    fn push(&mut self, commit: &RevCommit) {
+        match self {
+            Ready::Stack(stack) => stack.push(commit.id),
+            Ready::Dates(queue) => queue.push_time(commit.id, commit.commit.committer()),
+        }
+    }
+
+    fn pop(&mut self) -> Option<ObjectId> {
+        match self {
+            Ready::Stack(stack) => stack.pop(),
+            Ready::Dates(queue) => queue.pop_id(),
+        }
+    }
+}
+
+/// Commits waiting to be walked, newest committer date first. Commits with
+/// the same date come out in the order they were queued.
+#[derive(Default)]
+struct DateQueue {
+    heap: BinaryHeap<(i64, Reverse<u64>, ObjectId)>,
+    commits: HashMap<ObjectId, GitCommit>,
+    queued: u64,
+}
+
+impl DateQueue {
+    /// Queues the commit `id`, unless already waiting
+    fn push(&mut self, id: ObjectId, commit: GitCommit) {
+        if !self.commits.contains_key(&id) {
+            self.push_time(id, commit.committer());
+            self.commits.insert(id, commit);
+        }
+    }
+
+    /// Queues `id` alone, by the date of `committer`
+    fn push_time(&mut self, id: ObjectId, committer: Option<Signature>) {
+        let time = committer.map_or(0, |committer| committer.time);
+        self.heap.push((time, Reverse(self.queued), id));
+        self.queued += 1;
+    }
+
+    fn pop_id(&mut self) -> Option<ObjectId> {
+        self.heap.pop().map(|(_, _, id)| id)
+    }
+
+    fn pop(&mut self) -> Option<(ObjectId, GitCommit)> {
+        let id = self.pop_id()?;
+        let commit = self.commits.remove(&id)?;
+        Some((id, commit))
+    }
+
+    fn ids(&self) -> impl Iterator<Item = &ObjectId> {
+        self.heap.iter().map(|(_, _, id)| id)
+    }
+}
+
+/// The headers and message of a commit or tag. The headers keep their order
+/// and raw bytes, so that [`kv_serialize`] gives back exactly what
+/// [`kv_parser`] read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct KvList {
+    /// `(key, value)`, values spanning several lines hold `\n`
+    pub(crate) headers: Vec<(String, Vec<u8>)>,
+    /// `None` when no blank line follows the headers
+    pub(crate) message: Option<Vec<u8>>,
+}
+
+impl KvList {
+    pub(crate) fn push(&mut self, key: &str, value: impl Into<Vec<u8>>) {
+        self.headers.push((key.to_string(), value.into()));
+    }
+
+    /// The values of the header `key`, in order
+    fn values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [u8]> {
+        self.headers
+            .iter()
+            .filter(move |(name, _)| name == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// The first value of the header `key`
+    fn get(&self, key: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Checks that the values of the headers `keys` are object ids
+    fn check_ids(&self, keys: &[&str]) -> Result<(), OzError> {
+        for key in keys {
+            for value in self.values(key) {
+                let valid =
+                    std::str::from_utf8(value).is_ok_and(|value| ObjectId::from_hex(value).is_ok());
+                if !valid {
+                    let value = String::from_utf8_lossy(value);
+                    return Err(OzError::corrupt_object(format!("bad {key} id {value}")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn message(&self) -> &[u8] {
+        self.message.as_deref().unwrap_or_default()
+    }
+}
+
+/// Parses the headers of a commit or tag, up to the blank line, then the
+/// message. Lines starting with a space continue the value of the previous
+/// header.
+pub(crate) fn kv_parser(data: &[u8]) -> Result<KvList, OzError> {
+    let mut kv = KvList::default();
+    let mut pos = 0;
+    while pos < data.len() {
+        if data[pos] == b'\n' {
+            kv.message = Some(data[pos + 1..].to_vec());
+            break;
+        }
+        let end = data[pos..]
+            .iter()
+            .position(|&ch| ch == b'\n')
+            .map(|end| pos + end)
+            .ok_or_else(|| OzError::corrupt_object("unterminated header"))?;
+        let line = &data[pos..end];
+        pos = end + 1;
+
+        if let Some(more) = line.strip_prefix(b" ") {
+            let (_, value) = kv
+                .headers
+                .last_mut()
+                .ok_or_else(|| OzError::corrupt_object("malformed header section"))?;
+            value.push(b'\n');
+            value.extend_from_slice(more);
+            continue;
+        }
+        let space = line
+            .iter()
+            .position(|&ch| ch == b' ')
+            .ok_or_else(|| OzError::corrupt_object("malformed header section"))?;
+        let key = std::str::from_utf8(&line[..space])
+            .map_err(|_| OzError::corrupt_object("malformed header section"))?;
+        kv.push(key, &line[space + 1..]);
+    }
+    Ok(kv)
+}
+
+/// An object as stored and hashed: `<type> <size>\0` followed by `data`
+fn object_buffer(obj_type: &str, data: &[u8]) -> Vec<u8> {
+    let mut buffer: Vec<u8> = obj_type.as_bytes().to_vec();
+
+    buffer.push(b' ');
+
+    // push length of data
+    data.len()
+        .to_string()
+        .as_bytes()
+        .iter()
+        .for_each(|&ch| buffer.push(ch));
+
+    buffer.push(0x0);
+
+    // push data
+    data.iter().for_each(|&ch| buffer.push(ch));
+    buffer
+}
+
+pub(crate) fn kv_serialize(kv: &KvList) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in &kv.headers {
+        data.extend_from_slice(key.as_bytes());
+        data.push(b' ');
+        // Values with several lines go on continuation lines
+        for &ch in value {
+            data.push(ch);
+            if ch == b'\n' {
+                data.push(b' ');
+            }
+        }
+        data.push(b'\n');
+    }
+    if let Some(message) = &kv.message {
+        data.push(b'\n');
+        data.extend_from_slice(message);
+    }
+    data
+}
+
+pub trait Serializable {
+    fn serialize(self) -> Vec<u8>;
+}
+
+#[derive(Debug)]
+pub enum GitObject {
+    Blob(GitBlob),
+    Commit(GitCommit),
+    Tag(GitTag),
+    Tree(GitTree),
+}
+
+impl GitObject {
+    pub fn obj_type(&self) -> &'static str {
+        match self {
+            GitObject::Blob(_) => "blob",
+            GitObject::Commit(_) => "commit",
+            GitObject::Tag(_) => "tag",
+            GitObject::Tree(_) => "tree",
+        }
+    }
+}
+
+impl Serializable for GitObject {
+    fn serialize(self) -> Vec<u8> {
+        match self {
+            GitObject::Blob(data) => data.serialize(),
+            GitObject::Commit(data) => data.serialize(),
+            GitObject::Tag(data) => data.serialize(),
+            GitObject::Tree(data) => data.serialize(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GitBlob {
+    pub(crate) buffer: Vec<u8>,
+}
+#[derive(Debug)]
+pub struct GitTree {
+    pub(crate) items: Vec<TreeEntry>,
+}
+
+/// What a tree entry is, from its mode. Regular files are a blob or an
+/// executable depending on their execute bits, like git does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileMode {
+    Tree,
+    Blob,
+    Executable,
+    Symlink,
+    /// A commit of a submodule
+    Gitlink,
+}
+
+impl FileMode {
+    pub fn from_raw(mode: u32) -> Option<Self> {
+        match mode & 0o170000 {
+            0o040000 => Some(FileMode::Tree),
+            0o100000 if mode & 0o111 != 0 => Some(FileMode::Executable),
+            0o100000 => Some(FileMode::Blob),
+            0o120000 => Some(FileMode::Symlink),
+            0o160000 => Some(FileMode::Gitlink),
+            _ => None,
+        }
+    }
+
+    /// The mode as stored in trees and the index
+    pub fn raw(self) -> u32 {
+        match self {
+            FileMode::Tree => 0o040000,
+            FileMode::Blob => 0o100644,
+            FileMode::Executable => 0o100755,
+            FileMode::Symlink => 0o120000,
+            FileMode::Gitlink => 0o160000,
+        }
+    }
+
+    /// The type of the object the entry points to
+    pub fn obj_type(self) -> &'static str {
+        match self {
+            FileMode::Tree => "tree",
+            FileMode::Blob | FileMode::Executable | FileMode::Symlink => "blob",
+            FileMode::Gitlink => "commit",
+        }
+    }
+
+    pub fn is_tree(self) -> bool {
+        self == FileMode::Tree
+    }
+}
+
+/// Shows the mode like `ls-tree` does, with 6 digits
+impl fmt::Display for FileMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:06o}", self.raw())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeEntry {
+    pub mode: FileMode,
+    /// Git allows any bytes but `/` and NUL in names
+    pub name: Vec<u8>,
+    pub id: ObjectId,
+}
+
+impl TreeEntry {
+    pub fn new(mode: FileMode, name: impl Into<Vec<u8>>, id: ObjectId) -> Self {
+        TreeEntry {
+            mode,
+            name: name.into(),
+            id,
+        }
+    }
+
+    /// The name, with invalid UTF-8 replaced
+    pub fn name_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    /// Git's order of tree entries: by name, with trees compared as if their
+    /// name ended with a `/`
+    pub fn tree_order(&self, other: &Self) -> Ordering {
+        self.sort_key().cmp(other.sort_key())
+    }
+
+    fn sort_key(&self) -> impl Iterator<Item = u8> + '_ {
+        let slash = self.mode.is_tree().then_some(b'/');
+        self.name.iter().copied().chain(slash)
+    }
+}
+
+/// The tree of the commit `id`
+fn commit_tree(id: &ObjectId, commit: &GitCommit) -> Result<ObjectId, OzError> {
+    commit
+        .tree()
+        .ok_or_else(|| OzError::corrupt_object("no tree").in_object(id))
+}
+
+/// A file that differs between two trees, as found by
+/// [`GitRepository::diff_trees`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeChange {
+    pub change: Change,
+    /// From the root of the trees, separated by `/`
+    pub path: Vec<u8>,
+    /// `None` when added
+    pub old: Option<TreeEntry>,
+    /// `None` when deleted
+    pub new: Option<TreeEntry>,
+}
+
+impl TreeChange {
+    /// The path, with invalid UTF-8 replaced
+    pub fn path_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.path)
+    }
+}
+
+/// Whether the tree entry at `path` is one of `paths`, below one, or a tree
+/// holding one. Everything is wanted without `paths`.
+fn path_wanted(path: &[u8], is_tree: bool, paths: &[String]) -> bool {
+    let below = |parent: &[u8], child: &[u8]| {
+        child.len() > parent.len() && child.starts_with(parent) && child[parent.len()] == b'/'
+    };
+    paths.is_empty()
+        || paths.iter().any(|wanted| {
+            let wanted = wanted.trim_end_matches('/').as_bytes();
+            wanted.is_empty()
+                || path == wanted
+                || below(wanted, path)
+                || (is_tree && below(path, wanted))
+        })
+}
+
+#[derive(Debug)]
+pub struct GitCommit {
+    pub(crate) kv: KvList,
+}
+#[derive(Debug)]
+pub struct GitTag {
+    pub(crate) kv: KvList,
+}
+
+/// Who made a commit or tag, and when: `name <email> <epoch> <offset>`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub name: String,
+    pub email: String,
+    /// Seconds since the epoch
+    pub time: i64,
+    /// Offset of the time zone from UTC, in minutes
+    pub offset: i32,
+}
+
+impl Signature {
+    /// Parses the value of an `author`, `committer` or `tagger` header
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let open = data.iter().position(|&ch| ch == b'<')?;
+        let close = open + data[open..].iter().position(|&ch| ch == b'>')?;
+        let date = std::str::from_utf8(&data[close + 1..]).ok()?.trim();
+        let (time, zone) = date.split_once(' ')?;
+        let sign = match zone.as_bytes().first()? {
+            b'+' => 1,
+            b'-' => -1,
+            _ => return None,
+        };
+        let zone: i32 = zone[1..].parse().ok().filter(|_| zone.len() == 5)?;
+        Some(Signature {
+            name: String::from_utf8_lossy(data[..open].trim_ascii_end()).into_owned(),
+            email: String::from_utf8_lossy(&data[open + 1..close]).into_owned(),
+            time: time.parse().ok()?,
+            offset: sign * (zone / 100 * 60 + zone % 100),
+        })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.offset < 0 { '-' } else { '+' };
+        let offset = self.offset.abs();
+        write!(
+            f,
+            "{} <{}> {} {sign}{:02}{:02}",
+            self.name,
+            self.email,
+            self.time,
+            offset / 60,
+            offset % 60
+        )
+    }
+}
+
+impl GitBlob {
+    pub fn from(buffer: Vec<u8>) -> Self {
+        GitBlob { buffer }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl GitTree {
+    pub(crate) fn new() -> Self {
+        GitTree { items: Vec::new() }
+    }
+
+    /// Parses a tree whose entries are named with `format`
+    pub fn parse(data: &[u8], format: ObjectFormat) -> Result<Self, OzError> {
+        Ok(GitTree {
+            items: tree_parse(data, format)?,
+        })
+    }
+
+    /// The entries, in tree order
+    pub fn entries(&self) -> &[TreeEntry] {
+        &self.items
+    }
+}
+impl GitCommit {
+    pub fn new(
+        tree: ObjectId,
+        parents: &[ObjectId],
+        author: &Signature,
+        committer: &Signature,
+        message: &str,
+    ) -> Self {
+        let mut kv = KvList::default();
+        kv.push("tree", tree.to_string());
+        for parent in parents {
+            kv.push("parent", parent.to_string());
+        }
+        kv.push("author", author.to_string());
+        kv.push("committer", committer.to_string());
+        kv.message = Some(message.into());
+        GitCommit { kv }
+    }
+
+    pub fn tree(&self) -> Option<ObjectId> {
+        self.kv.get_str("tree").and_then(|sha| sha.parse().ok())
+    }
+
+    pub fn parents(&self) -> Vec<ObjectId> {
+        self.kv
+            .values("parent")
+            .filter_map(|sha| std::str::from_utf8(sha).ok()?.parse().ok())
+            .collect()
+    }
+
+    pub fn author(&self) -> Option<Signature> {
+        self.kv.get("author").and_then(Signature::parse)
+    }
+
+    pub fn committer(&self) -> Option<Signature> {
+        self.kv.get("committer").and_then(Signature::parse)
+    }
+
+    /// The charset of the message, when it isn't UTF-8
+    pub fn encoding(&self) -> Option<&str> {
+        self.kv.get_str("encoding")
+    }
+
+    /// The detached signature of the rest of the commit
+    pub fn gpgsig(&self) -> Option<&[u8]> {
+        self.kv.get("gpgsig")
+    }
+
+    /// The annotated tags of the commits merged, as recorded by merging a
+    /// signed tag
+    pub fn mergetags(&self) -> Result<Vec<GitTag>, OzError> {
+        self.kv
+            .values("mergetag")
+            .map(|tag| GitTag::try_from(tag.to_vec()))
+            .collect()
+    }
+
+    /// The raw value of the header `key`, for the ones without an accessor
+    pub fn header(&self, key: &str) -> Option<&[u8]> {
+        self.kv.get(key)
+    }
+
+    /// The message, with invalid UTF-8 replaced
+    pub fn message(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.kv.message())
+    }
+
+    pub fn message_bytes(&self) -> &[u8] {
+        self.kv.message()
+    }
+}
+impl TryFrom<Vec<u8>> for GitCommit {
+    type Error = OzError;
+
+    fn try_from(buffer: Vec<u8>) -> Result<Self, OzError> {
+        let kv = kv_parser(&buffer)?;
+        kv.check_ids(&["tree", "parent"])?;
+        Ok(GitCommit { kv })
+    }
+}
+impl TryFrom<Vec<u8>> for GitTag {
+    type Error = OzError;
+
+    fn try_from(buffer: Vec<u8>) -> Result<Self, OzError> {
+        let kv = kv_parser(&buffer)?;
+        kv.check_ids(&["object"])?;
+        Ok(GitTag { kv })
+    }
+}
+impl GitTag {
+    pub fn new(
+        object: ObjectId,
+        obj_type: &str,
+        name: &str,
+        tagger: &Signature,
+        message: &str,
+    ) -> Self {
+        let mut kv = KvList::default();
+        kv.push("object", object.to_string());
+        kv.push("type", obj_type);
+        kv.push("tag", name);
+        kv.push("tagger", tagger.to_string());
+        kv.message = Some(message.into());
+        GitTag { kv }
+    }
+
+    /// The id of the tagged object
+    pub fn object(&self) -> Option<ObjectId> {
+        self.kv.get_str("object").and_then(|sha| sha.parse().ok())
+    }
+
+    /// The type of the tagged object
+    pub fn target_type(&self) -> Option<&str> {
+        self.kv.get_str("type")
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.kv.get_str("tag")
+    }
+
+    pub fn tagger(&self) -> Option<Signature> {
+        self.kv.get("tagger").and_then(Signature::parse)
+    }
+
+    /// The message, with invalid UTF-8 replaced
+    pub fn message(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self.kv.message())
+    }
+}
+
+impl Serializable for GitBlob {
+    fn serialize(self) -> Vec<u8> {
+        self.buffer
+    }
+}
+
+impl Serializable for GitCommit {
+    fn serialize(self) -> Vec<u8> {
+        kv_serialize(&self.kv)
+    }
+}
+impl Serializable for GitTree {
+    fn serialize(self) -> Vec<u8> {
+        let mut items = self.items.clone();
+        tree_serialize(&mut items)
+    }
+}
+impl Serializable for GitTag {
+    fn serialize(self) -> Vec<u8> {
+        kv_serialize(&self.kv)
+    }
+}
+
+pub(crate) fn tree_parse(data: &[u8], format: ObjectFormat) -> Result<Vec<TreeEntry>, OzError> {
+    let malformed = || OzError::corrupt_object("malformed tree");
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let frame = &data[i..];
+
+        let null_pos = frame.iter().position(|&ch| ch == 0).ok_or_else(malformed)?;
+        let id = frame
+            .get(null_pos + 1..=null_pos + format.raw_len())
+            .and_then(ObjectId::from_raw)
+            .ok_or_else(malformed)?;
+        let space = frame[..null_pos]
+            .iter()
+            .position(|&ch| ch == b' ')
+            .ok_or_else(malformed)?;
+        let mode = &frame[..space];
+        let mode = std::str::from_utf8(mode)
+            .ok()
+            .filter(|mode| !mode.is_empty() && mode.bytes().all(|ch| matches!(ch, b'0'..=b'7')))
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .and_then(FileMode::from_raw)
+            .ok_or_else(|| {
+                OzError::corrupt_object(format!(
+                    "unknown file mode {}",
+                    String::from_utf8_lossy(mode)
+                ))
+            })?;
+        entries.push(TreeEntry {
+            mode,
+            name: frame[space + 1..null_pos].to_vec(),
+            id,
+        });
+        i += null_pos + 1 + format.raw_len();
+    }
+    Ok(entries)
+}
+
+/// Sorts `entries` in git's order and serializes them
+pub(crate) fn tree_serialize(entries: &mut [TreeEntry]) -> Vec<u8> {
+    entries.sort_by(TreeEntry::tree_order);
+
+    let mut result: Vec<u8> = Vec::new();
+    for entry in entries.iter() {
+        // Git writes modes without leading zeros: "40000"
+        result.extend_from_slice(format!("{:o} ", entry.mode.raw()).as_bytes());
+        result.extend_from_slice(&entry.name);
+        result.push(0);
+        result.extend_from_slice(entry.id.as_bytes());
+    }
+    result
+}
+
+/// A single file tracked by the staging area (`.git/index`).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GitIndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    /// Object type and unix permissions, e.g. `0o100644`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha: ObjectId,
+    pub assume_valid: bool,
+    /// Merge stage: 0 for normal entries, 1-3 during a conflicted merge
+    pub stage: u8,
+    pub skip_worktree: bool,
+    pub intent_to_add: bool,
+    pub name: Vec<u8>,
+}
+
+impl GitIndexEntry {
+    /// Builds the entry of the worktree file `name`, taking its stat data
+    /// from `meta` (which must not follow symlinks).
+    pub(crate) fn from_metadata(
+        name: impl Into<Vec<u8>>,
+        sha: ObjectId,
+        meta: &std::fs::Metadata,
+    ) -> Self {
+        let mode = if meta.is_symlink() {
+            0o120000
+        } else if meta.mode() & 0o111 != 0 {
+            0o100755
         } else {
-            a.1.clone() + "/"
+            0o100644
+        };
+        GitIndexEntry {
+            ctime: (meta.ctime() as u32, meta.ctime_nsec() as u32),
+            mtime: (meta.mtime() as u32, meta.mtime_nsec() as u32),
+            dev: meta.dev() as u32,
+            ino: meta.ino() as u32,
+            mode,
+            uid: meta.uid(),
+            gid: meta.gid(),
+            size: meta.size() as u32,
+            sha,
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    /// The path, with the bytes that aren't UTF-8 replaced
+    pub fn name_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
+    }
+
+    /// Whether the stat data of `meta` is the one recorded when the file was
+    /// staged, which means the file can be assumed unchanged.
+    pub(crate) fn stat_matches(&self, meta: &std::fs::Metadata) -> bool {
+        self.mtime == (meta.mtime() as u32, meta.mtime_nsec() as u32)
+            && self.ctime == (meta.ctime() as u32, meta.ctime_nsec() as u32)
+            && self.ino == meta.ino() as u32
+            && self.uid == meta.uid()
+            && self.gid == meta.gid()
+            && self.size == meta.size() as u32
+            && (self.mode == 0o120000) == meta.is_symlink()
+    }
+}
+
+/// The staging area, byte-compatible with git's `DIRC` format versions 2 to 4.
+///
+/// Extensions are kept as raw `(signature, data)` pairs so they survive a
+/// read/write cycle. Modifying entries through [`GitIndex::add`] and
+/// [`GitIndex::remove`] drops the cached trees, which would otherwise be stale.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitIndex {
+    pub version: u32,
+    pub entries: Vec<GitIndexEntry>,
+    pub extensions: Vec<([u8; 4], Vec<u8>)>,
+}
+
+impl GitIndex {
+    pub(crate) fn new() -> Self {
+        GitIndex {
+            version: 2,
+            entries: Vec::new(),
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Parses an index whose object ids and checksum use `format`
+    pub(crate) fn parse(data: &[u8], format: ObjectFormat) -> Result<Self, String> {
+        let raw_len = format.raw_len();
+        if data.len() < 12 + raw_len {
+            return Err("Index file is too short".to_string());
+        }
+        let (body, checksum) = data.split_at(data.len() - raw_len);
+        if checksum.iter().any(|&ch| ch != 0) && format.digest(body) != checksum {
+            return Err("Index checksum mismatch".to_string());
+        }
+        if &body[..4] != b"DIRC" {
+            return Err("Not an index file".to_string());
+        }
+
+        let read_u32 = |pos: usize| -> Result<u32, String> {
+            body.get(pos..pos + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or("Index file is truncated".to_string())
+        };
+        let read_u16 = |pos: usize| -> Result<u16, String> {
+            body.get(pos..pos + 2)
+                .map(|b| u16::from_be_bytes(b.try_into().unwrap()))
+                .ok_or("Index file is truncated".to_string())
         };
-        let y = if b.0[0] == b'1' && b.0[1] == b'0' {
-            b.1.clone()
+
+        let version = read_u32(4)?;
+        if !(2..=4).contains(&version) {
+            return Err(format!("Unsupported index version: {version}"));
+        }
+        let count = read_u32(8)?;
+
+        // The count comes from the file: no more entries than can fit in it,
+        // each being at least its stat data, id and flags
+        let mut entries = Vec::with_capacity((count as usize).min(body.len() / (42 + raw_len)));
+        let mut pos = 12;
+        let mut previous: Vec<u8> = Vec::new();
+        for _ in 0..count {
+            let start = pos;
+            let mut entry = GitIndexEntry {
+                ctime: (read_u32(pos)?, read_u32(pos + 4)?),
+                mtime: (read_u32(pos + 8)?, read_u32(pos + 12)?),
+                dev: read_u32(pos + 16)?,
+                ino: read_u32(pos + 20)?,
+                mode: read_u32(pos + 24)?,
+                uid: read_u32(pos + 28)?,
+                gid: read_u32(pos + 32)?,
+                size: read_u32(pos + 36)?,
+                ..Default::default()
+            };
+            entry.sha = body
+                .get(pos + 40..pos + 40 + raw_len)
+                .and_then(ObjectId::from_raw)
+                .ok_or("Index file is truncated")?;
+            pos += 40 + raw_len;
+            let flags = read_u16(pos)?;
+            entry.assume_valid = flags & 0x8000 != 0;
+            entry.stage = ((flags >> 12) & 0x3) as u8;
+            pos += 2;
+
+            if flags & 0x4000 != 0 {
+                if version < 3 {
+                    return Err("Extended flags in a version 2 index".to_string());
+                }
+                let extended = read_u16(pos)?;
+                entry.skip_worktree = extended & 0x4000 != 0;
+                entry.intent_to_add = extended & 0x2000 != 0;
+                pos += 2;
+            }
+
+            let name = if version == 4 {
+                // The path is stored as the number of bytes to drop from the end
+                // of the previous path, followed by the new suffix
+                let (strip, len) = varint_decode(body.get(pos..).unwrap_or_default())?;
+                pos += len;
+                let strip = usize::try_from(strip)
+                    .ok()
+                    .filter(|&strip| strip <= previous.len())
+                    .ok_or("Malformed index path compression")?;
+                let end = body[pos..]
+                    .iter()
+                    .position(|&ch| ch == 0)
+                    .ok_or("Index file is truncated")?;
+                let mut name = previous[..previous.len() - strip].to_vec();
+                name.extend_from_slice(&body[pos..pos + end]);
+                pos += end + 1;
+                name
+            } else {
+                let end = body[pos..]
+                    .iter()
+                    .position(|&ch| ch == 0)
+                    .ok_or("Index file is truncated")?;
+                let name = body[pos..pos + end].to_vec();
+                // Entries are padded with 1 to 8 NULs to a multiple of 8 bytes
+                pos = start + (pos + end - start + 8) / 8 * 8;
+                name
+            };
+            previous = name.clone();
+            entry.name = name;
+            entries.push(entry);
+        }
+
+        let mut extensions = Vec::new();
+        while pos < body.len() {
+            let signature: [u8; 4] = body
+                .get(pos..pos + 4)
+                .ok_or("Index file is truncated")?
+                .try_into()
+                .unwrap();
+            let size = read_u32(pos + 4)? as usize;
+            let data = body
+                .get(pos + 8..pos + 8 + size)
+                .ok_or("Index file is truncated")?;
+            extensions.push((signature, data.to_vec()));
+            pos += 8 + size;
+        }
+
+        Ok(GitIndex {
+            version,
+            entries,
+            extensions,
+        })
+    }
+
+    pub(crate) fn serialize(&self, format: ObjectFormat) -> Vec<u8> {
+        let mut result: Vec<u8> = b"DIRC".to_vec();
+        // Extended flags need at least version 3
+        let extended = self
+            .entries
+            .iter()
+            .any(|entry| entry.skip_worktree || entry.intent_to_add);
+        let version = if extended {
+            self.version.max(3)
         } else {
-            b.1.clone() + "/"
+            self.version
         };
+        result.extend_from_slice(&version.to_be_bytes());
+        result.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+
+        let mut previous: &[u8] = &[];
+        for entry in &self.entries {
+            let start = result.len();
+            for value in [
+                entry.ctime.0,
+                entry.ctime.1,
+                entry.mtime.0,
+                entry.mtime.1,
+                entry.dev,
+                entry.ino,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                result.extend_from_slice(&value.to_be_bytes());
+            }
+            result.extend_from_slice(entry.sha.as_bytes());
 
-        (&a.0, x, &a.2).cmp(&(&b.0, y, &b.2))
-    });
-
-    // let mut list = list
-    //     .iter()
-    //     .map(|data| {
-    //         if (data.0[0] == b'1' && data.0[1] == b'0') {
-    //             // file
-    //             data.clone()
-    //         } else {
-    //             // Directory
-    //             (data.0, data.1.clone() + "/", data.2.clone())
-    //         }
-    //     })
-    //     .collect::<Vec<_>>();
-    // list.sort();
-
-    // Serialize
-    let mut result: Vec<u8> = Vec::new();
+            let name = &entry.name[..];
+            let has_extended = entry.skip_worktree || entry.intent_to_add;
+            let mut flags = (name.len().min(0xfff) as u16) | ((entry.stage as u16 & 0x3) << 12);
+            if entry.assume_valid {
+                flags |= 0x8000;
+            }
+            if has_extended {
+                flags |= 0x4000;
+            }
+            result.extend_from_slice(&flags.to_be_bytes());
+            if has_extended {
+                let mut extended: u16 = 0;
+                if entry.skip_worktree {
+                    extended |= 0x4000;
+                }
+                if entry.intent_to_add {
+                    extended |= 0x2000;
+                }
+                result.extend_from_slice(&extended.to_be_bytes());
+            }
 
-    for entry in list {
-        for ele in entry.0 {
-            result.push(ele);
+            if version == 4 {
+                let common = previous
+                    .iter()
+                    .zip(name)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                result.extend(varint_encode((previous.len() - common) as u64));
+                result.extend_from_slice(&name[common..]);
+                result.push(0);
+            } else {
+                result.extend_from_slice(name);
+                let padding = 8 - (result.len() - start) % 8;
+                result.extend(std::iter::repeat_n(0, padding));
+            }
+            previous = name;
         }
-        // result.copy_from_slice(&entry.0);
-        result.push(b' ');
-        for ele in entry.1.as_bytes() {
-            result.push(*ele);
+
+        for (signature, data) in &self.extensions {
+            result.extend_from_slice(signature);
+            result.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            result.extend_from_slice(data);
         }
-        // result.copy_from_slice(entry.1.as_bytes());
-        result.push(0);
-        let mut hex: Vec<u8> = (0..entry.2.len())
-            .step_by(2)
-            .map(|i| u8::from_str_radix(&entry.2[i..i + 2], 16).expect("Problem with hex string"))
-            .collect();
-        result.append(&mut hex);
+
+        let checksum = format.digest(&result);
+        result.extend_from_slice(&checksum);
+        result
+    }
+
+    /// Returns the stage 0 entry for `name`, if it is tracked.
+    pub fn get(&self, name: impl AsRef<[u8]>) -> Option<&GitIndexEntry> {
+        self.position(name.as_ref(), 0)
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    /// Whether `name` is tracked, at any stage.
+    pub fn contains(&self, name: impl AsRef<[u8]>) -> bool {
+        (0..4).any(|stage| self.position(name.as_ref(), stage).is_ok())
+    }
+
+    /// Inserts or replaces the entry with the same path and stage. Entries
+    /// that clash with it (a file where it needs a directory, or the files
+    /// below it when it replaces a directory) are removed.
+    pub(crate) fn add(&mut self, entry: GitIndexEntry) {
+        self.invalidate_cache();
+        let prefix = [&entry.name[..], b"/"].concat();
+        let start = self.position(&prefix, 0).unwrap_or_else(|i| i);
+        let end = start
+            + self.entries[start..]
+                .iter()
+                .take_while(|other| other.name.starts_with(&prefix))
+                .count();
+        self.entries.drain(start..end);
+        let slashes = entry.name.iter().enumerate().filter(|&(_, &ch)| ch == b'/');
+        for (pos, _) in slashes {
+            for stage in 0..4 {
+                if let Ok(i) = self.position(&entry.name[..pos], stage) {
+                    self.entries.remove(i);
+                }
+            }
+        }
+        match self.position(&entry.name, entry.stage) {
+            Ok(i) => self.entries[i] = entry,
+            Err(i) => self.entries.insert(i, entry),
+        }
+    }
+
+    /// Removes every stage of `name`, returning whether anything was removed.
+    pub(crate) fn remove(&mut self, name: impl AsRef<[u8]>) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.name != name.as_ref());
+        if self.entries.len() != before {
+            self.invalidate_cache();
+            return true;
+        }
+        false
+    }
+
+    /// Entries are kept sorted by path bytes, then by stage.
+    fn position(&self, name: &[u8], stage: u8) -> Result<usize, usize> {
+        self.entries
+            .binary_search_by(|entry| (&entry.name[..], entry.stage).cmp(&(name, stage)))
+    }
+
+    /// Drops the extensions that cache information derived from the entries.
+    fn invalidate_cache(&mut self) {
+        self.extensions
+            .retain(|(signature, _)| signature != b"TREE" && signature != b"UNTR");
+    }
+}
+
+/// Decodes the variable length integer used by index v4 path compression
+/// (and pack offsets), returning the value and the number of bytes read.
+pub(crate) fn varint_decode(data: &[u8]) -> Result<(u64, usize), String> {
+    let mut bytes = data.iter();
+    let mut ch = *bytes.next().ok_or("Truncated variable length integer")?;
+    let mut value = (ch & 0x7f) as u64;
+    let mut len = 1;
+    while ch & 0x80 != 0 {
+        ch = *bytes.next().ok_or("Truncated variable length integer")?;
+        value = value
+            .checked_add(1)
+            .and_then(|value| value.checked_mul(0x80))
+            .ok_or("Variable length integer overflow")?
+            | (ch & 0x7f) as u64;
+        len += 1;
+    }
+    Ok((value, len))
+}
+
+pub(crate) fn varint_encode(mut value: u64) -> Vec<u8> {
+    let mut result = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        result.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
     }
+    result.reverse();
     result
 }
 
 #[test]
 fn test_tree_serializer() {
-    let mut data = Vec::new();
-    data.push((
-        [b'1', b'0', 3, 4, 5, 2],
-        "AEADME".to_string(),
-        "29c95630072cd48c6c227938e66681536613f9ad".to_string(),
-    ));
-    data.push((
-        [b'1', b'0', 3, 4, 5, 2],
-        "REAME".to_string(),
-        "29c95630072cd48c6c227938e66681536613f9ad".to_string(),
-    ));
-    data.push((
-        [b'0', b'0', 3, 4, 5, 2],
-        "README".to_string(),
-        "29c95630072cd48c6c227938e66681536613f9ad".to_string(),
-    ));
-    data.push((
-        [b'0', b'0', 3, 4, 5, 2],
-        "README".to_string(),
-        "29c95630072cd48c6c227938e66681536613f9ad".to_string(),
-    ));
-    data.push((
-        [b'0', b'0', 3, 4, 5, 2],
-        "AEADME".to_string(),
-        "29c95630072cd48c6c227938e66681536613f9ad".to_string(),
-    ));
-
-    println!("{data:#?}");
-    println!("----------------------------------");
+    let id: ObjectId = "29c95630072cd48c6c227938e66681536613f9ad".parse().unwrap();
+    let mut data = vec![
+        TreeEntry::new(FileMode::Blob, b"\xffinvalid".to_vec(), id),
+        TreeEntry::new(FileMode::Tree, "a", id),
+        TreeEntry::new(FileMode::Executable, "a0", id),
+        TreeEntry::new(FileMode::Blob, "a.b", id),
+        TreeEntry::new(FileMode::Symlink, "ab", id),
+        TreeEntry::new(FileMode::Gitlink, "a-b", id),
+    ];
+
     let x = tree_serialize(&mut data);
-    let y = tree_parse(&x);
+    let y = tree_parse(&x, ObjectFormat::Sha1).unwrap();
+    assert_eq!(y, data);
+    // Trees sort as if their name ended with "/"
+    let names: Vec<_> = y.iter().map(|entry| entry.name_lossy()).collect();
+    assert_eq!(names, ["a-b", "a.b", "a", "a0", "ab", "\u{fffd}invalid"]);
+    assert!(x.starts_with(b"160000 a-b\0"));
+    assert_eq!(y[2].mode.to_string(), "040000");
+
+    // Regular files are blobs or executables depending on their x bits
+    assert_eq!(FileMode::from_raw(0o100664), Some(FileMode::Blob));
+    assert_eq!(FileMode::from_raw(0o100775), Some(FileMode::Executable));
+    assert_eq!(FileMode::from_raw(0o070000), None);
+    assert!(tree_parse(b"10064x a\0aaaaaaaaaaaaaaaaaaaa", ObjectFormat::Sha1).is_err());
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::commands::repo_create;
+
+    /// Creates an empty repository in a fresh temporary directory.
+    pub(crate) fn temp_repo(name: &str) -> GitRepository {
+        temp_repo_with_format(name, ObjectFormat::Sha1)
+    }
+
+    pub(crate) fn temp_repo_with_format(name: &str, format: ObjectFormat) -> GitRepository {
+        let path = std::env::temp_dir().join(format!("oz-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        repo_create(path.clone(), format).unwrap();
+        GitRepository::new(path, false).unwrap()
+    }
+
+    #[test]
+    fn object_find_resolves_refs_and_prefixes() {
+        let repo = temp_repo("object-find");
+        let sha = write_blob(&repo, "hello\n");
+        let commit_sha = commit(&repo, &[("hello", "hello\n")], &[], 0, "msg\n");
+        let tree_sha = repo.commit_read(&commit_sha).unwrap().tree().unwrap();
+        std::fs::write(
+            repo.repo_file("refs/heads/master".into()),
+            format!("{commit_sha}\n"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            repo.object_find("HEAD".into(), "".into()).unwrap(),
+            commit_sha
+        );
+        assert_eq!(
+            repo.object_find("master".into(), "tree".into()).unwrap(),
+            tree_sha
+        );
+        assert_eq!(repo.object_find(sha.abbrev(6), "blob".into()).unwrap(), sha);
+        assert!(repo.object_find("nothing".into(), "".into()).is_err());
+        assert!(repo.object_find(sha.to_string(), "commit".into()).is_err());
+
+        // A branch named like a sha prefix wins over the object
+        let prefix = sha.abbrev(6);
+        refs::update(&repo, &format!("refs/heads/{prefix}"), &commit_sha, None).unwrap();
+        assert_eq!(repo.object_find(prefix, "".into()).unwrap(), commit_sha);
+        refs::update(&repo, "refs/remotes/origin/HEAD", &tree_sha, None).unwrap();
+        assert_eq!(
+            repo.object_find("origin".into(), "".into()).unwrap(),
+            tree_sha
+        );
+    }
 
-    println!("{y:#?}");
-    todo!()
+    /// Writes a blob of `data` and returns its sha.
+    pub(crate) fn write_blob(repo: &GitRepository, data: impl AsRef<[u8]>) -> ObjectId {
+        let blob = GitObject::Blob(GitBlob::from(data.as_ref().to_vec()));
+        GitRepository::object_write(Some(repo), blob).unwrap()
+    }
+
+    /// Commits a flat tree of the `(name, content)` files at `time`, in a
+    /// +0100 timezone, and returns the sha of the commit.
+    pub(crate) fn commit(
+        repo: &GitRepository,
+        files: &[(&str, &str)],
+        parents: &[ObjectId],
+        time: i64,
+        message: &str,
+    ) -> ObjectId {
+        let mut tree = GitTree::new();
+        for (name, content) in files {
+            let blob = write_blob(repo, content);
+            tree.items.push(TreeEntry::new(FileMode::Blob, *name, blob));
+        }
+        let tree = GitRepository::object_write(Some(repo), GitObject::Tree(tree)).unwrap();
+        let signature = Signature {
+            name: "A".into(),
+            email: "a@b".into(),
+            time,
+            offset: 60,
+        };
+        let commit = GitCommit::new(tree, parents, &signature, &signature, message);
+        GitRepository::object_write(Some(repo), GitObject::Commit(commit)).unwrap()
+    }
+
+    #[test]
+    fn rev_parse_walks_parents_and_paths() {
+        let repo = temp_repo("rev-parse");
+        let blob = write_blob(&repo, "content\n");
+        let files = [("file", "content\n")];
+        let root = commit(&repo, &files, &[], 0, "root\n");
+        let left = commit(&repo, &files, &[root], 0, "left\n");
+        let right = commit(&repo, &files, &[root], 0, "right\n");
+        let merge = commit(&repo, &files, &[left, right], 0, "merge\n");
+        let tree = repo.commit_read(&root).unwrap().tree().unwrap();
+        std::fs::write(
+            repo.repo_file("refs/heads/master".into()),
+            merge.to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(repo.rev_parse("HEAD").unwrap(), merge);
+        assert_eq!(repo.rev_parse("@~1").unwrap(), left);
+        assert_eq!(repo.rev_parse("master^2").unwrap(), right);
+        assert_eq!(repo.rev_parse("HEAD^2~").unwrap(), root);
+        assert_eq!(repo.rev_parse("HEAD~2^{tree}").unwrap(), tree);
+        assert_eq!(repo.rev_parse("HEAD^1:file").unwrap(), blob);
+        assert!(repo.rev_parse("HEAD~3").is_err());
+        assert!(repo.rev_parse("HEAD^3").is_err());
+        assert!(repo.rev_parse("HEAD:missing").is_err());
+    }
+
+    #[test]
+    fn rev_walk_orders_and_hides() {
+        let repo = temp_repo("rev-walk");
+        let root = commit(&repo, &[], &[], 1, "root\n");
+        let a1 = commit(&repo, &[], &[root], 2, "a1\n");
+        let b1 = commit(&repo, &[], &[root], 3, "b1\n");
+        let a2 = commit(&repo, &[], &[a1], 4, "a2\n");
+        let merge = commit(&repo, &[], &[a2, b1], 5, "merge\n");
+
+        let walk = |setup: &dyn Fn(&mut RevWalk)| -> Vec<ObjectId> {
+            let mut walk = repo.rev_walk();
+            setup(&mut walk);
+            walk.map(|commit| commit.unwrap().id).collect()
+        };
+        let from_merge = |walk: &mut RevWalk| walk.push(merge).unwrap();
+        assert_eq!(walk(&from_merge), [merge, a2, b1, a1, root]);
+        assert_eq!(
+            walk(&|walk| {
+                from_merge(walk);
+                walk.sort(RevOrder::Topo);
+            }),
+            [merge, b1, a2, a1, root]
+        );
+        assert_eq!(
+            walk(&|walk| {
+                from_merge(walk);
+                walk.reverse();
+            }),
+            [root, a1, b1, a2, merge]
+        );
+        assert_eq!(
+            walk(&|walk| {
+                from_merge(walk);
+                walk.first_parent();
+            }),
+            [merge, a2, a1, root]
+        );
+        assert_eq!(
+            walk(&|walk| walk.push_rev(&format!("{a1}..{merge}")).unwrap()),
+            [merge, a2, b1]
+        );
+
+        // Without a2, the merge's parents are rewritten to the listed ones
+        let mut rewritten = repo.rev_walk();
+        rewritten.push(merge).unwrap();
+        rewritten.rewrite_parents();
+        rewritten.filter_commits(|commit| commit.message() != "a2\n");
+        let parents: Vec<_> = rewritten.map(|commit| commit.unwrap().parents).collect();
+        assert_eq!(parents[0], [a1, b1]);
+
+        assert_eq!(repo.merge_bases(&a2, &b1).unwrap(), [root]);
+        assert_eq!(repo.merge_bases(&merge, &a1).unwrap(), [a1]);
+        let cross = commit(&repo, &[], &[a2, b1], 6, "cross\n");
+        let criss = commit(&repo, &[], &[b1, a2], 7, "criss\n");
+        assert_eq!(repo.merge_bases(&cross, &criss).unwrap(), [a2, b1]);
+    }
+
+    #[test]
+    fn discover_and_history() {
+        let repo = temp_repo("history");
+        let root = commit(&repo, &[], &[], 0, "root\n");
+        let left = commit(&repo, &[], &[root], 0, "left\n");
+        let right = commit(&repo, &[], &[root], 0, "right\n");
+        let merge = commit(&repo, &[], &[left, right], 0, "merge\n");
+        let tree = repo.commit_read(&root).unwrap().tree().unwrap();
+        std::fs::write(
+            repo.repo_file("refs/heads/master".into()),
+            merge.to_string(),
+        )
+        .unwrap();
+
+        let nested = repo.worktree().join("a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+        let found = GitRepository::discover(&nested).unwrap();
+        assert_eq!(found.worktree(), repo.worktree());
+        assert!(matches!(
+            GitRepository::open(&nested),
+            Err(OzError::NotARepository(_))
+        ));
+
+        let history: Vec<_> = found
+            .history("HEAD")
+            .unwrap()
+            .into_iter()
+            .map(|(sha, _)| sha)
+            .collect();
+        assert_eq!(history, vec![merge, left, right, root]);
+        assert!(matches!(
+            found.commit_read(&tree),
+            Err(OzError::WrongObjectType { .. })
+        ));
+    }
+
+    #[test]
+    fn index_round_trips_every_version() {
+        let mut index = GitIndex::new();
+        for name in [
+            "b",
+            "a/very/long/path/name.txt",
+            "a/very/long/other.txt",
+            "c",
+        ] {
+            index.add(GitIndexEntry {
+                mtime: (1700000000, 12),
+                mode: 0o100644,
+                size: 5,
+                sha: "29c95630072cd48c6c227938e66681536613f9ad".parse().unwrap(),
+                name: name.into(),
+                ..Default::default()
+            });
+        }
+        // Paths are bytes, not necessarily UTF-8
+        index.add(GitIndexEntry {
+            name: b"d\xe9".to_vec(),
+            ..Default::default()
+        });
+        index.extensions.push((*b"REUC", vec![1, 2, 3]));
+        let names: Vec<_> = index.entries.iter().map(|e| e.name_lossy()).collect();
+        assert_eq!(
+            names,
+            [
+                "a/very/long/other.txt",
+                "a/very/long/path/name.txt",
+                "b",
+                "c",
+                "d\u{fffd}"
+            ]
+        );
+
+        for version in 2..=4 {
+            index.version = version;
+            if version == 3 {
+                index.entries[2].intent_to_add = true;
+            }
+            let data = index.serialize(ObjectFormat::Sha1);
+            assert_eq!(
+                GitIndex::parse(&data, ObjectFormat::Sha1),
+                Ok(index.clone())
+            );
+        }
+
+        let mut data = index.serialize(ObjectFormat::Sha1);
+        data[20] ^= 1;
+        assert!(GitIndex::parse(&data, ObjectFormat::Sha1).is_err());
+        // A huge entry count is an error, not a huge allocation; an all-zero
+        // checksum skips verification
+        let mut data = b"DIRC\0\0\0\x02\xff\xff\xff\xff".to_vec();
+        data.extend_from_slice(&[0; 24 + 20]);
+        assert_eq!(
+            GitIndex::parse(&data, ObjectFormat::Sha1),
+            Err("Index file is truncated".to_string())
+        );
+        assert!(index.remove("b"));
+        assert!(!index.remove("b"));
+        assert_eq!(index.get("c").map(|e| e.size), Some(5));
+    }
+
+    #[test]
+    fn varint_matches_git_encoding() {
+        for value in [0, 1, 127, 128, 16511, 16512, 1 << 40] {
+            let data = varint_encode(value);
+            assert_eq!(varint_decode(&data), Ok((value, data.len())));
+        }
+        assert_eq!(varint_encode(128), [0x80, 0x00]);
+        assert!(varint_decode(&[0xff; 11]).is_err());
+    }
+
+    #[test]
+    fn index_add_replaces_clashing_entries() {
+        let mut index = GitIndex::new();
+        for name in ["a/b", "a/c/d", "ab", "a.txt"] {
+            index.add(GitIndexEntry {
+                name: name.into(),
+                ..Default::default()
+            });
+        }
+        index.add(GitIndexEntry {
+            name: "a".into(),
+            ..Default::default()
+        });
+        let names: Vec<_> = index.entries.iter().map(|e| e.name_lossy()).collect();
+        assert_eq!(names, ["a", "a.txt", "ab"]);
+
+        index.add(GitIndexEntry {
+            name: "a/x".into(),
+            ..Default::default()
+        });
+        let names: Vec<_> = index.entries.iter().map(|e| e.name_lossy()).collect();
+        assert_eq!(names, ["a.txt", "a/x", "ab"]);
+    }
+
+    #[test]
+    fn tree_from_index_builds_nested_trees() {
+        let repo = temp_repo("tree-from-index");
+        let blob = GitObject::Blob(GitBlob::from(b"data\n".to_vec()));
+        let blob = GitRepository::object_write(Some(&repo), blob).unwrap();
+        let mut index = GitIndex::new();
+        for name in ["a.txt", "a/b/c", "a/d", "z"] {
+            index.add(GitIndexEntry {
+                mode: 0o100644,
+                sha: blob,
+                name: name.into(),
+                ..Default::default()
+            });
+        }
+
+        let tree = repo.tree_from_index(&index).unwrap();
+        let Ok(GitObject::Tree(root)) = repo.object_read(&tree) else {
+            panic!("Expected a tree");
+        };
+        let names: Vec<_> = root.items.iter().map(|entry| entry.name_lossy()).collect();
+        assert_eq!(names, ["a.txt", "a", "z"]);
+        assert_eq!(root.items[1].mode, FileMode::Tree);
+        assert_eq!(repo.tree_lookup(tree, "a/b/c").unwrap(), blob.clone());
+
+        index.entries[0].stage = 2;
+        assert!(repo.tree_from_index(&index).is_err());
+    }
+
+    #[test]
+    fn diff_trees_skips_identical_subtrees() {
+        let repo = temp_repo("diff-trees");
+        let write = |object| GitRepository::object_write(Some(&repo), object).unwrap();
+        let blob = |data: &[u8]| write(GitObject::Blob(GitBlob::from(data.to_vec())));
+        let tree = |items| write(GitObject::Tree(GitTree { items }));
+        let (one, two) = (blob(b"one\n"), blob(b"two\n"));
+        // Never read: it is the same on both sides
+        let missing = ObjectId::hash(repo.format, b"missing");
+
+        let old = tree(vec![
+            TreeEntry::new(FileMode::Blob, "a", one),
+            TreeEntry::new(
+                FileMode::Tree,
+                "dir",
+                tree(vec![TreeEntry::new(FileMode::Blob, "x", one)]),
+            ),
+            TreeEntry::new(FileMode::Blob, "link", one),
+            TreeEntry::new(FileMode::Tree, "same", missing),
+        ]);
+        let new = tree(vec![
+            TreeEntry::new(FileMode::Executable, "a", one),
+            TreeEntry::new(FileMode::Blob, "dir", two),
+            TreeEntry::new(FileMode::Symlink, "link", one),
+            TreeEntry::new(FileMode::Tree, "same", missing),
+        ]);
+        let changes = repo.diff_trees(Some(&old), Some(&new), &[]).unwrap();
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|change| (change.change, change.path_lossy().into_owned()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Change::Modified, "a".into()),
+                (Change::Added, "dir".into()),
+                (Change::Deleted, "dir/x".into()),
+                (Change::TypeChanged, "link".into()),
+            ]
+        );
+        let limited = repo
+            .diff_trees(Some(&old), Some(&new), &["dir/x".into()])
+            .unwrap();
+        assert_eq!(limited, changes[2..3]);
+        assert!(
+            repo.diff_trees(None, Some(&new), &["same/file".into()])
+                .is_err()
+        );
+        assert!(
+            repo.diff_trees(Some(&new), Some(&new), &[])
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn tag_round_trips() {
+        let data = b"object 29c95630072cd48c6c227938e66681536613f9ad\n\
+type commit\n\
+tag v1.0\n\
+tagger A <a@b> 1700000000 +0200\n\
+\n\
+Release\n\
+\n\
+Notes\n"
+            .to_vec();
+        let tag = GitTag::try_from(data.clone()).unwrap();
+        assert_eq!(
+            tag.object().map(|sha| sha.to_string()).as_deref(),
+            Some("29c95630072cd48c6c227938e66681536613f9ad")
+        );
+        assert_eq!(tag.name(), Some("v1.0"));
+        assert_eq!(tag.tagger().map(|tagger| tagger.offset), Some(120));
+        assert_eq!(tag.message(), "Release\n\nNotes\n");
+        assert_eq!(tag.serialize(), data);
+    }
+
+    #[test]
+    fn commits_round_trip_byte_for_byte() {
+        let tag = "object 29c95630072cd48c6c227938e66681536613f9ad\n\
+type commit\n\
+tag v1.0\n\
+tagger T <t@b> 1700000000 +0000\n\
+\n\
+signed\n";
+        let mut data = b"tree 29c95630072cd48c6c227938e66681536613f9ad\n\
+parent 0000000000000000000000000000000000000001\n\
+author J\xe9r\xf4me <j@b> 1700000000 -0230\n\
+x-unknown some value\n\
+parent 0000000000000000000000000000000000000002\n\
+committer C <c@b> 1700000001 +0100\n\
+encoding ISO-8859-1\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n"
+            .to_vec();
+        data.extend_from_slice(b"mergetag ");
+        data.extend_from_slice(tag.trim_end().replace('\n', "\n ").as_bytes());
+        data.extend_from_slice(b"\n\nCaf\xe9\n\n  indented\n");
+
+        let commit = GitCommit::try_from(data.clone()).unwrap();
+        assert_eq!(
+            commit.parents(),
+            [
+                "0000000000000000000000000000000000000001".parse().unwrap(),
+                "0000000000000000000000000000000000000002".parse().unwrap()
+            ]
+        );
+        let author = commit.author().unwrap();
+        assert_eq!(author.name, "J\u{fffd}r\u{fffd}me");
+        assert_eq!((author.email.as_str(), author.time), ("j@b", 1700000000));
+        assert_eq!(author.offset, -150);
+        assert_eq!(
+            author.to_string(),
+            "J\u{fffd}r\u{fffd}me <j@b> 1700000000 -0230"
+        );
+        assert_eq!(commit.committer().unwrap().offset, 60);
+        assert_eq!(commit.encoding(), Some("ISO-8859-1"));
+        assert_eq!(
+            commit.gpgsig(),
+            Some(&b"-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----"[..])
+        );
+        assert_eq!(commit.header("x-unknown"), Some(&b"some value"[..]));
+        let mergetags = commit.mergetags().unwrap();
+        assert_eq!(mergetags.len(), 1);
+        assert_eq!(mergetags[0].name(), Some("v1.0"));
+        assert_eq!(mergetags[0].message(), "signed");
+        assert_eq!(commit.message_bytes(), b"Caf\xe9\n\n  indented\n");
+        assert_eq!(commit.serialize(), data);
+
+        // Without a message
+        let data = b"tree 29c95630072cd48c6c227938e66681536613f9ad\n".to_vec();
+        let commit = GitCommit::try_from(data.clone()).unwrap();
+        assert_eq!(commit.message(), "");
+        assert_eq!(commit.serialize(), data);
+        assert!(GitCommit::try_from(b"tree 29c9".to_vec()).is_err());
+    }
+
+    #[test]
+    fn malformed_objects_are_errors() {
+        let repo = temp_repo("malformed");
+        let commit =
+            GitRepository::object_write_raw(Some(&repo), "commit", b"tree abc\nno message")
+                .unwrap();
+        let tree =
+            GitRepository::object_write_raw(Some(&repo), "tree", b"100644 file\0short").unwrap();
+
+        for sha in [&commit, &tree] {
+            match repo.object_read(sha) {
+                Err(OzError::CorruptObject { sha: Some(bad), .. }) => assert_eq!(&bad, sha),
+                other => panic!("expected a corrupt object, got {other:?}"),
+            }
+        }
+        assert!(matches!(
+            repo.object_read(&ObjectId::null(ObjectFormat::Sha1)),
+            Err(OzError::ObjectNotFound(_))
+        ));
+        assert!(matches!(
+            repo.commit_parents(&tree),
+            Err(OzError::CorruptObject { .. })
+        ));
+        assert!(GitCommit::try_from(vec![0xff, b'\n', b'\n']).is_err());
+    }
+
+    #[test]
+    fn sha256_repositories_use_32_byte_ids() {
+        let repo = temp_repo_with_format("sha256", ObjectFormat::Sha256);
+        let repo = GitRepository::new(repo.worktree.clone(), false).unwrap();
+        assert_eq!(repo.object_format(), ObjectFormat::Sha256);
+
+        let blob = write_blob(&repo, "hello\n");
+        assert_eq!(
+            blob.to_string(),
+            "2cf8d83d9ee29543b34a87727421fdecb7e3f3a183d337639025de576db9ebb4"
+        );
+        let root = commit(&repo, &[("hello", "hello\n")], &[], 0, "root\n");
+        let tree = repo.commit_read(&root).unwrap().tree().unwrap();
+        std::fs::write(repo.repo_file("refs/heads/master".into()), root.to_string()).unwrap();
+        assert_eq!(repo.rev_parse("HEAD:hello").unwrap(), blob);
+        assert_eq!(repo.rev_parse(&root.abbrev(8)).unwrap(), root);
+
+        let mut index = GitIndex::new();
+        index.add(GitIndexEntry {
+            mode: 0o100644,
+            sha: blob,
+            name: "hello".into(),
+            ..Default::default()
+        });
+        repo.index_write(&index).unwrap();
+        assert_eq!(repo.index_read().unwrap(), index);
+
+        // The objects can still be read once packed
+        crate::commands::repack(&repo, true, 10, 50).unwrap();
+        let repo = GitRepository::new(repo.worktree.clone(), false).unwrap();
+        assert!(repo.object_packed(&tree));
+        let GitObject::Tree(read) = repo.object_read(&tree).unwrap() else {
+            panic!("{tree} is not a tree");
+        };
+        assert_eq!(read.entries()[0].id, blob);
+        let report = crate::fsck::fsck(&repo, false).unwrap();
+        assert!(!report.has_errors(), "{:?}", report.issues);
+
+        // Version 1 repositories can't use extensions oz doesn't know
+        let config = repo.repo_file("config".into());
+        let mut conf = Ini::new();
+        conf.load(&config).unwrap();
+        assert_eq!(
+            conf.get("extensions", "objectformat").as_deref(),
+            Some("sha256")
+        );
+        conf.set("extensions", "somethingnew", Some("true".into()));
+        conf.write(&config).unwrap();
+        assert!(matches!(
+            GitRepository::new(repo.worktree.clone(), false),
+            Err(OzError::NotARepository(_))
+        ));
+    }
 }
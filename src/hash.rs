@@ -0,0 +1,235 @@
+use crate::error::OzError;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// The hash function naming the objects of a repository, chosen by
+/// `extensions.objectFormat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ObjectFormat {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+impl ObjectFormat {
+    /// Parses the name used by `extensions.objectFormat` and `--object-format`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "sha1" => Some(ObjectFormat::Sha1),
+            "sha256" => Some(ObjectFormat::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectFormat::Sha1 => "sha1",
+            ObjectFormat::Sha256 => "sha256",
+        }
+    }
+
+    /// Length of an object id in bytes, as stored in trees, indexes and packs
+    pub fn raw_len(self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 20,
+            ObjectFormat::Sha256 => 32,
+        }
+    }
+
+    /// Length of an object id in hex
+    pub fn hex_len(self) -> usize {
+        self.raw_len() * 2
+    }
+
+    pub(crate) fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ObjectFormat::Sha1 => Sha1::digest(data).to_vec(),
+            ObjectFormat::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    /// Parses `hex` when it is a full, lowercase, object id of this format
+    pub(crate) fn parse_id(self, hex: &str) -> Option<ObjectId> {
+        let lowercase = hex
+            .bytes()
+            .all(|ch| matches!(ch, b'0'..=b'9' | b'a'..=b'f'));
+        ObjectId::from_hex(hex)
+            .ok()
+            .filter(|id| lowercase && id.format() == self)
+    }
+}
+
+/// The name of an object: the hash of its header and content. The default
+/// is the sha1 null id.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ObjectId {
+    /// Sha1 ids only use the first 20 bytes
+    bytes: [u8; 32],
+    format: ObjectFormat,
+}
+
+impl ObjectId {
+    /// The id of `data` (header included) with `format`
+    pub(crate) fn hash(format: ObjectFormat, data: &[u8]) -> Self {
+        ObjectId::from_raw(&format.digest(data)).unwrap()
+    }
+
+    /// Builds an id from its 20 (sha1) or 32 (sha256) bytes
+    pub fn from_raw(raw: &[u8]) -> Option<Self> {
+        let format = match raw.len() {
+            20 => ObjectFormat::Sha1,
+            32 => ObjectFormat::Sha256,
+            _ => return None,
+        };
+        let mut bytes = [0; 32];
+        bytes[..raw.len()].copy_from_slice(raw);
+        Some(ObjectId { bytes, format })
+    }
+
+    /// Parses a full id of 40 (sha1) or 64 (sha256) hex digits
+    pub fn from_hex(hex: &str) -> Result<Self, OzError> {
+        unhex(hex)
+            .and_then(|raw| ObjectId::from_raw(&raw))
+            .ok_or_else(|| OzError::InvalidArgument(format!("Invalid object id {hex}")))
+    }
+
+    /// The id made of zeros, standing for no object
+    pub fn null(format: ObjectFormat) -> Self {
+        ObjectId {
+            bytes: [0; 32],
+            format,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.bytes == [0; 32]
+    }
+
+    pub fn format(&self) -> ObjectFormat {
+        self.format
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.format.raw_len()]
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex(self.as_bytes())
+    }
+
+    /// The first `len` hex digits, as used to show ids to people
+    pub fn abbrev(&self, len: usize) -> String {
+        let mut hex = self.to_hex();
+        hex.truncate(len);
+        hex
+    }
+}
+
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
+
+impl fmt::Debug for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ObjectId({self})")
+    }
+}
+
+impl FromStr for ObjectId {
+    type Err = OzError;
+
+    fn from_str(hex: &str) -> Result<Self, OzError> {
+        ObjectId::from_hex(hex)
+    }
+}
+
+/// Ids sort like their bytes, which is also the order of their hex
+impl Ord for ObjectId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl PartialOrd for ObjectId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|ch| format!("{ch:02x}")).collect()
+}
+
+/// Decodes a hex string, `None` when it isn't made of hex digit pairs
+pub(crate) fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_hash_like_git() {
+        // The empty blob
+        let data = b"blob 0\0";
+        assert_eq!(
+            ObjectId::hash(ObjectFormat::Sha1, data).to_string(),
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+        );
+        assert_eq!(
+            ObjectId::hash(ObjectFormat::Sha256, data).to_string(),
+            "473a0f4c3be8a93681a267e3b1e9a7dcda1185436fe141f7749120a303721813"
+        );
+        assert_eq!(
+            ObjectFormat::from_name("SHA256"),
+            Some(ObjectFormat::Sha256)
+        );
+        assert_eq!(unhex("00ff"), Some(vec![0, 255]));
+        assert_eq!(unhex("0"), None);
+        assert_eq!(unhex("zz"), None);
+        let sha1 = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+        assert!(ObjectFormat::Sha1.parse_id(sha1).is_some());
+        assert!(ObjectFormat::Sha256.parse_id(sha1).is_none());
+        assert!(ObjectFormat::Sha1.parse_id(&sha1.to_uppercase()).is_none());
+    }
+
+    #[test]
+    fn object_ids_convert_and_sort() {
+        let sha = "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391";
+        let id: ObjectId = sha.parse().unwrap();
+        assert_eq!(id.to_string(), sha);
+        assert_eq!(id.abbrev(7), "e69de29");
+        assert_eq!(id.format(), ObjectFormat::Sha1);
+        assert_eq!(id, ObjectId::hash(ObjectFormat::Sha1, b"blob 0\0"));
+        assert_eq!(ObjectId::from_hex(&sha.to_uppercase()).unwrap(), id);
+        assert_eq!(ObjectId::from_raw(id.as_bytes()), Some(id));
+        for bad in ["", "e69de29", &sha[1..], "g".repeat(40).as_str()] {
+            assert!(ObjectId::from_hex(bad).is_err(), "{bad}");
+        }
+        assert!(ObjectId::null(ObjectFormat::Sha256).is_null());
+        assert_eq!(
+            ObjectId::null(ObjectFormat::Sha256).to_hex(),
+            "0".repeat(64)
+        );
+
+        let mut ids: Vec<ObjectId> = ["ff", "00", "a0"]
+            .iter()
+            .map(|byte| byte.repeat(20).parse().unwrap())
+            .collect();
+        ids.sort();
+        assert_eq!(
+            ids.iter().map(|id| id.abbrev(2)).collect::<Vec<_>>(),
+            ["00", "a0", "ff"]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+use crate::git_repo::GitRepository;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+/// A single pattern read from an ignore file.
+#[derive(Debug, Clone)]
+pub struct IgnorePattern {
+    /// The file the pattern comes from, relative to the worktree when inside it
+    pub source: String,
+    pub line: usize,
+    /// The line as written, for `check-ignore -v`
+    pub text: String,
+    pattern: Vec<u8>,
+    pub negated: bool,
+    dir_only: bool,
+    /// Patterns containing a slash are matched against the whole path
+    /// (relative to the ignore file), the others against the file name only.
+    anchored: bool,
+}
+
+impl IgnorePattern {
+    fn parse(source: &str, line: usize, text: &str) -> Option<Self> {
+        if text.is_empty() || text.starts_with('#') {
+            return None;
+        }
+        // Trailing spaces are dropped unless escaped with a backslash
+        let mut pattern = text.as_bytes();
+        while let [rest @ .., b' '] = pattern {
+            if rest.ends_with(b"\\") && !rest.ends_with(b"\\\\") {
+                break;
+            }
+            pattern = rest;
+        }
+        let (negated, pattern) = match pattern.strip_prefix(b"!") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix(b"/") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains(&b'/');
+        let pattern = pattern.strip_prefix(b"/").unwrap_or(pattern);
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(IgnorePattern {
+            source: source.to_string(),
+            line,
+            text: text.to_string(),
+            pattern: pattern.to_vec(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn parse_file(path: &Path, source: &str) -> Vec<IgnorePattern> {
+        std::fs::read_to_string(path)
+            .unwrap_or_default()
+            .lines()
+            .enumerate()
+            .filter_map(|(i, line)| IgnorePattern::parse(source, i + 1, line))
+            .collect()
+    }
+
+    /// `path` is relative to the directory holding the ignore file.
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            glob_match(&self.pattern, path.as_bytes())
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(&self.pattern, name.as_bytes())
+        }
+    }
+}
+
+/// Decides which worktree paths are ignored.
+///
+/// Patterns come from the `.gitignore` of every directory, then
+/// `.git/info/exclude`, then the file named by `core.excludesFile`. The
+/// closest source with a matching pattern decides, and within a file the last
+/// matching pattern wins.
+#[derive(Debug)]
+pub(crate) struct GitIgnore {
+    worktree: PathBuf,
+    exclude: Vec<IgnorePattern>,
+    /// Patterns of the `.gitignore` in each directory ("" is the worktree root),
+    /// loaded lazily.
+    scoped: HashMap<String, Vec<IgnorePattern>>,
+}
+
+impl GitIgnore {
+    pub(crate) fn new(repo: &GitRepository) -> Self {
+        let mut exclude = IgnorePattern::parse_file(
+            &repo.repo_file(["info", "exclude"].iter().collect()),
+            ".git/info/exclude",
+        );
+
+        let home = std::env::var_os("HOME").map(PathBuf::from);
+        let excludes_file = match repo.config_get("core", "excludesfile") {
+            Some(file) => match (file.strip_prefix("~/"), &home) {
+                (Some(file), Some(home)) => Some(home.join(file)),
+                _ => Some(PathBuf::from(file)),
+            },
+            None => std::env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| home.map(|home| home.join(".config")))
+                .map(|config| config.join("git").join("ignore")),
+        };
+        if let Some(file) = excludes_file {
+            // These are checked last, so they go first: the last match wins
+            let mut patterns = IgnorePattern::parse_file(&file, &file.to_string_lossy());
+            patterns.append(&mut exclude);
+            exclude = patterns;
+        }
+
+        GitIgnore {
+            worktree: repo.worktree.clone(),
+            exclude,
+            scoped: HashMap::new(),
+        }
+    }
+
+    /// `path` is relative to the worktree and uses `/` as separator. A path
+    /// inside an ignored directory is ignored too.
+    pub(crate) fn is_ignored(&mut self, path: &str, is_dir: bool) -> bool {
+        self.check(path, is_dir)
+            .is_some_and(|pattern| !pattern.negated)
+    }
+
+    /// Returns the pattern deciding whether `path` is ignored, if any.
+    pub(crate) fn check(&mut self, path: &str, is_dir: bool) -> Option<&IgnorePattern> {
+        // Files can't be re-included once their parent directory is excluded
+        let mut end = 0;
+        while let Some(pos) = path[end..].find('/') {
+            end += pos;
+            if let Some((dir, i)) = self.find(&path[..end], true)
+                && !self.pattern(&dir, i).negated
+            {
+                return Some(self.pattern(&dir, i));
+            }
+            end += 1;
+        }
+        let (dir, i) = self.find(path, is_dir)?;
+        Some(self.pattern(&dir, i))
+    }
+
+    /// Patterns are returned by position, as `(directory, index)`. `None` as the
+    /// directory stands for the exclude files.
+    fn find(&mut self, path: &str, is_dir: bool) -> Option<(Option<String>, usize)> {
+        // The closest .gitignore has the final say, then the parent ones
+        let mut dir = path;
+        while let Some(pos) = dir.rfind('/') {
+            dir = &dir[..pos];
+            if let Some(i) = self.find_in(dir, path, is_dir) {
+                return Some((Some(dir.to_string()), i));
+            }
+        }
+        if let Some(i) = self.find_in("", path, is_dir) {
+            return Some((Some(String::new()), i));
+        }
+        last_match(&self.exclude, path, is_dir).map(|i| (None, i))
+    }
+
+    fn pattern(&self, dir: &Option<String>, i: usize) -> &IgnorePattern {
+        match dir {
+            Some(dir) => &self.scoped[dir][i],
+            None => &self.exclude[i],
+        }
+    }
+
+    /// Checks `path` against the `.gitignore` of `dir`
+    fn find_in(&mut self, dir: &str, path: &str, is_dir: bool) -> Option<usize> {
+        let worktree = &self.worktree;
+        let patterns = self.scoped.entry(dir.to_string()).or_insert_with(|| {
+            let source = if dir.is_empty() {
+                ".gitignore".to_string()
+            } else {
+                format!("{dir}/.gitignore")
+            };
+            IgnorePattern::parse_file(&worktree.join(&source), &source)
+        });
+        let relative = if dir.is_empty() {
+            path
+        } else {
+            &path[dir.len() + 1..]
+        };
+        last_match(patterns, relative, is_dir)
+    }
+}
+
+/// In a single file the last matching pattern wins
+fn last_match(patterns: &[IgnorePattern], path: &str, is_dir: bool) -> Option<usize> {
+    patterns
+        .iter()
+        .rposition(|pattern| pattern.matches(path, is_dir))
+}
+
+/// Matches `text` against a gitignore glob.
+///
+/// `*`, `?` and `[...]` never match a `/`. A `**` between slashes (or at the
+/// start or end of the pattern) matches any number of directories, and a
+/// backslash escapes the next character.
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    glob_match_at(pattern, 0, text)
+}
+
+fn glob_match_at(pattern: &[u8], pos: usize, text: &[u8]) -> bool {
+    let rest = &pattern[pos..];
+    match rest {
+        [] => text.is_empty(),
+        [b'*', b'*', after @ ..] if pos == 0 || pattern[pos - 1] == b'/' => match after {
+            // Trailing `/**` matches everything below
+            [] => true,
+            // `**/` matches zero or more directories
+            [b'/', ..] => {
+                glob_match_at(pattern, pos + 3, text)
+                    || text
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, ch)| **ch == b'/')
+                        .any(|(i, _)| glob_match_at(pattern, pos + 3, &text[i + 1..]))
+            }
+            _ => glob_match_at(pattern, pos + 1, text),
+        },
+        [b'*', ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| glob_match_at(pattern, pos + 1, &text[i..])),
+        [b'?', ..] => match text.first() {
+            Some(&ch) if ch != b'/' => glob_match_at(pattern, pos + 1, &text[1..]),
+            _ => false,
+        },
+        [b'[', class @ ..] => match (text.first(), class_match(class, text.first())) {
+            (Some(_), Some((true, len))) => glob_match_at(pattern, pos + 1 + len, &text[1..]),
+            (_, Some((false, _))) | (None, Some(_)) => false,
+            // An unterminated class is a literal `[`
+            (_, None) => text.first() == Some(&b'[') && glob_match_at(pattern, pos + 1, &text[1..]),
+        },
+        [b'\\', ch, ..] => text.first() == Some(ch) && glob_match_at(pattern, pos + 2, &text[1..]),
+        [ch, ..] => text.first() == Some(ch) && glob_match_at(pattern, pos + 1, &text[1..]),
+    }
+}
+
+/// Matches `ch` against a `[...]` class whose content starts at `pattern`.
+/// Returns whether it matched and the length of the class including the `]`.
+fn class_match(pattern: &[u8], ch: Option<&u8>) -> Option<(bool, usize)> {
+    let (negated, start) = match pattern.first() {
+        Some(b'!' | b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut i = start;
+    loop {
+        let mut first = *pattern.get(i)?;
+        if first == b']' && i > start {
+            break;
+        }
+        if first == b'\\' {
+            i += 1;
+            first = *pattern.get(i)?;
+        }
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&c| c != b']') {
+            let mut last_pos = i + 2;
+            if pattern[last_pos] == b'\\' {
+                last_pos += 1;
+            }
+            let last = *pattern.get(last_pos)?;
+            matched |= ch.is_some_and(|&ch| first <= ch && ch <= last);
+            i = last_pos + 1;
+        } else {
+            matched |= ch == Some(&first);
+            i += 1;
+        }
+    }
+    Some((matched != negated && ch != Some(&b'/'), i + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(lines: &[&str]) -> Vec<IgnorePattern> {
+        lines
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| IgnorePattern::parse(".gitignore", i + 1, line))
+            .collect()
+    }
+
+    #[test]
+    fn glob_matches_like_wildmatch() {
+        assert!(glob_match(b"*.o", b"main.o"));
+        assert!(!glob_match(b"*.o", b"dir/main.o"));
+        assert!(glob_match(b"doc/*.txt", b"doc/a.txt"));
+        assert!(glob_match(b"file?.[ch]", b"file1.c"));
+        assert!(!glob_match(b"file?.[!ch]", b"file1.c"));
+        assert!(glob_match(b"[a-c]x", b"bx"));
+        assert!(glob_match(b"a[", b"a["));
+        assert!(glob_match(b"\\*x", b"*x"));
+        assert!(!glob_match(b"\\*x", b"ax"));
+        assert!(glob_match(b"**/foo", b"foo"));
+        assert!(glob_match(b"**/foo", b"a/b/foo"));
+        assert!(glob_match(b"a/**/b", b"a/b"));
+        assert!(glob_match(b"a/**/b", b"a/x/y/b"));
+        assert!(glob_match(b"abc/**", b"abc/x/y"));
+        assert!(!glob_match(b"abc/**", b"abc"));
+        assert!(!glob_match(b"a**b", b"a/b"));
+    }
+
+    #[test]
+    fn patterns_follow_gitignore_rules() {
+        let patterns = parse(&[
+            "build/",
+            "/target",
+            "*.log",
+            "!keep.log",
+            "\\#hash",
+            "sp\\ ",
+            "x  ",
+        ]);
+        assert!(patterns[0].matches("src/build", true));
+        assert!(!patterns[0].matches("src/build", false));
+        assert!(patterns[1].matches("target", true));
+        assert!(!patterns[1].matches("src/target", true));
+        assert!(patterns[4].matches("#hash", false));
+        assert!(patterns[5].matches("sp ", false));
+        assert!(patterns[6].matches("x", false));
+
+        assert_eq!(last_match(&patterns, "a/debug.log", false), Some(2));
+        assert_eq!(last_match(&patterns, "keep.log", false), Some(3));
+        assert_eq!(last_match(&patterns, "main.rs", false), None);
+        assert!(parse(&["# comment", "", "!", "/"]).is_empty());
+    }
+}
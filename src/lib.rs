@@ -1,162 +1,256 @@
+//! A git implementation. [`GitRepository`] opens repositories, reads their
+//! objects, refs and index, and runs the operations behind the `oz` commands.
+//! Nothing here prints: results are returned, failures are [`OzError`]s.
+
+mod branch;
 mod commands;
+mod diff;
+mod error;
+mod fsck;
 mod git_repo;
+mod hash;
+mod ignore;
+mod log;
+mod pack;
+mod refs;
+mod show;
 
-use std::{
-    collections::HashSet,
-    fs,
-    hash::Hash,
-    path::{Path, PathBuf},
+pub use crate::{
+    commands::{Change, Status, StatusEntry},
+    diff::{
+        COMBINED_MAX_PARENTS, Diff, DiffAlgorithm, DiffFile, DiffOptions, DiffSource, Edit,
+        FileChange, combined_diff, diff_lines, is_binary, split_lines, unified_diff,
+    },
+    error::OzError,
+    fsck::{FsckIssue, FsckReport},
+    git_repo::{
+        FileMode, GitBlob, GitCommit, GitIndex, GitIndexEntry, GitObject, GitRepository, GitTag,
+        GitTree, RevCommit, RevOrder, RevWalk, Serializable, Signature, TreeChange, TreeEntry,
+    },
+    hash::{ObjectFormat, ObjectId},
+    ignore::IgnorePattern,
+    log::{Graph, LogFormat, LogOptions, parse_date},
 };
 
-use crate::{
-    commands::{cat_file, hash_object, repo_create, repo_find},
-    git_repo::{GitObject, GitRepository, GitTree, log_graphviz},
+use crate::branch::{
+    branch_create, branch_delete, branch_list, branch_rename, switch, switch_detach,
+};
+use crate::commands::{
+    add, check_ignore, checkout_into, commit, gc, hash_object as object_hash, prune_expiry, repack,
+    repo_create, rm, status, tag_create, tag_delete, tag_list, worktree_path,
 };
+use std::path::{Path, PathBuf};
 
-pub fn cmd_init(path: String) -> Result<(), String> {
-    repo_create(path.into()).map(|_| ())
+/// Hashes `data` as an object of `obj_type` with `format`, without storing it
+pub fn hash_object(
+    obj_type: &str,
+    data: Vec<u8>,
+    format: ObjectFormat,
+) -> Result<ObjectId, OzError> {
+    object_hash(None, format, obj_type, data)
 }
 
-pub fn cmd_cat_file(obj_type: String, object: String) -> Result<(), String> {
-    cat_file(repo_find()?, object, obj_type)
-}
+/// Paths given to [`GitRepository::add`], [`GitRepository::rm`],
+/// [`GitRepository::check_ignore`] and [`GitRepository::log`] are relative to
+/// the current directory, like on the command line.
+impl GitRepository {
+    /// Creates an empty repository in `path`, naming its objects with `format`
+    pub fn init(path: impl Into<PathBuf>, format: ObjectFormat) -> Result<Self, OzError> {
+        repo_create(path.into(), format)
+    }
 
-pub fn cmd_hash_object(write: bool, obj_type: String, path: String) -> Result<(), String> {
-    let repo = if write { Some(repo_find()?) } else { None };
+    /// Stores `data` as an object of `obj_type` and returns its sha
+    pub fn hash_object(&self, obj_type: &str, data: Vec<u8>) -> Result<ObjectId, OzError> {
+        object_hash(Some(self), self.format, obj_type, data)
+    }
 
-    let shasum = hash_object(
-        repo,
-        obj_type,
-        std::fs::read(path).map_err(|e| e.to_string())?,
-    )?;
-    println!("{}", shasum);
-    Ok(())
-}
+    /// The refs whose name starts with `prefix`, as `(name, sha)` sorted by name
+    pub fn refs(&self, prefix: &str) -> Result<Vec<(String, ObjectId)>, OzError> {
+        refs::list(self, prefix)
+    }
 
-#[cfg(test)]
-mod tests {
-    // use super::*;
+    /// The sha the ref `name` (e.g. `HEAD` or `refs/heads/master`) points to
+    pub fn ref_resolve(&self, name: &str) -> Result<Option<ObjectId>, OzError> {
+        refs::resolve(self, name)
+    }
 
-    #[test]
-    fn parse_command() {}
-}
+    /// The object the ref `name`, pointing at `sha`, eventually points to
+    /// when it is an annotated tag
+    pub fn ref_peel(&self, name: &str, sha: &ObjectId) -> Result<Option<ObjectId>, OzError> {
+        refs::peel(self, name, sha)
+    }
 
-pub fn cmd_log(commit: String) -> Result<(), String> {
-    let repo = repo_find()?;
+    /// The branch HEAD points to, `None` when detached
+    pub fn current_branch(&self) -> Result<Option<String>, OzError> {
+        refs::current_branch(self)
+    }
 
-    println!("digraph ozlog{{");
-    println!("node[shape=rect]");
-    log_graphviz(
-        &repo,
-        &repo.object_find(commit, "commit".to_string()),
-        &mut HashSet::new(),
-    )?;
-    println!("}}");
+    /// The names of the tags, sorted
+    pub fn tags(&self) -> Result<Vec<String>, OzError> {
+        tag_list(self)
+    }
 
-    Ok(())
-}
+    /// Creates the tag `name` on the revision `object`, annotated when there
+    /// are `messages`. Returns the sha the tag points to.
+    pub fn tag_create(
+        &self,
+        name: &str,
+        object: &str,
+        messages: Vec<String>,
+        force: bool,
+    ) -> Result<ObjectId, OzError> {
+        tag_create(self, name, object.to_string(), messages, force)
+    }
 
-pub fn cmd_list_tree(recursive: bool, tree: String) -> Result<(), String> {
-    let repo = repo_find()?;
-    ls_tree(&repo, tree, recursive, "");
-    Ok(())
-    // todo!()
-}
+    /// Deletes the tag `name`, returning the sha it pointed at
+    pub fn tag_delete(&self, name: &str) -> Result<ObjectId, OzError> {
+        tag_delete(self, name)
+    }
 
-pub(self) fn ls_tree(repo: &GitRepository, tree: String, recursive: bool, prefix: &str) {
-    let sha = repo.object_find(tree, "tree".into());
-    let obj = repo.object_read(&sha);
-
-    if let Some(GitObject::Tree(obj)) = obj {
-        for entry in obj.items {
-            let obj_type = match &entry.0[0..2] {
-                [b'0', b'4'] => "tree",
-                [b'1', b'0'] => "blob", // Regular file
-                [b'1', b'2'] => "blob", // symlink, pointed to a blob file
-                [b'1', b'6'] => "commit",
-                _ => panic!("Unknown type of file"),
-            };
-
-            let is_tree = obj_type == "tree";
-            if !recursive || !is_tree {
-                println!(
-                    "{} {} {}\t{}",
-                    String::from_utf8(entry.0.into()).expect("Something wrong with the header"),
-                    obj_type,
-                    entry.2,
-                    prefix.to_string() + if prefix.is_empty() { "" } else { "/" } + &entry.1
-                );
-            } else {
-                ls_tree(
-                    repo,
-                    entry.2,
-                    recursive,
-                    &(prefix.to_string() + "/" + &entry.1),
-                );
-            }
-        }
+    /// The branches, as `(name, sha)` sorted by name
+    pub fn branches(&self) -> Result<Vec<(String, ObjectId)>, OzError> {
+        branch_list(self)
+    }
+
+    /// Creates the branch `name` on the commit `start`, returning its sha.
+    /// With `force` an existing branch is moved.
+    pub fn branch_create(&self, name: &str, start: &str, force: bool) -> Result<ObjectId, OzError> {
+        branch_create(self, name, start, force)
     }
-}
 
-pub fn cmd_checkout(commit: String, path: String) -> Result<(), String> {
-    let repo = repo_find()?;
-    let sha = repo.object_find(commit, "".to_string());
-    let obj = repo
-        .object_read(&sha)
-        .ok_or("Unable to read the git object")?;
-    let tree = match obj {
-        GitObject::Tree(tree) => tree,
-        GitObject::Commit(commit) => {
-            let hash = commit
-                .data
-                .get("tree")
-                .ok_or("Malformed Commit")?
-                .first()
-                .ok_or("Malformed Commit")?;
-            let obj = repo.object_read(hash).ok_or("Unable to read the tree")?;
-            if let GitObject::Tree(tree) = obj {
-                tree
-            } else {
-                Err("Expected tree")?
-            }
-        }
-        _ => Err("Expected commit or tree")?,
-    };
-
-    let path: PathBuf = path.into();
-    if path.exists() {
-        // Exist
-        if !path.is_dir() {
-            Err("Not a directory")?;
-        }
-        if path.read_dir().map_err(|_| "Unable to read dir")?.count() > 0 {
-            Err("The directory is not empty")?;
-        }
-    } else {
-        // Need to create one
-        fs::create_dir_all(&path).map_err(|_| "Failed to create the directory")?;
-    }
-
-    checkout_tree(&repo, &tree, &path)?;
-    Ok(())
+    /// Deletes the branch `name`, returning the sha it pointed at. Unless
+    /// `force`, it must be merged into HEAD.
+    pub fn branch_delete(&self, name: &str, force: bool) -> Result<ObjectId, OzError> {
+        branch_delete(self, name, force)
+    }
+
+    /// Renames the branch `old` to `new`, which HEAD follows
+    pub fn branch_rename(&self, old: &str, new: &str, force: bool) -> Result<(), OzError> {
+        branch_rename(self, old, new, force)
+    }
+
+    /// Puts HEAD on the branch `name`, first created on `start` when given,
+    /// and updates the worktree and the index. Refuses when local changes
+    /// would be lost.
+    pub fn switch(&self, name: &str, start: Option<&str>) -> Result<(), OzError> {
+        switch(self, name, start)
+    }
+
+    /// Detaches HEAD at the commit `rev` and updates the worktree and the
+    /// index, returning the commit
+    pub fn switch_detach(&self, rev: &str) -> Result<ObjectId, OzError> {
+        switch_detach(self, rev)
+    }
+
+    /// Stages files, or everything below directories
+    pub fn add(&self, paths: Vec<String>) -> Result<(), OzError> {
+        add(self, paths)
+    }
+
+    /// Unstages and, unless `cached`, deletes files. Returns the paths removed.
+    pub fn rm(
+        &self,
+        paths: Vec<String>,
+        cached: bool,
+        recursive: bool,
+        force: bool,
+    ) -> Result<Vec<String>, OzError> {
+        rm(self, paths, cached, recursive, force)
+    }
+
+    /// Commits the index on top of HEAD, returning the sha of the new commit
+    pub fn commit(&self, messages: Vec<String>, allow_empty: bool) -> Result<ObjectId, OzError> {
+        commit(self, messages, allow_empty)
+    }
+
+    /// The commits reachable from the revisions `revs` matching `options`.
+    /// `A..B` and `^A` leave out the commits reachable from `A`.
+    pub fn log(&self, revs: &[String], options: &LogOptions) -> Result<Vec<RevCommit>, OzError> {
+        let paths = options
+            .paths
+            .iter()
+            .map(|path| {
+                // Log paths are compared as text
+                String::from_utf8(worktree_path(self, path)?)
+                    .map_err(|_| OzError::InvalidArgument(format!("Non UTF-8 path {path}")))
+            })
+            .collect::<Result<_, _>>()?;
+        let options = LogOptions {
+            paths,
+            ..options.clone()
+        };
+        log::log(self, revs, &options)
+    }
+
+    /// The files that differ between `old` and `new`, e.g. the index and
+    /// the worktree for `git diff`
+    pub fn diff(
+        &self,
+        old: DiffSource,
+        new: DiffSource,
+        options: &DiffOptions,
+    ) -> Result<Diff<'_>, OzError> {
+        diff::diff(self, old, new, options)
+    }
+
+    /// What `git show` prints for the revisions `revs`: commits with their
+    /// patch, annotated tags, tree listings and blob contents
+    pub fn show(&self, revs: &[String], options: &DiffOptions) -> Result<Vec<u8>, OzError> {
+        show::show(self, revs, options)
+    }
+
+    /// Compares HEAD with the index and the index with the worktree, and
+    /// lists the untracked files that aren't ignored.
+    pub fn status(&self) -> Result<Status, OzError> {
+        status(self)
+    }
+
+    /// The pattern deciding whether each path is ignored, if any
+    pub fn check_ignore(
+        &self,
+        paths: &[String],
+    ) -> Result<Vec<(String, Option<IgnorePattern>)>, OzError> {
+        check_ignore(self, paths)
+    }
+
+    /// Writes the tree of the revision `rev` in the empty directory `path`,
+    /// leaving HEAD and the index alone (see `switch` to move HEAD)
+    pub fn checkout_into(&self, rev: &str, path: impl AsRef<Path>) -> Result<(), OzError> {
+        checkout_into(self, rev, path.as_ref())
+    }
+
+    /// Packs the reachable loose objects, or every reachable object with
+    /// `all`. Returns the pack name, its number of objects and of deltas.
+    pub fn repack(
+        &self,
+        all: bool,
+        window: usize,
+        depth: usize,
+    ) -> Result<Option<(String, usize, usize)>, OzError> {
+        repack(self, all, window, depth)
+    }
+
+    /// Checks the integrity and connectivity of every object
+    pub fn fsck(&self, unreachable: bool) -> Result<FsckReport, OzError> {
+        fsck::fsck(self, unreachable)
+    }
+
+    /// Packs refs and objects, then prunes the unreachable objects older than
+    /// `prune` (`gc.pruneExpire`, or 2 weeks, by default). Returns the number
+    /// of objects pruned.
+    pub fn gc(&self, prune: Option<&str>) -> Result<usize, OzError> {
+        let prune = prune
+            .map(str::to_string)
+            .or_else(|| self.config_get("gc", "pruneexpire"))
+            .unwrap_or("2.weeks.ago".to_string());
+        gc(self, prune_expiry(&prune)?)
+    }
 }
 
-fn checkout_tree(repo: &GitRepository, tree: &GitTree, path: &Path) -> Result<(), String> {
-    for items in &tree.items {
-        let obj = repo.object_read(&items.2).ok_or("Can't read object")?;
-        let path = path.join(&items.1);
-        match obj {
-            GitObject::Blob(blob) => {
-                fs::write(path, blob.buffer).map_err(|_| "Can't write into the file")?;
-            }
-            GitObject::Tree(tree) => {
-                // if !path.is_dir() {
-                fs::create_dir(&path).map_err(|_| "Failed to create the directory")?;
-                // }
-                checkout_tree(repo, &tree, &path)?;
-            }
-            _ => Err("Malformed tree")?,
-        }
-    }
-    Ok(())
+#[cfg(test)]
+mod tests {
+    // use super::*;
+
+    #[test]
+    fn parse_command() {}
 }
@@ -0,0 +1,569 @@
+use crate::{
+    error::OzError,
+    git_repo::{GitCommit, GitRepository, RevCommit, RevOrder, Signature},
+    hash::ObjectId,
+};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime};
+
+/// Which commits [`GitRepository::log`] lists, and in which order
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    pub max_count: Option<usize>,
+    pub order: RevOrder,
+    pub reverse: bool,
+    /// Only follow the first parent of merges
+    pub first_parent: bool,
+    /// List the closest listed ancestors as parents, as a graph needs
+    pub rewrite_parents: bool,
+    /// Only commits made at or after this time (committer date, in seconds
+    /// since the epoch, see [`parse_date`])
+    pub since: Option<i64>,
+    /// Only commits made at or before this time
+    pub until: Option<i64>,
+    /// Only commits whose `name <email>` author contains this
+    pub author: Option<String>,
+    /// Only commits whose message contains this
+    pub grep: Option<String>,
+    /// Only commits changing these paths, relative to the worktree
+    pub paths: Vec<String>,
+}
+
+/// Walks the history from the revisions `revs` (`A..B` and `^A` hide
+/// commits), keeping the commits matching `options`.
+pub(crate) fn log(
+    repo: &GitRepository,
+    revs: &[String],
+    options: &LogOptions,
+) -> Result<Vec<RevCommit>, OzError> {
+    let mut walk = repo.rev_walk();
+    for rev in revs {
+        walk.push_rev(rev)?;
+    }
+    walk.sort(options.order);
+    if options.reverse {
+        walk.reverse();
+    }
+    if options.first_parent {
+        walk.first_parent();
+    }
+    if options.rewrite_parents {
+        walk.rewrite_parents();
+    }
+    if let Some(count) = options.max_count {
+        walk.max_count(count);
+    }
+    if let Some(since) = options.since {
+        walk.since(since);
+    }
+    walk.paths(options.paths.clone());
+    walk.filter_commits(|commit| {
+        let time = commit.committer().map_or(0, |committer| committer.time);
+        options.until.is_none_or(|until| time <= until)
+            && options.author.as_ref().is_none_or(|author| {
+                commit
+                    .author()
+                    .is_some_and(|signature| signature.to_string().contains(author.as_str()))
+            })
+            && options
+                .grep
+                .as_ref()
+                .is_none_or(|grep| commit.message().contains(grep.as_str()))
+    });
+    walk.collect()
+}
+
+/// Parses the dates of `--since`, `--until` and `gc --prune`: `now`,
+/// `<n>.<unit>.ago` (or with spaces), `@<epoch>`, or `YYYY-MM-DD` optionally
+/// followed by `HH:MM[:SS]` in local time. Returns seconds since the epoch.
+pub fn parse_date(spec: &str) -> Result<i64, OzError> {
+    let invalid = || OzError::InvalidArgument(format!("Invalid date '{spec}'"));
+    let now = Local::now().timestamp();
+    let spec = spec.trim();
+    if spec == "now" {
+        return Ok(now);
+    }
+    if let Some(epoch) = spec.strip_prefix('@') {
+        return epoch.parse().map_err(|_| invalid());
+    }
+
+    let words: Vec<&str> = spec.split(['.', ' ']).filter(|w| !w.is_empty()).collect();
+    if let [count, unit, "ago"] | [count, unit] = words[..]
+        && let Ok(count) = count.parse::<i64>()
+    {
+        let unit = match unit.strip_suffix('s').unwrap_or(unit) {
+            "second" => 1,
+            "minute" => 60,
+            "hour" => 60 * 60,
+            "day" => 24 * 60 * 60,
+            "week" => 7 * 24 * 60 * 60,
+            "month" => 30 * 24 * 60 * 60,
+            "year" => 365 * 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        return Ok(now - count * unit);
+    }
+
+    let date = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(spec, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(spec, "%Y-%m-%d")
+                .ok()
+                .map(|date| date.and_time(NaiveTime::MIN))
+        })
+        .ok_or_else(invalid)?;
+    date.and_local_timezone(Local)
+        .earliest()
+        .map(|date| date.timestamp())
+        .ok_or_else(invalid)
+}
+
+/// `Thu Oct 16 10:00:00 2025 +0200`, in the time zone of the signature
+pub(crate) const DEFAULT_DATE: &str = "%a %b %-d %H:%M:%S %Y %z";
+const ISO_DATE: &str = "%Y-%m-%d %H:%M:%S %z";
+const STRICT_ISO_DATE: &str = "%Y-%m-%dT%H:%M:%S%:z";
+
+/// Formats the date of `signature` in its own time zone
+pub(crate) fn format_date(signature: &Signature, format: &str) -> String {
+    let zone =
+        FixedOffset::east_opt(signature.offset * 60).unwrap_or(FixedOffset::east_opt(0).unwrap());
+    DateTime::from_timestamp(signature.time, 0)
+        .unwrap_or_default()
+        .with_timezone(&zone)
+        .format(format)
+        .to_string()
+}
+
+/// The first paragraph of `message`, on one line
+fn subject(message: &str) -> String {
+    message
+        .lines()
+        .skip_while(|line| line.trim().is_empty())
+        .take_while(|line| !line.trim().is_empty())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// What follows the subject of `message`
+fn body(message: &str) -> String {
+    let mut lines = message
+        .lines()
+        .skip_while(|line| line.trim().is_empty())
+        .skip_while(|line| !line.trim().is_empty())
+        .skip_while(|line| line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if !lines.is_empty() {
+        lines.push('\n');
+    }
+    lines
+}
+
+/// How each commit is shown by `oz log`
+#[derive(Debug, Clone, Default)]
+pub enum LogFormat {
+    /// Sha, author, date and indented message, like git's default
+    #[default]
+    Medium,
+    /// Abbreviated sha and subject
+    Oneline,
+    /// A `--format` string: `%H` `%h` (commit), `%T` `%t` (tree), `%P` `%p`
+    /// (parents), `%an` `%ae` `%ad` `%at` `%ai` `%aI` (author name, email and
+    /// date), the same with `%c` for the committer, `%s` (subject), `%b`
+    /// (body), `%B` (message), `%n` and `%%`
+    Format(String),
+}
+
+impl LogFormat {
+    /// The text shown for the commit `id`, without a final newline
+    pub fn render(&self, id: &ObjectId, commit: &GitCommit) -> String {
+        match self {
+            LogFormat::Medium => {
+                let mut text = format!("commit {id}\n");
+                let parents = commit.parents();
+                if parents.len() > 1 {
+                    let parents: Vec<_> = parents.iter().map(|parent| parent.abbrev(7)).collect();
+                    text += &format!("Merge: {}\n", parents.join(" "));
+                }
+                if let Some(author) = commit.author() {
+                    text += &format!("Author: {} <{}>\n", author.name, author.email);
+                    text += &format!("Date:   {}\n", format_date(&author, DEFAULT_DATE));
+                }
+                text.push('\n');
+                let message = commit.message();
+                let lines: Vec<&str> = message
+                    .trim_end()
+                    .lines()
+                    .skip_while(|line| line.trim().is_empty())
+                    .collect();
+                let lines: Vec<String> = lines.iter().map(|line| format!("    {line}")).collect();
+                text + &lines.join("\n")
+            }
+            LogFormat::Oneline => format!("{} {}", id.abbrev(7), subject(&commit.message())),
+            LogFormat::Format(format) => format_commit(format, id, commit),
+        }
+    }
+}
+
+fn format_commit(format: &str, id: &ObjectId, commit: &GitCommit) -> String {
+    let mut text = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('%') {
+        text += &rest[..start];
+        rest = &rest[start + 1..];
+        let (value, len) = placeholder(rest, id, commit).unwrap_or(("%".to_string(), 0));
+        text += &value;
+        rest = &rest[len..];
+    }
+    text + rest
+}
+
+/// The value of the placeholder `spec` starts with, and its length
+fn placeholder(spec: &str, id: &ObjectId, commit: &GitCommit) -> Option<(String, usize)> {
+    let tree = || commit.tree().unwrap_or_default();
+    let parents = |abbrev: bool| {
+        let parents: Vec<_> = commit
+            .parents()
+            .iter()
+            .map(|parent| {
+                if abbrev {
+                    parent.abbrev(7)
+                } else {
+                    parent.to_string()
+                }
+            })
+            .collect();
+        parents.join(" ")
+    };
+    let value = match spec.get(..1)? {
+        "H" => id.to_string(),
+        "h" => id.abbrev(7),
+        "T" => tree().to_string(),
+        "t" => tree().abbrev(7),
+        "P" => parents(false),
+        "p" => parents(true),
+        "s" => subject(&commit.message()),
+        "b" => body(&commit.message()),
+        "B" => commit.message().into_owned(),
+        "n" => "\n".to_string(),
+        "%" => "%".to_string(),
+        "a" | "c" => {
+            let signature = match spec.get(..1)? {
+                "a" => commit.author(),
+                _ => commit.committer(),
+            }?;
+            let value = match spec.get(1..2)? {
+                "n" => signature.name.clone(),
+                "e" => signature.email.clone(),
+                "d" => format_date(&signature, DEFAULT_DATE),
+                "t" => signature.time.to_string(),
+                "i" => format_date(&signature, ISO_DATE),
+                "I" => format_date(&signature, STRICT_ISO_DATE),
+                _ => return None,
+            };
+            return Some((value, 2));
+        }
+        _ => return None,
+    };
+    Some((value, 1))
+}
+
+/// What drawing a commit does to the columns of a [`Graph`]
+struct Layout {
+    col: usize,
+    next: Vec<ObjectId>,
+    /// `(position, target)` of the lines, for each stage of moves
+    stages: Vec<Vec<(usize, usize)>>,
+    width: usize,
+}
+
+impl Layout {
+    fn new(
+        columns: usize,
+        col: usize,
+        next: Vec<ObjectId>,
+        stages: Vec<Vec<(usize, usize)>>,
+    ) -> Self {
+        // Two characters per column, like git
+        let width = 2 * columns.max(next.len()).max(1);
+        Layout {
+            col,
+            next,
+            stages,
+            width,
+        }
+    }
+}
+
+/// Draws the ASCII art of `oz log --graph`, one commit at a time. Each
+/// column is a line of history waiting for the next commit on it.
+#[derive(Debug, Default)]
+pub struct Graph {
+    columns: Vec<ObjectId>,
+}
+
+impl Graph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prefixes the `lines` shown for the commit `id`, whose parents in the
+    /// graph are `parents`, with the graph. The lines joining the commit to
+    /// its parents are added when `lines` are too few to hold them.
+    pub fn draw(&mut self, id: &ObjectId, parents: &[ObjectId], lines: &[&str]) -> Vec<String> {
+        let layout = self.layout(id, parents);
+        if layout.col == self.columns.len() {
+            self.columns.push(*id);
+        }
+
+        let mut rows = Vec::new();
+        let mut row = vec![b' '; layout.width];
+        for i in 0..self.columns.len() {
+            row[2 * i] = if i == layout.col { b'*' } else { b'|' };
+        }
+        rows.push(row);
+        // Lines move one column per row until they reach their place
+        for mut moves in layout.stages {
+            while moves.iter().any(|(position, target)| position != target) {
+                let mut row = vec![b' '; layout.width];
+                for (position, target) in &mut moves {
+                    if *position < *target {
+                        row[2 * *position + 1] = b'\\';
+                        *position += 1;
+                    } else if *position > *target {
+                        row[2 * *position - 1] = b'/';
+                        *position -= 1;
+                    } else {
+                        row[2 * *position] = b'|';
+                    }
+                }
+                rows.push(row);
+            }
+        }
+        let padding = Self::padding(layout.next.len(), layout.width);
+
+        let mut output = Vec::new();
+        for i in 0..rows.len().max(lines.len()) {
+            let prefix = String::from_utf8_lossy(rows.get(i).unwrap_or(&padding)).into_owned();
+            output.push(prefix + lines.get(i).unwrap_or(&""));
+        }
+        self.columns = layout.next;
+        output
+    }
+
+    /// The blank line shown before the commit `id`, already as wide as the
+    /// commit will make the graph
+    pub fn separator(&self, id: &ObjectId, parents: &[ObjectId]) -> String {
+        let layout = self.layout(id, parents);
+        String::from_utf8_lossy(&Self::padding(self.columns.len(), layout.width)).into_owned()
+    }
+
+    /// How the lines move below the commit `id`.
+    ///
+    /// The parents take the place of the commit and columns waiting for the
+    /// same commit join the leftmost one. When that would make moving lines
+    /// cross, the lines first go to the columns already waiting for the
+    /// parents, then the gaps left are closed.
+    fn layout(&self, id: &ObjectId, parents: &[ObjectId]) -> Layout {
+        let col = self
+            .columns
+            .iter()
+            .position(|column| column == id)
+            .unwrap_or(self.columns.len());
+
+        let mut next = Vec::new();
+        let mut push = |id: &ObjectId| {
+            if !next.contains(id) {
+                next.push(*id);
+            }
+        };
+        for (i, column) in self.columns.iter().enumerate() {
+            if i == col {
+                parents.iter().for_each(&mut push);
+            } else {
+                push(column);
+            }
+        }
+        if col == self.columns.len() {
+            parents.iter().for_each(&mut push);
+        }
+        let moves = self.moves(col, parents, |id| next.iter().position(|c| c == id));
+        let crossing = moves.iter().any(|&(start, target)| {
+            start != target
+                && moves
+                    .iter()
+                    .any(|&(other, to)| other != to && other > start && to < target)
+        });
+        if !crossing {
+            return Layout::new(self.columns.len().max(col + 1), col, next, vec![moves]);
+        }
+
+        let mut gaps = Vec::new();
+        for (i, column) in self.columns.iter().enumerate() {
+            if i == col {
+                let new = parents
+                    .iter()
+                    .filter(|parent| !self.columns.contains(parent));
+                let before = gaps.len();
+                for parent in new {
+                    if !gaps.contains(&Some(*parent)) {
+                        gaps.push(Some(*parent));
+                    }
+                }
+                if gaps.len() == before {
+                    gaps.push(None);
+                }
+            } else {
+                gaps.push(Some(*column));
+            }
+        }
+        let spread = self.moves(col, parents, |id| gaps.iter().position(|c| c == &Some(*id)));
+        let next: Vec<ObjectId> = gaps.iter().flatten().copied().collect();
+        let close = gaps
+            .iter()
+            .enumerate()
+            .filter_map(|(i, column)| {
+                Some((i, next.iter().position(|c| Some(c) == column.as_ref())?))
+            })
+            .collect();
+        Layout::new(gaps.len(), col, next, vec![spread, close])
+    }
+
+    /// `(position, target)` of each line below the commit at `col`
+    fn moves(
+        &self,
+        col: usize,
+        parents: &[ObjectId],
+        target: impl Fn(&ObjectId) -> Option<usize>,
+    ) -> Vec<(usize, usize)> {
+        let mut moves = Vec::new();
+        for (i, column) in self.columns.iter().enumerate() {
+            if i != col {
+                moves.extend(target(column).map(|target| (i, target)));
+            }
+        }
+        moves.extend(
+            parents
+                .iter()
+                .filter_map(|parent| Some((col, target(parent)?))),
+        );
+        moves.sort();
+        moves
+    }
+
+    fn padding(columns: usize, width: usize) -> Vec<u8> {
+        let mut padding = vec![b' '; width];
+        for i in 0..columns {
+            padding[2 * i] = b'|';
+        }
+        padding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::tests::{commit, temp_repo};
+
+    #[test]
+    fn log_walks_by_date_and_limits_paths() {
+        let repo = temp_repo("log");
+        let root = commit(&repo, &[("a", "1")], &[], 100, "root\n");
+        let old = commit(&repo, &[("a", "1"), ("b", "1")], &[root], 200, "add b\n");
+        let new = commit(&repo, &[("a", "2")], &[root], 300, "change a\n");
+        let merge = commit(
+            &repo,
+            &[("a", "2"), ("b", "1")],
+            &[new, old],
+            400,
+            "merge\n",
+        );
+
+        let ids = |options: &LogOptions| -> Vec<ObjectId> {
+            log(&repo, &[merge.to_string()], options)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.id)
+                .collect()
+        };
+        assert_eq!(ids(&LogOptions::default()), [merge, new, old, root]);
+        let limited = LogOptions {
+            max_count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(ids(&limited), [merge, new]);
+        let dated = LogOptions {
+            since: Some(200),
+            until: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(ids(&dated), [new, old]);
+        let grep = LogOptions {
+            grep: Some("a".into()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&grep), [new, old]);
+
+        // The merge took `a` from `new`, so only `new` is followed
+        let paths = LogOptions {
+            paths: vec!["a".into()],
+            ..Default::default()
+        };
+        let entries = log(&repo, &[merge.to_string()], &paths).unwrap();
+        let listed: Vec<_> = entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(listed, [new, root]);
+        assert_eq!(entries[0].parents, [root]);
+    }
+
+    #[test]
+    fn formats_commits() {
+        let repo = temp_repo("log-format");
+        let root = commit(&repo, &[], &[], 1700000000, "Subject\nline\n\nBody\n");
+        let commit = repo.commit_read(&root).unwrap();
+        let format = LogFormat::Format("%h %an <%ae> %ai%n%s|%b|%x%%".into());
+        assert_eq!(
+            format.render(&root, &commit),
+            format!(
+                "{} A <a@b> 2023-11-14 23:13:20 +0100\nSubject line|Body\n|%x%",
+                root.abbrev(7)
+            )
+        );
+        assert_eq!(
+            LogFormat::Medium.render(&root, &commit),
+            format!(
+                "commit {root}\nAuthor: A <a@b>\nDate:   Tue Nov 14 23:13:20 2023 +0100\n\n    \
+                 Subject\n    line\n    \n    Body"
+            )
+        );
+        assert_eq!(parse_date("@1700000000").unwrap(), 1700000000);
+        assert!(parse_date("2.weeks.ago").unwrap() < parse_date("1 week ago").unwrap());
+        assert!(parse_date("2023-11-14").is_ok());
+        assert!(parse_date("someday").is_err());
+    }
+
+    #[test]
+    fn graph_draws_merges_and_forks() {
+        let ids: Vec<ObjectId> = (1..=4u8)
+            .map(|i| ObjectId::from_raw(&[i; 20]).unwrap())
+            .collect();
+        let [merge, left, right, root] = [ids[0], ids[1], ids[2], ids[3]];
+        let mut graph = Graph::new();
+        let mut lines = Vec::new();
+        lines.extend(graph.draw(&merge, &[left, right], &["merge"]));
+        lines.extend(graph.draw(&left, &[root], &["left"]));
+        lines.extend(graph.draw(&right, &[root], &["right"]));
+        lines.extend(graph.draw(&root, &[], &["root"]));
+        assert_eq!(
+            lines,
+            [
+                "*   merge",
+                "|\\  ",
+                "* | left",
+                "| * right",
+                "|/  ",
+                "* root"
+            ]
+        );
+    }
+}
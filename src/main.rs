@@ -1,4 +1,9 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use oz::{
+    Change, DiffAlgorithm, DiffOptions, DiffSource, GitObject, GitRepository, Graph, LogFormat,
+    LogOptions, ObjectFormat, ObjectId, OzError, RevOrder, Serializable, StatusEntry, parse_date,
+};
+use std::io::Write;
 
 #[derive(Parser, Debug)]
 struct Cli {
@@ -9,6 +14,13 @@ struct Cli {
 #[derive(Subcommand, Debug)]
 enum Commands {
     Init {
+        #[arg(
+            long,
+            default_value = "sha1",
+            help = "Hash function naming the objects: sha1 or sha256"
+        )]
+        object_format: String,
+
         #[arg(default_value = ".")]
         path: String,
     },
@@ -30,8 +42,90 @@ enum Commands {
 
     #[command(about = "Display history of a given commit.")]
     Log {
-        #[arg(help = "Commit to start at.", default_value = "HEAD")]
-        commit: String,
+        #[arg(long, help = "Show each commit on one line")]
+        oneline: bool,
+        #[arg(long, help = "Show commits with placeholders like %H %h %an %ad %s %b")]
+        format: Option<String>,
+        #[arg(long, help = "Draw the history next to the commits")]
+        graph: bool,
+        #[arg(long, help = "Print the history as a Graphviz graph")]
+        graphviz: bool,
+        #[arg(short = 'n', long, help = "Show at most this many commits")]
+        max_count: Option<usize>,
+        #[arg(long, help = "Show commits more recent than a date")]
+        since: Option<String>,
+        #[arg(long, help = "Show commits older than a date")]
+        until: Option<String>,
+        #[arg(long, help = "Show commits whose author matches")]
+        author: Option<String>,
+        #[arg(long, help = "Show commits whose message matches")]
+        grep: Option<String>,
+        #[arg(long, help = "Show no parent before all its children")]
+        topo_order: bool,
+        #[arg(
+            long,
+            help = "Show no parent before all its children, otherwise by date"
+        )]
+        date_order: bool,
+        #[arg(long, help = "Show the oldest commits first")]
+        reverse: bool,
+        #[arg(long, help = "Only follow the first parent of merges")]
+        first_parent: bool,
+        #[arg(
+            help = "Commits to start at, HEAD by default. A..B and ^A leave out the history of A"
+        )]
+        revisions: Vec<String>,
+        #[arg(last = true, help = "Show commits changing these paths")]
+        paths: Vec<String>,
+    },
+    #[command(about = "Find the best common ancestor of two commits")]
+    MergeBase {
+        #[arg(short, long, help = "Show all the best common ancestors")]
+        all: bool,
+        one: String,
+        two: String,
+    },
+    #[command(about = "Show changes between the worktree, the index and commits")]
+    Diff {
+        #[arg(
+            long,
+            visible_alias = "staged",
+            help = "Compare the index with HEAD, or the given commit"
+        )]
+        cached: bool,
+        #[arg(
+            short = 'U',
+            long = "unified",
+            help = "Lines of context around changes"
+        )]
+        context: Option<usize>,
+        #[arg(long, help = "Show how many lines changed in each file")]
+        stat: bool,
+        #[arg(long, help = "Show only the names and kinds of the changed files")]
+        name_status: bool,
+        #[arg(long, help = "myers (the default) or histogram")]
+        diff_algorithm: Option<String>,
+        #[arg(long, help = "Use the histogram diff algorithm")]
+        histogram: bool,
+        #[arg(long, help = "Don't pair deleted and added files as renames")]
+        no_renames: bool,
+        #[arg(
+            help = "A commit to compare with the worktree, or two commits or trees (A B or A..B)"
+        )]
+        revisions: Vec<String>,
+    },
+    #[command(about = "Show commits with their changes, tags, trees and blobs")]
+    Show {
+        #[arg(
+            short = 'U',
+            long = "unified",
+            help = "Lines of context around changes"
+        )]
+        context: Option<usize>,
+        #[arg(long, help = "Don't pair deleted and added files as renames")]
+        no_renames: bool,
+        #[arg(default_value = "HEAD", help = "The objects to show")]
+        revisions: Vec<String>,
     },
     #[command(about = "Preety-print the tree object")]
     LsTree {
@@ -42,38 +136,1072 @@ enum Commands {
     },
     #[command(about = "Checkout the specific version from the git history to the given path")]
     Checkout {
-        #[arg(help = "The commit or tree to checkout")]
+        #[arg(help = "The branch, commit or tree to checkout")]
         commit: String,
-        #[arg(help = "The path where to store those files")]
-        path: String,
+        #[arg(help = "The empty directory where to write the files, instead of switching to it")]
+        path: Option<String>,
+    },
+    #[command(about = "Resolve revisions to object ids")]
+    RevParse {
+        #[arg(long, help = "Require exactly one revision that can be resolved")]
+        verify: bool,
+        #[arg(required = true, help = "Revisions such as HEAD~2, v1.0^{tree}, A..B")]
+        revs: Vec<String>,
+    },
+    #[command(about = "Add file contents to the index")]
+    Add {
+        #[arg(required = true, help = "Files or directories to stage")]
+        paths: Vec<String>,
+    },
+    #[command(about = "Show which paths are excluded by the ignore rules")]
+    CheckIgnore {
+        #[arg(short, long, help = "Show the pattern deciding each path")]
+        verbose: bool,
+        #[arg(short, long, help = "With --verbose, also show paths that don't match")]
+        non_matching: bool,
+        #[arg(required = true, help = "Paths to check")]
+        paths: Vec<String>,
+    },
+    #[command(about = "Record the staged changes in a new commit")]
+    Commit {
+        #[arg(
+            short,
+            long = "message",
+            required = true,
+            help = "Commit message, paragraphs are joined"
+        )]
+        messages: Vec<String>,
+        #[arg(long, help = "Allow a commit that doesn't change the tree")]
+        allow_empty: bool,
+    },
+    #[command(about = "Show the files in the staging area")]
+    LsFiles {
+        #[arg(short, long, help = "Show mode, object name and stage of each file")]
+        stage: bool,
+    },
+    #[command(about = "Remove files from the working tree and from the index")]
+    Rm {
+        #[arg(long, help = "Only remove from the index, keep the working tree file")]
+        cached: bool,
+        #[arg(short, help = "Allow recursive removal when a directory is given")]
+        recursive: bool,
+        #[arg(
+            short,
+            long,
+            help = "Remove files even if they have local modifications"
+        )]
+        force: bool,
+        #[arg(required = true, help = "Files to remove")]
+        paths: Vec<String>,
+    },
+    #[command(about = "List references and the objects they point to")]
+    ShowRef(ShowRefArgs),
+    #[command(about = "Show the working tree status")]
+    Status {
+        #[arg(
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "v1",
+            value_parser = ["v1", "v2"],
+            help = "Machine readable output"
+        )]
+        porcelain: Option<String>,
+        #[arg(short, long, help = "Show the branch in porcelain output")]
+        branch: bool,
+    },
+    #[command(about = "List, create or delete tags")]
+    Tag {
+        #[arg(short, help = "Make an annotated tag (implied by -m)")]
+        annotate: bool,
+        #[arg(short, long = "message", help = "Tag message, paragraphs are joined")]
+        messages: Vec<String>,
+        #[arg(short, long, requires = "name", help = "Delete the tag")]
+        delete: bool,
+        #[arg(short, long, help = "Replace an existing tag")]
+        force: bool,
+        #[arg(help = "Name of the tag, lists the tags when missing")]
+        name: Option<String>,
+        #[arg(default_value = "HEAD", help = "The object the tag points to")]
+        object: String,
     },
-    // Add,
-    // CheckIgnore,
-    // Commit,
-    // LsFiles,
-    // RevParse,
-    // Rm,
-    // ShowRef,
-    // Status,
-    // Tag,
+    #[command(about = "List, create, rename or delete branches")]
+    Branch {
+        #[arg(
+            short,
+            long,
+            requires = "name",
+            help = "Delete a branch merged into HEAD"
+        )]
+        delete: bool,
+        #[arg(
+            short = 'D',
+            requires = "name",
+            help = "Delete a branch, even if not merged"
+        )]
+        force_delete: bool,
+        #[arg(
+            short = 'm',
+            long = "move",
+            requires = "name",
+            help = "Rename a branch, or the current one when only the new name is given"
+        )]
+        rename: bool,
+        #[arg(
+            short = 'M',
+            requires = "name",
+            help = "Rename a branch, replacing an existing one"
+        )]
+        force_rename: bool,
+        #[arg(short, long, help = "Replace an existing branch")]
+        force: bool,
+        #[arg(short, long, help = "Show the sha and subject of each branch")]
+        verbose: bool,
+        #[arg(help = "Name of the branch, lists the branches when missing")]
+        name: Option<String>,
+        #[arg(help = "The commit the branch starts at (HEAD), or its new name when renaming")]
+        start: Option<String>,
+    },
+    #[command(about = "Switch to a branch, updating the worktree and the index")]
+    Switch {
+        #[arg(short, long, help = "Create the branch first")]
+        create: bool,
+        #[arg(long, help = "Detach HEAD at a commit instead")]
+        detach: bool,
+        #[arg(help = "The branch, or the commit with --detach")]
+        branch: String,
+        #[arg(help = "The commit a created branch starts at (HEAD)")]
+        start: Option<String>,
+    },
+    #[command(about = "Pack the reachable loose objects")]
+    Repack {
+        #[arg(short, help = "Pack every reachable object in a single pack")]
+        all: bool,
+        #[arg(
+            long,
+            default_value_t = 10,
+            help = "Number of objects tried as delta base"
+        )]
+        window: usize,
+        #[arg(long, default_value_t = 50, help = "Maximum length of delta chains")]
+        depth: usize,
+    },
+    #[command(about = "Verify the integrity and connectivity of the objects")]
+    Fsck {
+        #[arg(long, help = "Report every unreachable object, not only dangling ones")]
+        unreachable: bool,
+    },
+    #[command(about = "Pack refs and objects, and prune unreachable objects")]
+    Gc {
+        #[arg(
+            long,
+            help = "Prune the unreachable objects older than this (default 2.weeks.ago)"
+        )]
+        prune: Option<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct ShowRefArgs {
+    #[arg(long, help = "Also show HEAD")]
+    head: bool,
+    #[arg(long, help = "Only show branches")]
+    heads: bool,
+    #[arg(long, help = "Only show tags")]
+    tags: bool,
+    #[arg(short, long, help = "Also show the object annotated tags point to")]
+    dereference: bool,
+    #[arg(short = 's', long, help = "Only show the object names")]
+    hash: bool,
+    #[arg(long, help = "Patterns must be full ref names")]
+    verify: bool,
+    #[arg(short, long, help = "Don't print anything, only set the exit code")]
+    quiet: bool,
+    #[arg(help = "Only show refs whose name ends with one of these")]
+    patterns: Vec<String>,
 }
 
 fn main() {
     let x = Cli::parse();
     let result = match x.command {
-        Commands::Init { path } => oz::cmd_init(path),
-        Commands::CatFile { obj_type, object } => oz::cmd_cat_file(obj_type, object),
+        Commands::Init {
+            object_format,
+            path,
+        } => cmd_init(object_format, path),
+        Commands::CatFile { obj_type, object } => cmd_cat_file(obj_type, object),
         Commands::HashObject {
             write,
             file_type,
             file_path,
-        } => oz::cmd_hash_object(write, file_type, file_path),
-        Commands::Log { commit } => oz::cmd_log(commit),
-        Commands::LsTree { recursive, tree } => oz::cmd_list_tree(recursive, tree),
-        Commands::Checkout { commit, path } => oz::cmd_checkout(commit, path),
+        } => cmd_hash_object(write, file_type, file_path),
+        Commands::Log {
+            oneline,
+            format,
+            graph,
+            graphviz,
+            max_count,
+            since,
+            until,
+            author,
+            grep,
+            topo_order,
+            date_order,
+            reverse,
+            first_parent,
+            revisions,
+            paths,
+        } => {
+            let format = match (format, oneline) {
+                (Some(format), _) => LogFormat::Format(format),
+                (None, true) => LogFormat::Oneline,
+                (None, false) => LogFormat::Medium,
+            };
+            // Like git, the graph needs children before their parents
+            let order = match (date_order, topo_order || graph) {
+                (true, _) => RevOrder::Date,
+                (false, true) => RevOrder::Topo,
+                (false, false) => RevOrder::Walk,
+            };
+            let options = LogOptions {
+                max_count,
+                order,
+                reverse,
+                first_parent,
+                rewrite_parents: graph || graphviz,
+                author,
+                grep,
+                paths,
+                ..Default::default()
+            };
+            cmd_log(revisions, options, since, until, format, graph, graphviz)
+        }
+        Commands::MergeBase { all, one, two } => cmd_merge_base(all, one, two),
+        Commands::Diff {
+            cached,
+            context,
+            stat,
+            name_status,
+            diff_algorithm,
+            histogram,
+            no_renames,
+            revisions,
+        } => cmd_diff(
+            cached,
+            context,
+            stat,
+            name_status,
+            diff_algorithm.or(histogram.then(|| "histogram".into())),
+            no_renames,
+            revisions,
+        ),
+        Commands::Show {
+            context,
+            no_renames,
+            revisions,
+        } => cmd_show(context, no_renames, revisions),
+        Commands::LsTree { recursive, tree } => cmd_list_tree(recursive, tree),
+        Commands::Checkout { commit, path } => cmd_checkout(commit, path),
+        Commands::RevParse { verify, revs } => cmd_rev_parse(verify, revs),
+        Commands::LsFiles { stage } => cmd_ls_files(stage),
+        Commands::Add { paths } => cmd_add(paths),
+        Commands::Status { porcelain, branch } => cmd_status(porcelain, branch),
+        Commands::Tag {
+            annotate,
+            messages,
+            delete,
+            force,
+            name,
+            object,
+        } => cmd_tag(name, object, annotate, messages, delete, force),
+        Commands::CheckIgnore {
+            verbose,
+            non_matching,
+            paths,
+        } => cmd_check_ignore(verbose, non_matching, paths).map(|found| {
+            if !found {
+                std::process::exit(1);
+            }
+        }),
+        Commands::ShowRef(args) => cmd_show_ref(args).map(|found| {
+            if !found {
+                std::process::exit(1);
+            }
+        }),
+        Commands::Commit {
+            messages,
+            allow_empty,
+        } => cmd_commit(messages, allow_empty),
+        Commands::Rm {
+            cached,
+            recursive,
+            force,
+            paths,
+        } => cmd_rm(paths, cached, recursive, force),
+        Commands::Branch {
+            delete,
+            force_delete,
+            rename,
+            force_rename,
+            force,
+            verbose,
+            name,
+            start,
+        } => cmd_branch(
+            name,
+            start,
+            delete || force_delete,
+            rename || force_rename,
+            force || force_delete || force_rename,
+            verbose,
+        ),
+        Commands::Switch {
+            create,
+            detach,
+            branch,
+            start,
+        } => cmd_switch(branch, start, create, detach),
+        Commands::Repack { all, window, depth } => cmd_repack(all, window, depth),
+        Commands::Fsck { unreachable } => cmd_fsck(unreachable).map(|healthy| {
+            if !healthy {
+                std::process::exit(1);
+            }
+        }),
+        Commands::Gc { prune } => cmd_gc(prune),
+    };
+    match result {
+        // The reader, like `head`, has seen enough
+        Err(OzError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        Ok(()) => {}
+    }
+}
+
+fn cmd_init(object_format: String, path: String) -> Result<(), OzError> {
+    let format = ObjectFormat::from_name(&object_format).ok_or_else(|| {
+        OzError::InvalidArgument(format!("Unknown object format {object_format}"))
+    })?;
+    GitRepository::init(path, format).map(|_| ())
+}
+
+fn cmd_cat_file(obj_type: String, object: String) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let sha = repo.rev_parse(&object)?;
+    let sha = repo.rev_parse(&format!("{sha}^{{{obj_type}}}"))?;
+    let obj = repo.object_read(&sha)?;
+
+    Ok(std::io::stdout().write_all(&obj.serialize())?)
+}
+
+fn cmd_hash_object(write: bool, obj_type: String, path: String) -> Result<(), OzError> {
+    let data = std::fs::read(path)?;
+    let shasum = if write {
+        GitRepository::discover(".")?.hash_object(&obj_type, data)?
+    } else {
+        // Use the format of the current repository, if any
+        let format =
+            GitRepository::discover(".").map_or(ObjectFormat::Sha1, |repo| repo.object_format());
+        oz::hash_object(&obj_type, data, format)?
     };
-    if let Err(e) = result {
-        eprintln!("Error: {}", e);
+    println!("{}", shasum);
+    Ok(())
+}
+
+fn cmd_log(
+    mut revisions: Vec<String>,
+    mut options: LogOptions,
+    since: Option<String>,
+    until: Option<String>,
+    format: LogFormat,
+    graph: bool,
+    graphviz: bool,
+) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    options.since = since.as_deref().map(parse_date).transpose()?;
+    options.until = until.as_deref().map(parse_date).transpose()?;
+    if revisions.is_empty() {
+        revisions.push("HEAD".to_string());
+    }
+    let entries = repo.log(&revisions, &options)?;
+    let mut out = std::io::stdout().lock();
+
+    if graphviz {
+        writeln!(out, "digraph ozlog{{")?;
+        writeln!(out, "node[shape=rect]")?;
+        for entry in entries {
+            let sha = entry.id;
+            let msg = entry.commit.message().replace("\\", "\\\\");
+            let msg = msg.replace("\"", "\\\"");
+
+            // Only 1st line when there are multiple lines in the commit
+            let msg = msg.split_once("\n").unwrap_or(("default", "")).0;
+
+            writeln!(out, "  c_{} [label=\"{} : {}\"]", sha, sha.abbrev(7), &msg)?;
+            for parent in entry.parents {
+                writeln!(out, "  c_{sha} -> c_{parent};")?;
+            }
+        }
+        writeln!(out, "}}")?;
+        return Ok(());
+    }
+
+    // Commits shown on several lines are separated by a blank line
+    let separate = matches!(format, LogFormat::Medium);
+    let mut drawing = Graph::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let text = format.render(&entry.id, &entry.commit);
+        let lines: Vec<&str> = text.split('\n').collect();
+        if graph {
+            if separate && i > 0 {
+                writeln!(out, "{}", drawing.separator(&entry.id, &entry.parents))?;
+            }
+            for line in drawing.draw(&entry.id, &entry.parents, &lines) {
+                writeln!(out, "{line}")?;
+            }
+        } else {
+            if separate && i > 0 {
+                writeln!(out)?;
+            }
+            writeln!(out, "{text}")?;
+        }
+    }
+    Ok(())
+}
+
+fn cmd_merge_base(all: bool, one: String, two: String) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let one = repo.rev_parse(&format!("{one}^{{commit}}"))?;
+    let two = repo.rev_parse(&format!("{two}^{{commit}}"))?;
+    let bases = repo.merge_bases(&one, &two)?;
+    if bases.is_empty() {
         std::process::exit(1);
     }
+    for base in bases.iter().take(if all { bases.len() } else { 1 }) {
+        println!("{base}");
+    }
+    Ok(())
+}
+
+fn cmd_diff(
+    cached: bool,
+    context: Option<usize>,
+    stat: bool,
+    name_status: bool,
+    algorithm: Option<String>,
+    no_renames: bool,
+    revisions: Vec<String>,
+) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let options = DiffOptions {
+        context: context.unwrap_or(3),
+        algorithm: diff_algorithm(&repo, algorithm)?,
+        renames: !no_renames,
+    };
+
+    let range = match revisions.as_slice() {
+        [rev] => rev.split_once(".."),
+        _ => None,
+    };
+    let (old, new) = match (revisions.as_slice(), range) {
+        ([], _) if cached => (DiffSource::Rev("HEAD"), DiffSource::Index),
+        ([], _) => (DiffSource::Index, DiffSource::Worktree),
+        (_, Some((old, new))) => (
+            DiffSource::Rev(if old.is_empty() { "HEAD" } else { old }),
+            DiffSource::Rev(if new.is_empty() { "HEAD" } else { new }),
+        ),
+        ([rev], None) if cached => (DiffSource::Rev(rev), DiffSource::Index),
+        ([rev], None) => (DiffSource::Rev(rev), DiffSource::Worktree),
+        ([old, new], _) => (DiffSource::Rev(old), DiffSource::Rev(new)),
+        _ => {
+            return Err(OzError::InvalidArgument(
+                "Too many revisions to compare".into(),
+            ));
+        }
+    };
+    let diff = repo.diff(old, new, &options)?;
+
+    let mut out = std::io::stdout().lock();
+    if stat {
+        if !diff.files.is_empty() {
+            out.write_all(diff.stat()?.as_bytes())?;
+        }
+    } else if name_status {
+        out.write_all(diff.name_status().as_bytes())?;
+    } else {
+        out.write_all(&diff.patch()?)?;
+    }
+    Ok(())
+}
+
+/// The algorithm `name`, or else the one of `diff.algorithm`
+fn diff_algorithm(repo: &GitRepository, name: Option<String>) -> Result<DiffAlgorithm, OzError> {
+    match name.or_else(|| repo.config_get("diff", "algorithm")) {
+        Some(name) => DiffAlgorithm::from_name(&name)
+            .ok_or_else(|| OzError::InvalidArgument(format!("Unknown diff algorithm '{name}'"))),
+        None => Ok(DiffAlgorithm::default()),
+    }
+}
+
+fn cmd_show(
+    context: Option<usize>,
+    no_renames: bool,
+    revisions: Vec<String>,
+) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let options = DiffOptions {
+        context: context.unwrap_or(3),
+        algorithm: diff_algorithm(&repo, None)?,
+        renames: !no_renames,
+    };
+    std::io::stdout().write_all(&repo.show(&revisions, &options)?)?;
+    Ok(())
+}
+
+fn cmd_list_tree(recursive: bool, tree: String) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    ls_tree(&repo, tree, recursive, "")
+}
+
+fn ls_tree(
+    repo: &GitRepository,
+    tree: String,
+    recursive: bool,
+    prefix: &str,
+) -> Result<(), OzError> {
+    let sha = repo.rev_parse(&format!("{tree}^{{tree}}"))?;
+    let obj = repo.object_read(&sha)?;
+
+    if let GitObject::Tree(obj) = obj {
+        for entry in obj.entries() {
+            let path =
+                prefix.to_string() + if prefix.is_empty() { "" } else { "/" } + &entry.name_lossy();
+            if !recursive || !entry.mode.is_tree() {
+                println!(
+                    "{} {} {}\t{}",
+                    entry.mode,
+                    entry.mode.obj_type(),
+                    entry.id,
+                    path
+                );
+            } else {
+                ls_tree(repo, entry.id.to_string(), recursive, &path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn cmd_checkout(commit: String, path: Option<String>) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    match path {
+        Some(path) => repo.checkout_into(&commit, path),
+        None => {
+            let is_branch = repo
+                .ref_resolve(&format!("refs/heads/{commit}"))
+                .is_ok_and(|sha| sha.is_some());
+            cmd_switch(commit, None, false, !is_branch)
+        }
+    }
+}
+
+fn cmd_rev_parse(verify: bool, revs: Vec<String>) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let single = || OzError::InvalidArgument("Needed a single revision".to_string());
+    if verify && revs.len() != 1 {
+        Err(single())?;
+    }
+
+    for rev in revs {
+        if rev.contains("...") && !rev.contains(':') {
+            Err(OzError::InvalidArgument(format!(
+                "{rev}: symmetric differences are not supported"
+            )))?;
+        }
+        let range = if rev.contains(':') {
+            None
+        } else {
+            rev.split_once("..")
+        };
+        if let Some((from, to)) = range {
+            if verify {
+                Err(single())?;
+            }
+            let from = if from.is_empty() { "HEAD" } else { from };
+            let to = if to.is_empty() { "HEAD" } else { to };
+            println!("{}", repo.rev_parse(to)?);
+            println!("^{}", repo.rev_parse(from)?);
+        } else if let Some(rev) = rev.strip_prefix('^') {
+            println!("^{}", repo.rev_parse(rev)?);
+        } else {
+            println!("{}", repo.rev_parse(&rev)?);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_ls_files(stage: bool) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    for entry in repo.index_read()?.entries {
+        if stage {
+            println!(
+                "{:06o} {} {}\t{}",
+                entry.mode,
+                entry.sha,
+                entry.stage,
+                entry.name_lossy()
+            );
+        } else {
+            println!("{}", entry.name_lossy());
+        }
+    }
+    Ok(())
+}
+
+fn cmd_add(paths: Vec<String>) -> Result<(), OzError> {
+    GitRepository::discover(".")?.add(paths)
+}
+
+fn cmd_rm(paths: Vec<String>, cached: bool, recursive: bool, force: bool) -> Result<(), OzError> {
+    for name in GitRepository::discover(".")?.rm(paths, cached, recursive, force)? {
+        println!("rm '{name}'");
+    }
+    Ok(())
+}
+
+fn cmd_commit(messages: Vec<String>, allow_empty: bool) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let sha = repo.commit(messages, allow_empty)?;
+    let commit = repo.commit_read(&sha)?;
+    let branch = repo
+        .current_branch()?
+        .unwrap_or("detached HEAD".to_string());
+
+    println!(
+        "[{branch}{} {}] {}",
+        if commit.parents().is_empty() {
+            " (root-commit)"
+        } else {
+            ""
+        },
+        sha.abbrev(7),
+        commit.message().lines().next().unwrap_or_default()
+    );
+    Ok(())
+}
+
+fn cmd_status(porcelain: Option<String>, branch: bool) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    let status = repo.status()?;
+    let head = status.head;
+
+    match porcelain.as_deref() {
+        None => {
+            match (&status.branch, head) {
+                (Some(branch), _) => println!("On branch {branch}"),
+                (None, Some(head)) => println!("HEAD detached at {}", head.abbrev(7)),
+                (None, None) => {}
+            }
+            if head.is_none() {
+                println!("\nNo commits yet");
+            }
+            let unmerged: Vec<_> = status.entries.iter().filter(|e| e.is_unmerged()).collect();
+            print_status_section("Unmerged paths", &unmerged, 17, |e| Some(unmerged_label(e)));
+            let staged: Vec<_> = status
+                .entries
+                .iter()
+                .filter(|e| e.staged.is_some())
+                .collect();
+            print_status_section("Changes to be committed", &staged, 12, |e| {
+                e.staged.map(change_label)
+            });
+            let unstaged: Vec<_> = status
+                .entries
+                .iter()
+                .filter(|e| e.unstaged.is_some())
+                .collect();
+            print_status_section("Changes not staged for commit", &unstaged, 12, |e| {
+                e.unstaged.map(change_label)
+            });
+            if !status.untracked.is_empty() {
+                println!("\nUntracked files:");
+                for path in &status.untracked {
+                    println!("\t{path}");
+                }
+            }
+            if status.entries.is_empty() && status.untracked.is_empty() {
+                println!("nothing to commit, working tree clean");
+            }
+        }
+        Some("v1") => {
+            if branch {
+                println!(
+                    "## {}",
+                    status.branch.as_deref().unwrap_or("HEAD (no branch)")
+                );
+            }
+            for entry in &status.entries {
+                let code = if entry.is_unmerged() {
+                    unmerged_code(entry).to_string()
+                } else {
+                    [entry.staged, entry.unstaged]
+                        .map(|change| Change::code(change).to_string().replace('.', " "))
+                        .concat()
+                };
+                println!("{code} {}", entry.path);
+            }
+            for path in &status.untracked {
+                println!("?? {path}");
+            }
+        }
+        Some("v2") => {
+            let zero = ObjectId::null(repo.object_format());
+            if branch {
+                match head {
+                    Some(head) => println!("# branch.oid {head}"),
+                    None => println!("# branch.oid (initial)"),
+                }
+                println!(
+                    "# branch.head {}",
+                    status.branch.as_deref().unwrap_or("(detached)")
+                );
+            }
+            let mode = |entry: Option<&(u32, ObjectId)>| entry.map_or(0, |entry| entry.0);
+            let sha = |entry: Option<&(u32, ObjectId)>| entry.map_or(zero, |entry| entry.1);
+            for entry in &status.entries {
+                let worktree = entry.worktree_mode.unwrap_or(0);
+                if entry.is_unmerged() {
+                    let [_, one, two, three] = &entry.stages;
+                    println!(
+                        "u {} N... {:06o} {:06o} {:06o} {worktree:06o} {} {} {} {}",
+                        unmerged_code(entry),
+                        mode(one.as_ref()),
+                        mode(two.as_ref()),
+                        mode(three.as_ref()),
+                        sha(one.as_ref()),
+                        sha(two.as_ref()),
+                        sha(three.as_ref()),
+                        entry.path
+                    );
+                } else {
+                    println!(
+                        "1 {}{} N... {:06o} {:06o} {worktree:06o} {} {} {}",
+                        Change::code(entry.staged),
+                        Change::code(entry.unstaged),
+                        mode(entry.head.as_ref()),
+                        mode(entry.stages[0].as_ref()),
+                        sha(entry.head.as_ref()),
+                        sha(entry.stages[0].as_ref()),
+                        entry.path
+                    );
+                }
+            }
+            for path in &status.untracked {
+                println!("? {path}");
+            }
+        }
+        Some(version) => Err(OzError::InvalidArgument(format!(
+            "Unsupported porcelain format: {version}"
+        )))?,
+    }
+    Ok(())
+}
+
+fn print_status_section(
+    title: &str,
+    entries: &[&StatusEntry],
+    width: usize,
+    label: impl Fn(&StatusEntry) -> Option<&'static str>,
+) {
+    if entries.is_empty() {
+        return;
+    }
+    println!("\n{title}:");
+    for entry in entries {
+        if let Some(label) = label(entry) {
+            println!("\t{:<width$}{}", format!("{label}:"), entry.path);
+        }
+    }
+}
+
+fn change_label(change: Change) -> &'static str {
+    match change {
+        Change::Added => "new file",
+        Change::Modified => "modified",
+        Change::Deleted => "deleted",
+        Change::TypeChanged => "typechange",
+        Change::Renamed => "renamed",
+    }
+}
+
+/// Which sides of the merge touched an unmerged path, as `git status` shows it
+fn unmerged_code(entry: &StatusEntry) -> &'static str {
+    match [1, 2, 3].map(|stage| entry.stages[stage].is_some()) {
+        [true, true, true] => "UU",
+        [false, true, true] => "AA",
+        [true, true, false] => "UD",
+        [true, false, true] => "DU",
+        [false, true, false] => "AU",
+        [false, false, true] => "UA",
+        _ => "DD",
+    }
+}
+
+fn unmerged_label(entry: &StatusEntry) -> &'static str {
+    match unmerged_code(entry) {
+        "UU" => "both modified",
+        "AA" => "both added",
+        "UD" => "deleted by them",
+        "DU" => "deleted by us",
+        "AU" => "added by us",
+        "UA" => "added by them",
+        _ => "both deleted",
+    }
+}
+
+/// Returns whether any of the paths is ignored.
+fn cmd_check_ignore(
+    verbose: bool,
+    non_matching: bool,
+    paths: Vec<String>,
+) -> Result<bool, OzError> {
+    let repo = GitRepository::discover(".")?;
+    let mut found = false;
+    for (path, pattern) in repo.check_ignore(&paths)? {
+        match pattern {
+            Some(pattern) if verbose => {
+                println!(
+                    "{}:{}:{}\t{path}",
+                    pattern.source, pattern.line, pattern.text
+                );
+                found |= !pattern.negated;
+            }
+            Some(pattern) if !pattern.negated => {
+                println!("{path}");
+                found = true;
+            }
+            _ if verbose && non_matching => println!("::\t{path}"),
+            _ => {}
+        }
+    }
+    Ok(found)
+}
+
+fn cmd_tag(
+    name: Option<String>,
+    object: String,
+    annotate: bool,
+    messages: Vec<String>,
+    delete: bool,
+    force: bool,
+) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    match name {
+        None => {
+            for tag in repo.tags()? {
+                println!("{tag}");
+            }
+        }
+        Some(name) if delete => {
+            let sha = repo.tag_delete(&name)?;
+            println!("Deleted tag '{name}' (was {})", sha.abbrev(7));
+        }
+        Some(name) => {
+            if annotate && messages.is_empty() {
+                Err(OzError::InvalidArgument(
+                    "An annotated tag needs a message (-m)".to_string(),
+                ))?;
+            }
+            repo.tag_create(&name, &object, messages, force)?;
+        }
+    }
+    Ok(())
+}
+
+fn cmd_branch(
+    name: Option<String>,
+    start: Option<String>,
+    delete: bool,
+    rename: bool,
+    force: bool,
+    verbose: bool,
+) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    match name {
+        None => {
+            let current = repo.current_branch()?;
+            let mut lines = Vec::new();
+            if current.is_none()
+                && let Some(head) = repo.ref_resolve("HEAD")?
+            {
+                lines.push((true, format!("(HEAD detached at {})", head.abbrev(7)), head));
+            }
+            for (name, sha) in repo.branches()? {
+                lines.push((current.as_ref() == Some(&name), name, sha));
+            }
+            let width = lines
+                .iter()
+                .map(|(_, name, _)| name.len())
+                .max()
+                .unwrap_or(0);
+            for (is_current, name, sha) in lines {
+                let marker = if is_current { '*' } else { ' ' };
+                if verbose {
+                    let commit = match repo.object_read(&sha)? {
+                        GitObject::Commit(commit) => commit,
+                        object => Err(OzError::WrongObjectType {
+                            sha,
+                            expected: "commit",
+                            actual: object.obj_type(),
+                        })?,
+                    };
+                    let line = LogFormat::Oneline.render(&sha, &commit);
+                    println!("{marker} {name:<width$} {line}");
+                } else {
+                    println!("{marker} {name}");
+                }
+            }
+        }
+        Some(name) if delete => {
+            let sha = repo.branch_delete(&name, force)?;
+            println!("Deleted branch {name} (was {}).", sha.abbrev(7));
+        }
+        Some(name) if rename => {
+            let (old, new) = match start {
+                Some(new) => (name, new),
+                None => {
+                    let current = repo.current_branch()?.ok_or_else(|| {
+                        OzError::Refused("HEAD is not on a branch to rename".to_string())
+                    })?;
+                    (current, name)
+                }
+            };
+            repo.branch_rename(&old, &new, force)?;
+        }
+        Some(name) => {
+            repo.branch_create(&name, start.as_deref().unwrap_or("HEAD"), force)?;
+        }
+    }
+    Ok(())
+}
+
+fn cmd_switch(
+    branch: String,
+    start: Option<String>,
+    create: bool,
+    detach: bool,
+) -> Result<(), OzError> {
+    let repo = GitRepository::discover(".")?;
+    if detach {
+        let sha = repo.switch_detach(&branch)?;
+        let line = match repo.object_read(&sha)? {
+            GitObject::Commit(commit) => LogFormat::Oneline.render(&sha, &commit),
+            _ => sha.abbrev(7),
+        };
+        println!("HEAD is now at {line}");
+    } else if create {
+        repo.switch(&branch, Some(start.as_deref().unwrap_or("HEAD")))?;
+        println!("Switched to a new branch '{branch}'");
+    } else if start.is_some() {
+        Err(OzError::InvalidArgument(
+            "A start point needs -c to create the branch".to_string(),
+        ))?;
+    } else if repo.current_branch()?.as_ref() == Some(&branch) {
+        println!("Already on '{branch}'");
+    } else {
+        repo.switch(&branch, None)?;
+        println!("Switched to branch '{branch}'");
+    }
+    Ok(())
+}
+
+fn cmd_show_ref(args: ShowRefArgs) -> Result<bool, OzError> {
+    let ShowRefArgs {
+        head,
+        heads,
+        tags,
+        dereference,
+        hash,
+        verify,
+        quiet,
+        patterns,
+    } = args;
+    let repo = GitRepository::discover(".")?;
+    let mut shown = Vec::new();
+    if verify {
+        for pattern in &patterns {
+            let sha = if pattern == "HEAD" || pattern.starts_with("refs/") {
+                repo.ref_resolve(pattern)?
+            } else {
+                None
+            };
+            match sha {
+                Some(sha) => shown.push((pattern.clone(), sha)),
+                None if quiet => return Ok(false),
+                None => Err(OzError::NotFound(format!("'{pattern}' - not a valid ref")))?,
+            }
+        }
+    } else {
+        if head && let Some(sha) = repo.ref_resolve("HEAD")? {
+            shown.push(("HEAD".to_string(), sha));
+        }
+        for (name, sha) in repo.refs("refs/")? {
+            let selected = (!heads && !tags)
+                || (heads && name.starts_with("refs/heads/"))
+                || (tags && name.starts_with("refs/tags/"));
+            let matched = patterns.is_empty()
+                || patterns
+                    .iter()
+                    .any(|pattern| name == *pattern || name.ends_with(&format!("/{pattern}")));
+            if selected && matched {
+                shown.push((name, sha));
+            }
+        }
+    }
+
+    if !quiet {
+        for (name, sha) in &shown {
+            if hash {
+                println!("{sha}");
+            } else {
+                println!("{sha} {name}");
+            }
+            if dereference && let Some(peeled) = repo.ref_peel(name, sha)? {
+                if hash {
+                    println!("{peeled}");
+                } else {
+                    println!("{peeled} {name}^{{}}");
+                }
+            }
+        }
+    }
+    Ok(!shown.is_empty())
+}
+
+fn cmd_repack(all: bool, window: usize, depth: usize) -> Result<(), OzError> {
+    match GitRepository::discover(".")?.repack(all, window, depth)? {
+        Some((name, count, deltas)) => {
+            println!("Packed {count} objects ({deltas} deltas) into {name}.pack")
+        }
+        None => println!("Nothing new to pack."),
+    }
+    Ok(())
+}
+
+/// Prints the problems found in the repository, returns whether it is healthy
+fn cmd_fsck(unreachable: bool) -> Result<bool, OzError> {
+    let report = GitRepository::discover(".")?.fsck(unreachable)?;
+    for issue in &report.issues {
+        println!("{issue}");
+    }
+    Ok(!report.has_errors())
+}
+
+fn cmd_gc(prune: Option<String>) -> Result<(), OzError> {
+    let pruned = GitRepository::discover(".")?.gc(prune.as_deref())?;
+    if pruned > 0 {
+        println!("Pruned {pruned} unreachable objects");
+    }
+    Ok(())
 }
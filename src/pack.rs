@@ -0,0 +1,803 @@
+use crate::{
+    error::OzError,
+    hash::{ObjectFormat, ObjectId, hex},
+};
+use flate2::{Compression, Crc, read::ZlibDecoder, write::ZlibEncoder};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{Read, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+/// Objects stored in a pack are either whole, or a delta against another
+/// object of the pack (by offset) or of the repository (by sha)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PackEntry {
+    Whole(&'static str),
+    OfsDelta(u64),
+    RefDelta(ObjectId),
+}
+
+/// A `.pack` file and its `.idx` (version 2)
+#[derive(Debug)]
+pub(crate) struct Pack {
+    pub(crate) path: PathBuf,
+    file: fs::File,
+    index: Vec<u8>,
+    count: usize,
+    /// Names the objects, and checksums the pack and its index
+    format: ObjectFormat,
+}
+
+impl Pack {
+    /// Opens `path` (the `.idx` file) and the `.pack` next to it
+    pub(crate) fn open(path: &Path, format: ObjectFormat) -> Result<Self, OzError> {
+        let index = fs::read(path)?;
+        let raw_len = format.raw_len();
+        if index.len() < 8 + 256 * 4 + 2 * raw_len || index[..4] != *b"\xfftOc" {
+            return Err(OzError::corrupt_file(path, "not a version 2 pack index"));
+        }
+        if u32::from_be_bytes(index[4..8].try_into().unwrap()) != 2 {
+            return Err(OzError::corrupt_file(
+                path,
+                "unsupported pack index version",
+            ));
+        }
+        let count = u32::from_be_bytes(index[8 + 255 * 4..8 + 256 * 4].try_into().unwrap());
+        let count = count as usize;
+        if index.len() < 8 + 256 * 4 + count * (raw_len + 8) + 2 * raw_len {
+            return Err(OzError::corrupt_file(path, "truncated pack index"));
+        }
+        // Lookups rely on the fanout being sorted, and on the large offsets
+        // being in their table
+        let read_u32 = |pos: usize| u32::from_be_bytes(index[pos..pos + 4].try_into().unwrap());
+        if (1..256).any(|i| read_u32(4 + i * 4) > read_u32(8 + i * 4)) {
+            return Err(OzError::corrupt_file(path, "unsorted pack index fanout"));
+        }
+        let offsets = 8 + 256 * 4 + count * (raw_len + 4);
+        let large = (index.len() - 2 * raw_len - offsets - count * 4) / 8;
+        let out_of_range = (0..count).any(|i| {
+            let offset = read_u32(offsets + i * 4);
+            offset & 0x8000_0000 != 0 && (offset & 0x7fff_ffff) as usize >= large
+        });
+        if out_of_range {
+            return Err(OzError::corrupt_file(
+                path,
+                "pack index offset out of range",
+            ));
+        }
+
+        let pack_path = path.with_extension("pack");
+        let file = fs::File::open(&pack_path)?;
+        let mut header = [0; 12];
+        if file.read_exact_at(&mut header, 0).is_err() {
+            return Err(OzError::corrupt_file(pack_path, "truncated pack"));
+        }
+        let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        if header[..4] != *b"PACK" || !(2..=3).contains(&version) {
+            return Err(OzError::corrupt_file(pack_path, "not a pack file"));
+        }
+        if u32::from_be_bytes(header[8..12].try_into().unwrap()) as usize != count {
+            return Err(OzError::corrupt_file(pack_path, "doesn't match its index"));
+        }
+
+        Ok(Pack {
+            path: pack_path,
+            file,
+            index,
+            count,
+            format,
+        })
+    }
+
+    /// Lists the packs of `objects/pack`, skipping those that can't be read.
+    pub(crate) fn open_all(dir: &Path, format: ObjectFormat) -> Vec<Self> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Vec::new();
+        };
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "idx"))
+            .collect();
+        paths.sort();
+        paths
+            .iter()
+            .filter_map(|path| Pack::open(path, format).ok())
+            .collect()
+    }
+
+    /// Checks the trailing checksums of the pack and of its index
+    pub(crate) fn verify(&self) -> Result<(), OzError> {
+        let raw_len = self.format.raw_len();
+        let data = fs::read(&self.path)?;
+        let (content, checksum) = data.split_at(data.len().saturating_sub(raw_len));
+        if self.format.digest(content) != checksum {
+            return Err(OzError::corrupt_file(&self.path, "pack checksum mismatch"));
+        }
+        let (index, index_checksum) = self.index.split_at(self.index.len() - raw_len);
+        if self.format.digest(index) != index_checksum {
+            return Err(OzError::corrupt_file(&self.path, "index checksum mismatch"));
+        }
+        if index[index.len() - raw_len..] != *checksum {
+            return Err(OzError::corrupt_file(
+                &self.path,
+                "index doesn't belong to this pack",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Number of objects in the pack
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    fn sha_at(&self, i: usize) -> &[u8] {
+        let raw_len = self.format.raw_len();
+        let start = 8 + 256 * 4 + i * raw_len;
+        &self.index[start..start + raw_len]
+    }
+
+    /// The id of the `i`th object, in id order
+    pub(crate) fn sha(&self, i: usize) -> ObjectId {
+        ObjectId::from_raw(self.sha_at(i)).unwrap()
+    }
+
+    /// Offset in the pack of the `i`th object
+    pub(crate) fn offset(&self, i: usize) -> u64 {
+        let offsets = 8 + 256 * 4 + self.count * (self.format.raw_len() + 4);
+        let start = offsets + i * 4;
+        let offset = u32::from_be_bytes(self.index[start..start + 4].try_into().unwrap());
+        if offset & 0x8000_0000 == 0 {
+            return offset as u64;
+        }
+        // Offsets past 2GiB are in a table of 64 bits offsets
+        let start = offsets + self.count * 4 + (offset & 0x7fff_ffff) as usize * 8;
+        u64::from_be_bytes(self.index[start..start + 8].try_into().unwrap())
+    }
+
+    /// Range of the objects whose sha starts with `byte`
+    fn fanout(&self, byte: u8) -> (usize, usize) {
+        let entry = |i: usize| {
+            u32::from_be_bytes(self.index[8 + i * 4..12 + i * 4].try_into().unwrap()) as usize
+        };
+        let start = if byte == 0 {
+            0
+        } else {
+            entry(byte as usize - 1)
+        };
+        (start, entry(byte as usize))
+    }
+
+    /// Offset in the pack of the object `sha`
+    pub(crate) fn find(&self, sha: &ObjectId) -> Option<u64> {
+        if sha.format() != self.format {
+            return None;
+        }
+        let sha = sha.as_bytes();
+        let (mut low, mut high) = self.fanout(sha[0]);
+        while low < high {
+            let mid = (low + high) / 2;
+            match self.sha_at(mid).cmp(sha) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return Some(self.offset(mid)),
+            }
+        }
+        None
+    }
+
+    /// The objects whose (lowercase hex) sha starts with `prefix`
+    pub(crate) fn find_prefix(&self, prefix: &str) -> Vec<ObjectId> {
+        let Some(first) = prefix
+            .get(..2)
+            .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        else {
+            return Vec::new();
+        };
+        let (start, end) = self.fanout(first);
+        (start..end)
+            .filter(|&i| hex(self.sha_at(i)).starts_with(prefix))
+            .map(|i| self.sha(i))
+            .collect()
+    }
+
+    /// Reads the entry at `offset`: its kind, and its data (or delta) inflated
+    pub(crate) fn entry(&self, offset: u64) -> Result<(PackEntry, Vec<u8>), OzError> {
+        let corrupt =
+            |reason: &str| OzError::corrupt_file(&self.path, format!("{reason} at {offset}"));
+        let mut reader = PackReader {
+            file: &self.file,
+            offset,
+        };
+        let mut byte = reader.byte()?;
+        let kind = (byte >> 4) & 7;
+        let mut size = (byte & 0x0f) as u64;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            byte = reader.byte()?;
+            size |= shift_exact((byte & 0x7f) as u64, shift)
+                .ok_or_else(|| corrupt("object size overflow"))?;
+            shift += 7;
+        }
+
+        let entry = match kind {
+            1 => PackEntry::Whole("commit"),
+            2 => PackEntry::Whole("tree"),
+            3 => PackEntry::Whole("blob"),
+            4 => PackEntry::Whole("tag"),
+            6 => {
+                // The distance to the base uses a variant of varints where
+                // each continuation adds one, so no encoding is redundant
+                let mut byte = reader.byte()?;
+                let mut distance = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    byte = reader.byte()?;
+                    distance = distance
+                        .checked_add(1)
+                        .and_then(|distance| distance.checked_mul(0x80))
+                        .ok_or_else(|| corrupt("bad delta base offset"))?
+                        | (byte & 0x7f) as u64;
+                }
+                match offset.checked_sub(distance) {
+                    Some(base) if distance > 0 => PackEntry::OfsDelta(base),
+                    _ => return Err(corrupt("bad delta base offset")),
+                }
+            }
+            7 => {
+                let mut sha = vec![0; self.format.raw_len()];
+                reader.read_exact(&mut sha)?;
+                PackEntry::RefDelta(ObjectId::from_raw(&sha).unwrap())
+            }
+            _ => return Err(corrupt(&format!("unknown object type {kind}"))),
+        };
+
+        let mut data = Vec::with_capacity(size.min(PREALLOCATE_MAX) as usize);
+        ZlibDecoder::new(reader)
+            .take(size)
+            .read_to_end(&mut data)
+            .map_err(|_| corrupt("unable to inflate object"))?;
+        if data.len() as u64 != size {
+            return Err(corrupt("truncated object"));
+        }
+        Ok((entry, data))
+    }
+}
+
+/// Sizes come from the file, so buffers only start this big and grow as the
+/// data actually shows up
+const PREALLOCATE_MAX: u64 = 1 << 24;
+
+/// `value << shift`, or `None` when bits would be lost
+fn shift_exact(value: u64, shift: u32) -> Option<u64> {
+    value
+        .checked_shl(shift)
+        .filter(|shifted| shifted >> shift == value)
+}
+
+/// Reads a pack from some offset, without moving a shared cursor
+struct PackReader<'a> {
+    file: &'a fs::File,
+    offset: u64,
+}
+
+impl PackReader<'_> {
+    fn byte(&mut self) -> std::io::Result<u8> {
+        let mut byte = [0];
+        self.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+}
+
+impl Read for PackReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// Rebuilds an object from its `base` and a `delta` against it
+pub(crate) fn delta_apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    fn size(delta: &[u8], pos: &mut usize) -> Result<usize, String> {
+        let mut size = 0;
+        let mut shift = 0;
+        loop {
+            let byte = *delta.get(*pos).ok_or("Truncated delta")?;
+            *pos += 1;
+            size |= shift_exact((byte & 0x7f) as u64, shift)
+                .and_then(|bits| usize::try_from(bits).ok())
+                .ok_or("Delta size overflow")?;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(size);
+            }
+        }
+    }
+
+    let mut pos = 0;
+    if size(delta, &mut pos)? != base.len() {
+        return Err("Delta doesn't apply to its base".to_string());
+    }
+    let result_size = size(delta, &mut pos)?;
+    let mut result = Vec::with_capacity(result_size.min(PREALLOCATE_MAX as usize));
+    while pos < delta.len() {
+        let cmd = delta[pos];
+        pos += 1;
+        if cmd & 0x80 != 0 {
+            // Copy from the base: bits 0-3 say which offset bytes follow,
+            // bits 4-6 which size bytes do
+            let mut fields = [0usize; 2];
+            for bit in 0..7 {
+                if cmd & (1 << bit) != 0 {
+                    let byte = *delta.get(pos).ok_or("Truncated delta")? as usize;
+                    pos += 1;
+                    let (field, shift) = if bit < 4 { (0, bit) } else { (1, bit - 4) };
+                    fields[field] |= byte << (8 * shift);
+                }
+            }
+            let [offset, mut len] = fields;
+            if len == 0 {
+                len = 0x10000;
+            }
+            let copy = base
+                .get(offset..offset + len)
+                .ok_or("Delta copies outside of its base")?;
+            result.extend_from_slice(copy);
+            if result.len() > result_size {
+                return Err("Delta result has the wrong size".to_string());
+            }
+        } else if cmd != 0 {
+            let insert = delta
+                .get(pos..pos + cmd as usize)
+                .ok_or("Truncated delta")?;
+            result.extend_from_slice(insert);
+            pos += cmd as usize;
+        } else {
+            return Err("Invalid delta instruction".to_string());
+        }
+    }
+    if result.len() != result_size {
+        return Err("Delta result has the wrong size".to_string());
+    }
+    Ok(result)
+}
+
+/// Smallest match worth a copy instruction in a delta
+const DELTA_BLOCK: usize = 16;
+
+/// Computes a delta turning `base` into `target`.
+///
+/// `base` is indexed by blocks of [`DELTA_BLOCK`] bytes; each position of
+/// `target` starting such a block is copied (extending the match as far as
+/// possible), everything else is inserted.
+pub(crate) fn delta_create(base: &[u8], target: &[u8]) -> Vec<u8> {
+    fn size(delta: &mut Vec<u8>, mut size: usize) {
+        while size >= 0x80 {
+            delta.push((size & 0x7f) as u8 | 0x80);
+            size >>= 7;
+        }
+        delta.push(size as u8);
+    }
+    fn flush(delta: &mut Vec<u8>, insert: &mut Vec<u8>) {
+        for chunk in insert.chunks(0x7f) {
+            delta.push(chunk.len() as u8);
+            delta.extend_from_slice(chunk);
+        }
+        insert.clear();
+    }
+
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(DELTA_BLOCK).enumerate().rev() {
+        blocks.insert(block, i * DELTA_BLOCK);
+    }
+
+    let mut delta = Vec::new();
+    size(&mut delta, base.len());
+    size(&mut delta, target.len());
+    let mut insert = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let found = target
+            .get(i..i + DELTA_BLOCK)
+            .and_then(|block| blocks.get(block));
+        let Some(&offset) = found else {
+            insert.push(target[i]);
+            i += 1;
+            continue;
+        };
+        let mut len = DELTA_BLOCK;
+        while offset + len < base.len()
+            && i + len < target.len()
+            && base[offset + len] == target[i + len]
+            && len < 0xff_ffff
+        {
+            len += 1;
+        }
+        flush(&mut delta, &mut insert);
+        // Only the non-zero bytes of the offset and size are stored
+        let mut cmd = 0x80;
+        let mut args = Vec::new();
+        for (bit, byte) in offset.to_le_bytes()[..4].iter().enumerate() {
+            if *byte != 0 {
+                cmd |= 1 << bit;
+                args.push(*byte);
+            }
+        }
+        for (bit, byte) in len.to_le_bytes()[..3].iter().enumerate() {
+            if *byte != 0 {
+                cmd |= 0x10 << bit;
+                args.push(*byte);
+            }
+        }
+        delta.push(cmd);
+        delta.extend_from_slice(&args);
+        i += len;
+    }
+    flush(&mut delta, &mut insert);
+    delta
+}
+
+/// An object to write in a pack
+#[derive(Debug)]
+pub(crate) struct PackObject {
+    pub(crate) sha: ObjectId,
+    pub(crate) obj_type: &'static str,
+    pub(crate) data: Vec<u8>,
+    /// Hash of the path the object was found at, so files with the same name
+    /// end up close to each other when looking for delta bases
+    pub(crate) name_hash: u32,
+}
+
+impl PackObject {
+    /// git's `pack_name_hash`: mostly made of the last characters of `path`
+    pub(crate) fn name_hash(path: &str) -> u32 {
+        path.bytes()
+            .filter(|ch| !ch.is_ascii_whitespace())
+            .fold(0u32, |hash, ch| (hash >> 2).wrapping_add((ch as u32) << 24))
+    }
+}
+
+/// Writes `objects` as `pack-<checksum>.pack` and its `.idx` in `dir`.
+///
+/// Each object is compared with the `window` objects before it (similar
+/// objects are sorted next to each other) and stored as a delta against
+/// the best one, unless that makes a chain longer than `depth`. Returns the
+/// name of the pack and the number of deltas.
+pub(crate) fn pack_write(
+    dir: &Path,
+    mut objects: Vec<PackObject>,
+    window: usize,
+    depth: usize,
+    format: ObjectFormat,
+) -> Result<(String, usize), OzError> {
+    objects.sort_by(|a, b| {
+        (a.obj_type, b.name_hash, b.data.len()).cmp(&(b.obj_type, a.name_hash, a.data.len()))
+    });
+
+    let mut pack = Vec::from(*b"PACK");
+    pack.extend_from_slice(&2u32.to_be_bytes());
+    pack.extend_from_slice(&(objects.len() as u32).to_be_bytes());
+    // `(sha, crc, offset)` of every object, and the depth of its delta chain
+    let mut entries: Vec<(ObjectId, u32, u64)> = Vec::with_capacity(objects.len());
+    let mut depths = vec![0; objects.len()];
+    let mut deltas = 0;
+    for (i, object) in objects.iter().enumerate() {
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        if object.data.len() >= 2 * DELTA_BLOCK {
+            for j in (i.saturating_sub(window)..i).rev() {
+                let base = &objects[j];
+                if base.obj_type != object.obj_type || depths[j] >= depth {
+                    continue;
+                }
+                let delta = delta_create(&base.data, &object.data);
+                let limit = best
+                    .as_ref()
+                    .map_or(object.data.len() / 2, |(_, best)| best.len());
+                if delta.len() < limit {
+                    best = Some((j, delta));
+                }
+            }
+        }
+
+        let offset = pack.len() as u64;
+        let (kind, data) = match &best {
+            Some((j, delta)) => {
+                depths[i] = depths[*j] + 1;
+                deltas += 1;
+                (6, delta.as_slice())
+            }
+            None => (type_code(object.obj_type), object.data.as_slice()),
+        };
+        let mut header = Vec::new();
+        let mut size = data.len();
+        let mut byte = (kind << 4) | (size & 0x0f) as u8;
+        size >>= 4;
+        while size > 0 {
+            header.push(byte | 0x80);
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        header.push(byte);
+        if let Some((j, _)) = &best {
+            // Distance to the base, see `Pack::entry`
+            let mut distance = offset - entries[*j].2;
+            let mut bytes = vec![(distance & 0x7f) as u8];
+            distance >>= 7;
+            while distance > 0 {
+                distance -= 1;
+                bytes.push(0x80 | (distance & 0x7f) as u8);
+                distance >>= 7;
+            }
+            bytes.reverse();
+            header.extend_from_slice(&bytes);
+        }
+        let mut zlib = ZlibEncoder::new(header, Compression::default());
+        zlib.write_all(data)?;
+        let entry = zlib.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(&entry);
+        pack.extend_from_slice(&entry);
+        entries.push((object.sha, crc.sum(), offset));
+    }
+    let checksum = format.digest(&pack);
+    pack.extend_from_slice(&checksum);
+
+    entries.sort();
+    let mut index = Vec::from(*b"\xfftOc");
+    index.extend_from_slice(&2u32.to_be_bytes());
+    for byte in 0..=255u8 {
+        let count = entries.partition_point(|(sha, _, _)| sha.as_bytes()[0] <= byte);
+        index.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+    for (sha, _, _) in &entries {
+        if sha.format() != format {
+            return Err(OzError::InvalidArgument(format!(
+                "{sha} is not a {} id",
+                format.name()
+            )));
+        }
+        index.extend_from_slice(sha.as_bytes());
+    }
+    for (_, crc, _) in &entries {
+        index.extend_from_slice(&crc.to_be_bytes());
+    }
+    let mut large = Vec::new();
+    for (_, _, offset) in &entries {
+        let offset = match u32::try_from(*offset) {
+            Ok(offset) if offset < 0x8000_0000 => offset,
+            _ => {
+                large.push(*offset);
+                0x8000_0000 | (large.len() - 1) as u32
+            }
+        };
+        index.extend_from_slice(&offset.to_be_bytes());
+    }
+    for offset in large {
+        index.extend_from_slice(&offset.to_be_bytes());
+    }
+    index.extend_from_slice(&checksum);
+    let index_checksum = format.digest(&index);
+    index.extend_from_slice(&index_checksum);
+
+    // The index goes last: a pack is only used once its index exists
+    let name = format!("pack-{}", hex(&checksum));
+    fs::create_dir_all(dir)?;
+    for (ext, data) in [("pack", &pack), ("idx", &index)] {
+        let path = dir.join(format!("{name}.{ext}"));
+        let tmp = dir.join(format!("tmp_{name}.{ext}"));
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+    }
+    Ok((name, deltas))
+}
+
+fn type_code(obj_type: &str) -> u8 {
+    match obj_type {
+        "commit" => 1,
+        "tree" => 2,
+        "blob" => 3,
+        _ => 4,
+    }
+}
+
+/// An inflated object: its type and content
+pub(crate) type PackedObject = (&'static str, Rc<Vec<u8>>);
+
+/// Keeps the recently used delta bases, as long as they fit in the budget.
+/// The oldest ones are dropped first.
+#[derive(Debug)]
+pub(crate) struct DeltaBaseCache {
+    entries: HashMap<(usize, u64), PackedObject>,
+    order: VecDeque<(usize, u64)>,
+    size: usize,
+    budget: usize,
+}
+
+impl DeltaBaseCache {
+    pub(crate) fn new(budget: usize) -> Self {
+        DeltaBaseCache {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            size: 0,
+            budget,
+        }
+    }
+
+    /// Looks up the object at `offset` in the `pack`th pack
+    pub(crate) fn get(&self, pack: usize, offset: u64) -> Option<PackedObject> {
+        self.entries.get(&(pack, offset)).cloned()
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        pack: usize,
+        offset: u64,
+        obj_type: &'static str,
+        data: Rc<Vec<u8>>,
+    ) {
+        if data.len() > self.budget || self.entries.contains_key(&(pack, offset)) {
+            return;
+        }
+        self.size += data.len();
+        self.entries.insert((pack, offset), (obj_type, data));
+        self.order.push_back((pack, offset));
+        while self.size > self.budget {
+            let Some(key) = self.order.pop_front() else {
+                break;
+            };
+            if let Some((_, data)) = self.entries.remove(&key) {
+                self.size -= data.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_copies_and_inserts() {
+        let base = b"hello world, hello oz";
+        // base size 21, result size 17, copy 6 bytes at 0, insert "git", copy 8 at 13
+        let delta = [21, 17, 0x90, 6, 3, b'g', b'i', b't', 0x91, 13, 8];
+        assert_eq!(delta_apply(base, &delta).unwrap(), b"hello githello oz");
+        assert!(delta_apply(b"short", &delta).is_err());
+        assert!(delta_apply(base, &[21, 1, 0]).is_err());
+        // Sizes too big for any buffer are refused, not allocated
+        let huge = [
+            21, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
+        ];
+        assert!(delta_apply(base, &huge).is_err());
+        assert!(delta_apply(base, &[0x80; 12]).is_err());
+    }
+
+    #[test]
+    fn created_deltas_apply() {
+        let base: Vec<u8> = (0..5000u32).flat_map(|i| (i % 251).to_le_bytes()).collect();
+        let mut target = base[100..9000].to_vec();
+        target.extend_from_slice(b"something new");
+        target.extend_from_slice(&base[..70000.min(base.len())]);
+        let delta = delta_create(&base, &target);
+        assert!(delta.len() < 100);
+        assert_eq!(delta_apply(&base, &delta).unwrap(), target);
+        for (base, target) in [(&b""[..], &b"abc"[..]), (b"abc", b""), (b"same", b"same")] {
+            assert_eq!(
+                delta_apply(base, &delta_create(base, target)).unwrap(),
+                target
+            );
+        }
+    }
+
+    #[test]
+    fn written_packs_can_be_read() {
+        let dir = std::env::temp_dir().join(format!("oz-test-{}-pack", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let text: Vec<u8> = (0..200)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .collect();
+        let mut objects = Vec::new();
+        for i in 0..5 {
+            let mut data = text.clone();
+            data.extend_from_slice(format!("version {i}\n").as_bytes());
+            objects.push(PackObject {
+                sha: format!("{i:02x}").repeat(20).parse().unwrap(),
+                obj_type: "blob",
+                data,
+                name_hash: PackObject::name_hash("file.txt"),
+            });
+        }
+        let expected: Vec<_> = objects.iter().map(|o| (o.sha, o.data.clone())).collect();
+        let (name, deltas) = pack_write(&dir, objects, 10, 2, ObjectFormat::Sha1).unwrap();
+        assert_eq!(deltas, 4);
+
+        let pack = Pack::open(&dir.join(format!("{name}.idx")), ObjectFormat::Sha1).unwrap();
+        assert_eq!(pack.len(), 5);
+        for (sha, data) in expected {
+            let mut offset = pack.find(&sha).unwrap();
+            let mut chain = Vec::new();
+            let base = loop {
+                match pack.entry(offset).unwrap() {
+                    (PackEntry::Whole(obj_type), base) => {
+                        assert_eq!(obj_type, "blob");
+                        break base;
+                    }
+                    (PackEntry::OfsDelta(base), delta) => {
+                        chain.push(delta);
+                        offset = base;
+                    }
+                    (PackEntry::RefDelta(_), _) => panic!("only offset deltas are written"),
+                }
+            };
+            assert!(chain.len() <= 2);
+            let object = chain
+                .iter()
+                .rev()
+                .fold(base, |base, delta| delta_apply(&base, delta).unwrap());
+            assert_eq!(object, data);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_packs_are_errors() {
+        let dir = std::env::temp_dir().join(format!("oz-test-{}-pack-corrupt", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let objects = (0..3)
+            .map(|i| PackObject {
+                sha: format!("{:02x}", i * 0x70).repeat(20).parse().unwrap(),
+                obj_type: "blob",
+                data: format!("blob {i}\n").into_bytes(),
+                name_hash: 0,
+            })
+            .collect();
+        let (name, _) = pack_write(&dir, objects, 10, 2, ObjectFormat::Sha1).unwrap();
+        let idx = dir.join(format!("{name}.idx"));
+        let data = fs::read(&idx).unwrap();
+        let reopen = |data: &[u8]| {
+            fs::write(&idx, data).unwrap();
+            Pack::open(&idx, ObjectFormat::Sha1)
+        };
+
+        // A fanout that goes down
+        let mut bad = data.clone();
+        bad[8..12].copy_from_slice(&3u32.to_be_bytes());
+        assert!(matches!(reopen(&bad), Err(OzError::CorruptFile { .. })));
+        // An offset into a large offset table that isn't there
+        let mut bad = data.clone();
+        let offsets = 8 + 256 * 4 + 3 * 24;
+        bad[offsets..offsets + 4].copy_from_slice(&0x8000_0000u32.to_be_bytes());
+        assert!(matches!(reopen(&bad), Err(OzError::CorruptFile { .. })));
+
+        // An object whose size doesn't fit in 64 bits
+        let pack = reopen(&data).unwrap();
+        let offset = pack.offset(0);
+        let file = fs::OpenOptions::new().write(true).open(&pack.path).unwrap();
+        file.write_all_at(&[0xbf; 12], offset).unwrap();
+        assert!(matches!(
+            pack.entry(offset),
+            Err(OzError::CorruptFile { .. })
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_drops_the_oldest_bases() {
+        let mut cache = DeltaBaseCache::new(10);
+        cache.insert(0, 1, "blob", Rc::new(vec![0; 6]));
+        cache.insert(0, 2, "blob", Rc::new(vec![0; 4]));
+        assert!(cache.get(0, 1).is_some());
+        cache.insert(1, 1, "tree", Rc::new(vec![0; 3]));
+        assert!(cache.get(0, 1).is_none());
+        assert_eq!(cache.get(1, 1).map(|(obj_type, _)| obj_type), Some("tree"));
+        cache.insert(0, 3, "blob", Rc::new(vec![0; 11]));
+        assert!(cache.get(0, 3).is_none());
+    }
+}
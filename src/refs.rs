@@ -0,0 +1,485 @@
+use crate::{
+    error::OzError,
+    git_repo::{GitObject, GitRepository},
+    hash::ObjectId,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// What a ref file holds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RefValue {
+    /// The id of an object
+    Direct(ObjectId),
+    /// `ref: <name>`, pointing to another ref
+    Symbolic(String),
+}
+
+/// Content of the `packed-refs` file: `name -> (sha, peeled sha)`. The peeled
+/// sha is the object an annotated tag eventually points to.
+pub(crate) type PackedRefs = BTreeMap<String, (ObjectId, Option<ObjectId>)>;
+
+/// A `<file>.lock` that replaces `<file>` when committed, and is removed
+/// otherwise. Creating the lock fails when someone else holds it.
+pub(crate) struct LockFile {
+    path: PathBuf,
+    lock: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl LockFile {
+    pub(crate) fn new(path: PathBuf) -> Result<Self, OzError> {
+        let mut lock = path.clone().into_os_string();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        if let Some(dir) = lock.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let Ok(file) = fs::File::create_new(&lock) else {
+            return Err(OzError::Locked(lock));
+        };
+        Ok(LockFile {
+            path,
+            lock,
+            file: Some(file),
+        })
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> Result<(), OzError> {
+        let file = self.file.as_mut().ok_or_else(already_committed)?;
+        Ok(file.write_all(data)?)
+    }
+
+    /// Atomically moves the new content in place
+    pub(crate) fn commit(mut self) -> Result<(), OzError> {
+        let file = self.file.take().ok_or_else(already_committed)?;
+        file.sync_all()?;
+        drop(file);
+        Ok(fs::rename(&self.lock, &self.path)?)
+    }
+}
+
+fn already_committed() -> OzError {
+    OzError::Refused("Lock file already committed".to_string())
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = fs::remove_file(&self.lock);
+        }
+    }
+}
+
+/// Checks `name` against git's rules for ref names (see `git check-ref-format`)
+pub(crate) fn check_ref_name(name: &str) -> Result<(), OzError> {
+    let invalid = name.is_empty()
+        || name == "@"
+        || name.contains("..")
+        || name.contains("@{")
+        || name.contains("//")
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with('.')
+        || name
+            .bytes()
+            .any(|ch| ch < 0x20 || ch == 0x7f || b" ~^:?*[\\".contains(&ch))
+        || name
+            .split('/')
+            .any(|part| part.starts_with('.') || part.ends_with(".lock"));
+    if invalid {
+        return Err(OzError::InvalidArgument(format!(
+            "'{name}' is not a valid ref name"
+        )));
+    }
+    Ok(())
+}
+
+/// Refs live in the git dir: `HEAD`-like names at the top, the others below refs/
+fn ref_path(repo: &GitRepository, name: &str) -> Result<PathBuf, OzError> {
+    let is_root_ref = name.bytes().all(|ch| ch.is_ascii_uppercase() || ch == b'_');
+    if !is_root_ref && !name.starts_with("refs/") {
+        return Err(OzError::InvalidArgument(format!(
+            "'{name}' is not a valid ref name"
+        )));
+    }
+    if !is_root_ref {
+        check_ref_name(name)?;
+    }
+    Ok(repo.repo_file(name.into()))
+}
+
+/// Reads `packed-refs`, returning an empty list when there is none.
+pub(crate) fn packed_read(repo: &GitRepository) -> Result<PackedRefs, OzError> {
+    let mut refs = PackedRefs::new();
+    let path = repo.repo_file("packed-refs".into());
+    let Ok(data) = fs::read_to_string(&path) else {
+        return Ok(refs);
+    };
+    let malformed = |line: &str| OzError::corrupt_file(&path, format!("malformed line: {line}"));
+    let mut last: Option<String> = None;
+    for line in data.lines() {
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            let entry = last
+                .as_ref()
+                .and_then(|name| refs.get_mut(name))
+                .ok_or_else(|| OzError::corrupt_file(&path, "peeled line without a ref"))?;
+            entry.1 = Some(peeled.parse().map_err(|_| malformed(line))?);
+            continue;
+        }
+        let (sha, name) = line.split_once(' ').ok_or_else(|| malformed(line))?;
+        let sha = sha.parse().map_err(|_| malformed(line))?;
+        refs.insert(name.to_string(), (sha, None));
+        last = Some(name.to_string());
+    }
+    Ok(refs)
+}
+
+/// Replaces `packed-refs`, going through `packed-refs.lock`
+pub(crate) fn packed_write(repo: &GitRepository, refs: &PackedRefs) -> Result<(), OzError> {
+    let mut data = String::from("# pack-refs with: peeled fully-peeled sorted \n");
+    for (name, (sha, peeled)) in refs {
+        data += &format!("{sha} {name}\n");
+        if let Some(peeled) = peeled {
+            data += &format!("^{peeled}\n");
+        }
+    }
+    let mut lock = LockFile::new(repo.repo_file("packed-refs".into()))?;
+    lock.write(data.as_bytes())?;
+    lock.commit()
+}
+
+/// Reads a single ref without following symbolic refs. Loose refs take
+/// precedence over packed ones.
+pub(crate) fn read(repo: &GitRepository, name: &str) -> Result<Option<RefValue>, OzError> {
+    let path = ref_path(repo, name)?;
+    if path.is_file() {
+        let data = fs::read_to_string(&path)?;
+        let data = data.trim();
+        return Ok(Some(match data.strip_prefix("ref: ") {
+            Some(target) => RefValue::Symbolic(target.trim().to_string()),
+            None => RefValue::Direct(
+                data.parse()
+                    .map_err(|_| OzError::corrupt_file(&path, "not a ref"))?,
+            ),
+        }));
+    }
+    if path.is_dir() {
+        return Ok(None);
+    }
+    Ok(packed_read(repo)?
+        .remove(name)
+        .map(|(sha, _)| RefValue::Direct(sha)))
+}
+
+/// Follows symbolic refs from `name`. Returns the name of the last ref of the
+/// chain, and its sha when it exists.
+pub(crate) fn resolve_name(
+    repo: &GitRepository,
+    name: &str,
+) -> Result<(String, Option<ObjectId>), OzError> {
+    let mut seen = HashSet::new();
+    let mut name = name.to_string();
+    loop {
+        if !seen.insert(name.clone()) {
+            return Err(OzError::corrupt_file(
+                repo.repo_file(name.into()),
+                "symbolic ref loop",
+            ));
+        }
+        match read(repo, &name)? {
+            Some(RefValue::Symbolic(target)) => name = target,
+            Some(RefValue::Direct(sha)) => return Ok((name, Some(sha))),
+            None => return Ok((name, None)),
+        }
+    }
+}
+
+/// Returns the object `name` points to, following symbolic refs.
+pub(crate) fn resolve(repo: &GitRepository, name: &str) -> Result<Option<ObjectId>, OzError> {
+    resolve_name(repo, name).map(|(_, sha)| sha)
+}
+
+/// The branch HEAD points to (without `refs/heads/`), `None` when detached.
+pub(crate) fn current_branch(repo: &GitRepository) -> Result<Option<String>, OzError> {
+    Ok(match read(repo, "HEAD")? {
+        Some(RefValue::Symbolic(target)) => Some(
+            target
+                .strip_prefix("refs/heads/")
+                .unwrap_or(&target)
+                .to_string(),
+        ),
+        _ => None,
+    })
+}
+
+/// The object the annotated tag `sha` (stored as `name`) eventually points
+/// to, `None` when it is not a tag. Uses the peeled value of `packed-refs`
+/// when it is known.
+pub(crate) fn peel(
+    repo: &GitRepository,
+    name: &str,
+    sha: &ObjectId,
+) -> Result<Option<ObjectId>, OzError> {
+    if let Some((packed, Some(peeled))) = packed_read(repo)?.remove(name)
+        && packed == *sha
+    {
+        return Ok(Some(peeled));
+    }
+    let mut peeled = None;
+    let mut target = *sha;
+    loop {
+        let tag = match repo.object_read(&target) {
+            Ok(GitObject::Tag(tag)) => tag,
+            Ok(_) | Err(OzError::ObjectNotFound(_)) => break,
+            Err(e) => return Err(e),
+        };
+        target = tag
+            .object()
+            .ok_or_else(|| OzError::corrupt_object("missing object").in_object(&target))?;
+        peeled = Some(target);
+    }
+    Ok(peeled)
+}
+
+/// Lists the refs whose name starts with `prefix`, loose and packed, sorted
+/// by name, as `(name, sha)`. Symbolic refs are resolved, and dangling ones
+/// skipped.
+pub(crate) fn list(repo: &GitRepository, prefix: &str) -> Result<Vec<(String, ObjectId)>, OzError> {
+    fn walk(dir: &Path, name: &str, names: &mut Vec<String>) -> Result<(), OzError> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Ok(());
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let name = format!("{name}/{file_name}");
+            if entry.file_type()?.is_dir() {
+                walk(&entry.path(), &name, names)?;
+            } else if !file_name.ends_with(".lock") {
+                names.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    let mut names = Vec::new();
+    walk(&repo.repo_file("refs".into()), "refs", &mut names)?;
+    let mut refs: BTreeMap<String, ObjectId> = BTreeMap::new();
+    for (name, (sha, _)) in packed_read(repo)? {
+        refs.insert(name, sha);
+    }
+    for name in names {
+        match resolve(repo, &name)? {
+            Some(sha) => refs.insert(name, sha),
+            None => refs.remove(&name),
+        };
+    }
+    Ok(refs
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(prefix))
+        .collect())
+}
+
+/// Points `name` (or the ref it symbolically points to) at `sha`.
+///
+/// When `old` is given the update only happens if the ref currently has
+/// that value, `Some(None)` meaning the ref must not exist yet.
+pub(crate) fn update(
+    repo: &GitRepository,
+    name: &str,
+    sha: &ObjectId,
+    old: Option<Option<&ObjectId>>,
+) -> Result<(), OzError> {
+    let (name, _) = resolve_name(repo, name)?;
+    let mut lock = LockFile::new(ref_path(repo, &name)?)?;
+    // Read the value once nobody else can change it
+    let current = resolve(repo, &name)?;
+    if let Some(old) = old
+        && current.as_ref() != old
+    {
+        return Err(OzError::Refused(format!(
+            "{name} has been changed meanwhile"
+        )));
+    }
+    lock.write(format!("{sha}\n").as_bytes())?;
+    lock.commit()
+}
+
+/// Points `name` itself at `sha`, even when it is a symbolic ref, e.g. to
+/// detach HEAD
+pub(crate) fn write_direct(
+    repo: &GitRepository,
+    name: &str,
+    sha: &ObjectId,
+) -> Result<(), OzError> {
+    let mut lock = LockFile::new(ref_path(repo, name)?)?;
+    lock.write(format!("{sha}\n").as_bytes())?;
+    lock.commit()
+}
+
+/// Makes `name` a symbolic ref to `target`
+pub(crate) fn write_symbolic(
+    repo: &GitRepository,
+    name: &str,
+    target: &str,
+) -> Result<(), OzError> {
+    check_ref_name(target)?;
+    let mut lock = LockFile::new(ref_path(repo, name)?)?;
+    lock.write(format!("ref: {target}\n").as_bytes())?;
+    lock.commit()
+}
+
+/// Removes the directories left empty above the ref file `path`, keeping
+/// refs/heads/ and the like
+fn remove_empty_dirs(repo: &GitRepository, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if dir.parent() == Some(&repo.repo_file("refs".into())) || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+/// Deletes `name`, both loose and packed, returning the object it pointed to.
+pub(crate) fn delete(repo: &GitRepository, name: &str) -> Result<Option<ObjectId>, OzError> {
+    let path = ref_path(repo, name)?;
+    let sha = match read(repo, name)? {
+        Some(RefValue::Direct(sha)) => Some(sha),
+        Some(RefValue::Symbolic(_)) | None => resolve(repo, name)?,
+    };
+
+    let lock = LockFile::new(path.clone())?;
+    let mut packed = packed_read(repo)?;
+    if packed.remove(name).is_some() {
+        packed_write(repo, &packed)?;
+    }
+    if path.is_file() {
+        fs::remove_file(&path)?;
+    }
+    drop(lock);
+    remove_empty_dirs(repo, &path);
+    Ok(sha)
+}
+
+/// Moves every loose ref under refs/ into `packed-refs`, recording the peeled
+/// value of annotated tags. Symbolic refs stay loose, and so do the refs
+/// someone else holds the lock of.
+pub(crate) fn pack(repo: &GitRepository) -> Result<usize, OzError> {
+    let mut packed = packed_read(repo)?;
+    let mut loose = Vec::new();
+    for (name, _) in list(repo, "refs/")? {
+        let path = repo.repo_file((&name).into());
+        if !path.is_file() {
+            continue;
+        }
+        // Held until the loose ref is gone, so that no update is lost
+        let Ok(lock) = LockFile::new(path.clone()) else {
+            continue;
+        };
+        if let Some(RefValue::Direct(sha)) = read(repo, &name)? {
+            let peeled = peel(repo, &name, &sha)?;
+            packed.insert(name, (sha, peeled));
+            loose.push((path, lock));
+        }
+    }
+    packed_write(repo, &packed)?;
+    let count = loose.len();
+    for (path, lock) in loose {
+        fs::remove_file(&path)?;
+        drop(lock);
+        remove_empty_dirs(repo, &path);
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::tests::temp_repo;
+
+    #[test]
+    fn ref_names_follow_check_ref_format() {
+        for name in ["refs/tags/v1.0", "refs/heads/feature/x", "refs/heads/a-b_c"] {
+            assert!(check_ref_name(name).is_ok(), "{name}");
+        }
+        for name in [
+            "refs/tags/a..b",
+            "refs/heads/.hidden",
+            "refs/heads/x.lock",
+            "refs/heads/a b",
+            "refs/heads/a~1",
+            "refs/heads/x/",
+            "refs/heads//x",
+            "refs/heads/x@{1}",
+            "@",
+        ] {
+            assert!(check_ref_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn loose_refs_shadow_packed_refs() {
+        let repo = temp_repo("refs");
+        let [a, b, c]: [ObjectId; 3] = ["a", "b", "c"].map(|ch| ch.repeat(40).parse().unwrap());
+        let mut packed = PackedRefs::new();
+        packed.insert("refs/heads/master".into(), (a, None));
+        packed.insert("refs/tags/v1".into(), (b, Some(c)));
+        packed_write(&repo, &packed).unwrap();
+        assert_eq!(packed_read(&repo).unwrap(), packed);
+
+        assert_eq!(resolve(&repo, "HEAD").unwrap(), Some(a));
+        update(&repo, "HEAD", &c, Some(Some(&a))).unwrap();
+        assert!(update(&repo, "HEAD", &b, Some(Some(&a))).is_err());
+        assert!(update(&repo, "refs/heads/new", &b, Some(Some(&a))).is_err());
+        update(&repo, "refs/heads/new", &b, Some(None)).unwrap();
+        delete(&repo, "refs/heads/new").unwrap();
+        assert_eq!(
+            read(&repo, "refs/heads/master").unwrap(),
+            Some(RefValue::Direct(c))
+        );
+        assert_eq!(
+            list(&repo, "refs/").unwrap(),
+            vec![
+                ("refs/heads/master".to_string(), c),
+                ("refs/tags/v1".to_string(), b),
+            ]
+        );
+
+        assert_eq!(delete(&repo, "refs/tags/v1").unwrap(), Some(b));
+        assert_eq!(list(&repo, "refs/tags/").unwrap(), vec![]);
+        assert_eq!(current_branch(&repo).unwrap(), Some("master".to_string()));
+
+        write_symbolic(&repo, "refs/heads/loop", "refs/heads/other").unwrap();
+        write_symbolic(&repo, "refs/heads/other", "refs/heads/loop").unwrap();
+        assert!(resolve(&repo, "refs/heads/loop").is_err());
+    }
+
+    #[test]
+    fn pack_leaves_locked_refs_loose() {
+        let repo = temp_repo("refs-pack");
+        let [a, b]: [ObjectId; 2] = ["a", "b"].map(|ch| ch.repeat(40).parse().unwrap());
+        update(&repo, "refs/heads/master", &a, None).unwrap();
+        update(&repo, "refs/heads/busy", &b, None).unwrap();
+        let lock = LockFile::new(repo.repo_file("refs/heads/busy".into())).unwrap();
+
+        assert_eq!(pack(&repo).unwrap(), 1);
+        assert!(!repo.repo_file("refs/heads/master".into()).exists());
+        assert!(repo.repo_file("refs/heads/busy".into()).is_file());
+        assert!(!packed_read(&repo).unwrap().contains_key("refs/heads/busy"));
+        drop(lock);
+        assert_eq!(
+            list(&repo, "refs/").unwrap(),
+            vec![
+                ("refs/heads/busy".to_string(), b),
+                ("refs/heads/master".to_string(), a),
+            ]
+        );
+    }
+}
@@ -0,0 +1,168 @@
+use crate::{
+    diff::{DiffOptions, combined_patch, tree_diff},
+    error::OzError,
+    git_repo::{GitCommit, GitObject, GitRepository, GitTag},
+    hash::ObjectId,
+    log::{DEFAULT_DATE, LogFormat, format_date},
+};
+use std::collections::HashSet;
+
+/// The text `git show` prints for the revisions `revs`: commits with their
+/// patch, tags followed by what they point to, tree listings and raw blobs
+pub(crate) fn show(
+    repo: &GitRepository,
+    revs: &[String],
+    options: &DiffOptions,
+) -> Result<Vec<u8>, OzError> {
+    let mut out = Vec::new();
+    // Objects after the first one are set apart by a blank line, except blobs
+    let mut shown_one = false;
+    // Like git, each commit is only shown once, even when tags point to it
+    let mut commits = HashSet::new();
+    for rev in revs {
+        let mut id = repo.rev_parse(rev)?;
+        loop {
+            let object = repo.object_read(&id)?;
+            if matches!(object, GitObject::Commit(_)) && !commits.insert(id) {
+                break;
+            }
+            if !matches!(object, GitObject::Blob(_)) && shown_one {
+                out.push(b'\n');
+            }
+            match object {
+                GitObject::Blob(blob) => out.extend_from_slice(&blob.buffer),
+                GitObject::Commit(commit) => {
+                    out.extend(show_commit(repo, &id, &commit, options)?);
+                    shown_one = true;
+                }
+                GitObject::Tag(tag) => {
+                    out.extend(show_tag(&tag).bytes());
+                    shown_one = true;
+                    id = tag.object().ok_or_else(|| {
+                        OzError::corrupt_object("tag without object").in_object(&id)
+                    })?;
+                    continue;
+                }
+                GitObject::Tree(tree) => {
+                    out.extend(format!("tree {rev}\n\n").bytes());
+                    for entry in tree.entries() {
+                        out.extend_from_slice(&entry.name);
+                        if entry.mode.is_tree() {
+                            out.push(b'/');
+                        }
+                        out.push(b'\n');
+                    }
+                    shown_one = true;
+                }
+            }
+            break;
+        }
+    }
+    Ok(out)
+}
+
+/// The log entry of the commit, then its changes: against its parent, the
+/// empty tree for a root commit, or combined against all parents of a merge
+fn show_commit(
+    repo: &GitRepository,
+    id: &ObjectId,
+    commit: &GitCommit,
+    options: &DiffOptions,
+) -> Result<Vec<u8>, OzError> {
+    let mut out = LogFormat::Medium.render(id, commit).into_bytes();
+    out.push(b'\n');
+    let Some(tree) = commit.tree() else {
+        return Ok(out);
+    };
+    let parents = commit
+        .parents()
+        .into_iter()
+        .map(|parent| repo.object_peel_to(parent, "tree"))
+        .collect::<Result<Vec<_>, _>>()?;
+    let patch = match parents.as_slice() {
+        [] => tree_diff(repo, None, Some(&tree), options)?.patch()?,
+        [parent] => tree_diff(repo, Some(parent), Some(&tree), options)?.patch()?,
+        _ => combined_patch(repo, &parents, &tree, options)?,
+    };
+    // Like git, merges get the blank line even without changes to show
+    if !patch.is_empty() || parents.len() > 1 {
+        out.push(b'\n');
+        out.extend(patch);
+    }
+    Ok(out)
+}
+
+fn show_tag(tag: &GitTag) -> String {
+    let mut text = format!("tag {}\n", tag.name().unwrap_or_default());
+    if let Some(tagger) = tag.tagger() {
+        text += &format!("Tagger: {} <{}>\n", tagger.name, tagger.email);
+        text += &format!("Date:   {}\n", format_date(&tagger, DEFAULT_DATE));
+    }
+    text.push('\n');
+    text += &tag.message();
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_repo::{
+        FileMode, GitTree, Signature, TreeEntry,
+        tests::{commit, temp_repo, write_blob},
+    };
+
+    #[test]
+    fn show_peels_tags_and_shows_commits_once() {
+        let repo = temp_repo("show-tag");
+        let root = commit(&repo, &[("a", "one\n")], &[], 0, "root\n");
+        let tagger = Signature {
+            name: "T".into(),
+            email: "t@b".into(),
+            time: 0,
+            offset: 0,
+        };
+        let tag = GitTag::new(root, "commit", "v1", &tagger, "release\n");
+        let tag = GitRepository::object_write(Some(&repo), GitObject::Tag(tag)).unwrap();
+
+        let revs = [tag.to_string(), root.to_string()];
+        let out = String::from_utf8(show(&repo, &revs, &DiffOptions::default()).unwrap()).unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "tag v1\nTagger: T <t@b>\nDate:   Thu Jan 1 00:00:00 1970 +0000\n\nrelease\n\n\
+                 commit {root}\nAuthor: A <a@b>\nDate:   Thu Jan 1 01:00:00 1970 +0100\n\n    root\n\n\
+                 diff --git a/a b/a\nnew file mode 100644\nindex 0000000..5626abf\n\
+                 --- /dev/null\n+++ b/a\n@@ -0,0 +1 @@\n+one\n"
+            )
+        );
+    }
+
+    #[test]
+    fn show_lists_trees_and_prints_blobs_raw() {
+        let repo = temp_repo("show-tree");
+        let blob = write_blob(&repo, b"\xff\xfe raw\n");
+        let dir = GitTree {
+            items: vec![TreeEntry::new(FileMode::Blob, "file", blob)],
+        };
+        let dir = GitRepository::object_write(Some(&repo), GitObject::Tree(dir)).unwrap();
+        let tree = GitTree {
+            items: vec![
+                TreeEntry::new(FileMode::Tree, "dir", dir),
+                TreeEntry::new(FileMode::Blob, &b"n\xe9"[..], blob),
+            ],
+        };
+        let tree = GitRepository::object_write(Some(&repo), GitObject::Tree(tree)).unwrap();
+
+        let revs = [tree.to_string(), blob.to_string(), dir.to_string()];
+        let out = show(&repo, &revs, &DiffOptions::default()).unwrap();
+        // Blobs aren't set apart from what comes before them
+        let expected = [
+            format!("tree {tree}\n\ndir/\nn").as_bytes(),
+            b"\xe9\n",
+            b"\xff\xfe raw\n",
+            format!("\ntree {dir}\n\nfile\n").as_bytes(),
+        ]
+        .concat();
+        assert_eq!(out, expected);
+    }
+}